@@ -0,0 +1,92 @@
+// Standard
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+// Project
+use common::{
+    net,
+    util::msg::{
+        AccountRequest, ClientMsg, ClientPostOffice, Credentials, LoginToken, ServerMsg, SessionKind, Version,
+    },
+};
+
+// Local
+use crate::{Error, ServerKey, CONNECT_TIMEOUT};
+
+// Constants
+// Where `login-cli` keeps the token of the account it logged in to, for the clients to connect with
+pub const LOGIN_FILE: &str = "./login.token";
+
+fn request<S: ToSocketAddrs>(remote_addr: S, request: AccountRequest) -> Result<LoginToken, Error> {
+    let remote_addr = remote_addr
+        .to_socket_addrs()
+        .map_err(net::Error::from)?
+        .collect::<Vec<SocketAddr>>();
+    let postoffice = ClientPostOffice::to_server(&remote_addr[..])?;
+    // The password is only sent encrypted, to the server that had the address before
+    ServerKey::Remembered.secure(&postoffice)?;
+
+    let pb = postoffice.create_postbox(SessionKind::Account);
+    let _ = pb.send(ClientMsg::Account {
+        version: Version::current(),
+        request,
+    });
+    match pb.recv_timeout(CONNECT_TIMEOUT)? {
+        ServerMsg::AccountResult { result } => result.map_err(Error::Account),
+        ServerMsg::Disconnect { reason } => Err(Error::Disconnected(reason)),
+        _ => Err(Error::InvalidResponse),
+    }
+}
+
+/// Register an account on the server, returns a token to connect to it with
+pub fn register<S: ToSocketAddrs>(remote_addr: S, account: &str, password: &str) -> Result<LoginToken, Error> {
+    request(
+        remote_addr,
+        AccountRequest::Register {
+            account: account.to_string(),
+            password: password.to_string(),
+        },
+    )
+}
+
+/// Log in to an account on the server, returns a token to connect to it with
+pub fn login<S: ToSocketAddrs>(remote_addr: S, account: &str, password: &str) -> Result<LoginToken, Error> {
+    request(
+        remote_addr,
+        AccountRequest::Login {
+            account: account.to_string(),
+            password: password.to_string(),
+        },
+    )
+}
+
+/// Keep the token in `LOGIN_FILE`, as the account name and the token in hex on a line each. Only the user may read it
+pub fn store_login(account: &str, token: &LoginToken) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(LOGIN_FILE)?;
+    // The mode only applies to new files, a token file from before may be readable by others
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    write!(file, "{}\n{}\n", account, token.to_hex())
+}
+
+/// The credentials kept by `store_login`, if there are any
+pub fn stored_login() -> Option<Credentials> {
+    let login = fs::read_to_string(LOGIN_FILE).ok()?;
+    let mut lines = login.lines();
+    let account = lines.next()?.trim().to_string();
+    let token = LoginToken::from_hex(lines.next()?.trim())?;
+    Some(Credentials::Token { account, token })
+}
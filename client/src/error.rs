@@ -2,12 +2,19 @@
 use std::sync::mpsc;
 
 // Project
-use common::net;
+use common::{
+    net,
+    util::msg::{AccountError, DisconnectReason},
+};
 
 #[derive(Debug)]
 pub enum Error {
     InvalidResponse,
+    Disconnected(DisconnectReason),
+    Account(AccountError),
     AlreadyRunning,
+    // The server couldn't prove it's the one expected, see `ServerKey`
+    UnexpectedServerKey,
     MpscRecvErr(mpsc::RecvError),
     MpscRecvTimeoutErr(mpsc::RecvTimeoutError),
     MpscSendErr,
@@ -6,23 +6,34 @@ extern crate world as world_crate; // TODO: Fix this naming conflict
 extern crate log;
 
 // Modules
+pub mod account;
 mod error;
 mod music;
 mod net;
 mod player;
+mod server_key;
 mod tick;
 mod world;
 
 // Reexport
-pub use common::util::msg::PlayMode;
+pub use crate::{
+    error::Error,
+    server_key::{ServerKey, KNOWN_SERVERS_FILE},
+};
+pub use common::util::msg::{Credentials, DisconnectReason, PlayMode};
 
 // Standard
 use std::{
+    cmp::min,
     collections::HashMap,
     mem,
-    net::ToSocketAddrs,
-    sync::{atomic::Ordering, Arc},
-    time::Duration,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 // Library
@@ -33,17 +44,23 @@ use vek::*;
 use common::{
     audio::{AudioGen, AudioMgr, Buffer},
     get_asset_path,
+    net::{NetStats, Transport},
     terrain::{chunk::ChunkContainer, ChunkMgr, Entity, FnDropFunc, FnGenFunc, VolGen, VolOffs, VoxRel},
     util::{
         clock::Clock,
         manager::{Managed, Manager},
-        msg::{ClientMsg, ClientPostOffice, ServerMsg, SessionKind},
+        msg::{
+            ClientMsg, ClientPostOffice, ResumeToken, ServerMsg, SessionKind, Version, FEATURE_LZ4, FEATURE_UDP,
+            INPUT_DT,
+        },
+        prediction::Interpolation,
+        snapshot::SnapshotReceiver,
     },
     Uid,
 };
 
 // Local
-use crate::{error::Error, player::Player};
+use crate::player::Player;
 
 // Reexports
 pub use common::terrain::chunk::CHUNK_SIZE;
@@ -55,6 +72,40 @@ pub const CHUNK_MID: Vec3<f32> = Vec3 {
     z: CHUNK_SIZE.z as f32 / 2.0,
 };
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(8);
+// The server keeps a lost player around for about as long
+const RECONNECT_GIVE_UP: Duration = Duration::from_secs(60);
+const DEBUG_INTERVAL: Duration = Duration::from_secs(5);
+
+// Opens a new connection to the server, for reconnecting
+type Connector = Box<dyn Fn() -> Result<Manager<ClientPostOffice>, Error> + Send + Sync>;
+
+// When to try reconnecting next. The pause doubles after every failed attempt, and there's no next attempt once it
+// would be later than RECONNECT_GIVE_UP after losing the connection
+struct Backoff {
+    pause: Duration,
+    give_up: Instant,
+}
+
+impl Backoff {
+    fn new(lost: Instant) -> Backoff {
+        Backoff {
+            pause: RECONNECT_BACKOFF_MIN,
+            give_up: lost + RECONNECT_GIVE_UP,
+        }
+    }
+
+    // None once it's time to give up
+    fn next_attempt(&mut self, now: Instant) -> Option<Instant> {
+        let wake = now + self.pause;
+        if wake > self.give_up {
+            return None;
+        }
+        self.pause = min(self.pause * 2, RECONNECT_BACKOFF_MAX);
+        Some(wake)
+    }
+}
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum ClientStatus {
@@ -71,19 +122,36 @@ pub trait Payloads: 'static {
 
 pub enum ClientEvent {
     RecvChatMsg { text: String },
+    // The answer to a command sent with `send_cmd`, or as chat starting with '/'
+    CmdResult { success: bool, lines: Vec<String> },
+    // The connection broke, the client tries to resume the session in the background
+    ConnectionLost,
+    Reconnected,
+    // None if the connection was lost without the server telling why
+    Disconnected { reason: Option<DisconnectReason> },
 }
 
 pub struct Client<P: Payloads> {
     status: RwLock<ClientStatus>,
-    postoffice: Manager<ClientPostOffice>,
+    postoffice: RwLock<Arc<Manager<ClientPostOffice>>>,
+    connector: Option<Connector>,
+    server_key: ServerKey,
+    resume_token: RwLock<ResumeToken>,
 
     clock: RwLock<Clock>,
     clock_tick_time: RwLock<Duration>,
     player: RwLock<Player>,
     entities: RwLock<HashMap<Uid, Arc<RwLock<Entity<<P as Payloads>::Entity>>>>>,
+    snapshots: Mutex<SnapshotReceiver>,
+    // remote entities, the player's own entity is predicted instead
+    interpolation: Mutex<HashMap<Uid, Interpolation>>,
     phys_lock: Mutex<()>,
 
     chunk_mgr: ChunkMgr<<P as Payloads>::Chunk>,
+    // chunks asked the server for, and when
+    chunk_requests: Mutex<HashMap<Vec3<VolOffs>, Instant>>,
+    world_seed: RwLock<u32>,
+    local_terrain: AtomicBool,
     audio_mgr: AudioMgr<<P as Payloads>::Audio>,
 
     events: Mutex<Vec<ClientEvent>>,
@@ -101,58 +169,249 @@ impl<P: Payloads> Client<P> {
     >(
         mode: PlayMode,
         alias: String,
+        credentials: Credentials,
         remote_addr: S,
         gen_payload: GP,
         drop_payload: DP,
         audio_gen: Arc<<P as Payloads>::Audio>,
         view_distance: i64,
     ) -> Result<Manager<Client<P>>, Error> {
-        // Attempt to connect to the server
-        let postoffice = ClientPostOffice::to_server(remote_addr)?;
+        // Attempt to connect to the server, later connections go to the same address
+        let remote_addr = remote_addr
+            .to_socket_addrs()
+            .map_err(common::net::Error::from)?
+            .collect::<Vec<SocketAddr>>();
+        let connector: Connector = Box::new(move || Ok(ClientPostOffice::to_server(&remote_addr[..])?));
+        Client::connect(
+            mode,
+            alias,
+            credentials,
+            connector()?,
+            Some(connector),
+            ServerKey::Remembered,
+            gen_payload,
+            drop_payload,
+            audio_gen,
+            view_distance,
+        )
+    }
 
-        // Initiate a connection handshake
-        let pb = postoffice.create_postbox(SessionKind::Connect);
-        let _ = pb.send(ClientMsg::Connect {
-            alias: alias.clone(),
+    /// Connect over any transport, e.g. `Memory` to run client and server in one process
+    pub fn with_transport<
+        GP: FnGenFunc<Vec3<VolOffs>, ChunkContainer<P::Chunk>>,
+        DP: FnDropFunc<Vec3<VolOffs>, ChunkContainer<P::Chunk>>,
+    >(
+        mode: PlayMode,
+        alias: String,
+        credentials: Credentials,
+        transport: Transport,
+        server_key: ServerKey,
+        gen_payload: GP,
+        drop_payload: DP,
+        audio_gen: Arc<<P as Payloads>::Audio>,
+        view_distance: i64,
+    ) -> Result<Manager<Client<P>>, Error> {
+        Client::connect(
+            mode,
+            alias,
+            credentials,
+            ClientPostOffice::to_server_with(transport)?,
+            None,
+            server_key,
+            gen_payload,
+            drop_payload,
+            audio_gen,
+            view_distance,
+        )
+    }
+
+    /// Like `with_transport`, but calls `connect` again for every attempt to reconnect after losing the connection
+    pub fn with_connector<
+        C: Fn() -> Result<Transport, common::net::Error> + Send + Sync + 'static,
+        GP: FnGenFunc<Vec3<VolOffs>, ChunkContainer<P::Chunk>>,
+        DP: FnDropFunc<Vec3<VolOffs>, ChunkContainer<P::Chunk>>,
+    >(
+        mode: PlayMode,
+        alias: String,
+        credentials: Credentials,
+        connect: C,
+        server_key: ServerKey,
+        gen_payload: GP,
+        drop_payload: DP,
+        audio_gen: Arc<<P as Payloads>::Audio>,
+        view_distance: i64,
+    ) -> Result<Manager<Client<P>>, Error> {
+        let connector: Connector = Box::new(move || Ok(ClientPostOffice::to_server_with(connect()?)?));
+        Client::connect(
             mode,
+            alias,
+            credentials,
+            connector()?,
+            Some(connector),
+            server_key,
+            gen_payload,
+            drop_payload,
+            audio_gen,
+            view_distance,
+        )
+    }
+
+    fn connect<
+        GP: FnGenFunc<Vec3<VolOffs>, ChunkContainer<P::Chunk>>,
+        DP: FnDropFunc<Vec3<VolOffs>, ChunkContainer<P::Chunk>>,
+    >(
+        mode: PlayMode,
+        alias: String,
+        credentials: Credentials,
+        postoffice: Manager<ClientPostOffice>,
+        connector: Option<Connector>,
+        server_key: ServerKey,
+        gen_payload: GP,
+        drop_payload: DP,
+        audio_gen: Arc<<P as Payloads>::Audio>,
+        view_distance: i64,
+    ) -> Result<Manager<Client<P>>, Error> {
+        let (player_uid, time, resume_token, world_seed) = Client::<P>::handshake(
+            &postoffice,
+            &server_key,
+            ClientMsg::Connect {
+                version: Version::current(),
+                alias: alias.clone(),
+                mode,
+            },
+            Some(credentials),
+        )?;
+
+        let client = Manager::init(Client {
+            status: RwLock::new(ClientStatus::Connected),
+            postoffice: RwLock::new(Arc::new(postoffice)),
+            connector,
+            server_key,
+            resume_token: RwLock::new(resume_token),
+
+            clock: RwLock::new(Clock::new(INPUT_DT)),
+            clock_tick_time: RwLock::new(time),
+            player: RwLock::new(Player::new(alias)),
+            entities: RwLock::new(HashMap::new()),
+            snapshots: Mutex::new(SnapshotReceiver::new()),
+            interpolation: Mutex::new(HashMap::new()),
+            phys_lock: Mutex::new(()),
+
+            chunk_mgr: ChunkMgr::new(
+                CHUNK_SIZE,
+                VolGen::new(world::gen_chunk, gen_payload, world::drop_chunk, drop_payload),
+            ),
+            chunk_requests: Mutex::new(HashMap::new()),
+            world_seed: RwLock::new(world_seed),
+            local_terrain: AtomicBool::new(true),
+            audio_mgr: AudioMgr::new(audio_gen),
+
+            events: Mutex::new(vec![]),
+            next_ambient: RwLock::new(time),
+            next_steps: RwLock::new(time),
+
+            view_distance: view_distance.max(CHUNK_SIZE.x as i64),
         });
 
+        client.player.write().entity_uid = player_uid;
+
+        Ok(client)
+    }
+
+    // Say hello with either `ClientMsg::Connect` or `ClientMsg::Resume` and set the connection up the way the server
+    // supports it
+    fn handshake(
+        postoffice: &Manager<ClientPostOffice>,
+        server_key: &ServerKey,
+        hello: ClientMsg,
+        credentials: Option<Credentials>,
+    ) -> Result<(Option<Uid>, Duration, ResumeToken, u32), Error> {
+        // Agree on session keys with the expected server first, so the handshake and everything after it is encrypted
+        server_key.secure(postoffice)?;
+
+        // Initiate a connection handshake
+        let pb = postoffice.create_postbox(SessionKind::Connect);
+        let _ = pb.send(hello);
+        if let Some(credentials) = credentials {
+            let _ = pb.send(ClientMsg::Authenticate { credentials });
+        }
+
         // Was the handshake successful?
-        if let ServerMsg::Connected { player_uid, time } = pb.recv_timeout(CONNECT_TIMEOUT)? {
-            let client = Manager::init(Client {
-                status: RwLock::new(ClientStatus::Connected),
-                postoffice,
-
-                clock: RwLock::new(Clock::new(Duration::from_millis(20))),
-                clock_tick_time: RwLock::new(time),
-                player: RwLock::new(Player::new(alias)),
-                entities: RwLock::new(HashMap::new()),
-                phys_lock: Mutex::new(()),
-
-                chunk_mgr: ChunkMgr::new(
-                    CHUNK_SIZE,
-                    VolGen::new(world::gen_chunk, gen_payload, world::drop_chunk, drop_payload),
-                ),
-                audio_mgr: AudioMgr::new(audio_gen),
-
-                events: Mutex::new(vec![]),
-                next_ambient: RwLock::new(time),
-                next_steps: RwLock::new(time),
-
-                view_distance: view_distance.max(CHUNK_SIZE.x as i64),
-            });
-
-            client.player.write().entity_uid = player_uid;
-
-            Ok(client)
-        } else {
-            Err(Error::InvalidResponse)
+        let (version, player_uid, time, resume_token, world_seed) = match pb.recv_timeout(CONNECT_TIMEOUT)? {
+            ServerMsg::Connected {
+                version,
+                player_uid,
+                time,
+                resume_token,
+                world_seed,
+            } => (version, player_uid, time, resume_token, world_seed),
+            ServerMsg::Disconnect { reason } => return Err(Error::Disconnected(reason)),
+            _ => return Err(Error::InvalidResponse),
+        };
+        info!("Connected to server version {}", version);
+        postoffice.set_compression(version.supports(FEATURE_LZ4));
+
+        // Position updates can take a shortcut over udp, fall back to tcp if that isn't possible
+        if version.supports(FEATURE_UDP) {
+            if let Err(e) = postoffice.open_udp() {
+                warn!("Could not open udp, using tcp only: {:?}", e);
+            }
         }
+
+        Ok((player_uid, time, resume_token, world_seed))
     }
 
-    pub fn send_chat_msg(&self, text: String) { let _ = self.postoffice.send_one(ClientMsg::ChatMsg { text }); }
+    // Try to take the player over again after losing the connection, backing off further after every failed
+    // attempt. Gives the reason if the server refused, e.g. because the session expired
+    fn reconnect(&self, running: &AtomicBool) -> Result<(), Option<DisconnectReason>> {
+        let connector = match &self.connector {
+            Some(connector) => connector,
+            None => return Err(None),
+        };
+        let mut backoff = Backoff::new(Instant::now());
+        loop {
+            let hello = ClientMsg::Resume {
+                version: Version::current(),
+                token: *self.resume_token.read(),
+            };
+            let attempt =
+                connector().and_then(|po| Ok((Client::<P>::handshake(&po, &self.server_key, hello, None)?, po)));
+            match attempt {
+                Ok(((player_uid, time, resume_token, world_seed), postoffice)) => {
+                    *self.postoffice.write() = Arc::new(postoffice);
+                    *self.resume_token.write() = resume_token;
+                    *self.world_seed.write() = world_seed;
+                    // Requests on the old connection won't be answered
+                    self.chunk_requests.lock().clear();
+                    *self.clock_tick_time.write() = time;
+                    self.player.write().entity_uid = player_uid;
+                    *self.status.write() = ClientStatus::Connected;
+                    return Ok(());
+                },
+                Err(Error::Disconnected(reason)) => return Err(Some(reason)),
+                Err(e) => warn!("Reconnecting failed: {:?}", e),
+            }
+
+            let wake = match backoff.next_attempt(Instant::now()) {
+                Some(wake) => wake,
+                None => return Err(None),
+            };
+            // Sleep in small steps, so dropping the client doesn't have to wait for the next attempt
+            while Instant::now() < wake {
+                if !running.load(Ordering::Relaxed) || *self.status() == ClientStatus::Disconnected {
+                    return Err(None);
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    // The connection changes when reconnecting, so don't hold on to it
+    pub(crate) fn postoffice(&self) -> Arc<Manager<ClientPostOffice>> { self.postoffice.read().clone() }
+
+    pub fn send_chat_msg(&self, text: String) { let _ = self.postoffice().send_one(ClientMsg::ChatMsg { text }); }
 
-    pub fn send_cmd(&self, args: Vec<String>) { let _ = self.postoffice.send_one(ClientMsg::Cmd { args }); }
+    pub fn send_cmd(&self, args: Vec<String>) { let _ = self.postoffice().send_one(ClientMsg::Cmd { args }); }
 
     pub fn view_distance(&self) -> f32 { self.view_distance as f32 }
 
@@ -168,6 +427,8 @@ impl<P: Payloads> Client<P> {
 
     pub fn time(&self) -> Duration { *self.clock_tick_time.read() }
 
+    pub fn net_stats(&self) -> NetStats { self.postoffice().stats() }
+
     pub fn player<'a>(&'a self) -> RwLockReadGuard<'a, Player> { self.player.read() }
     pub fn player_mut<'a>(&'a self) -> RwLockWriteGuard<'a, Player> { self.player.write() }
 
@@ -205,13 +466,37 @@ impl<P: Payloads> Managed for Client<P> {
     fn init_workers(&self, manager: &mut Manager<Self>) {
         // Incoming messages worker
         Manager::add_worker(manager, |client, running, mut mgr| {
-            while running.load(Ordering::Relaxed) && *client.status() == ClientStatus::Connected {
-                client.handle_incoming(&mut mgr);
+            loop {
+                let reason = client.handle_incoming(&mut mgr);
+
+                // A connection that was lost rather than closed by the server may be resumed
+                let resumable = reason.as_ref().map(|r| r.is_resumable()).unwrap_or(true);
+                let reason = if running.load(Ordering::Relaxed)
+                    && *client.status() != ClientStatus::Disconnected
+                    && resumable
+                    && client.connector.is_some()
+                {
+                    *client.status.write() = ClientStatus::Timeout;
+                    client.events.lock().push(ClientEvent::ConnectionLost);
+                    match client.reconnect(running) {
+                        Ok(()) => {
+                            client.events.lock().push(ClientEvent::Reconnected);
+                            continue;
+                        },
+                        Err(refused) => refused.or(reason),
+                    }
+                } else {
+                    reason
+                };
+
+                *client.status.write() = ClientStatus::Disconnected;
+                client.events.lock().push(ClientEvent::Disconnected { reason });
+                break;
             }
 
             // Send a disconnect message to the server
             let _ = client
-                .postoffice
+                .postoffice()
                 .create_postbox(SessionKind::Disconnect)
                 .send(ClientMsg::Disconnect {
                     reason: "Logging out".into(),
@@ -220,7 +505,7 @@ impl<P: Payloads> Managed for Client<P> {
 
         // Tick worker
         Manager::add_worker(manager, |client, running, mut mgr| {
-            while running.load(Ordering::Relaxed) && *client.status() == ClientStatus::Connected {
+            while running.load(Ordering::Relaxed) && *client.status() != ClientStatus::Disconnected {
                 let mut clocklock = client.clock.write();
                 client.tick(clocklock.reference_duration(), &mut mgr);
                 clocklock.tick();
@@ -231,17 +516,21 @@ impl<P: Payloads> Managed for Client<P> {
         // Chunkmgr worker
         Manager::add_worker(manager, |client, running, mut mgr| {
             let mut clock = Clock::new(Duration::from_millis(200));
-            while running.load(Ordering::Relaxed) && *client.status() == ClientStatus::Connected {
+            while running.load(Ordering::Relaxed) && *client.status() != ClientStatus::Disconnected {
                 client.manage_chunks(&mut mgr);
                 clock.tick();
             }
         });
 
-        // Debug worker
+        // Debug worker, wakes up often so dropping the client doesn't wait for the next round
         Manager::add_worker(manager, |client, running, mut mgr| {
-            let mut clock = Clock::new(Duration::from_millis(5000));
-            while running.load(Ordering::Relaxed) && *client.status() == ClientStatus::Connected {
-                client.debug(&mut mgr);
+            let mut clock = Clock::new(Duration::from_millis(100));
+            let mut next_debug = Instant::now();
+            while running.load(Ordering::Relaxed) && *client.status() != ClientStatus::Disconnected {
+                if Instant::now() >= next_debug {
+                    client.debug(&mut mgr);
+                    next_debug += DEBUG_INTERVAL;
+                }
                 clock.tick();
             }
         });
@@ -264,7 +553,7 @@ impl<P: Payloads> Managed for Client<P> {
                 "voxygen/audio/music/Snowtop_with_Celesta.ogg",
             )));
             let mut clock = Clock::new(Duration::from_millis(100));
-            while running.load(Ordering::Relaxed) && *client.status() == ClientStatus::Connected {
+            while running.load(Ordering::Relaxed) && *client.status() != ClientStatus::Disconnected {
                 client.manage_audio(&mut mgr);
                 clock.tick();
             }
@@ -273,6 +562,117 @@ impl<P: Payloads> Managed for Client<P> {
 
     fn on_drop(&self, _: &mut Manager<Self>) {
         *self.status.write() = ClientStatus::Disconnected;
-        self.postoffice.stop();
+        self.postoffice().stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, Client, Error, Payloads, ServerKey, RECONNECT_GIVE_UP};
+    use common::{
+        audio::{AudioGen, Buffer, Stream},
+        net::{Identity, PublicKeyBytes},
+        util::{
+            manager::Manager,
+            msg::{
+                ClientMsg, ClientPostOffice, Credentials, PlayMode, ResumeToken, ServerMsg, ServerPostOffice,
+                SessionKind, Version, FEATURE_UDP,
+            },
+            post::Incoming,
+        },
+    };
+    use std::{
+        net::TcpListener,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    struct NoAudio;
+
+    impl AudioGen for NoAudio {
+        fn gen_stream(&self, _id: u64, _buffer: &Buffer, _stream: &Stream) {}
+        fn gen_buffer(&self, _id: u64, _buffer: &Buffer) {}
+        fn drop_stream(&self, _id: u64, _buffer: &Buffer, _stream: &Stream) {}
+        fn drop_buffer(&self, _id: u64, _buffer: &Buffer) {}
+    }
+
+    struct NoPayloads;
+
+    impl Payloads for NoPayloads {
+        type Chunk = ();
+        type Entity = ();
+        type Audio = NoAudio;
+    }
+
+    // Answers a client's handshake like a server of `version` would
+    fn fake_server(listener: TcpListener, identity: Identity, version: Version) {
+        let po = ServerPostOffice::to_client(listener.accept().unwrap().0, identity).unwrap();
+        let pb = match po.await_incoming() {
+            Ok(Incoming::Session(session)) => session.postbox,
+            _ => return,
+        };
+        let _ = pb.recv();
+        let _ = pb.recv();
+        let _ = pb.send(ServerMsg::Connected {
+            version,
+            player_uid: None,
+            time: Duration::from_secs(0),
+            resume_token: ResumeToken::generate(),
+            world_seed: 0,
+        });
+        // Keep the connection until the client hung up
+        while po.await_incoming().is_ok() {}
+    }
+
+    // Connect to a fake server, expecting it to have the `expected` key, or its own if None
+    fn connect(version: Version, expected: Option<PublicKeyBytes>) -> Result<Manager<ClientPostOffice>, Error> {
+        let identity = Identity::generate();
+        let server_key = ServerKey::Pinned(expected.unwrap_or(identity.public()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || fake_server(listener, identity, version));
+        let po = ClientPostOffice::to_server(addr).unwrap();
+        let hello = ClientMsg::Connect {
+            version: Version::current(),
+            alias: "player".to_string(),
+            mode: PlayMode::Headless,
+        };
+        Client::<NoPayloads>::handshake(&po, &server_key, hello, Some(Credentials::Guest))?;
+        Ok(po)
+    }
+
+    #[test]
+    fn udp_only_if_the_server_lists_it() {
+        assert!(connect(Version::current(), None).unwrap().local_udp().is_some());
+        let mut without_udp = Version::current();
+        without_udp.features.retain(|feature| feature != FEATURE_UDP);
+        assert!(connect(without_udp, None).unwrap().local_udp().is_none());
+    }
+
+    #[test]
+    fn servers_have_to_prove_their_key() {
+        match connect(Version::current(), Some(Identity::generate().public())) {
+            Err(Error::UnexpectedServerKey) => {},
+            Err(e) => panic!("expected the server to be refused, got {:?}", e),
+            Ok(_) => panic!("expected the server to be refused"),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_and_gives_up() {
+        let lost = Instant::now();
+        let mut backoff = Backoff::new(lost);
+        let mut now = lost;
+        let mut pauses = vec![];
+        while let Some(wake) = backoff.next_attempt(now) {
+            pauses.push(wake - now);
+            // every attempt takes a while of its own
+            now = wake + Duration::from_millis(100);
+        }
+        let ms = Duration::from_millis;
+        assert_eq!(pauses[..6], [ms(500), ms(1000), ms(2000), ms(4000), ms(8000), ms(8000)]);
+        // The client tries for as long as the server keeps the player, counted from when the connection was lost
+        assert!(now <= lost + RECONNECT_GIVE_UP + Duration::from_millis(100));
+        assert!(now + Duration::from_secs(8) > lost + RECONNECT_GIVE_UP);
     }
 }
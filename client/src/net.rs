@@ -1,51 +1,84 @@
 // Standard
-use std::{thread, time::Duration};
+use std::{
+    sync::mpsc::RecvTimeoutError,
+    thread,
+    time::{Duration, Instant},
+};
 
 // Library
-use parking_lot::Mutex;
 use vek::*;
 
 // Project
 use common::{
+    physics::physics,
     terrain::Entity,
     util::{
         manager::Manager,
-        msg::{ClientMsg, CompStore, ServerMsg, SessionKind},
+        msg::{ClientMsg, CompStore, ServerMsg, SessionKind, INPUT_DT, PLAYER_STREAM},
+        ping::Pinger,
         post::Incoming,
+        prediction::Interpolation,
+        snapshot::SNAPSHOT_ACK_STREAM,
     },
 };
 
 // Local
-use crate::{Client, ClientEvent, ClientStatus, Payloads};
+use crate::{Client, ClientEvent, DisconnectReason, Payloads};
 
 // Constants
 const PING_TIMEOUT: Duration = Duration::from_secs(10);
 const PING_FREQ: Duration = Duration::from_secs(2);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 impl<P: Payloads> Client<P> {
-    pub(crate) fn handle_incoming(&self, mgr: &mut Manager<Self>) {
-        while let Ok(incoming) = self.postoffice.await_incoming() {
+    // Handles everything arriving on the current connection until it ends, returns why the server ended it if it told us
+    pub(crate) fn handle_incoming(&self, _mgr: &mut Manager<Self>) -> Option<DisconnectReason> {
+        let postoffice = self.postoffice();
+        let mut reason = None;
+
+        while let Ok(incoming) = postoffice.await_incoming() {
             match incoming {
                 // Sessions
                 Incoming::Session(session) => match session.kind {
                     SessionKind::Ping => {
-                        let pb = Mutex::new(session.postbox);
-                        // TODO: Move this to a dedicated method?
-                        Manager::add_worker(mgr, |_client, _running, _| {
-                            thread::spawn(move || {
-                                let pb = pb.into_inner();
-
-                                loop {
-                                    thread::sleep(PING_FREQ);
-                                    let _ = pb.send(ClientMsg::Ping);
-
-                                    match pb.recv_timeout(PING_TIMEOUT) {
-                                        Ok(ServerMsg::Ping) => {},
-                                        _ => break, // Anything other than a ping over this session is invalid
-                                    }
+                        let pb = session.postbox;
+                        let po = Manager::internal(&postoffice).clone();
+                        // Not a worker, so shutting down doesn't have to wait for the next ping
+                        thread::spawn(move || {
+                            let mut pinger = Pinger::new(PING_FREQ, PING_TIMEOUT);
+
+                            // Ping the server and answer its pings, until it stops answering
+                            loop {
+                                let now = Instant::now();
+                                if pinger.timed_out(now) {
+                                    break;
                                 }
-                            });
-                        })
+                                if let Some(id) = pinger.poll(now) {
+                                    let _ = pb.send(ClientMsg::Ping { id });
+                                }
+
+                                match pb.recv_timeout(pinger.wait(now)) {
+                                    Ok(ServerMsg::Ping { id }) => {
+                                        let _ = pb.send(ClientMsg::Pong { id });
+                                    },
+                                    Ok(ServerMsg::Pong { id }) => {
+                                        if let Some(rtt) = pinger.pong(id, Instant::now()) {
+                                            po.record_rtt(rtt);
+                                        }
+                                    },
+                                    Err(RecvTimeoutError::Timeout) => {},
+                                    _ => break, // Anything other than a ping over this session is invalid
+                                }
+                            }
+                        });
+                    },
+                    SessionKind::Disconnect => {
+                        if let Ok(ServerMsg::Disconnect { reason: r }) =
+                            session.postbox.recv_timeout(DISCONNECT_TIMEOUT)
+                        {
+                            info!("Disconnected by the server: {}", r);
+                            reason = Some(r);
+                        }
                     },
                     _ => {},
                 },
@@ -54,6 +87,9 @@ impl<P: Payloads> Client<P> {
                 Incoming::Msg(ServerMsg::ChatMsg { text }) => {
                     self.events.lock().push(ClientEvent::RecvChatMsg { text })
                 },
+                Incoming::Msg(ServerMsg::CmdResult { success, lines }) => {
+                    self.events.lock().push(ClientEvent::CmdResult { success, lines })
+                },
                 Incoming::Msg(ServerMsg::CompUpdate { uid, store }) => {
                     let entity = self.entity(uid).unwrap_or_else(|| {
                         // Create an entity with default attributes if it doesn't already exist
@@ -67,15 +103,63 @@ impl<P: Payloads> Client<P> {
                     });
 
                     match store {
-                        CompStore::Pos(pos) => *entity.write().pos_mut() = pos,
+                        CompStore::Pos(pos) => {
+                            // Moved by the server rather than by its snapshots, e.g. teleported
+                            self.interpolation.lock().remove(&uid);
+                            *entity.write().pos_mut() = pos
+                        },
                         CompStore::Vel(vel) => *entity.write().vel_mut() = vel,
                         CompStore::Dir(dir) => *entity.write().look_dir_mut() = dir,
                         _ => {},
                     }
                 },
                 Incoming::Msg(ServerMsg::EntityDeleted { uid }) => {
+                    self.interpolation.lock().remove(&uid);
                     self.remove_entity(uid);
                 },
+                Incoming::Msg(ServerMsg::Snapshot(snapshot)) => {
+                    if let Some(state) = self.snapshots.lock().receive(&snapshot) {
+                        // Entities are created by the `CompUpdate` they enter the view with. A snapshot arriving after
+                        // one left must not bring it back
+                        let now = Instant::now();
+                        let player_uid = self.player().entity_uid;
+                        let entities = self.entities();
+                        let mut interpolation = self.interpolation.lock();
+                        for (uid, entity_state) in state {
+                            if entities.contains_key(uid) && Some(*uid) != player_uid {
+                                interpolation
+                                    .entry(*uid)
+                                    .or_insert_with(Interpolation::new)
+                                    .push(now, *entity_state);
+                            }
+                        }
+                        let _ = postoffice
+                            .send_one_sequenced(ClientMsg::SnapshotAck { id: snapshot.id }, SNAPSHOT_ACK_STREAM);
+                    }
+                },
+
+                Incoming::Msg(ServerMsg::PlayerState { seq, pos, vel }) => {
+                    if let Some(player_entity) = self.player_entity() {
+                        let _phys_lock = self.take_phys_lock();
+                        // The inputs the server didn't get to yet are simulated again from where it ended up
+                        let replayed = self
+                            .player_mut()
+                            .prediction
+                            .reconcile(seq, pos, vel, |pos, vel, input| {
+                                physics::simulate(&self.chunk_mgr, pos, vel, input.ctrl_acc, INPUT_DT)
+                            });
+                        if let Some((pos, vel)) = replayed {
+                            let mut player_entity = player_entity.write();
+                            *player_entity.pos_mut() = pos;
+                            *player_entity.vel_mut() = vel;
+                        }
+                    }
+                },
+
+                Incoming::Msg(ServerMsg::ChunkData { pos, data }) => self.receive_chunk(pos, &data),
+                Incoming::Msg(ServerMsg::BlockUpdate { pos, block }) => {
+                    self.chunk_mgr.set_block(pos, block);
+                },
 
                 Incoming::Msg(ServerMsg::TimeUpdate(time)) => {
                     *self.clock_tick_time.write() = time;
@@ -85,22 +169,27 @@ impl<P: Payloads> Client<P> {
                 Incoming::Msg(_) => {},
 
                 // End
-                Incoming::End => {}, // TODO: Something here
+                Incoming::End => break,
             }
         }
 
-        *self.status.write() = ClientStatus::Disconnected;
+        reason
     }
 
-    /// Update the server with information about the player
+    /// Send the server how the player wants to move this tick, it simulates the movement itself
     pub(crate) fn update_server(&self) {
         if let Some(player_entity) = self.player_entity() {
-            let player_entity = player_entity.read();
-            let _ = self.postoffice.send_one(ClientMsg::PlayerEntityUpdate {
-                pos: *player_entity.pos(),
-                vel: *player_entity.vel(),
-                dir: *player_entity.look_dir(),
-            });
+            let inputs = {
+                let player_entity = player_entity.read();
+                self.player_mut().prediction.record(
+                    *player_entity.ctrl_acc(),
+                    *player_entity.look_dir(),
+                    *player_entity.pos(),
+                )
+            };
+            let _ = self
+                .postoffice()
+                .send_one_sequenced(ClientMsg::PlayerInputs { inputs }, PLAYER_STREAM);
         }
     }
 }
@@ -1,9 +1,10 @@
 // Project
-use common::Uid;
+use common::{util::prediction::Prediction, Uid};
 
 pub struct Player {
     pub alias: String,
     pub entity_uid: Option<Uid>,
+    pub(crate) prediction: Prediction,
 }
 
 impl Player {
@@ -11,6 +12,7 @@ impl Player {
         Player {
             alias,
             entity_uid: None,
+            prediction: Prediction::new(),
         }
     }
 
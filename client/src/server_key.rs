@@ -0,0 +1,98 @@
+// Standard
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+};
+
+// Project
+use common::{
+    net::{self, PublicKeyBytes},
+    util::{manager::Manager, msg::ClientPostOffice},
+};
+
+// Local
+use crate::{Error, CONNECT_TIMEOUT};
+
+// Constants
+// The keys of the servers connected to before, the address and the key in hex on a line each
+pub const KNOWN_SERVERS_FILE: &str = "./known_servers";
+
+/// The key the server has to prove it holds before anything is sent to it, so nobody relaying the traffic can pose as
+/// the server and read along
+#[derive(Clone, Debug)]
+pub enum ServerKey {
+    /// The one the server at the connection's address had the first time, remembered in `KNOWN_SERVERS_FILE`. Needs a
+    /// transport with an address
+    Remembered,
+    /// Only this one, e.g. of a server running in the same process
+    Pinned(PublicKeyBytes),
+}
+
+impl ServerKey {
+    // Agree on session keys with the server and make sure it's the expected one
+    pub(crate) fn secure(&self, postoffice: &Manager<ClientPostOffice>) -> Result<(), Error> {
+        let key = postoffice.exchange_keys(CONNECT_TIMEOUT)?;
+        match self {
+            ServerKey::Pinned(pinned) if *pinned == key => Ok(()),
+            ServerKey::Pinned(_) => Err(Error::UnexpectedServerKey),
+            ServerKey::Remembered => match postoffice.peer_addr() {
+                Some(addr) => remember(Path::new(KNOWN_SERVERS_FILE), addr, key),
+                None => Err(Error::UnexpectedServerKey),
+            },
+        }
+    }
+}
+
+// Fails if another key was remembered for `addr`, remembers `key` if there was none
+fn remember(path: &Path, addr: SocketAddr, key: PublicKeyBytes) -> Result<(), Error> {
+    let addr = addr.to_string();
+    let known = fs::read_to_string(path).unwrap_or_default();
+    for line in known.lines() {
+        let mut parts = line.split_whitespace();
+        if parts.next() == Some(&addr) {
+            return match parts.next().and_then(net::from_hex) {
+                Some(known) if known == key => Ok(()),
+                _ => Err(Error::UnexpectedServerKey),
+            };
+        }
+    }
+
+    // Not being able to remember it only means the next connection is trusted on its own again
+    let remembered = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{} {}", addr, net::to_hex(&key)));
+    if let Err(e) = remembered {
+        warn!("Could not remember the key of {}: {}", addr, e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::remember;
+    use crate::Error;
+    use std::{env, fs, process};
+
+    #[test]
+    fn keys_are_remembered_per_address() {
+        let path = env::temp_dir().join(format!("known-servers-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let (a, b) = ("127.0.0.1:14004".parse().unwrap(), "127.0.0.1:14005".parse().unwrap());
+
+        // The first key is trusted, later ones have to match it
+        remember(&path, a, [1; 32]).unwrap();
+        remember(&path, a, [1; 32]).unwrap();
+        match remember(&path, a, [2; 32]) {
+            Err(Error::UnexpectedServerKey) => {},
+            r => panic!("expected another key to be refused, got {:?}", r),
+        }
+        remember(&path, b, [2; 32]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        let _ = fs::remove_file(&path);
+    }
+}
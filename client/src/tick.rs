@@ -1,5 +1,5 @@
 // Standard
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // Project
 use common::{physics::physics, util::manager::Manager};
@@ -14,8 +14,32 @@ impl<P: Payloads> Client<P> {
         // Physics tick
         {
             // Take the physics lock to sync client and frontend updates
-            let _ = self.take_phys_lock();
-            physics::tick(entities.iter(), &self.chunk_mgr, dt);
+            let _phys_lock = self.take_phys_lock();
+
+            // The player moves right away, the server simulates the same input later
+            if let Some(player_entity) = self.player().entity_uid.and_then(|uid| entities.get(&uid)) {
+                let mut player_entity = player_entity.write();
+                let (pos, vel) = physics::simulate(
+                    &self.chunk_mgr,
+                    *player_entity.pos(),
+                    *player_entity.vel(),
+                    *player_entity.ctrl_acc(),
+                    dt,
+                );
+                *player_entity.pos_mut() = pos;
+                *player_entity.vel_mut() = vel;
+            }
+
+            // Everything else is shown between the snapshots the server sent
+            let now = Instant::now();
+            for (uid, interpolation) in self.interpolation.lock().iter() {
+                if let (Some(entity), Some((pos, vel, dir))) = (entities.get(uid), interpolation.sample(now)) {
+                    let mut entity = entity.write();
+                    *entity.pos_mut() = pos;
+                    *entity.vel_mut() = vel;
+                    *entity.look_dir_mut() = dir;
+                }
+            }
         }
 
         self.update_server();
@@ -1,73 +1,34 @@
 // Standard
-use std::{fs::File, io::prelude::*, path::Path, sync::Arc, u8};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
 // Library
+use parking_lot::{Mutex, RwLock};
 use vek::*;
 
 // Project
 use common::{
     terrain::{
-        self,
-        chunk::{Chunk, ChunkContainer, HeterogeneousData},
-        BlockLoader, Container, Key, PersState, VolCluster, VolOffs, VoxAbs,
+        chunk::{Block, Chunk, ChunkContainer},
+        BlockLoader, VolCluster, VolOffs, VoxAbs,
     },
-    util::manager::Manager,
+    util::{manager::Manager, msg::ClientMsg},
 };
-use parking_lot::{Mutex, RwLock};
 
 // Local
-use crate::{world_crate, Client, Payloads, CHUNK_SIZE};
+use crate::{world_crate, Client, Payloads};
 
-pub(crate) fn gen_chunk<P: Send + Sync + 'static>(pos: Vec3<VolOffs>, con: Arc<Mutex<Option<ChunkContainer<P>>>>) {
-    let filename = pos.print() + ".dat";
-    let filepath = "./saves/".to_owned() + &(filename);
-    let path = Path::new(&filepath);
-    'load: {
-        if path.exists() {
-            let datfile = File::open(&filepath);
-            if let Ok(mut datfile) = datfile {
-                let mut content = Vec::<u8>::new();
-                datfile
-                    .read_to_end(&mut content)
-                    .expect(&format!("read of file {} failed", &filepath));
-                let cc = Chunk::from_bytes(&content);
-                if let Ok(cc) = cc {
-                    *con.lock() = Some(ChunkContainer::<P>::new(cc));
-                    break 'load;
-                }
-            }
-            warn!(
-                "there was a problem reading a chunk {} from file, it was newly generated",
-                pos
-            );
-        }
-        let c = world_crate::World::gen_chunk(pos.map(|e| e as i32));
-        *con.lock() = Some(ChunkContainer::<P>::new(c));
-    }
-}
+// Constants
+// A chunk the server didn't send by then is asked for again, or generated locally if possible
+const CHUNK_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub(crate) fn drop_chunk<P: Send + Sync + 'static>(pos: Vec3<VolOffs>, con: Arc<ChunkContainer<P>>) {
-    let filename = pos.print() + ".dat";
-    let filepath = "./saves/".to_owned() + &(filename);
-    let path = Path::new(&filepath);
-    'load: {
-        if !path.exists() {
-            let mut data = con.data_mut();
-            let bytes = data.to_bytes();
-            if let Ok(bytes) = bytes {
-                let datfile = File::create(filepath);
-                if let Ok(mut datfile) = datfile {
-                    match datfile.write_all(&bytes) {
-                        Ok(_) => debug!("write to file: {}, bytes: {}", filename, bytes.len()),
-                        Err(_) => warn!("problem writing chunk {} to file, ignoring it", pos),
-                    };
-                } else {
-                    warn!("problem creating file for chunk {}, ignoring it", pos)
-                }
-            }
-        }
-    }
-}
+// The terrain comes from the server, `maintain_chunks` requests the chunks left empty here
+pub(crate) fn gen_chunk<P: Send + Sync + 'static>(_pos: Vec3<VolOffs>, _con: Arc<Mutex<Option<ChunkContainer<P>>>>) {}
+
+// The server keeps the terrain, there's nothing to save
+pub(crate) fn drop_chunk<P: Send + Sync + 'static>(_pos: Vec3<VolOffs>, _con: Arc<ChunkContainer<P>>) {}
 
 impl<P: Payloads> Client<P> {
     pub(crate) fn maintain_chunks(&self, _mgr: &mut Manager<Self>) {
@@ -94,5 +55,60 @@ impl<P: Payloads> Client<P> {
         }
         //TODO: maybe remove this from CHUNMGR, and just pass it here
         self.chunk_mgr().maintain();
+        self.request_chunks();
     }
+
+    // Ask the server for the chunks the chunk manager waits for. If it doesn't send one in time, it's generated locally
+    // instead if that gives the same terrain as the server has
+    fn request_chunks(&self) {
+        let now = Instant::now();
+        let unfilled = self.chunk_mgr.unfilled();
+        let mut requests = self.chunk_requests.lock();
+        requests.retain(|pos, _| unfilled.contains(pos));
+
+        for pos in unfilled {
+            match requests.get(&pos).cloned() {
+                Some(requested) if now < requested + CHUNK_REQUEST_TIMEOUT => continue,
+                Some(_) if self.local_terrain() => {
+                    debug!("The server didn't send chunk {}, generating it locally", pos);
+                    requests.remove(&pos);
+                    self.chunk_mgr
+                        .fill(pos, world_crate::World::gen_chunk(pos.map(|e| e as i32)));
+                    continue;
+                },
+                _ => {},
+            }
+            let _ = self.postoffice().send_one(ClientMsg::RequestChunk { pos });
+            requests.insert(pos, now);
+        }
+    }
+
+    pub(crate) fn receive_chunk(&self, pos: Vec3<VolOffs>, data: &[u8]) {
+        self.chunk_requests.lock().remove(&pos);
+        // `from_bytes` expects at least the kind of chunk
+        let chunk = if data.is_empty() {
+            Err(())
+        } else {
+            Chunk::from_bytes(data)
+        };
+        match chunk {
+            Ok(chunk) => {
+                self.chunk_mgr.fill(pos, chunk);
+            },
+            Err(_) => warn!("Received an invalid chunk {}", pos),
+        }
+    }
+
+    /// Ask the server to change a block. It's only changed once the server agrees, like for every other client
+    pub fn set_block(&self, pos: Vec3<VoxAbs>, block: Block) {
+        let _ = self.postoffice().send_one(ClientMsg::SetBlock { pos, block });
+    }
+
+    fn local_terrain(&self) -> bool {
+        self.local_terrain.load(Ordering::Relaxed) && *self.world_seed.read() == world_crate::World::seed()
+    }
+
+    /// Whether chunks the server doesn't send are generated locally. They only are if the local world generator has
+    /// the same seed as the server's
+    pub fn set_local_terrain(&self, enabled: bool) { self.local_terrain.store(enabled, Ordering::Relaxed) }
 }
@@ -0,0 +1,37 @@
+// Standard
+use std::io::Cursor;
+
+// Library
+use byteorder::{LittleEndian, ReadBytesExt};
+use lz4_flex::{compress_prepend_size, decompress_size_prepended};
+
+// Parent
+use super::Error;
+
+// Smaller payloads don't gain enough to be worth the cpu time
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+// Refuse to allocate more than this for a single packet, the size prefix comes from the remote
+const MAX_DECOMPRESSED_SIZE: u32 = 256 * 1024 * 1024;
+
+// Returns None if compressing doesn't make the payload smaller
+pub fn compress(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.len() < COMPRESSION_THRESHOLD {
+        return None;
+    }
+    let compressed = compress_prepend_size(bytes);
+    if compressed.len() < bytes.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let size = Cursor::new(bytes)
+        .read_u32::<LittleEndian>()
+        .map_err(|_| Error::CannotDecompress)?;
+    if size > MAX_DECOMPRESSED_SIZE {
+        return Err(Error::CannotDecompress);
+    }
+    decompress_size_prepended(bytes).map_err(|_| Error::CannotDecompress)
+}
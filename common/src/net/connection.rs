@@ -1,88 +1,134 @@
 // Standard
 use std::{
     collections::{vec_deque::VecDeque, HashMap},
-    io::ErrorKind,
-    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    fmt,
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc, Arc,
+        mpsc, Arc, Weak,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 // Library
-use get_if_addrs::get_if_addrs;
 use parking_lot::{Mutex, RwLock};
 
 // Parent
 use super::{
-    packet::{Frame, FrameError, IncomingPacket, OutgoingPacket},
-    protocol::Protocol,
+    compression::{compress, decompress},
+    crypto::{Cipher, OVERHEAD},
+    packet::{Frame, IncomingPacket, IncomingPackets, OutgoingPacket},
+    protocol::{Protocol, Transport},
+    queue::{OutgoingQueue, PRIO_DEFAULT, PRIO_MAX},
+    reactor::{Handler, PolledUdp, Reactor},
+    stats::NetStats,
     tcp::Tcp,
-    udp::Udp,
     udpmgr::UdpMgr,
-    ConnectionMessage, Error, Message,
+    Error, Message,
 };
 
+// Sequenced packets must fit into a single datagram, stay below common MTUs
+const MAX_SEQUENCED_SIZE: usize = 1200;
+// Reliable packets are split into frames of this size
+const SPLIT_SIZE: u64 = 2000;
+
 #[derive(Debug)]
 enum ConnectionError {
     Disconnected,
 }
 
+#[derive(Copy, Clone, Debug)]
+pub enum Delivery {
+    // Sent over tcp, arrives in order with every other reliable message of the same priority
+    Reliable { prio: u8 },
+    // Sent once over udp, lost datagrams are not retransmitted. A message arriving after a newer one of the same
+    // stream is dropped as stale. Falls back to reliable delivery if udp is not open or the message is too large.
+    Sequenced { stream: u64 },
+}
+
+// Receives every message as soon as it is complete, None once the connection ended
+struct Dispatch<RM>(Box<dyn Fn(Option<RM>) + Send + Sync>);
+
+impl<RM> fmt::Debug for Dispatch<RM> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Dispatch") }
+}
+
 #[derive(Debug)]
 pub struct Connection<RM: Message> {
-    // sorted by prio and then chronically
-    tcp: Tcp,
+    // reliable and ordered, usually tcp
+    transport: Transport,
     udpmgr: Arc<UdpMgr>,
-    udp: Mutex<Option<Udp>>,
-    packet_in: Mutex<HashMap<u64, IncomingPacket>>,
-    packet_out: Mutex<Vec<VecDeque<OutgoingPacket>>>,
-    packet_out_count: RwLock<u64>,
+    udp: Mutex<Option<Arc<dyn Protocol>>>,
+    udp_local: Mutex<Option<SocketAddr>>,
+    // bound on the transport's reactor, waiting for the remote's address
+    udp_bound: Mutex<Option<Arc<PolledUdp>>>,
+    packet_in: Mutex<IncomingPackets>,
+    packet_out: Mutex<OutgoingQueue>,
+    // newest id received per stream
+    sequenced_in: Mutex<HashMap<u64, u64>>,
+    // at most one frame per stream, older ones are replaced before they are sent
+    sequenced_out: Mutex<VecDeque<Frame>>,
+    // only compress if the remote announced it can decompress
+    compression: AtomicBool,
+    // set once a key exchange finished, from then on every packet is encrypted
+    cipher: RwLock<Option<Arc<Cipher>>>,
+    // counters only, queue lengths and retransmits are filled in by `stats`
+    stats: Mutex<NetStats>,
+    // a frame was taken from packet_out, but not handed to the transport yet
+    sending: AtomicBool,
+    // the transport is driven by a reactor, there are no worker threads
+    polled: AtomicBool,
     running: AtomicBool,
     send_thread: Mutex<Option<JoinHandle<()>>>,
     recv_thread: Mutex<Option<JoinHandle<()>>>,
     send_thread_udp: Mutex<Option<JoinHandle<()>>>,
     recv_thread_udp: Mutex<Option<JoinHandle<()>>>,
-    next_id: Mutex<u64>,
 
     // Message channel
     recvd_message_write: Mutex<mpsc::Sender<Result<RM, ConnectionError>>>,
     recvd_message_read: Mutex<mpsc::Receiver<Result<RM, ConnectionError>>>,
+    dispatch: RwLock<Option<Dispatch<RM>>>,
 }
 
 impl<RM: Message> Connection<RM> {
     pub fn new<A: ToSocketAddrs>(remote: &A, udpmgr: Arc<UdpMgr>) -> Result<Arc<Connection<RM>>, Error> {
-        Connection::new_internal(Tcp::new(&remote)?, udpmgr)
+        Connection::with_transport(Box::new(Tcp::new(&remote)?), udpmgr)
     }
 
     pub fn new_stream(stream: TcpStream, udpmgr: Arc<UdpMgr>) -> Result<Arc<Connection<RM>>, Error> {
-        Connection::new_internal(Tcp::new_stream(stream)?, udpmgr)
+        Connection::with_transport(Box::new(Tcp::new_stream(stream)?), udpmgr)
     }
 
-    fn new_internal(tcp: Tcp, udpmgr: Arc<UdpMgr>) -> Result<Arc<Connection<RM>>, Error> {
-        let mut packet_out = Vec::new();
-        for _i in 0..255 {
-            packet_out.push(VecDeque::new());
-        }
-
+    // Run on any transport, e.g. `Memory` to connect within one process
+    pub fn with_transport(transport: Transport, udpmgr: Arc<UdpMgr>) -> Result<Arc<Connection<RM>>, Error> {
         //let (error_sender, error_receiver) = mpsc::channel();
         let (message_sender, message_receiver) = mpsc::channel();
 
         let m = Connection {
-            tcp,
+            transport,
             udpmgr,
             udp: Mutex::new(None),
-            packet_in: Mutex::new(HashMap::new()),
-            packet_out_count: RwLock::new(0),
-            packet_out: Mutex::new(packet_out),
+            udp_local: Mutex::new(None),
+            udp_bound: Mutex::new(None),
+            packet_in: Mutex::new(IncomingPackets::new()),
+            packet_out: Mutex::new(OutgoingQueue::new()),
+            sequenced_in: Mutex::new(HashMap::new()),
+            sequenced_out: Mutex::new(VecDeque::new()),
+            compression: AtomicBool::new(false),
+            cipher: RwLock::new(None),
+            stats: Mutex::new(NetStats::default()),
+            sending: AtomicBool::new(false),
+            polled: AtomicBool::new(false),
             running: AtomicBool::new(true),
             send_thread: Mutex::new(None),
             recv_thread: Mutex::new(None),
             send_thread_udp: Mutex::new(None),
             recv_thread_udp: Mutex::new(None),
-            next_id: Mutex::new(1),
             recvd_message_write: Mutex::new(message_sender),
             recvd_message_read: Mutex::new(message_receiver),
+            dispatch: RwLock::new(None),
             //error_write: Mutex::new(error_sender),
             //error_read: Mutex::new(error_receiver),
         };
@@ -90,12 +136,82 @@ impl<RM: Message> Connection<RM> {
         Ok(Arc::new(m))
     }
 
+    // Bind a local udp socket on the interface of the transport, returns the address the remote has to send to
+    pub fn bind_udp(&self) -> Result<SocketAddr, Error> {
+        let mut local = self.udp_local.lock();
+        if let Some(addr) = *local {
+            return Ok(addr);
+        }
+        let ip = self
+            .transport
+            .local_addr()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "transport has no address"))?
+            .ip();
+        let addr = match self.transport.reactor() {
+            Some(reactor) => {
+                let udp = Reactor::bind_udp(&reactor, &SocketAddr::new(ip, 0))?;
+                let addr = udp.local_addr()?;
+                *self.udp_bound.lock() = Some(udp);
+                addr
+            },
+            None => UdpMgr::bind_udp(self.udpmgr.clone(), &SocketAddr::new(ip, 0))?,
+        };
+        *local = Some(addr);
+        Ok(addr)
+    }
+
+    pub fn local_udp(&self) -> Option<SocketAddr> { *self.udp_local.lock() }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> { self.transport.peer_addr() }
+
+    // Compress outgoing packets above `COMPRESSION_THRESHOLD`, incoming ones are always decompressed as flagged
+    pub fn set_compression(&self, enabled: bool) { self.compression.store(enabled, Ordering::Relaxed); }
+
+    // returns the bytes to put on the wire and whether they got compressed
+    fn maybe_compress(&self, bytes: Vec<u8>) -> (Vec<u8>, bool) {
+        if self.compression.load(Ordering::Relaxed) {
+            if let Some(compressed) = compress(&bytes) {
+                return (compressed, true);
+            }
+        }
+        (bytes, false)
+    }
+
+    // Encrypt every packet from now on and only accept encrypted ones. `last_plain` is still sent unencrypted, so the
+    // remote can finish its side of the key exchange
+    pub fn enable_encryption<M: Message>(&self, cipher: Cipher, last_plain: Option<M>) {
+        // incoming packets wait for the cipher, the remote may answer before we are done here. Senders hold it until
+        // their message is queued, so every message queued after `last_plain` is encrypted
+        let mut slot = self.cipher.write();
+        if let Some(message) = last_plain {
+            self.queue_packet(message.to_bytes().unwrap(), false, None, PRIO_MAX);
+        }
+        *slot = Some(Arc::new(cipher));
+    }
+
+    pub fn is_encrypted(&self) -> bool { self.cipher.read().is_some() }
+
     pub fn open_udp<'b>(manager: &'b Arc<Connection<RM>>, listen: SocketAddr, sender: SocketAddr) {
-        if let Some(..) = *manager.udp.lock() {
-            panic!("not implemented");
+        {
+            let mut udp = manager.udp.lock();
+            if udp.is_some() {
+                warn!("udp is already open, ignoring remote {}", sender);
+                return;
+            }
+            let started: Arc<dyn Protocol> = match manager.udp_bound.lock().take() {
+                Some(polled) => {
+                    polled.connect(sender);
+                    polled.attach(Arc::downgrade(manager) as Weak<dyn Handler>);
+                    polled
+                },
+                None => UdpMgr::start_udp(manager.udpmgr.clone(), &listen, &sender),
+            };
+            *udp = Some(manager.transport.wrap_unreliable(started));
+        }
+        // the reactor delivers what arrives, and sending doesn't block
+        if manager.polled.load(Ordering::Relaxed) {
+            return;
         }
-        *manager.udp.lock() = Some(Udp::new(listen, sender).unwrap());
-        manager.send(ConnectionMessage::OpenedUdp { host: listen });
 
         let m = manager.clone();
         let mut rt = manager.recv_thread_udp.lock();
@@ -111,6 +227,11 @@ impl<RM: Message> Connection<RM> {
     }
 
     pub fn start<'b>(manager: &'b Arc<Connection<RM>>) {
+        if manager.transport.attach(Arc::downgrade(manager) as Weak<dyn Handler>) {
+            manager.polled.store(true, Ordering::Relaxed);
+            return;
+        }
+
         let m = manager.clone();
         let mut rt = manager.recv_thread.lock();
         *rt = Some(thread::spawn(move || {
@@ -127,16 +248,133 @@ impl<RM: Message> Connection<RM> {
     pub fn stop<'b>(manager: &'b Arc<Connection<RM>>) {
         let m = manager.clone();
         m.running.store(false, Ordering::Relaxed);
-        let _ = m.recvd_message_write.lock().send(Err(ConnectionError::Disconnected));
-        // non blocking stop for now
+        m.deliver(Err(ConnectionError::Disconnected));
+        // wake up parked send workers, so they notice
+        for worker in [&m.send_thread, &m.send_thread_udp].iter() {
+            if let Some(handle) = worker.lock().as_ref() {
+                handle.thread().unpark();
+            }
+        }
     }
 
-    pub fn send<M: Message>(&self, message: M) {
-        let mut id = self.next_id.lock();
-        self.packet_out.lock()[16].push_back(OutgoingPacket::new(message.to_bytes().unwrap(), *id));
-        *id += 1;
-        let mut p = self.packet_out_count.write();
-        *p += 1;
+    // Stop receiving right away, but deliver every reliable message queued so far before stopping and closing the
+    // transport. Doesn't block, gives up on the queue after `timeout`
+    pub fn close<'b>(manager: &'b Arc<Connection<RM>>, timeout: Duration) {
+        manager.deliver(Err(ConnectionError::Disconnected));
+        let m = manager.clone();
+        thread::spawn(move || {
+            if !m.flush(timeout) {
+                warn!("Closing connection with unsent frames");
+            }
+            Connection::stop(&m);
+            m.transport.close();
+        });
+    }
+
+    // Block until every reliable frame queued so far was handed to the transport, false if that took too long
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self.running.load(Ordering::Relaxed) {
+            {
+                let queue = self.packet_out.lock();
+                if queue.frames_left(SPLIT_SIZE) == 0 && !self.sending.load(Ordering::Relaxed) {
+                    return true;
+                }
+            }
+            if start.elapsed() > timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    pub fn send<M: Message>(&self, message: M) { self.send_with_priority(message, PRIO_DEFAULT) }
+
+    // Frames are interleaved across priorities, lower prio values get a larger share of the bandwidth
+    pub fn send_with_priority<M: Message>(&self, message: M, prio: u8) {
+        let (bytes, compressed) = self.maybe_compress(message.to_bytes().unwrap());
+        let cipher = self.cipher.read();
+        self.queue_packet(bytes, compressed, cipher.clone(), prio);
+    }
+
+    // See `Delivery::Sequenced`
+    pub fn send_sequenced<M: Message>(&self, message: M, stream: u64) {
+        let (bytes, compressed) = self.maybe_compress(message.to_bytes().unwrap());
+        let cipher = self.cipher.read();
+        let overhead = if cipher.is_some() { OVERHEAD } else { 0 };
+        if bytes.len() + overhead > MAX_SEQUENCED_SIZE || self.udp.lock().is_none() {
+            return self.queue_packet(bytes, compressed, cipher.clone(), PRIO_DEFAULT);
+        }
+        {
+            // the reliable packets' ids are taken in order too, and frames of a stream have to be queued in id order
+            let mut packets = self.packet_out.lock();
+            let id = packets.take_id();
+            let (bytes, encrypted) = match cipher.as_ref() {
+                Some(cipher) => (cipher.encrypt(id, &bytes), true),
+                None => (bytes, false),
+            };
+            let mut queue = self.sequenced_out.lock();
+            // an older message of this stream that wasn't sent yet is stale now
+            queue.retain(|f| match f {
+                Frame::Sequenced { stream: s, .. } => *s != stream,
+                _ => true,
+            });
+            queue.push_back(Frame::Sequenced {
+                id,
+                stream,
+                compressed,
+                encrypted,
+                data: bytes,
+            });
+        }
+        drop(cipher);
+        if self.polled.load(Ordering::Relaxed) {
+            self.send_sequenced_frames();
+        } else if let Some(cb) = self.send_thread_udp.lock().as_mut() {
+            //trigger sending
+            cb.thread().unpark();
+        }
+    }
+
+    // udp doesn't block, so without a worker the sender hands its frames over itself
+    fn send_sequenced_frames(&self) {
+        loop {
+            let next = self.sequenced_out.lock().pop_front();
+            let frame = match next {
+                Some(frame) => frame,
+                None => break,
+            };
+            let udp = self.udp.lock().clone();
+            let size = frame.wire_size();
+            match udp.map(|udp| udp.send(frame)) {
+                Some(Ok(_)) => self.count_sent(size),
+                Some(Err(e)) => warn!("Failed to send udp frame: {:?}", e),
+                None => {},
+            }
+        }
+    }
+
+    pub fn send_with<M: Message>(&self, message: M, delivery: Delivery) {
+        match delivery {
+            Delivery::Reliable { prio } => self.send_with_priority(message, prio),
+            Delivery::Sequenced { stream } => self.send_sequenced(message, stream),
+        }
+    }
+
+    // the caller holds the cipher lock, so encryption can't be turned on between choosing `cipher` and queueing
+    fn queue_packet(&self, bytes: Vec<u8>, compressed: bool, cipher: Option<Arc<Cipher>>, prio: u8) {
+        let mut packet = OutgoingPacket::with_prio(bytes, prio);
+        if compressed {
+            packet = packet.compressed();
+        }
+        if let Some(cipher) = cipher {
+            packet = packet.sealed(cipher);
+        }
+        self.packet_out.lock().push(packet);
+        if self.polled.load(Ordering::Relaxed) {
+            return self.transport.wake();
+        }
         let mut rt = self.send_thread.lock();
         if let Some(cb) = rt.as_mut() {
             //trigger sending
@@ -144,6 +382,49 @@ impl<RM: Message> Connection<RM> {
         }
     }
 
+    pub fn stats(&self) -> NetStats {
+        let mut stats = *self.stats.lock();
+        stats.frames_queued = self.packet_out.lock().frames_left(SPLIT_SIZE) + self.sequenced_out.lock().len() as u64;
+        stats.retransmits = self.transport.retransmits();
+        if let Some(udp) = self.udp.lock().as_ref() {
+            stats.retransmits += udp.retransmits();
+        }
+        stats
+    }
+
+    // Round trips are measured by whoever sends pings over this connection
+    pub fn record_rtt(&self, sample: Duration) { self.stats.lock().record_rtt(sample) }
+
+    fn count_sent(&self, size: usize) {
+        let mut stats = self.stats.lock();
+        stats.bytes_sent += size as u64;
+        stats.frames_sent += 1;
+    }
+
+    // Hand messages to `f` as soon as they are complete, on whichever thread completed them, instead of queueing them
+    // for `recv`. Messages received so far are handed over first
+    pub fn dispatch_to<F: Fn(Option<RM>) + Send + Sync + 'static>(&self, f: F) {
+        let mut dispatch = self.dispatch.write();
+        loop {
+            let queued = self.recvd_message_read.lock().try_recv();
+            match queued {
+                Ok(Ok(msg)) => f(Some(msg)),
+                Ok(Err(_)) => f(None),
+                Err(_) => break,
+            }
+        }
+        *dispatch = Some(Dispatch(Box::new(f)));
+    }
+
+    fn deliver(&self, msg: Result<RM, ConnectionError>) {
+        match self.dispatch.read().as_ref() {
+            Some(Dispatch(f)) => f(msg.ok()),
+            None => {
+                let _ = self.recvd_message_write.lock().send(msg);
+            },
+        }
+    }
+
     pub fn try_recv(&self) -> Result<RM, ()> {
         match self.recvd_message_read.lock().try_recv() {
             Ok(Ok(msg)) => Ok(msg),
@@ -163,49 +444,42 @@ impl<RM: Message> Connection<RM> {
             if !self.running.load(Ordering::Relaxed) {
                 break;
             }
-            if *self.packet_out_count.read() == 0 {
-                thread::park();
-                continue;
-            }
-            // find next package
-            let mut packets = self.packet_out.lock();
-            for i in 0..255 {
-                if packets[i].len() != 0 {
-                    // build part
-                    const SPLIT_SIZE: u64 = 2000;
-                    match packets[i][0].generate_frame(SPLIT_SIZE) {
-                        Ok(frame) => {
-                            // send it
-                            match self.tcp.send(frame) {
-                                Ok(_) => {},
-                                Err(e) => match e {
-                                    Error::NetworkErr(io_err) => match io_err.kind() {
-                                        /* Shut down the thread */
-                                        ErrorKind::ConnectionReset
-                                        | ErrorKind::ConnectionRefused
-                                        | ErrorKind::ConnectionAborted => {
-                                            //Close recv thread, since connection has been severed
-                                            let recvd_message_write = self.recvd_message_write.lock();
-                                            recvd_message_write
-                                                .send(Err(ConnectionError::Disconnected))
-                                                .unwrap_or_else(|e| eprintln!("send_worker> {:?}", e));
-                                            break 'thread;
-                                        },
-                                        e => panic!("{:?}", e), /* Panic on any IOError we aren't expecting here*/
-                                    },
-                                    _ => { /* Cannot (De)Serialize packet, discard */ },
-                                },
-                            }
-                        },
-                        Err(FrameError::SendDone) => {
-                            packets[i].pop_front();
-                            let mut p = self.packet_out_count.write();
-                            *p -= 1;
+            // find next frame, don't hold the lock while sending so new messages can still be queued
+            let next = {
+                let mut queue = self.packet_out.lock();
+                let next = queue.next_frame(SPLIT_SIZE);
+                self.sending.store(next.is_some(), Ordering::Relaxed);
+                next
+            };
+            let frame = match next {
+                Some(frame) => frame,
+                None => {
+                    thread::park();
+                    continue;
+                },
+            };
+            // send it
+            let size = frame.wire_size();
+            let result = self.transport.send(frame);
+            self.sending.store(false, Ordering::Relaxed);
+            match result {
+                Ok(_) => self.count_sent(size),
+                Err(e) => match e {
+                    Error::NetworkErr(io_err) => match io_err.kind() {
+                        /* Shut down the thread */
+                        ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionRefused
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe => {
+                            //Close recv thread, since connection has been severed
+                            self.running.store(false, Ordering::Relaxed);
+                            self.deliver(Err(ConnectionError::Disconnected));
+                            break 'thread;
                         },
-                    }
-
-                    break;
-                }
+                        e => panic!("{:?}", e), /* Panic on any IOError we aren't expecting here*/
+                    },
+                    _ => { /* Cannot (De)Serialize packet, discard */ },
+                },
             }
         }
     }
@@ -215,30 +489,9 @@ impl<RM: Message> Connection<RM> {
             if !self.running.load(Ordering::Relaxed) {
                 break;
             }
-            let frame = self.tcp.recv();
+            let frame = self.transport.recv();
             match frame {
-                Ok(frame) => {
-                    match frame {
-                        Frame::Header { id, .. } => {
-                            let msg = IncomingPacket::new(frame);
-                            let mut packets = self.packet_in.lock();
-                            packets.insert(id, msg);
-                        },
-                        Frame::Data { id, .. } => {
-                            let mut packets = self.packet_in.lock();
-                            let packet = packets.get_mut(&id);
-                            if packet.unwrap().load_data_frame(frame) {
-                                //convert
-                                let packet = packets.get_mut(&id);
-                                let data = packet.unwrap().data();
-                                debug!("received packet: {:?}", &data);
-
-                                let recvd_message_write = self.recvd_message_write.lock();
-                                recvd_message_write.send(Ok(RM::from_bytes(data).unwrap())).unwrap();
-                            }
-                        },
-                    }
-                },
+                Ok(frame) => self.handle_frame(frame),
                 Err(e) => {
                     error!("Net Error {:?}", &e);
 
@@ -247,12 +500,10 @@ impl<RM: Message> Connection<RM> {
                             ErrorKind::ConnectionReset //Connection reset by remote server
                             | ErrorKind::ConnectionAborted //Connection aborted (terminated) by remote server
                             | ErrorKind::ConnectionRefused //Connection refused by remote server
+                            | ErrorKind::UnexpectedEof //Connection closed by remote
                             => {
                                 //Close recv thread, since connection has been severed
-                                let recvd_message_write = self.recvd_message_write.lock();
-                                recvd_message_write
-                                    .send(Err(ConnectionError::Disconnected))
-                                    .unwrap_or_else(|e| eprintln!("recv_worker> {:?}", e));
+                                self.deliver(Err(ConnectionError::Disconnected));
                                 break 'thread;
                             },
                             e => {
@@ -274,31 +525,20 @@ impl<RM: Message> Connection<RM> {
             if !self.running.load(Ordering::Relaxed) {
                 break;
             }
-            if *self.packet_out_count.read() == 0 {
-                thread::park();
-                continue;
-            }
-            // find next package
-            let mut packets = self.packet_out.lock();
-            for i in 0..255 {
-                if packets[i].len() != 0 {
-                    // build part
-                    const SPLIT_SIZE: u64 = 2000;
-                    match packets[i][0].generate_frame(SPLIT_SIZE) {
-                        Ok(frame) => {
-                            // send it
-                            let mut udp = self.udp.lock();
-                            udp.as_mut().unwrap().send(frame).unwrap();
-                        },
-                        Err(FrameError::SendDone) => {
-                            packets[i].pop_front();
-                            let mut p = self.packet_out_count.write();
-                            *p -= 1;
-                        },
-                    }
-
-                    break;
-                }
+            let next = self.sequenced_out.lock().pop_front();
+            let frame = match next {
+                Some(frame) => frame,
+                None => {
+                    thread::park();
+                    continue;
+                },
+            };
+            // send it
+            let udp = self.udp.lock().clone();
+            let size = frame.wire_size();
+            match udp.unwrap().send(frame) {
+                Ok(_) => self.count_sent(size),
+                Err(e) => warn!("Failed to send udp frame: {:?}", e),
             }
         }
     }
@@ -308,31 +548,11 @@ impl<RM: Message> Connection<RM> {
             if !self.running.load(Ordering::Relaxed) {
                 break;
             }
-            let mut udp = self.udp.lock();
-            let frame = udp.as_mut().unwrap().recv();
+            // don't hold the lock while blocking, the send worker needs it too
+            let udp = self.udp.lock().clone();
+            let frame = udp.unwrap().recv();
             match frame {
-                Ok(frame) => {
-                    match frame {
-                        Frame::Header { id, .. } => {
-                            let msg = IncomingPacket::new(frame);
-                            let mut packets = self.packet_in.lock();
-                            packets.insert(id, msg);
-                        },
-                        Frame::Data { id, .. } => {
-                            let mut packets = self.packet_in.lock();
-                            let packet = packets.get_mut(&id);
-                            if packet.unwrap().load_data_frame(frame) {
-                                //convert
-                                let packet = packets.get_mut(&id);
-                                let data = packet.unwrap().data();
-                                debug!("received packet: {:?}", &data);
-
-                                let recvd_message_write = self.recvd_message_write.lock();
-                                recvd_message_write.send(Ok(RM::from_bytes(data).unwrap())).unwrap();
-                            }
-                        },
-                    }
-                },
+                Ok(frame) => self.handle_frame(frame),
                 Err(e) => {
                     error!("Net Error {:?}", &e);
 
@@ -343,10 +563,7 @@ impl<RM: Message> Connection<RM> {
                             | ErrorKind::ConnectionRefused //Connection refused by remote server
                             => {
                                 //Close recv thread, since connection has been severed
-                                let recvd_message_write = self.recvd_message_write.lock();
-                                recvd_message_write
-                                    .send(Err(ConnectionError::Disconnected))
-                                    .unwrap_or_else(|e| eprintln!("recv_worker_udp> {:?}", e));
+                                self.deliver(Err(ConnectionError::Disconnected));
                                 break 'thread;
                             },
                             e => {
@@ -363,18 +580,97 @@ impl<RM: Message> Connection<RM> {
         }
     }
 
-    #[allow(dead_code)]
-    fn bind_udp<T: ToSocketAddrs>(bind_addr: &T) -> Result<UdpSocket, Error> {
-        let sock = UdpSocket::bind(&bind_addr);
-        match sock {
-            Ok(s) => Ok(s),
-            Err(_e) => {
-                let new_bind = bind_addr.to_socket_addrs()?.next().unwrap().port() + 1;
-                let ip = get_if_addrs().unwrap()[0].ip();
-                let new_addr = SocketAddr::new(ip, new_bind);
-                warn!("Binding local port failed, trying {}", new_addr);
-                Connection::<RM>::bind_udp(&new_addr)
+    // Sort a received frame into its packet and publish the message once the packet is complete
+    fn handle_frame(&self, frame: Frame) {
+        {
+            let mut stats = self.stats.lock();
+            stats.bytes_recv += frame.wire_size() as u64;
+            stats.frames_recv += 1;
+        }
+        let stream = match frame {
+            Frame::Sequenced { id, stream, .. } if self.is_stale(id, stream) => return,
+            Frame::Sequenced { stream, .. } => Some(stream),
+            _ => None,
+        };
+        let id = frame.id();
+        // whoever the message is delivered to may send right away, don't hold the lock
+        let result = self.packet_in.lock().load_frame(frame);
+        match result {
+            Ok(Some(packet)) => {
+                let data = match self.decode(id, packet) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("Could not decode packet {}: {:?}", id, e);
+                        return;
+                    },
+                };
+                // only a packet that decrypted fine may make older ones of its stream stale, a forged id could
+                // silence the stream otherwise
+                if let Some(stream) = stream {
+                    let mut newest = self.sequenced_in.lock();
+                    let newest = newest.entry(stream).or_insert(0);
+                    if id <= *newest {
+                        debug!("dropping stale packet {} of stream {}", id, stream);
+                        self.stats.lock().frames_stale += 1;
+                        return;
+                    }
+                    *newest = id;
+                }
+                debug!("received packet: {:?}", &data);
+
+                match RM::from_bytes(&data) {
+                    Ok(msg) => self.deliver(Ok(msg)),
+                    Err(e) => warn!("Could not deserialize packet {}: {:?}", id, e),
+                }
             },
+            Ok(None) => {},
+            Err(e) => warn!("Dropping corrupt packet {}: {:?}", id, e),
+        }
+    }
+
+    // whether a newer packet of the stream was received already
+    fn is_stale(&self, id: u64, stream: u64) -> bool {
+        let stale = self
+            .sequenced_in
+            .lock()
+            .get(&stream)
+            .map(|newest| id <= *newest)
+            .unwrap_or(false);
+        if stale {
+            debug!("dropping stale packet {} of stream {}", id, stream);
+            self.stats.lock().frames_stale += 1;
         }
+        stale
     }
+
+    // undo encryption and compression of a complete packet
+    fn decode(&self, id: u64, packet: IncomingPacket) -> Result<Vec<u8>, Error> {
+        let (compressed, encrypted) = (packet.is_compressed(), packet.is_encrypted());
+        let mut data = packet.into_data();
+        match (self.cipher.read().as_ref(), encrypted) {
+            (Some(cipher), true) => data = cipher.decrypt(id, &data)?,
+            (None, false) => {},
+            // once encryption is on, plaintext can't be trusted anymore
+            _ => return Err(Error::CannotDecrypt),
+        }
+        if compressed {
+            data = decompress(&data)?;
+        }
+        Ok(data)
+    }
+}
+
+impl<RM: Message> Handler for Connection<RM> {
+    fn on_frame(&self, frame: Frame) { self.handle_frame(frame) }
+
+    fn next_frame(&self) -> Option<Frame> {
+        if !self.running.load(Ordering::Relaxed) {
+            return None;
+        }
+        let frame = self.packet_out.lock().next_frame(SPLIT_SIZE)?;
+        self.count_sent(frame.wire_size());
+        Some(frame)
+    }
+
+    fn on_closed(&self) { self.deliver(Err(ConnectionError::Disconnected)) }
 }
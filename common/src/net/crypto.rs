@@ -0,0 +1,165 @@
+// Standard
+use std::fmt;
+
+// Library
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret, StaticSecret};
+
+// Parent
+use super::Error;
+
+pub type PublicKeyBytes = [u8; 32];
+
+// What encrypting adds to a packet, the authentication tag
+pub const OVERHEAD: usize = 16;
+
+// To keep keys in a text file
+pub fn to_hex(bytes: &[u8; 32]) -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() }
+
+pub fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// The long-lived key the answering side of a key exchange proves it holds. Whoever checks the public half against the
+// one they expected knows nobody else can read along
+#[derive(Clone)]
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKeyBytes,
+}
+
+impl Identity {
+    pub fn generate() -> Identity { Identity::from_bytes(StaticSecret::random().to_bytes()) }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Identity {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret).to_bytes();
+        Identity { secret, public }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] { self.secret.to_bytes() }
+
+    pub fn public(&self) -> PublicKeyBytes { self.public }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Identity {{ public: {:?} }}", self.public) }
+}
+
+// Our half of an x25519 key exchange. The initiator sends its key, the responder answers with a key of its own and its
+// identity. The session keys are derived from both the exchange of the two keys and the one of the initiator's key with
+// the identity, so only the identity's holder ends up with them
+pub struct Handshake {
+    secret: ReusableSecret,
+    public: PublicKeyBytes,
+}
+
+impl Handshake {
+    pub fn new() -> Handshake {
+        let secret = ReusableSecret::random();
+        let public = PublicKey::from(&secret).to_bytes();
+        Handshake { secret, public }
+    }
+
+    pub fn public(&self) -> PublicKeyBytes { self.public }
+
+    // Finish an exchange we started with the responder's answer. Only safe once `identity` is known to be the expected
+    pub fn finish(self, remote: PublicKeyBytes, identity: PublicKeyBytes) -> Result<Cipher, Error> {
+        let shared = [
+            self.secret.diffie_hellman(&PublicKey::from(remote)),
+            self.secret.diffie_hellman(&PublicKey::from(identity)),
+        ];
+        Cipher::derive(&shared, [self.public, remote, identity], true)
+    }
+
+    // Answer an exchange the remote started as `identity`, the remote is sent our public key and the identity's
+    pub fn respond(self, remote: PublicKeyBytes, identity: &Identity) -> Result<Cipher, Error> {
+        let shared = [
+            self.secret.diffie_hellman(&PublicKey::from(remote)),
+            identity.secret.diffie_hellman(&PublicKey::from(remote)),
+        ];
+        Cipher::derive(&shared, [remote, self.public, identity.public], false)
+    }
+}
+
+impl fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Handshake {{ public: {:?} }}", self.public) }
+}
+
+// Authenticated encryption of whole packets. The packet id is used as nonce, it never repeats for one direction
+pub struct Cipher {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+}
+
+fn nonce(id: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(&id.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+impl Cipher {
+    // Each direction gets its own key, derived from the shared secrets and the initiator's, the responder's and the
+    // identity's public keys
+    fn derive(shared: &[SharedSecret; 2], keys: [PublicKeyBytes; 3], initiator: bool) -> Result<Cipher, Error> {
+        if !shared.iter().all(|shared| shared.was_contributory()) {
+            return Err(Error::InvalidKey);
+        }
+        let derive = |label: &[u8]| {
+            let mut hasher = Sha256::new();
+            for shared in shared {
+                hasher.update(shared.as_bytes());
+            }
+            for key in &keys {
+                hasher.update(key);
+            }
+            hasher.update(label);
+            ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
+        };
+        let (to_responder, to_initiator) = (derive(b"to responder"), derive(b"to initiator"));
+        Ok(if initiator {
+            Cipher {
+                send: to_responder,
+                recv: to_initiator,
+            }
+        } else {
+            Cipher {
+                send: to_initiator,
+                recv: to_responder,
+            }
+        })
+    }
+
+    pub fn encrypt(&self, id: u64, bytes: &[u8]) -> Vec<u8> {
+        let payload = Payload {
+            msg: bytes,
+            aad: &id.to_le_bytes(),
+        };
+        self.send
+            .encrypt(&nonce(id), payload)
+            .expect("packet too large to encrypt")
+    }
+
+    pub fn decrypt(&self, id: u64, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let payload = Payload {
+            msg: bytes,
+            aad: &id.to_le_bytes(),
+        };
+        self.recv.decrypt(&nonce(id), payload).map_err(|_| Error::CannotDecrypt)
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Cipher") }
+}
@@ -0,0 +1,101 @@
+// Standard
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+};
+
+// Library
+use parking_lot::Mutex;
+
+// Parent
+use super::{
+    packet::Frame,
+    protocol::{Listener, Protocol, Transport},
+    Error,
+};
+
+fn closed() -> Error {
+    Error::NetworkErr(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "memory transport closed",
+    ))
+}
+
+// In-process transport, frames are handed over through mpscs without touching any socket
+#[derive(Debug)]
+pub struct Memory {
+    send: Mutex<mpsc::Sender<Frame>>,
+    recv: Mutex<mpsc::Receiver<Frame>>,
+}
+
+impl Memory {
+    // Two ends connected to each other
+    pub fn pair() -> (Memory, Memory) {
+        let (a_send, b_recv) = mpsc::channel();
+        let (b_send, a_recv) = mpsc::channel();
+        (
+            Memory {
+                send: Mutex::new(a_send),
+                recv: Mutex::new(a_recv),
+            },
+            Memory {
+                send: Mutex::new(b_send),
+                recv: Mutex::new(b_recv),
+            },
+        )
+    }
+}
+
+impl Protocol for Memory {
+    fn send(&self, frame: Frame) -> Result<(), Error> { self.send.lock().send(frame).map_err(|_| closed()) }
+
+    //blocking
+    fn recv(&self) -> Result<Frame, Error> { self.recv.lock().recv().map_err(|_| closed()) }
+
+    // drop our sender, the remote's recv fails once it took everything sent before
+    fn close(&self) { *self.send.lock() = mpsc::channel().0; }
+}
+
+// The in-process counterpart of a TcpListener, `connect` hands the other end to whoever is accepting
+#[derive(Debug)]
+pub struct MemoryListener {
+    incoming_send: Mutex<mpsc::Sender<Memory>>,
+    incoming_recv: Mutex<mpsc::Receiver<Memory>>,
+    stopped: AtomicBool,
+}
+
+impl MemoryListener {
+    pub fn new() -> Arc<MemoryListener> {
+        let (incoming_send, incoming_recv) = mpsc::channel();
+        Arc::new(MemoryListener {
+            incoming_send: Mutex::new(incoming_send),
+            incoming_recv: Mutex::new(incoming_recv),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    pub fn connect(&self) -> Result<Memory, Error> {
+        let (client, server) = Memory::pair();
+        self.incoming_send.lock().send(server).map_err(|_| closed())?;
+        Ok(client)
+    }
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> Result<Transport, Error> {
+        let server = self.incoming_recv.lock().recv().map_err(|_| closed())?;
+        if self.stopped.load(Ordering::Relaxed) {
+            return Err(closed());
+        }
+        Ok(Box::new(server))
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        self.stopped.store(true, Ordering::Relaxed);
+        // wake up a blocked `accept`
+        self.connect().map(|_| ())
+    }
+}
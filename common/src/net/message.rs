@@ -11,6 +11,9 @@ pub enum Error {
     NetworkErr(io::Error),
     CannotSerialize,
     CannotDeserialize,
+    CannotDecompress,
+    CannotDecrypt,
+    InvalidKey,
 }
 
 impl From<io::Error> for Error {
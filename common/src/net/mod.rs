@@ -1,7 +1,14 @@
+mod compression;
 pub mod connection;
+mod crypto;
+mod memory;
 pub mod message;
+mod netsim;
 mod packet;
 mod protocol;
+mod queue;
+mod reactor;
+mod stats;
 mod tcp;
 #[cfg(test)]
 pub mod tests;
@@ -10,7 +17,16 @@ pub mod udpmgr;
 
 // Reexports
 pub use self::{
-    connection::Connection,
+    connection::{Connection, Delivery},
+    crypto::{from_hex, to_hex, Handshake, Identity, PublicKeyBytes},
+    memory::{Memory, MemoryListener},
     message::{ConnectionMessage, Error, Message},
+    netsim::{NetProfile, SimulatedListener, Simulator},
+    packet::Frame,
+    protocol::{Listener, Protocol, Transport},
+    queue::{PRIO_DEFAULT, PRIO_MAX, PRIO_MIN},
+    reactor::{PolledListener, PolledTcp, PolledUdp, Reactor},
+    stats::NetStats,
+    tcp::Tcp,
     udpmgr::UdpMgr,
 };
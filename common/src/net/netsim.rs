@@ -0,0 +1,325 @@
+// Standard
+use std::{
+    cmp::{max, Ordering},
+    collections::BinaryHeap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Library
+use parking_lot::{Condvar, Mutex};
+use rand::{thread_rng, Rng};
+
+// Parent
+use super::{
+    packet::Frame,
+    protocol::{Listener, Protocol, Transport},
+    Error,
+};
+
+// A reliable transport resends a lost frame after at least this long
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+// How bad the simulated link is, every value applies to the outgoing direction only
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetProfile {
+    pub latency: Duration,
+    // frames are delayed by latency +- up to jitter, which reorders unreliable frames
+    pub jitter: Duration,
+    // chance from 0 to 1 that a frame is lost
+    pub loss: f32,
+    // bytes per second, None for unlimited
+    pub bandwidth: Option<u64>,
+}
+
+impl NetProfile {
+    pub fn perfect() -> NetProfile {
+        NetProfile {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            bandwidth: None,
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<NetProfile> {
+        let (latency, jitter, loss, bandwidth) = match name {
+            "perfect" => return Some(NetProfile::perfect()),
+            "lan" => (1, 1, 0.0, None),
+            "wifi" => (20, 10, 0.01, Some(2_000_000)),
+            "bad-wifi" => (80, 60, 0.05, Some(250_000)),
+            "mobile" => (150, 50, 0.02, Some(100_000)),
+            _ => return None,
+        };
+        Some(NetProfile {
+            latency: Duration::from_millis(latency),
+            jitter: Duration::from_millis(jitter),
+            loss,
+            bandwidth,
+        })
+    }
+
+    fn transmit_time(&self, bytes: usize) -> Duration {
+        match self.bandwidth {
+            Some(bw) if bw > 0 => {
+                let nanos = bytes as u64 * 1_000_000_000 / bw;
+                Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+            },
+            _ => Duration::from_millis(0),
+        }
+    }
+
+    fn sample_delay(&self) -> Duration {
+        let jitter = self.jitter.as_secs() * 1_000_000 + self.jitter.subsec_micros() as u64;
+        if jitter == 0 {
+            return self.latency;
+        }
+        let offset = thread_rng().gen_range(0, 2 * jitter + 1);
+        if offset >= jitter {
+            self.latency + Duration::from_micros(offset - jitter)
+        } else {
+            self.latency
+                .checked_sub(Duration::from_micros(jitter - offset))
+                .unwrap_or(Duration::from_millis(0))
+        }
+    }
+
+    fn lost(&self) -> bool { self.loss > 0.0 && thread_rng().gen::<f32>() < self.loss }
+}
+
+// Either the name of a preset (perfect, lan, wifi, bad-wifi, mobile) or a list like
+// `latency=120,jitter=40,loss=0.05,bandwidth=100000` with milliseconds and bytes per second
+impl FromStr for NetProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NetProfile, String> {
+        if let Some(profile) = NetProfile::preset(s) {
+            return Ok(profile);
+        }
+        let mut profile = NetProfile::perfect();
+        for pair in s.split(',').filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let value = kv
+                .next()
+                .ok_or_else(|| format!("expected key=value, got '{}'", pair))?
+                .trim();
+            let invalid = || format!("invalid value for {}: '{}'", key, value);
+            match key {
+                "latency" => profile.latency = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                "jitter" => profile.jitter = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+                "loss" => {
+                    let loss: f32 = value.parse().map_err(|_| invalid())?;
+                    if loss < 0.0 || loss > 1.0 {
+                        return Err(format!("loss has to be between 0 and 1, got {}", loss));
+                    }
+                    profile.loss = loss;
+                },
+                "bandwidth" => profile.bandwidth = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("unknown network profile or key '{}'", key)),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+#[derive(Debug)]
+struct Delayed {
+    due: Instant,
+    seq: u64,
+    frame: Frame,
+}
+
+// BinaryHeap is a max heap, the earliest frame has to come out first
+impl Ord for Delayed {
+    fn cmp(&self, other: &Delayed) -> Ordering { (other.due, other.seq).cmp(&(self.due, self.seq)) }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Delayed) -> bool { self.due == other.due && self.seq == other.seq }
+}
+
+impl Eq for Delayed {}
+
+#[derive(Debug)]
+struct Link {
+    // when the link is done transmitting everything queued so far
+    free: Instant,
+    // reliable frames are never delivered before the ones sent earlier
+    last_due: Instant,
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    inner: Arc<dyn Protocol>,
+    queue: Mutex<BinaryHeap<Delayed>>,
+    wakeup: Condvar,
+    running: AtomicBool,
+}
+
+// Wraps a transport and delays, reorders, drops and throttles the frames sent over it.
+// A reliable link models loss as a retransmit and keeps frames in order, an unreliable one really drops and reorders.
+// Only outgoing frames are affected, wrap both ends for a symmetric link.
+#[derive(Debug)]
+pub struct Simulator {
+    profile: NetProfile,
+    reliable: bool,
+    link: Mutex<Link>,
+    retransmits: AtomicUsize,
+    shared: Arc<Shared>,
+}
+
+impl Simulator {
+    pub fn reliable(inner: Transport, profile: NetProfile) -> Simulator {
+        Simulator::new(Arc::from(inner), profile, true)
+    }
+
+    pub fn unreliable(inner: Arc<dyn Protocol>, profile: NetProfile) -> Simulator {
+        Simulator::new(inner, profile, false)
+    }
+
+    fn new(inner: Arc<dyn Protocol>, profile: NetProfile, reliable: bool) -> Simulator {
+        let shared = Arc::new(Shared {
+            inner,
+            queue: Mutex::new(BinaryHeap::new()),
+            wakeup: Condvar::new(),
+            running: AtomicBool::new(true),
+        });
+        let s = shared.clone();
+        thread::spawn(move || Simulator::deliver_worker(&s));
+        let now = Instant::now();
+        Simulator {
+            profile,
+            reliable,
+            link: Mutex::new(Link {
+                free: now,
+                last_due: now,
+                seq: 0,
+            }),
+            retransmits: AtomicUsize::new(0),
+            shared,
+        }
+    }
+
+    pub fn profile(&self) -> &NetProfile { &self.profile }
+
+    // Once stopped, whatever is still in flight is delivered before the inner transport is closed
+    fn deliver_worker(shared: &Shared) {
+        let mut queue = shared.queue.lock();
+        loop {
+            let now = Instant::now();
+            let wait = match queue.peek() {
+                None if !shared.running.load(AtomicOrdering::Relaxed) => break,
+                None => None,
+                Some(d) if d.due <= now => {
+                    let frame = queue.pop().unwrap().frame;
+                    drop(queue);
+                    if let Err(e) = shared.inner.send(frame) {
+                        warn!("Simulated link failed to send frame: {:?}", e);
+                        return;
+                    }
+                    queue = shared.queue.lock();
+                    continue;
+                },
+                Some(d) => Some(d.due - now),
+            };
+            match wait {
+                None => shared.wakeup.wait(&mut queue),
+                Some(wait) => {
+                    shared.wakeup.wait_for(&mut queue, wait);
+                },
+            }
+        }
+        drop(queue);
+        shared.inner.close();
+    }
+
+    fn stop(&self) {
+        self.shared.running.store(false, AtomicOrdering::Relaxed);
+        let _queue = self.shared.queue.lock();
+        self.shared.wakeup.notify_one();
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) { Simulator::stop(self) }
+}
+
+impl Protocol for Simulator {
+    fn send(&self, frame: Frame) -> Result<(), Error> {
+        let lost = self.profile.lost();
+        if lost && !self.reliable {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut link = self.link.lock();
+        link.free = max(link.free, now) + self.profile.transmit_time(frame.wire_size());
+        let mut due = link.free + self.profile.sample_delay();
+        if self.reliable {
+            if lost {
+                self.retransmits.fetch_add(1, AtomicOrdering::Relaxed);
+                due += max(RETRANSMIT_TIMEOUT, self.profile.latency * 2);
+            }
+            due = max(due, link.last_due);
+            link.last_due = due;
+        }
+        let seq = link.seq;
+        link.seq += 1;
+        drop(link);
+
+        self.shared.queue.lock().push(Delayed { due, seq, frame });
+        self.shared.wakeup.notify_one();
+        Ok(())
+    }
+
+    //blocking
+    fn recv(&self) -> Result<Frame, Error> { self.shared.inner.recv() }
+
+    fn local_addr(&self) -> Option<SocketAddr> { self.shared.inner.local_addr() }
+
+    fn peer_addr(&self) -> Option<SocketAddr> { self.shared.inner.peer_addr() }
+
+    fn retransmits(&self) -> u64 {
+        self.retransmits.load(AtomicOrdering::Relaxed) as u64 + self.shared.inner.retransmits()
+    }
+
+    fn close(&self) { Simulator::stop(self) }
+
+    fn wrap_unreliable(&self, udp: Arc<dyn Protocol>) -> Arc<dyn Protocol> {
+        Arc::new(Simulator::unreliable(udp, self.profile.clone()))
+    }
+}
+
+// Wraps every accepted transport in a reliable `Simulator`
+pub struct SimulatedListener {
+    inner: Arc<dyn Listener>,
+    profile: NetProfile,
+}
+
+impl SimulatedListener {
+    pub fn new(inner: Arc<dyn Listener>, profile: NetProfile) -> Arc<SimulatedListener> {
+        Arc::new(SimulatedListener { inner, profile })
+    }
+}
+
+impl Listener for SimulatedListener {
+    fn accept(&self) -> Result<Transport, Error> {
+        Ok(Box::new(Simulator::reliable(
+            self.inner.accept()?,
+            self.profile.clone(),
+        )))
+    }
+
+    fn stop(&self) -> Result<(), Error> { self.inner.stop() }
+}
@@ -1,7 +1,178 @@
+// Standard
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    io::Cursor,
+    sync::Arc,
+};
+
+// Library
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// Parent
+use super::{
+    crypto::Cipher,
+    protocol::{flags, parse_flags, PROTOCOL_FRAME_DATA, PROTOCOL_FRAME_HEADER, PROTOCOL_FRAME_SEQUENCED},
+    queue::{PRIO_DEFAULT, PRIO_MIN},
+    Error,
+};
+
+// Anything larger is taken for garbage rather than waited for
+const MAX_FRAME_DATA: u64 = 16 * 1024 * 1024;
+const MAX_PACKET_SIZE: u64 = 16 * 1024 * 1024;
+// How far ahead of the next frame in order a data frame may be
+const MAX_PENDING_FRAMES: u64 = 1024;
+// A remote sends one reliable packet per priority lane at a time, more open ones are garbage
+const MAX_OPEN_PACKETS: usize = PRIO_MIN as usize + 1;
+// How many ids of completed packets are remembered to recognize late and duplicate frames
+const COMPLETED_WINDOW: usize = 1024;
+
 #[derive(Debug)]
 pub enum Frame {
-    Header { id: u64, length: u64 },
-    Data { id: u64, frame_no: u64, data: Vec<u8> },
+    // `compressed` and `encrypted` tell how the packet's bytes have to be decoded once complete
+    Header {
+        id: u64,
+        length: u64,
+        compressed: bool,
+        encrypted: bool,
+    },
+    Data {
+        id: u64,
+        frame_no: u64,
+        data: Vec<u8>,
+    },
+    // A whole packet in one frame, stale if a frame with a higher id of the same stream was received already
+    Sequenced {
+        id: u64,
+        stream: u64,
+        compressed: bool,
+        encrypted: bool,
+        data: Vec<u8>,
+    },
+}
+
+impl Frame {
+    // roughly what the frame takes on the wire
+    pub fn wire_size(&self) -> usize {
+        match self {
+            Frame::Header { .. } => 18,
+            Frame::Data { data, .. } => 25 + data.len(),
+            Frame::Sequenced { data, .. } => 26 + data.len(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            Frame::Header { id, .. } => *id,
+            Frame::Data { id, .. } => *id,
+            Frame::Sequenced { id, .. } => *id,
+        }
+    }
+
+    // Append the frame in the same layout `Tcp` writes it
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        // writing to a Vec can't fail
+        match self {
+            Frame::Header {
+                id,
+                length,
+                compressed,
+                encrypted,
+            } => {
+                buf.write_u8(PROTOCOL_FRAME_HEADER).unwrap();
+                buf.write_u8(flags(*compressed, *encrypted)).unwrap();
+                buf.write_u64::<LittleEndian>(*id).unwrap();
+                buf.write_u64::<LittleEndian>(*length).unwrap();
+            },
+            Frame::Data { id, frame_no, data } => {
+                buf.write_u8(PROTOCOL_FRAME_DATA).unwrap();
+                buf.write_u64::<LittleEndian>(*id).unwrap();
+                buf.write_u64::<LittleEndian>(*frame_no).unwrap();
+                buf.write_u64::<LittleEndian>(data.len() as u64).unwrap();
+                buf.extend_from_slice(data);
+            },
+            Frame::Sequenced {
+                id,
+                stream,
+                compressed,
+                encrypted,
+                data,
+            } => {
+                buf.write_u8(PROTOCOL_FRAME_SEQUENCED).unwrap();
+                buf.write_u8(flags(*compressed, *encrypted)).unwrap();
+                buf.write_u64::<LittleEndian>(*id).unwrap();
+                buf.write_u64::<LittleEndian>(*stream).unwrap();
+                buf.write_u64::<LittleEndian>(data.len() as u64).unwrap();
+                buf.extend_from_slice(data);
+            },
+        }
+    }
+
+    // Parse the frame at the start of `buf`, returns it with the number of bytes it took. None if `buf` doesn't hold
+    // a whole frame yet
+    pub fn read_from(buf: &[u8]) -> Result<Option<(Frame, usize)>, Error> {
+        let mut cur = Cursor::new(buf);
+        // running out of bytes only means the rest didn't arrive yet
+        macro_rules! read {
+            ($e:expr) => {
+                match $e {
+                    Ok(v) => v,
+                    Err(_) => return Ok(None),
+                }
+            };
+        }
+        let read_data = |cur: &mut Cursor<&[u8]>| -> Result<Option<Vec<u8>>, Error> {
+            let len = read!(cur.read_u64::<LittleEndian>());
+            if len > MAX_FRAME_DATA {
+                return Err(Error::CannotDeserialize);
+            }
+            let start = cur.position() as usize;
+            if buf.len() - start < len as usize {
+                return Ok(None);
+            }
+            cur.set_position(start as u64 + len);
+            Ok(Some(buf[start..start + len as usize].to_vec()))
+        };
+
+        let frame = match read!(cur.read_u8()) {
+            PROTOCOL_FRAME_HEADER => {
+                let (compressed, encrypted) = parse_flags(read!(cur.read_u8()));
+                Frame::Header {
+                    id: read!(cur.read_u64::<LittleEndian>()),
+                    length: read!(cur.read_u64::<LittleEndian>()),
+                    compressed,
+                    encrypted,
+                }
+            },
+            PROTOCOL_FRAME_DATA => {
+                let id = read!(cur.read_u64::<LittleEndian>());
+                let frame_no = read!(cur.read_u64::<LittleEndian>());
+                match read_data(&mut cur)? {
+                    Some(data) => Frame::Data { id, frame_no, data },
+                    None => return Ok(None),
+                }
+            },
+            PROTOCOL_FRAME_SEQUENCED => {
+                let (compressed, encrypted) = parse_flags(read!(cur.read_u8()));
+                let id = read!(cur.read_u64::<LittleEndian>());
+                let stream = read!(cur.read_u64::<LittleEndian>());
+                match read_data(&mut cur)? {
+                    Some(data) => Frame::Sequenced {
+                        id,
+                        stream,
+                        compressed,
+                        encrypted,
+                        data,
+                    },
+                    None => return Ok(None),
+                }
+            },
+            x => {
+                error!("invalid frame recieved: {}", x);
+                return Err(Error::CannotDeserialize);
+            },
+        };
+        Ok(Some((frame, cur.position() as usize)))
+    }
 }
 
 #[derive(Debug)]
@@ -9,16 +180,38 @@ pub enum FrameError {
     SendDone,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum PacketError {
+    // A frame belonging to another packet was handed to this one
+    IdMismatch { expected: u64, got: u64 },
+    // A second header announced a different length than the first one
+    LengthMismatch { expected: u64, got: u64 },
+    // More data arrived than the header announced
+    Overflow { length: u64, received: u64 },
+    // The header announced more than a packet may hold
+    TooLarge { length: u64 },
+    // A data frame too far ahead of the ones received in order
+    TooManyFrames { frame_no: u64 },
+    // Too many packets were started and not completed
+    TooManyOpen,
+}
+
 //TODO: enhance this PacketData / OutgoingPacket structure, so that only one byte stream is keept for broadcast
 #[derive(Debug)]
 pub struct PacketData {
     bytes: Vec<u8>,
     id: u64,
+    compressed: bool,
+    encrypted: bool,
 }
 
 #[derive(Debug)]
 pub struct OutgoingPacket {
     data: PacketData,
+    // the id is given once the header is due, see `number`
+    numbered: bool,
+    // encrypts the bytes once the id is known
+    seal: Option<Arc<Cipher>>,
     pos: u64,
     headersend: bool,
     dataframesno: u64,
@@ -28,29 +221,76 @@ pub struct OutgoingPacket {
 #[derive(Debug)]
 pub struct IncomingPacket {
     data: PacketData,
-    pos: u64,
+    // unknown until the header frame arrived, which might be after some data frames on udp
+    length: Option<u64>,
     dataframesno: u64,
+    // data frames which arrived before all their predecessors, keyed by frame_no
+    pending: BTreeMap<u64, Vec<u8>>,
+    pending_bytes: u64,
 }
 
-impl PacketData {
-    pub fn new(bytes: Vec<u8>, id: u64) -> PacketData { PacketData { bytes, id } }
+// Every packet of a connection that is being reassembled
+#[derive(Debug, Default)]
+pub struct IncomingPackets {
+    open: HashMap<u64, IncomingPacket>,
+    // the most recent ids of completed packets, frames of them or below them are late
+    completed: BTreeSet<u64>,
+}
 
-    pub fn new_size(size: u64, id: u64) -> PacketData {
+impl PacketData {
+    pub fn new(bytes: Vec<u8>, id: u64) -> PacketData {
         PacketData {
-            bytes: vec![0; size as usize],
+            bytes,
             id,
+            compressed: false,
+            encrypted: false,
         }
     }
 }
 
 impl OutgoingPacket {
+    #[allow(dead_code)]
     pub fn new(bytes: Vec<u8>, id: u64) -> OutgoingPacket {
+        let mut packet = OutgoingPacket::with_prio(bytes, PRIO_DEFAULT);
+        packet.number(id);
+        packet
+    }
+
+    // Without an id yet, `OutgoingQueue` numbers it when its header is due
+    pub fn with_prio(bytes: Vec<u8>, prio: u8) -> OutgoingPacket {
         OutgoingPacket {
-            data: PacketData::new(bytes, id),
+            data: PacketData::new(bytes, 0),
+            numbered: false,
+            seal: None,
             pos: 0,
             headersend: false,
             dataframesno: 0,
-            prio: 16,
+            prio,
+        }
+    }
+
+    // mark the bytes as compressed, the remote decompresses them after reassembly
+    pub fn compressed(mut self) -> OutgoingPacket {
+        self.data.compressed = true;
+        self
+    }
+
+    // encrypt the bytes once numbered, the remote decrypts them after reassembly
+    pub fn sealed(mut self, cipher: Arc<Cipher>) -> OutgoingPacket {
+        self.seal = Some(cipher);
+        self
+    }
+
+    pub fn is_numbered(&self) -> bool { self.numbered }
+
+    // The id is the nonce, so sealing has to wait for it. Ids are given in the order headers are sent, a header older
+    // than the packets the remote completed is refused
+    pub fn number(&mut self, id: u64) {
+        self.data.id = id;
+        self.numbered = true;
+        if let Some(cipher) = self.seal.take() {
+            self.data.bytes = cipher.encrypt(id, &self.data.bytes);
+            self.data.encrypted = true;
         }
     }
 
@@ -61,6 +301,8 @@ impl OutgoingPacket {
             Ok(Frame::Header {
                 id: self.data.id,
                 length: self.data.bytes.len() as u64,
+                compressed: self.data.compressed,
+                encrypted: self.data.encrypted,
             })
         } else {
             let remaining = self.data.bytes.len() as u64 - self.pos;
@@ -80,50 +322,153 @@ impl OutgoingPacket {
         }
     }
 
-    #[allow(dead_code)]
+    // frames `generate_frame` still has to produce with this size
+    pub fn frames_left(&self, size: u64) -> u64 {
+        let remaining = self.data.bytes.len() as u64 - self.pos;
+        let header = if self.headersend { 0 } else { 1 };
+        header + (remaining + size - 1) / size
+    }
+
     pub fn prio(&self) -> &u8 { &self.prio }
 }
 
 impl IncomingPacket {
-    pub fn new(header: Frame) -> IncomingPacket {
-        match header {
-            Frame::Header { id, length } => IncomingPacket {
-                data: PacketData::new_size(length, id),
-                pos: 0,
-                dataframesno: 0,
-            },
-            Frame::Data { .. } => {
-                panic!("not implemented");
-            },
+    pub fn new(id: u64) -> IncomingPacket {
+        IncomingPacket {
+            data: PacketData::new(Vec::new(), id),
+            length: None,
+            dataframesno: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
         }
     }
 
-    // returns finished
-    pub fn load_data_frame(&mut self, data: Frame) -> bool {
-        match data {
-            Frame::Header { .. } => {
-                panic!("not implemented");
+    // returns finished, frames may arrive in any order and duplicates are ignored
+    pub fn load_frame(&mut self, frame: Frame) -> Result<bool, PacketError> {
+        if frame.id() != self.data.id {
+            return Err(PacketError::IdMismatch {
+                expected: self.data.id,
+                got: frame.id(),
+            });
+        }
+        match frame {
+            Frame::Header {
+                length,
+                compressed,
+                encrypted,
+                ..
+            } => match self.length {
+                Some(expected) if expected != length => {
+                    return Err(PacketError::LengthMismatch { expected, got: length });
+                },
+                _ if length > MAX_PACKET_SIZE => return Err(PacketError::TooLarge { length }),
+                _ => {
+                    self.length = Some(length);
+                    self.data.compressed = compressed;
+                    self.data.encrypted = encrypted;
+                    self.data.bytes.reserve(length as usize);
+                },
             },
-            Frame::Data { id, frame_no, data } => {
-                if id != self.data.id {
-                    panic!("id missmatch {} <> {}", id, self.data.id);
+            Frame::Data { frame_no, data, .. } => {
+                if frame_no < self.dataframesno || self.pending.contains_key(&frame_no) {
+                    debug!("dropping duplicate frame {} of packet {}", frame_no, self.data.id);
+                    return Ok(self.is_done());
                 }
-                if frame_no != self.dataframesno {
-                    panic!("bufferin for frames not yet implemented");
+                if frame_no - self.dataframesno >= MAX_PENDING_FRAMES {
+                    return Err(PacketError::TooManyFrames { frame_no });
                 }
-                // copy data starting from self.pos
-                //TODO: check size of send with reserved
-                for (dst, src) in self.data.bytes[self.pos as usize..].iter_mut().zip(&data) {
-                    *dst = *src;
+                // out of order frames count as well, the header may not even be there yet
+                let length = self.length.unwrap_or(MAX_PACKET_SIZE);
+                let received = self.data.bytes.len() as u64 + self.pending_bytes + data.len() as u64;
+                if received > length {
+                    return Err(PacketError::Overflow { length, received });
                 }
-                self.pos += data.len() as u64;
-                self.dataframesno += 1;
-
-                self.pos == self.data.bytes.len() as u64
+                self.pending_bytes += data.len() as u64;
+                self.pending.insert(frame_no, data);
+                // copy over every frame that is now in order
+                while let Some(data) = self.pending.remove(&self.dataframesno) {
+                    self.pending_bytes -= data.len() as u64;
+                    self.data.bytes.extend_from_slice(&data);
+                    self.dataframesno += 1;
+                }
+            },
+            Frame::Sequenced {
+                compressed,
+                encrypted,
+                data,
+                ..
+            } => {
+                self.length = Some(data.len() as u64);
+                self.data.compressed = compressed;
+                self.data.encrypted = encrypted;
+                self.data.bytes = data;
             },
         }
+
+        match self.length {
+            Some(length) if self.data.bytes.len() as u64 > length => Err(PacketError::Overflow {
+                length,
+                received: self.data.bytes.len() as u64,
+            }),
+            _ => Ok(self.is_done()),
+        }
     }
 
+    pub fn is_done(&self) -> bool { self.length == Some(self.data.bytes.len() as u64) && self.pending.is_empty() }
+
     #[allow(dead_code)]
     pub fn data(&self) -> &Vec<u8> { &self.data.bytes }
+
+    pub fn into_data(self) -> Vec<u8> { self.data.bytes }
+
+    pub fn is_compressed(&self) -> bool { self.data.compressed }
+
+    pub fn is_encrypted(&self) -> bool { self.data.encrypted }
+}
+
+impl IncomingPackets {
+    pub fn new() -> IncomingPackets { IncomingPackets::default() }
+
+    // Returns the packet the frame completed. Frames of packets that were completed already are dropped, and so are
+    // frames older than every remembered id unless their packet is open. Headers are sent in id order, so one below the
+    // window can only be a replay and doesn't open the packet again. A packet that turns out corrupt is dropped with
+    // the error
+    pub fn load_frame(&mut self, frame: Frame) -> Result<Option<IncomingPacket>, PacketError> {
+        let id = frame.id();
+        if let Frame::Sequenced { .. } = frame {
+            // a whole packet, staleness is up to the stream
+            let mut packet = IncomingPacket::new(id);
+            packet.load_frame(frame)?;
+            return Ok(Some(packet));
+        }
+        let below_window = self.completed.len() >= COMPLETED_WINDOW
+            && self.completed.iter().next().map(|oldest| id < *oldest).unwrap_or(false);
+        let late = below_window && !self.open.contains_key(&id);
+        if late || self.completed.contains(&id) {
+            debug!("dropping late frame of packet {}", id);
+            return Ok(None);
+        }
+
+        let open = self.open.len();
+        let result = match self.open.entry(id) {
+            Entry::Occupied(mut e) => e.get_mut().load_frame(frame),
+            Entry::Vacant(_) if open >= MAX_OPEN_PACKETS => return Err(PacketError::TooManyOpen),
+            Entry::Vacant(e) => e.insert(IncomingPacket::new(id)).load_frame(frame),
+        };
+        match result {
+            Ok(true) => {
+                self.completed.insert(id);
+                if self.completed.len() > COMPLETED_WINDOW {
+                    let oldest = *self.completed.iter().next().unwrap();
+                    self.completed.remove(&oldest);
+                }
+                Ok(self.open.remove(&id))
+            },
+            Ok(false) => Ok(None),
+            Err(e) => {
+                self.open.remove(&id);
+                Err(e)
+            },
+        }
+    }
 }
@@ -1,10 +1,107 @@
+// Standard
+use std::{
+    fmt::Debug,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Weak},
+};
+
 // Parent
-use super::{packet::Frame, Error};
+use super::{
+    packet::Frame,
+    reactor::{Handler, Reactor},
+    tcp::Tcp,
+    Error,
+};
 
 pub const PROTOCOL_FRAME_HEADER: u8 = 1;
 pub const PROTOCOL_FRAME_DATA: u8 = 2;
+pub const PROTOCOL_FRAME_SEQUENCED: u8 = 3;
+
+// Flags following the type of frames that start a packet
+pub const PROTOCOL_FLAG_COMPRESSED: u8 = 1;
+pub const PROTOCOL_FLAG_ENCRYPTED: u8 = 2;
+
+pub fn flags(compressed: bool, encrypted: bool) -> u8 {
+    let mut flags = 0;
+    if compressed {
+        flags |= PROTOCOL_FLAG_COMPRESSED;
+    }
+    if encrypted {
+        flags |= PROTOCOL_FLAG_ENCRYPTED;
+    }
+    flags
+}
+
+// returns (compressed, encrypted)
+pub fn parse_flags(flags: u8) -> (bool, bool) {
+    (
+        flags & PROTOCOL_FLAG_COMPRESSED != 0,
+        flags & PROTOCOL_FLAG_ENCRYPTED != 0,
+    )
+}
 
-pub trait Protocol {
+pub trait Protocol: Debug + Send + Sync {
     fn send(&self, frame: Frame) -> Result<(), Error>;
     fn recv(&self) -> Result<Frame, Error>;
+
+    // The address udp sockets of this connection should bind to, None if it doesn't run over the network
+    fn local_addr(&self) -> Option<SocketAddr> { None }
+
+    // The address of the remote, None if it doesn't run over the network
+    fn peer_addr(&self) -> Option<SocketAddr> { None }
+
+    // Frames that had to be sent again, as far as the transport knows. The kernel hides them for tcp
+    fn retransmits(&self) -> u64 { 0 }
+
+    // End the outgoing direction once everything sent so far is out, a blocked `recv` returns
+    fn close(&self) {}
+
+    // Gives wrappers of this transport the chance to wrap the udp side channel opened next to it as well
+    fn wrap_unreliable(&self, udp: Arc<dyn Protocol>) -> Arc<dyn Protocol> { udp }
+
+    // A transport driven by a `Reactor` hands every received frame to `handler` and pulls the frames to send from it,
+    // so nobody has to block in `send` and `recv`. Blocking transports return false
+    fn attach(&self, _handler: Weak<dyn Handler>) -> bool { false }
+
+    // The attached handler has new frames to pull
+    fn wake(&self) {}
+
+    // The reactor this transport runs on, the udp side channel is bound on it as well
+    fn reactor(&self) -> Option<Arc<Reactor>> { None }
+}
+
+// The reliable, ordered transport a `Connection` runs on
+pub type Transport = Box<dyn Protocol>;
+
+// Accepts incoming transports on the server side
+pub trait Listener: Send + Sync {
+    fn accept(&self) -> Result<Transport, Error>;
+
+    // Make a blocked `accept` return, so whoever is accepting can shut down
+    fn stop(&self) -> Result<(), Error>;
+}
+
+// A nonblocking listener still doesn't wake an `accept` that's already blocked, so connect to it once. Whoever is
+// accepting gets that connection and sees the listener is stopped at the next `accept`
+pub(crate) fn stop_tcp_listener(listener: &TcpListener) -> Result<(), Error> {
+    listener.set_nonblocking(true)?;
+    let mut addr = listener.local_addr()?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+        });
+    }
+    // Nothing is blocked if this fails, e.g. because nobody is accepting and the backlog is full
+    let _ = TcpStream::connect(addr);
+    Ok(())
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> Result<Transport, Error> {
+        let (stream, _) = TcpListener::accept(self)?;
+        Ok(Box::new(Tcp::new_stream(stream)?))
+    }
+
+    fn stop(&self) -> Result<(), Error> { stop_tcp_listener(self) }
 }
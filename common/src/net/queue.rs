@@ -0,0 +1,93 @@
+// Standard
+use std::collections::vec_deque::VecDeque;
+
+// Parent
+use super::packet::{Frame, FrameError, OutgoingPacket};
+
+// Lower values are more important, every u8 is a valid priority
+pub const PRIO_MAX: u8 = 0;
+pub const PRIO_DEFAULT: u8 = 16;
+pub const PRIO_MIN: u8 = 255;
+
+const LANES: usize = PRIO_MIN as usize + 1;
+
+// Outgoing packets, sorted by prio and then chronologically.
+// Lanes are served by a smooth weighted round robin: every frame goes to the non-empty lane with the most credit,
+// so a busy low prio lane is slowed down by higher ones, but never starved and never blocks them.
+// Packets are numbered when their header is sent, so headers leave in id order whatever their lane.
+#[derive(Debug)]
+pub struct OutgoingQueue {
+    lanes: Vec<VecDeque<OutgoingPacket>>,
+    credit: Vec<i64>,
+    next_id: u64,
+}
+
+impl OutgoingQueue {
+    pub fn new() -> OutgoingQueue {
+        OutgoingQueue {
+            lanes: (0..LANES).map(|_| VecDeque::new()).collect(),
+            credit: vec![0; LANES],
+            next_id: 1,
+        }
+    }
+
+    // An id no packet of this queue gets, e.g. for a sequenced frame
+    pub fn take_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn push(&mut self, packet: OutgoingPacket) { self.lanes[*packet.prio() as usize].push_back(packet); }
+
+    // prio 0 gets 256 times the bandwidth of prio 255
+    fn weight(prio: usize) -> i64 { (LANES - prio) as i64 }
+
+    fn pick_lane(&mut self) -> Option<usize> {
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (prio, lane) in self.lanes.iter().enumerate() {
+            if lane.is_empty() {
+                continue;
+            }
+            let weight = OutgoingQueue::weight(prio);
+            self.credit[prio] += weight;
+            total += weight;
+            if best.map(|b| self.credit[prio] > self.credit[b]).unwrap_or(true) {
+                best = Some(prio);
+            }
+        }
+        if let Some(b) = best {
+            self.credit[b] -= total;
+        }
+        best
+    }
+
+    pub fn frames_left(&self, size: u64) -> u64 {
+        self.lanes
+            .iter()
+            .flat_map(|lane| lane.iter())
+            .map(|packet| packet.frames_left(size))
+            .sum()
+    }
+
+    // Generate the next frame to put on the wire, None if there is nothing left to send
+    pub fn next_frame(&mut self, size: u64) -> Option<Frame> {
+        loop {
+            let lane = self.pick_lane()?;
+            if !self.lanes[lane][0].is_numbered() {
+                let id = self.take_id();
+                self.lanes[lane][0].number(id);
+            }
+            match self.lanes[lane][0].generate_frame(size) {
+                Ok(frame) => return Some(frame),
+                Err(FrameError::SendDone) => {
+                    self.lanes[lane].pop_front();
+                    if self.lanes[lane].is_empty() {
+                        self.credit[lane] = 0;
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,534 @@
+// Standard
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    net::{self as std_net, Shutdown, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+// Library
+use mio::{
+    net::{TcpStream, UdpSocket},
+    Event, Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token,
+};
+use parking_lot::{Mutex, RwLock};
+
+// Parent
+use super::{
+    packet::Frame,
+    protocol::{stop_tcp_listener, Listener, Protocol, Transport},
+    Error,
+};
+
+// Information
+// -----------
+// A reactor runs every socket registered with it on a single thread, waiting for readiness with epoll/kqueue/iocp.
+// Instead of a send and a recv thread per connection, a `Connection` attaches itself to its transport as `Handler`:
+// the reactor hands it every frame that arrives and pulls the frames to send from it whenever the socket can take
+// more. Handlers must never block, they run on the reactor thread.
+
+const WAKE: Token = Token(0);
+// An idle reactor still checks every so often whether it was dropped
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+const EVENTS_CAPACITY: usize = 1024;
+// Frames are pulled from the handler until this much is waiting to be written
+const WRITE_AHEAD: usize = 64 * 1024;
+const READ_CHUNK: usize = 64 * 1024;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+fn closed() -> Error { Error::NetworkErr(io::Error::new(ErrorKind::ConnectionAborted, "polled transport closed")) }
+
+// What a transport attached to a reactor talks to, see `Protocol::attach`
+pub trait Handler: Send + Sync {
+    // A frame arrived
+    fn on_frame(&self, frame: Frame);
+    // The next reliable frame to send, None if nothing is queued
+    fn next_frame(&self) -> Option<Frame>;
+    // The transport broke or was closed, no frames arrive anymore
+    fn on_closed(&self);
+}
+
+// Frames arriving before a handler is attached are kept here, `recv` takes them if none is ever attached
+struct Inbox {
+    handler: RwLock<Option<Weak<dyn Handler>>>,
+    send: Mutex<mpsc::Sender<Frame>>,
+    recv: Mutex<mpsc::Receiver<Frame>>,
+}
+
+impl Inbox {
+    fn new() -> Inbox {
+        let (send, recv) = mpsc::channel();
+        Inbox {
+            handler: RwLock::new(None),
+            send: Mutex::new(send),
+            recv: Mutex::new(recv),
+        }
+    }
+
+    fn handler(&self) -> Option<Arc<dyn Handler>> { self.handler.read().as_ref().and_then(|h| h.upgrade()) }
+
+    fn deliver(&self, frame: Frame) {
+        match self.handler.read().as_ref() {
+            Some(handler) => {
+                if let Some(handler) = handler.upgrade() {
+                    handler.on_frame(frame);
+                }
+            },
+            None => {
+                let _ = self.send.lock().send(frame);
+            },
+        }
+    }
+
+    // Hand everything received so far to the handler, then everything new goes there directly
+    fn attach(&self, handler: Weak<dyn Handler>) {
+        let mut slot = self.handler.write();
+        if let Some(handler) = handler.upgrade() {
+            while let Ok(frame) = self.recv.lock().try_recv() {
+                handler.on_frame(frame);
+            }
+        }
+        *slot = Some(handler);
+    }
+
+    fn recv(&self) -> Result<Frame, Error> { self.recv.lock().recv().map_err(|_| closed()) }
+
+    fn close(&self) {
+        // drop our sender, a blocked `recv` returns once it took everything received before
+        *self.send.lock() = mpsc::channel().0;
+        if let Some(handler) = self.handler() {
+            handler.on_closed();
+        }
+    }
+}
+
+struct WriteBuf {
+    bytes: Vec<u8>,
+    // everything before was written already
+    pos: usize,
+}
+
+// A tcp connection registered with the reactor
+struct Stream {
+    socket: TcpStream,
+    inbox: Inbox,
+    // bytes of a frame that didn't arrive completely yet
+    read: Mutex<Vec<u8>>,
+    write: Mutex<WriteBuf>,
+    // shut down once everything queued was written
+    closing: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl Stream {
+    fn on_ready(&self) {
+        self.read_frames();
+        self.flush();
+    }
+
+    fn read_frames(&self) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut read = self.read.lock();
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match (&self.socket).read(&mut chunk) {
+                Ok(0) => return self.close_now(),
+                Ok(n) => read.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("Polled tcp failed to read: {:?}", e);
+                    return self.close_now();
+                },
+            }
+
+            // hand over every complete frame right away, the buffer only keeps the incomplete rest
+            let mut used = 0;
+            loop {
+                match Frame::read_from(&read[used..]) {
+                    Ok(Some((frame, len))) => {
+                        used += len;
+                        self.inbox.deliver(frame);
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Polled tcp received garbage, closing: {:?}", e);
+                        return self.close_now();
+                    },
+                }
+            }
+            read.drain(..used);
+        }
+    }
+
+    // Write as much as the socket takes, topping the buffer up from the handler
+    fn flush(&self) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut write = self.write.lock();
+        loop {
+            if let Some(handler) = self.inbox.handler() {
+                while write.bytes.len() - write.pos < WRITE_AHEAD {
+                    match handler.next_frame() {
+                        Some(frame) => frame.write_to(&mut write.bytes),
+                        None => break,
+                    }
+                }
+            }
+            if write.pos == write.bytes.len() {
+                write.bytes.clear();
+                write.pos = 0;
+                break;
+            }
+
+            let pos = write.pos;
+            match (&self.socket).write(&write.bytes[pos..]) {
+                Ok(0) => return self.close_now(),
+                Ok(n) => write.pos += n,
+                // the reactor calls again once the socket is writable
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => {
+                    debug!("Polled tcp failed to write: {:?}", e);
+                    return self.close_now();
+                },
+            }
+        }
+
+        // the kernel still sends what was written before the FIN
+        if self.closing.load(Ordering::Relaxed) {
+            drop(write);
+            let _ = self.socket.shutdown(Shutdown::Both);
+            self.close_now();
+        }
+    }
+
+    fn close_now(&self) {
+        if !self.closed.swap(true, Ordering::Relaxed) {
+            self.inbox.close();
+        }
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stream {{ peer: {:?} }}", self.socket.peer_addr().ok())
+    }
+}
+
+// A udp socket registered with the reactor, only datagrams from `remote` are accepted
+struct Datagram {
+    socket: UdpSocket,
+    remote: RwLock<Option<SocketAddr>>,
+    inbox: Inbox,
+}
+
+impl Datagram {
+    fn on_ready(&self) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, from)) => {
+                    if Some(from) != *self.remote.read() {
+                        continue;
+                    }
+                    match Frame::read_from(&buf[..size]) {
+                        Ok(Some((frame, _))) => self.inbox.deliver(frame),
+                        _ => debug!("Dropping invalid datagram from {}", from),
+                    }
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                // e.g. an icmp error for an earlier datagram, udp doesn't care
+                Err(e) => debug!("Polled udp failed to receive: {:?}", e),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Datagram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Datagram {{ local: {:?}, remote: {:?} }}",
+            self.socket.local_addr().ok(),
+            *self.remote.read()
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Source {
+    Stream(Arc<Stream>),
+    Datagram(Arc<Datagram>),
+}
+
+#[derive(Debug)]
+pub struct Reactor {
+    poll: Poll,
+    _wake_registration: Registration,
+    wake: SetReadiness,
+    sources: Mutex<HashMap<Token, Source>>,
+    // streams with new frames to pull from their handler
+    pending: Mutex<Vec<Token>>,
+    next_token: AtomicUsize,
+}
+
+impl Reactor {
+    // The reactor thread runs until the last transport and handle of it are dropped
+    pub fn new() -> Result<Arc<Reactor>, Error> {
+        let poll = Poll::new()?;
+        let (registration, wake) = Registration::new2();
+        poll.register(&registration, WAKE, Ready::readable(), PollOpt::edge())?;
+        let reactor = Arc::new(Reactor {
+            poll,
+            _wake_registration: registration,
+            wake,
+            sources: Mutex::new(HashMap::new()),
+            pending: Mutex::new(Vec::new()),
+            next_token: AtomicUsize::new(1),
+        });
+        let weak = Arc::downgrade(&reactor);
+        thread::spawn(move || Reactor::run(weak));
+        Ok(reactor)
+    }
+
+    // Connect to a remote, the client side counterpart of `PolledListener`
+    pub fn connect<A: ToSocketAddrs>(this: &Arc<Reactor>, remote: &A) -> Result<PolledTcp, Error> {
+        Reactor::stream(this, std_net::TcpStream::connect(remote)?)
+    }
+
+    // Take over a connected tcp stream
+    pub fn stream(this: &Arc<Reactor>, stream: std_net::TcpStream) -> Result<PolledTcp, Error> {
+        stream.set_nodelay(true)?;
+        let stream = Arc::new(Stream {
+            socket: TcpStream::from_stream(stream)?,
+            inbox: Inbox::new(),
+            read: Mutex::new(Vec::new()),
+            write: Mutex::new(WriteBuf {
+                bytes: Vec::new(),
+                pos: 0,
+            }),
+            closing: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        });
+        let token = this.add(Source::Stream(stream.clone()));
+        this.poll.register(
+            &stream.socket,
+            token,
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        )?;
+        Ok(PolledTcp {
+            reactor: this.clone(),
+            token,
+            stream,
+        })
+    }
+
+    // Bind a udp socket, it accepts datagrams once `PolledUdp::connect` told it the remote
+    pub fn bind_udp(this: &Arc<Reactor>, listen: &SocketAddr) -> Result<Arc<PolledUdp>, Error> {
+        let datagram = Arc::new(Datagram {
+            socket: UdpSocket::bind(listen)?,
+            remote: RwLock::new(None),
+            inbox: Inbox::new(),
+        });
+        let token = this.add(Source::Datagram(datagram.clone()));
+        this.poll
+            .register(&datagram.socket, token, Ready::readable(), PollOpt::edge())?;
+        Ok(Arc::new(PolledUdp {
+            reactor: this.clone(),
+            token,
+            datagram,
+        }))
+    }
+
+    fn add(&self, source: Source) -> Token {
+        let token = Token(self.next_token.fetch_add(1, Ordering::Relaxed));
+        self.sources.lock().insert(token, source);
+        token
+    }
+
+    fn remove(&self, token: Token) {
+        let source = self.sources.lock().remove(&token);
+        let _ = match source {
+            Some(Source::Stream(stream)) => self.poll.deregister(&stream.socket),
+            Some(Source::Datagram(datagram)) => self.poll.deregister(&datagram.socket),
+            None => Ok(()),
+        };
+    }
+
+    // Have the reactor pull new frames from the stream's handler
+    fn wake(&self, token: Token) {
+        self.pending.lock().push(token);
+        let _ = self.wake.set_readiness(Ready::readable());
+    }
+
+    fn run(weak: Weak<Reactor>) {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        while let Some(reactor) = weak.upgrade() {
+            if let Err(e) = reactor.poll.poll(&mut events, Some(POLL_TIMEOUT)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Reactor failed to poll, stopping: {:?}", e);
+                break;
+            }
+            for event in events.iter() {
+                reactor.handle(event);
+            }
+        }
+    }
+
+    fn handle(&self, event: Event) {
+        if event.token() == WAKE {
+            // reset before taking the list, a wake in between just causes one more empty round
+            let _ = self.wake.set_readiness(Ready::empty());
+            let pending = mem::replace(&mut *self.pending.lock(), Vec::new());
+            for token in pending {
+                if let Some(Source::Stream(stream)) = self.source(token) {
+                    stream.flush();
+                }
+            }
+            return;
+        }
+        // don't hold the lock while handling, handlers may add or remove sources
+        match self.source(event.token()) {
+            // hangups and errors show up as readable, reading finds out what happened
+            Some(Source::Stream(stream)) => stream.on_ready(),
+            Some(Source::Datagram(datagram)) => datagram.on_ready(),
+            None => {},
+        }
+    }
+
+    fn source(&self, token: Token) -> Option<Source> { self.sources.lock().get(&token).cloned() }
+}
+
+// A tcp transport driven by a `Reactor`. Usable like a blocking transport as well, until something attaches
+#[derive(Debug)]
+pub struct PolledTcp {
+    reactor: Arc<Reactor>,
+    token: Token,
+    stream: Arc<Stream>,
+}
+
+impl Protocol for PolledTcp {
+    fn send(&self, frame: Frame) -> Result<(), Error> {
+        if self.stream.closed.load(Ordering::Relaxed) {
+            return Err(closed());
+        }
+        frame.write_to(&mut self.stream.write.lock().bytes);
+        self.reactor.wake(self.token);
+        Ok(())
+    }
+
+    //blocking
+    fn recv(&self) -> Result<Frame, Error> { self.stream.inbox.recv() }
+
+    fn local_addr(&self) -> Option<SocketAddr> { self.stream.socket.local_addr().ok() }
+
+    fn peer_addr(&self) -> Option<SocketAddr> { self.stream.socket.peer_addr().ok() }
+
+    fn close(&self) {
+        self.stream.closing.store(true, Ordering::Relaxed);
+        self.reactor.wake(self.token);
+    }
+
+    fn attach(&self, handler: Weak<dyn Handler>) -> bool {
+        self.stream.inbox.attach(handler.clone());
+        if self.stream.closed.load(Ordering::Relaxed) {
+            if let Some(handler) = handler.upgrade() {
+                handler.on_closed();
+            }
+        }
+        true
+    }
+
+    fn wake(&self) { self.reactor.wake(self.token) }
+
+    fn reactor(&self) -> Option<Arc<Reactor>> { Some(self.reactor.clone()) }
+}
+
+impl Drop for PolledTcp {
+    fn drop(&mut self) { self.reactor.remove(self.token) }
+}
+
+// The udp side channel of a `PolledTcp`. Sending doesn't wait, a datagram the socket can't take right now is lost
+#[derive(Debug)]
+pub struct PolledUdp {
+    reactor: Arc<Reactor>,
+    token: Token,
+    datagram: Arc<Datagram>,
+}
+
+impl PolledUdp {
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> { Ok(self.datagram.socket.local_addr()?) }
+
+    pub fn connect(&self, remote: SocketAddr) { *self.datagram.remote.write() = Some(remote); }
+}
+
+impl Protocol for PolledUdp {
+    fn send(&self, frame: Frame) -> Result<(), Error> {
+        let remote = match *self.datagram.remote.read() {
+            Some(remote) => remote,
+            None => return Err(closed()),
+        };
+        let mut buf = Vec::with_capacity(frame.wire_size());
+        frame.write_to(&mut buf);
+        match self.datagram.socket.send_to(&buf, &remote) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    //blocking
+    fn recv(&self) -> Result<Frame, Error> { self.datagram.inbox.recv() }
+
+    fn local_addr(&self) -> Option<SocketAddr> { self.datagram.socket.local_addr().ok() }
+
+    fn attach(&self, handler: Weak<dyn Handler>) -> bool {
+        self.datagram.inbox.attach(handler);
+        true
+    }
+}
+
+impl Drop for PolledUdp {
+    fn drop(&mut self) { self.reactor.remove(self.token) }
+}
+
+// Accepts tcp connections and runs them on a reactor. Accepting itself still blocks, in whichever thread calls it
+pub struct PolledListener {
+    listener: std_net::TcpListener,
+    reactor: Arc<Reactor>,
+}
+
+impl PolledListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A, reactor: Arc<Reactor>) -> Result<Arc<PolledListener>, Error> {
+        Ok(Arc::new(PolledListener {
+            listener: std_net::TcpListener::bind(addr)?,
+            reactor,
+        }))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> { Ok(self.listener.local_addr()?) }
+}
+
+impl Listener for PolledListener {
+    fn accept(&self) -> Result<Transport, Error> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(Box::new(Reactor::stream(&self.reactor, stream)?))
+    }
+
+    fn stop(&self) -> Result<(), Error> { stop_tcp_listener(&self.listener) }
+}
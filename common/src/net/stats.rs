@@ -0,0 +1,59 @@
+// Standard
+use std::{fmt, time::Duration};
+
+// Snapshot of the traffic of a connection
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NetStats {
+    // smoothed round trip time, None until the first sample arrived
+    pub rtt: Option<Duration>,
+    // mean deviation of the round trip time
+    pub rtt_jitter: Duration,
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    pub frames_sent: u64,
+    pub frames_recv: u64,
+    // frames waiting to be sent, reliable and sequenced
+    pub frames_queued: u64,
+    // sequenced frames dropped on arrival because a newer one of their stream was there first
+    pub frames_stale: u64,
+    pub retransmits: u64,
+}
+
+impl NetStats {
+    // Smooth like tcp does (RFC 6298), a single slow sample shows up as jitter rather than as rtt
+    pub fn record_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_jitter = sample / 2;
+            },
+            Some(rtt) => {
+                let deviation = if sample > rtt { sample - rtt } else { rtt - sample };
+                self.rtt_jitter = (self.rtt_jitter * 3 + deviation) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            },
+        }
+    }
+}
+
+fn millis(d: Duration) -> f64 { d.as_secs() as f64 * 1000.0 + d.subsec_micros() as f64 / 1000.0 }
+
+impl fmt::Display for NetStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "rtt {:.1}ms ±{:.1}ms", millis(rtt), millis(self.rtt_jitter))?,
+            None => write!(f, "rtt unknown")?,
+        }
+        write!(
+            f,
+            ", sent {} B in {} frames, received {} B in {} frames, {} queued, {} stale, {} retransmitted",
+            self.bytes_sent,
+            self.frames_sent,
+            self.bytes_recv,
+            self.frames_recv,
+            self.frames_queued,
+            self.frames_stale,
+            self.retransmits
+        )
+    }
+}
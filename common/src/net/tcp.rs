@@ -1,7 +1,7 @@
 // Standard
 use std::{
     io::{Read, Write},
-    net::{TcpStream, ToSocketAddrs},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
 };
 
 // Library
@@ -11,7 +11,7 @@ use parking_lot::Mutex;
 // Parent
 use super::{
     packet::Frame,
-    protocol::{Protocol, PROTOCOL_FRAME_DATA, PROTOCOL_FRAME_HEADER},
+    protocol::{flags, parse_flags, Protocol, PROTOCOL_FRAME_DATA, PROTOCOL_FRAME_HEADER, PROTOCOL_FRAME_SEQUENCED},
     Error,
 };
 
@@ -40,8 +40,14 @@ impl Protocol for Tcp {
     fn send(&self, frame: Frame) -> Result<(), Error> {
         let mut stream = self.stream_out.lock();
         match frame {
-            Frame::Header { id, length } => {
+            Frame::Header {
+                id,
+                length,
+                compressed,
+                encrypted,
+            } => {
                 stream.write_u8(PROTOCOL_FRAME_HEADER)?;
+                stream.write_u8(flags(compressed, encrypted))?;
                 stream.write_u64::<LittleEndian>(id)?;
                 stream.write_u64::<LittleEndian>(length)?;
                 Ok(())
@@ -54,6 +60,21 @@ impl Protocol for Tcp {
                 stream.write_all(&data)?;
                 Ok(())
             },
+            Frame::Sequenced {
+                id,
+                stream: seq_stream,
+                compressed,
+                encrypted,
+                data,
+            } => {
+                stream.write_u8(PROTOCOL_FRAME_SEQUENCED)?;
+                stream.write_u8(flags(compressed, encrypted))?;
+                stream.write_u64::<LittleEndian>(id)?;
+                stream.write_u64::<LittleEndian>(seq_stream)?;
+                stream.write_u64::<LittleEndian>(data.len() as u64)?;
+                stream.write_all(&data)?;
+                Ok(())
+            },
         }
     }
 
@@ -63,9 +84,15 @@ impl Protocol for Tcp {
         let frame = stream.read_u8()? as u8;
         match frame {
             1 => {
+                let (compressed, encrypted) = parse_flags(stream.read_u8()?);
                 let id = stream.read_u64::<LittleEndian>()? as u64;
                 let length = stream.read_u64::<LittleEndian>()? as u64;
-                Ok(Frame::Header { id, length })
+                Ok(Frame::Header {
+                    id,
+                    length,
+                    compressed,
+                    encrypted,
+                })
             },
             2 => {
                 let id = stream.read_u64::<LittleEndian>()? as u64;
@@ -75,10 +102,32 @@ impl Protocol for Tcp {
                 stream.read_exact(&mut data)?;
                 Ok(Frame::Data { id, frame_no, data })
             },
+            3 => {
+                let (compressed, encrypted) = parse_flags(stream.read_u8()?);
+                let id = stream.read_u64::<LittleEndian>()? as u64;
+                let seq_stream = stream.read_u64::<LittleEndian>()? as u64;
+                let packet_size = stream.read_u64::<LittleEndian>()? as u64;
+                let mut data = vec![0; packet_size as usize];
+                stream.read_exact(&mut data)?;
+                Ok(Frame::Sequenced {
+                    id,
+                    stream: seq_stream,
+                    compressed,
+                    encrypted,
+                    data,
+                })
+            },
             x => {
                 error!("invalid frame recieved: {}", x);
                 Err(Error::CannotDeserialize)
             },
         }
     }
+
+    fn local_addr(&self) -> Option<SocketAddr> { self.stream_out.lock().local_addr().ok() }
+
+    fn peer_addr(&self) -> Option<SocketAddr> { self.stream_out.lock().peer_addr().ok() }
+
+    // the kernel still sends what was written before the FIN
+    fn close(&self) { let _ = self.stream_out.lock().shutdown(Shutdown::Both); }
 }
@@ -1,9 +1,10 @@
 // Standard
 use std::{
     io::ErrorKind::UnexpectedEof,
-    net::{Shutdown::Both, TcpListener, TcpStream},
+    net::{Shutdown::Both, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 // Library
@@ -11,34 +12,58 @@ use serde_derive::{Deserialize, Serialize};
 
 // Parent
 use super::{
+    compression::{compress, decompress},
+    connection::Connection,
+    crypto::{Handshake, Identity},
+    memory::Memory,
     message::{Error::NetworkErr, Message},
-    packet::{Frame, FrameError, IncomingPacket, OutgoingPacket},
+    netsim::{NetProfile, Simulator},
+    packet::{Frame, FrameError, IncomingPacket, IncomingPackets, OutgoingPacket, PacketError},
     protocol::Protocol,
+    queue::{OutgoingQueue, PRIO_DEFAULT, PRIO_MAX, PRIO_MIN},
+    stats::NetStats,
     tcp::Tcp,
     udpmgr::UdpMgr,
 };
 
-// Local
-use crate::util::testutils::PORTS;
-
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum TestMessage {
     SmallMessage { value: u64 },
     LargeMessage { text: String },
+    BulkMessage { data: Vec<u8> },
 }
 impl Message for TestMessage {}
 
+// The tests that need real sockets let the OS pick free ports, so they can't collide when run in parallel
+fn tcp_listener() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+fn udp_addr(mgr: &Arc<UdpMgr>) -> SocketAddr { UdpMgr::bind_udp(mgr.clone(), &"127.0.0.1:0").unwrap() }
+
+fn header(id: u64, length: u64) -> Frame {
+    Frame::Header {
+        id,
+        length,
+        compressed: false,
+        encrypted: false,
+    }
+}
+
 fn check_header(frame: &Result<Frame, FrameError>, id: u64, length: u64) {
     match frame {
         Ok(frame) => match frame {
             Frame::Header {
                 id: id2,
                 length: length2,
+                ..
             } => {
                 assert_eq!(id, *id2);
                 assert_eq!(length, *length2);
             },
-            Frame::Data { .. } => {
+            Frame::Data { .. } | Frame::Sequenced { .. } => {
                 assert!(false);
             },
         },
@@ -51,7 +76,7 @@ fn check_header(frame: &Result<Frame, FrameError>, id: u64, length: u64) {
 fn check_data(frame: &Result<Frame, FrameError>, id: u64, frame_no: u64, data: Vec<u8>) {
     match frame {
         Ok(frame) => match frame {
-            Frame::Header { .. } => {
+            Frame::Header { .. } | Frame::Sequenced { .. } => {
                 assert!(false);
             },
             Frame::Data {
@@ -195,12 +220,13 @@ fn construct_message() {
     check_data(&f6, 123, 4, vec![49, 50, 51, 52, 53, 54, 55, 56, 57, 48]);
     let f7 = p.generate_frame(10);
     check_done(&f7);
-    let mut i = IncomingPacket::new(f1.unwrap());
-    assert!(!i.load_data_frame(f2.unwrap()));
-    assert!(!i.load_data_frame(f3.unwrap()));
-    assert!(!i.load_data_frame(f4.unwrap()));
-    assert!(!i.load_data_frame(f5.unwrap())); //false
-    assert!(i.load_data_frame(f6.unwrap())); //true
+    let mut i = IncomingPacket::new(123);
+    assert!(!i.load_frame(f1.unwrap()).unwrap());
+    assert!(!i.load_frame(f2.unwrap()).unwrap());
+    assert!(!i.load_frame(f3.unwrap()).unwrap());
+    assert!(!i.load_frame(f4.unwrap()).unwrap());
+    assert!(!i.load_frame(f5.unwrap()).unwrap()); //false
+    assert!(i.load_frame(f6.unwrap()).unwrap()); //true
     let data = i.data();
     assert_eq!(
         *data,
@@ -215,7 +241,6 @@ fn construct_message() {
 }
 
 #[test]
-#[should_panic]
 fn construct_message_wrong_order() {
     let msg = TestMessage::LargeMessage {
         text: "1234567890A1234567890B1234567890C1234567890D1234567890E1234567890F1234567890G1234567890H1234567890"
@@ -252,12 +277,13 @@ fn construct_message_wrong_order() {
     check_data(&f6, 123, 4, vec![49, 50, 51, 52, 53, 54, 55, 56, 57, 48]);
     let f7 = p.generate_frame(10);
     check_done(&f7);
-    let mut i = IncomingPacket::new(f1.unwrap());
-    assert!(!i.load_data_frame(f6.unwrap()));
-    assert!(!i.load_data_frame(f4.unwrap()));
-    assert!(!i.load_data_frame(f2.unwrap()));
-    assert!(!i.load_data_frame(f5.unwrap())); //false
-    assert!(i.load_data_frame(f3.unwrap())); //true
+    let mut i = IncomingPacket::new(123);
+    assert!(!i.load_frame(f1.unwrap()).unwrap());
+    assert!(!i.load_frame(f6.unwrap()).unwrap());
+    assert!(!i.load_frame(f4.unwrap()).unwrap());
+    assert!(!i.load_frame(f2.unwrap()).unwrap());
+    assert!(!i.load_frame(f5.unwrap()).unwrap()); //false
+    assert!(i.load_frame(f3.unwrap()).unwrap()); //true
     let data = i.data();
     assert_eq!(
         *data,
@@ -271,20 +297,585 @@ fn construct_message_wrong_order() {
     assert_eq!(data.len(), 110);
 }
 
+#[test]
+fn construct_message_header_last() {
+    let msg = TestMessage::SmallMessage { value: 7 };
+    let mut p = OutgoingPacket::new(msg.to_bytes().unwrap(), 3);
+    let f1 = p.generate_frame(10);
+    let f2 = p.generate_frame(5);
+    let f3 = p.generate_frame(5);
+    let f4 = p.generate_frame(5);
+    check_done(&p.generate_frame(5));
+    let mut i = IncomingPacket::new(3);
+    assert!(!i.load_frame(f3.unwrap()).unwrap());
+    assert!(!i.load_frame(f2.unwrap()).unwrap());
+    assert!(!i.load_frame(f4.unwrap()).unwrap());
+    assert!(i.load_frame(f1.unwrap()).unwrap());
+    assert_eq!(*i.data(), vec![0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn construct_message_duplicates() {
+    let mut i = IncomingPacket::new(9);
+    let data = |frame_no, data| Frame::Data { id: 9, frame_no, data };
+    assert!(!i.load_frame(header(9, 4)).unwrap());
+    assert!(!i.load_frame(data(1, vec![3, 4])).unwrap());
+    assert!(!i.load_frame(data(1, vec![3, 4])).unwrap());
+    assert!(!i.load_frame(header(9, 4)).unwrap());
+    assert!(i.load_frame(data(0, vec![1, 2])).unwrap());
+    assert!(i.load_frame(data(0, vec![1, 2])).unwrap());
+    assert_eq!(*i.data(), vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn construct_message_errors() {
+    let mut i = IncomingPacket::new(9);
+    assert_eq!(
+        i.load_frame(header(10, 4)),
+        Err(PacketError::IdMismatch { expected: 9, got: 10 })
+    );
+    assert!(!i.load_frame(header(9, 4)).unwrap());
+    assert_eq!(
+        i.load_frame(header(9, 5)),
+        Err(PacketError::LengthMismatch { expected: 4, got: 5 })
+    );
+    assert_eq!(
+        i.load_frame(Frame::Data {
+            id: 9,
+            frame_no: 0,
+            data: vec![1, 2, 3, 4, 5],
+        }),
+        Err(PacketError::Overflow { length: 4, received: 5 })
+    );
+}
+
+#[test]
+fn construct_message_limits() {
+    let data = |frame_no, len| Frame::Data {
+        id: 9,
+        frame_no,
+        data: vec![0; len],
+    };
+    let mut i = IncomingPacket::new(9);
+    assert_eq!(
+        i.load_frame(header(9, 1 << 40)),
+        Err(PacketError::TooLarge { length: 1 << 40 })
+    );
+    let mut i = IncomingPacket::new(9);
+    assert_eq!(
+        i.load_frame(data(5000, 1)),
+        Err(PacketError::TooManyFrames { frame_no: 5000 })
+    );
+    // frames waiting for their predecessors count towards the length too
+    let mut i = IncomingPacket::new(9);
+    assert!(!i.load_frame(header(9, 4)).unwrap());
+    assert!(!i.load_frame(data(1, 3)).unwrap());
+    assert_eq!(
+        i.load_frame(data(2, 3)),
+        Err(PacketError::Overflow { length: 4, received: 6 })
+    );
+}
+
+#[test]
+fn incoming_packets_late_frames() {
+    let mut packets = IncomingPackets::new();
+    let data = |id, frame_no| Frame::Data {
+        id,
+        frame_no,
+        data: vec![1, 2],
+    };
+    assert!(packets.load_frame(header(1, 4)).unwrap().is_none());
+    assert!(packets.load_frame(data(1, 0)).unwrap().is_none());
+    assert_eq!(
+        *packets.load_frame(data(1, 1)).unwrap().unwrap().data(),
+        vec![1, 2, 1, 2]
+    );
+    // a retransmit of a completed packet neither opens it again nor completes it twice
+    assert!(packets.load_frame(header(1, 4)).unwrap().is_none());
+    assert!(packets.load_frame(data(1, 0)).unwrap().is_none());
+    assert!(packets.load_frame(data(1, 1)).unwrap().is_none());
+
+    // once the window moved on, data frames below it are dropped
+    for id in 2..1100 {
+        assert!(packets.load_frame(header(id, 0)).unwrap().is_some());
+    }
+    assert!(packets.load_frame(data(3, 0)).unwrap().is_none());
+    assert!(packets.load_frame(data(3, 1)).unwrap().is_none());
+    // headers are sent in id order, one below the window is a replay and doesn't open the packet again
+    assert!(packets.load_frame(header(0, 0)).unwrap().is_none());
+    assert!(packets.load_frame(header(3, 4)).unwrap().is_none());
+    assert!(packets.load_frame(data(3, 0)).unwrap().is_none());
+    assert!(packets.load_frame(data(3, 1)).unwrap().is_none());
+    assert!(packets.load_frame(header(1100, 2)).unwrap().is_none());
+    assert!(packets.load_frame(data(1100, 0)).unwrap().is_some());
+}
+
+#[test]
+fn incoming_packets_limits() {
+    let mut packets = IncomingPackets::new();
+    for id in 0..256 {
+        assert!(packets.load_frame(header(id, 4)).unwrap().is_none());
+    }
+    assert_eq!(
+        packets.load_frame(header(256, 4)).unwrap_err(),
+        PacketError::TooManyOpen
+    );
+    // a corrupt packet is dropped, which makes room again
+    assert_eq!(
+        packets.load_frame(header(0, 5)).unwrap_err(),
+        PacketError::LengthMismatch { expected: 4, got: 5 }
+    );
+    assert!(packets.load_frame(header(256, 4)).unwrap().is_none());
+}
+
+#[test]
+fn queue_prio_overtakes() {
+    let bulk = TestMessage::BulkMessage {
+        data: vec![42; 100_000],
+    };
+    let small = TestMessage::SmallMessage { value: 7 };
+    let mut q = OutgoingQueue::new();
+    q.push(OutgoingPacket::with_prio(bulk.to_bytes().unwrap(), PRIO_MIN));
+    for _ in 0..10 {
+        assert_eq!(q.next_frame(1000).unwrap().id(), 1);
+    }
+    q.push(OutgoingPacket::with_prio(small.to_bytes().unwrap(), PRIO_MAX));
+    // header and data of the small packet are both sent before the bulk packet gets another frame, its id is the next
+    // one although it was queued later
+    assert_eq!(q.next_frame(1000).unwrap().id(), 2);
+    assert_eq!(q.next_frame(1000).unwrap().id(), 2);
+    assert_eq!(q.next_frame(1000).unwrap().id(), 1);
+}
+
+#[test]
+fn queue_prio_no_starvation() {
+    let bulk = TestMessage::BulkMessage {
+        data: vec![42; 100_000],
+    };
+    let mut q = OutgoingQueue::new();
+    q.push(OutgoingPacket::with_prio(bulk.to_bytes().unwrap(), PRIO_MIN));
+    q.push(OutgoingPacket::with_prio(bulk.to_bytes().unwrap(), PRIO_DEFAULT));
+    q.push(OutgoingPacket::with_prio(bulk.to_bytes().unwrap(), PRIO_MAX));
+    let mut sent = [0; 3];
+    let mut headers = vec![];
+    for _ in 0..600 {
+        let frame = q.next_frame(100).unwrap();
+        if let Frame::Header { id, .. } = frame {
+            headers.push(id);
+        }
+        sent[frame.id() as usize - 1] += 1;
+    }
+    // the more important lanes get their headers out first, and so the lower ids
+    assert_eq!(headers, vec![1, 2, 3]);
+    // every lane makes progress, weighted by its priority
+    assert!(sent[0] > sent[1]);
+    assert!(sent[1] > sent[2]);
+    assert!(sent[2] > 0);
+}
+
+#[test]
+fn connection_memory() {
+    let (a, b) = Memory::pair();
+    let client = Connection::<TestMessage>::with_transport(Box::new(a), UdpMgr::new()).unwrap();
+    let server = Connection::<TestMessage>::with_transport(Box::new(b), UdpMgr::new()).unwrap();
+    Connection::start(&client);
+    Connection::start(&server);
+    // there is no address to bind udp to, sequenced messages fall back to the transport
+    assert!(client.bind_udp().is_err());
+
+    for value in 0..100 {
+        client.send(TestMessage::SmallMessage { value });
+    }
+    client.send_sequenced(TestMessage::SmallMessage { value: 100 }, 0);
+    for value in 0..101 {
+        match server.recv().unwrap() {
+            TestMessage::SmallMessage { value: v } => assert_eq!(v, value),
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+    server.send(TestMessage::BulkMessage {
+        data: vec![42; 1_000_000],
+    });
+    match client.recv().unwrap() {
+        TestMessage::BulkMessage { data } => assert_eq!(data, vec![42; 1_000_000]),
+        m => panic!("unexpected message {:?}", m),
+    }
+    Connection::stop(&client);
+    Connection::stop(&server);
+}
+
+#[test]
+fn connection_sequenced_forged() {
+    // the remote is a raw transport, so any frame can be handed to the connection
+    let (remote, local) = Memory::pair();
+    let conn = Connection::<TestMessage>::with_transport(Box::new(local), UdpMgr::new()).unwrap();
+    Connection::start(&conn);
+    let sequenced = |id, compressed, data| Frame::Sequenced {
+        id,
+        stream: 0,
+        compressed,
+        encrypted: false,
+        data,
+    };
+    let msg = |value| TestMessage::SmallMessage { value }.to_bytes().unwrap();
+
+    // garbage with a high id doesn't make the stream stale
+    remote.send(sequenced(1000, true, vec![1, 2, 3])).unwrap();
+    remote.send(sequenced(5, false, msg(5))).unwrap();
+    remote.send(sequenced(4, false, msg(4))).unwrap();
+    remote.send(sequenced(6, false, msg(6))).unwrap();
+    for value in &[5, 6] {
+        match conn.recv().unwrap() {
+            TestMessage::SmallMessage { value: v } => assert_eq!(v, *value),
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+    assert_eq!(conn.stats().frames_stale, 1);
+    Connection::stop(&conn);
+}
+
+fn data(frame_no: u64) -> Frame {
+    Frame::Data {
+        id: 0,
+        frame_no,
+        data: vec![0; 100],
+    }
+}
+
+fn frame_no(frame: Frame) -> u64 {
+    match frame {
+        Frame::Data { frame_no, .. } => frame_no,
+        f => panic!("unexpected frame {:?}", f),
+    }
+}
+
+#[test]
+fn netsim_profile() {
+    assert_eq!("perfect".parse::<NetProfile>(), Ok(NetProfile::perfect()));
+    assert_eq!("bad-wifi".parse::<NetProfile>().ok(), NetProfile::preset("bad-wifi"));
+    let profile: NetProfile = "latency=120,jitter=40,loss=0.05,bandwidth=100000".parse().unwrap();
+    assert_eq!(profile.latency, Duration::from_millis(120));
+    assert_eq!(profile.jitter, Duration::from_millis(40));
+    assert_eq!(profile.loss, 0.05);
+    assert_eq!(profile.bandwidth, Some(100_000));
+    assert!("loss=2".parse::<NetProfile>().is_err());
+    assert!("latency=fast".parse::<NetProfile>().is_err());
+    assert!("terrible".parse::<NetProfile>().is_err());
+}
+
+#[test]
+fn netsim_reliable() {
+    // lost frames are retransmitted and nothing overtakes, no matter the jitter
+    let (a, b) = Memory::pair();
+    let profile = NetProfile {
+        latency: Duration::from_millis(50),
+        jitter: Duration::from_millis(40),
+        loss: 0.2,
+        bandwidth: None,
+    };
+    let sim = Simulator::reliable(Box::new(a), profile);
+    let start = Instant::now();
+    for i in 0..100 {
+        sim.send(data(i)).unwrap();
+    }
+    for i in 0..100 {
+        assert_eq!(frame_no(b.recv().unwrap()), i);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(10));
+}
+
+#[test]
+fn netsim_latency_bandwidth() {
+    let (a, b) = Memory::pair();
+    let profile: NetProfile = "latency=100,bandwidth=12500".parse().unwrap();
+    let sim = Simulator::reliable(Box::new(a), profile);
+    let start = Instant::now();
+    sim.send(data(0)).unwrap();
+    b.recv().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    // 10 frames of 125 bytes take 100ms on a 12.5 kB/s link
+    let start = Instant::now();
+    for i in 0..10 {
+        sim.send(data(i)).unwrap();
+    }
+    for _ in 0..10 {
+        b.recv().unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(190));
+}
+
+#[test]
+fn netsim_unreliable() {
+    // frames get lost and reordered
+    let (a, b) = Memory::pair();
+    let profile: NetProfile = "latency=20,jitter=20,loss=0.3".parse().unwrap();
+    let sim = Simulator::unreliable(Arc::new(a), profile);
+    for i in 0..200 {
+        sim.send(data(i)).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    thread::sleep(Duration::from_millis(200));
+    drop(sim);
+    let mut received = vec![];
+    while let Ok(frame) = b.recv() {
+        received.push(frame_no(frame));
+    }
+    assert!(received.len() > 50 && received.len() < 200);
+    assert!(received.windows(2).any(|w| w[0] > w[1]));
+}
+
+#[test]
+fn connection_stats() {
+    let (a, b) = Memory::pair();
+    let profile: NetProfile = "loss=0.5".parse().unwrap();
+    let client =
+        Connection::<TestMessage>::with_transport(Box::new(Simulator::reliable(Box::new(a), profile)), UdpMgr::new())
+            .unwrap();
+    let server = Connection::<TestMessage>::with_transport(Box::new(b), UdpMgr::new()).unwrap();
+    assert_eq!(client.stats(), NetStats::default());
+    Connection::start(&client);
+    Connection::start(&server);
+
+    for value in 0..20 {
+        client.send(TestMessage::SmallMessage { value });
+    }
+    for _ in 0..20 {
+        server.recv().unwrap();
+    }
+    let (sent, recvd) = (client.stats(), server.stats());
+    // one header and one data frame each
+    assert_eq!(sent.frames_sent, 40);
+    assert_eq!(recvd.frames_recv, 40);
+    assert_eq!(sent.bytes_sent, recvd.bytes_recv);
+    assert_eq!(sent.frames_queued, 0);
+    assert!(sent.retransmits > 0);
+    assert_eq!(recvd.retransmits, 0);
+
+    client.record_rtt(Duration::from_millis(100));
+    assert_eq!(client.stats().rtt, Some(Duration::from_millis(100)));
+    for _ in 0..100 {
+        client.record_rtt(Duration::from_millis(20));
+    }
+    let stats = client.stats();
+    assert!(stats.rtt.unwrap() < Duration::from_millis(21));
+    assert!(stats.rtt_jitter < Duration::from_millis(1));
+    Connection::stop(&client);
+    Connection::stop(&server);
+}
+
+#[test]
+fn connection_prio_overtakes() {
+    let (a, b) = Memory::pair();
+    let handle = thread::spawn(move || {
+        let server = Connection::<TestMessage>::with_transport(Box::new(b), UdpMgr::new()).unwrap();
+        Connection::start(&server);
+        let first = server.recv().unwrap();
+        let second = server.recv().unwrap();
+        Connection::stop(&server);
+        (first, second)
+    });
+    let client = Connection::<TestMessage>::with_transport(Box::new(a), UdpMgr::new()).unwrap();
+    Connection::start(&client);
+    client.send_with_priority(
+        TestMessage::BulkMessage {
+            data: vec![42; 8_000_000],
+        },
+        PRIO_MIN,
+    );
+    client.send_with_priority(TestMessage::SmallMessage { value: 7 }, PRIO_MAX);
+    match handle.join().unwrap() {
+        (TestMessage::SmallMessage { value: 7 }, TestMessage::BulkMessage { data }) => {
+            assert_eq!(data.len(), 8_000_000)
+        },
+        (first, _) => panic!("small message was stuck behind the bulk one, got {:?} first", first),
+    }
+    Connection::stop(&client);
+}
+
+#[test]
+fn connection_sequenced_lossy() {
+    // sequenced messages only skip the reliable transport over udp, which needs a real interface
+    let (listen, serverip) = tcp_listener();
+    let client = Connection::<TestMessage>::new(&serverip, UdpMgr::new()).unwrap();
+    let server = Connection::<TestMessage>::new_stream(listen.accept().unwrap().0, UdpMgr::new()).unwrap();
+    Connection::start(&client);
+    Connection::start(&server);
+
+    // client -> proxy -> server, the proxy loses and reorders datagrams. The server sees the proxy as its remote
+    let server_udp = server.bind_udp().unwrap();
+    let client_udp = client.bind_udp().unwrap();
+    let proxy = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 2048];
+        let mut held: Option<Vec<u8>> = None;
+        let mut i = 0;
+        while let Ok((len, from)) = proxy.recv_from(&mut buf) {
+            if from != client_udp {
+                continue;
+            }
+            i += 1;
+            if i % 7 == 0 {
+                continue;
+            }
+            if i % 5 == 1 && held.is_none() {
+                held = Some(buf[..len].to_vec());
+                continue;
+            }
+            let _ = proxy.send_to(&buf[..len], &server_udp);
+            if let Some(late) = held.take() {
+                let _ = proxy.send_to(&late, &server_udp);
+            }
+        }
+    });
+    Connection::open_udp(&server, server_udp, proxy_addr);
+    Connection::open_udp(&client, client_udp, proxy_addr);
+
+    for i in 0..100 {
+        client.send_sequenced(TestMessage::SmallMessage { value: i }, 0);
+        if i % 10 == 0 {
+            client.send(TestMessage::LargeMessage { text: i.to_string() });
+        }
+        thread::sleep(Duration::from_millis(2));
+    }
+    thread::sleep(Duration::from_millis(200));
+    client.send(TestMessage::BulkMessage { data: vec![] });
+
+    let mut sequenced = vec![];
+    let mut reliable = vec![];
+    loop {
+        match server.recv().unwrap() {
+            TestMessage::SmallMessage { value } => sequenced.push(value),
+            TestMessage::LargeMessage { text } => reliable.push(text),
+            TestMessage::BulkMessage { .. } => break,
+        }
+    }
+    // stale and lost datagrams are gone, everything else arrives in order
+    assert!(sequenced.windows(2).all(|w| w[0] < w[1]));
+    assert!(sequenced.len() > 50);
+    assert!(sequenced.len() < 100);
+    assert_eq!(reliable, (0..10).map(|i| (i * 10).to_string()).collect::<Vec<_>>());
+    Connection::stop(&client);
+    Connection::stop(&server);
+}
+
+#[test]
+fn compression_roundtrip() {
+    let text = TestMessage::LargeMessage {
+        text: "veloren ".repeat(1000),
+    }
+    .to_bytes()
+    .unwrap();
+    let compressed = compress(&text).unwrap();
+    assert!(compressed.len() < text.len());
+    assert_eq!(decompress(&compressed).unwrap(), text);
+    // too small to be worth it
+    assert!(compress(&text[..100]).is_none());
+    // garbage and absurd sizes from the remote are refused
+    assert!(decompress(&[1, 2]).is_err());
+    assert!(decompress(&[255, 255, 255, 255, 0]).is_err());
+}
+
+#[test]
+fn construct_message_compressed() {
+    let bytes = compress(&vec![7; 10_000]).unwrap();
+    let mut o = OutgoingPacket::new(bytes.clone(), 5).compressed();
+    let mut i = IncomingPacket::new(5);
+    while let Ok(frame) = o.generate_frame(100) {
+        i.load_frame(frame).unwrap();
+    }
+    assert!(i.is_done());
+    assert!(i.is_compressed());
+    assert_eq!(decompress(&i.into_data()).unwrap(), vec![7; 10_000]);
+}
+
+#[test]
+fn connection_compressed() {
+    // a sequenced message too large for a datagram fits once compressed, so this needs udp
+    let (listen, serverip) = tcp_listener();
+    let client = Connection::<TestMessage>::new(&serverip, UdpMgr::new()).unwrap();
+    let server = Connection::<TestMessage>::new_stream(listen.accept().unwrap().0, UdpMgr::new()).unwrap();
+    Connection::start(&client);
+    Connection::start(&server);
+    client.set_compression(true);
+
+    let server_udp = server.bind_udp().unwrap();
+    let client_udp = client.bind_udp().unwrap();
+    Connection::open_udp(&server, server_udp, client_udp);
+    Connection::open_udp(&client, client_udp, server_udp);
+
+    let text = "veloren ".repeat(100_000);
+    client.send(TestMessage::LargeMessage { text: text.clone() });
+    client.send_sequenced(TestMessage::BulkMessage { data: vec![3; 5000] }, 0);
+    for _ in 0..2 {
+        match server.recv().unwrap() {
+            TestMessage::LargeMessage { text: t } => assert_eq!(t, text),
+            TestMessage::BulkMessage { data } => assert_eq!(data, vec![3; 5000]),
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+    Connection::stop(&client);
+    Connection::stop(&server);
+}
+
+#[test]
+fn key_exchange() {
+    let identity = Identity::generate();
+    let (client, server) = (Handshake::new(), Handshake::new());
+    let (client_public, server_public) = (client.public(), server.public());
+    let client = client.finish(server_public, identity.public()).unwrap();
+    let server = server.respond(client_public, &identity).unwrap();
+
+    let sealed = client.encrypt(42, b"password");
+    assert_ne!(&sealed[..8], b"password");
+    assert_eq!(server.decrypt(42, &sealed).unwrap(), b"password");
+    assert_eq!(client.decrypt(7, &server.encrypt(7, b"token")).unwrap(), b"token");
+    // the id is authenticated, tampering or replaying under another id is detected
+    assert!(server.decrypt(43, &sealed).is_err());
+    let mut tampered = sealed.clone();
+    tampered[0] ^= 1;
+    assert!(server.decrypt(42, &tampered).is_err());
+    // each direction has its own key
+    assert!(client.decrypt(42, &sealed).is_err());
+    // a low order point can't be used to force a known secret
+    assert!(Handshake::new().finish([0; 32], identity.public()).is_err());
+    assert!(Handshake::new().finish(server_public, [0; 32]).is_err());
+}
+
+#[test]
+fn key_exchange_needs_the_identity() {
+    // Someone in between answering with the expected identity but without its secret doesn't get the same keys
+    let identity = Identity::generate();
+    let (client, relay) = (Handshake::new(), Handshake::new());
+    let (client_public, relay_public) = (client.public(), relay.public());
+    let client = client.finish(relay_public, identity.public()).unwrap();
+    let relay = relay.respond(client_public, &Identity::generate()).unwrap();
+    assert!(relay.decrypt(1, &client.encrypt(1, b"password")).is_err());
+
+    // The identity survives being saved
+    let restored = Identity::from_bytes(identity.to_bytes());
+    assert_eq!(restored.public(), identity.public());
+}
+
 #[test]
 fn tcp_pingpong() {
-    let serverip = PORTS.next();
-    let listen = TcpListener::bind(&serverip).unwrap();
+    let (listen, serverip) = tcp_listener();
     let handle = thread::spawn(move || {
         let stream = listen.accept().unwrap().0; //blocks until client connected
         let server = Tcp::new_stream(stream).unwrap();
         let frame = server.recv().unwrap(); //wait for ping
         match frame {
-            Frame::Header { id, length } => {
+            Frame::Header {
+                id,
+                length,
+                compressed,
+                encrypted,
+            } => {
                 assert_eq!(id, 123);
                 assert_eq!(length, 9876);
+                assert!(compressed && encrypted);
             },
-            Frame::Data { .. } => {
+            Frame::Data { .. } | Frame::Sequenced { .. } => {
                 assert!(false);
             },
         }
@@ -297,10 +888,17 @@ fn tcp_pingpong() {
             .unwrap(); //send pong
     });
     let client = Tcp::new(&serverip).unwrap();
-    client.send(Frame::Header { id: 123, length: 9876 }).unwrap(); //send ping
+    client
+        .send(Frame::Header {
+            id: 123,
+            length: 9876,
+            compressed: true,
+            encrypted: true,
+        })
+        .unwrap(); //send ping
     let frame = client.recv().unwrap(); //wait for pong
     match frame {
-        Frame::Header { .. } => {
+        Frame::Header { .. } | Frame::Sequenced { .. } => {
             assert!(false);
         },
         Frame::Data { id, frame_no, data } => {
@@ -315,8 +913,7 @@ fn tcp_pingpong() {
 
 #[test]
 fn tcp_disconnect() {
-    let serverip = PORTS.next();
-    let listen = TcpListener::bind(&serverip).unwrap();
+    let (listen, serverip) = tcp_listener();
     let handle = thread::spawn(move || {
         let stream = listen.accept().unwrap().0; //blocks until client connected
         let server = Tcp::new_stream(stream).unwrap();
@@ -344,18 +941,17 @@ fn tcp_doublerecv() {
     // then server will send a pong
     // but there are 2 threads listening on the same client
     // only one will recv it, the other one will panic
-    let serverip = PORTS.next();
-    let listen = TcpListener::bind(&serverip).unwrap();
+    let (listen, serverip) = tcp_listener();
     let handle = thread::spawn(move || {
         let stream = listen.accept().unwrap().0; //blocks until client connected
         let server = Tcp::new_stream(stream).unwrap();
         let frame = server.recv().unwrap(); //wait for ping
         match frame {
-            Frame::Header { id, length } => {
+            Frame::Header { id, length, .. } => {
                 assert_eq!(id, 123);
                 assert_eq!(length, 9876);
             },
-            Frame::Data { .. } => {
+            Frame::Data { .. } | Frame::Sequenced { .. } => {
                 assert!(false);
             },
         }
@@ -372,7 +968,7 @@ fn tcp_doublerecv() {
     let handle2 = thread::spawn(move || {
         let frame = client.recv().unwrap(); //wait for pong
         match frame {
-            Frame::Header { .. } => {
+            Frame::Header { .. } | Frame::Sequenced { .. } => {
                 assert!(false);
             },
             Frame::Data { id, frame_no, data } => {
@@ -387,7 +983,7 @@ fn tcp_doublerecv() {
     let handle3 = thread::spawn(move || {
         let frame = client.recv().unwrap(); //wait for pong
         match frame {
-            Frame::Header { .. } => {
+            Frame::Header { .. } | Frame::Sequenced { .. } => {
                 assert!(false);
             },
             Frame::Data { id, frame_no, data } => {
@@ -399,7 +995,7 @@ fn tcp_doublerecv() {
         }
     });
     let client = Tcp::new_stream(clientstream.try_clone().unwrap()).unwrap();
-    client.send(Frame::Header { id: 123, length: 9876 }).unwrap(); //send ping
+    client.send(header(123, 9876)).unwrap(); //send ping
     handle.join().unwrap();
     handle2.join().unwrap();
     handle3.join().unwrap();
@@ -408,18 +1004,18 @@ fn tcp_doublerecv() {
 #[test]
 fn udp_pingpong() {
     let mgr = UdpMgr::new();
-    let serverip = PORTS.next();
-    let clientip = PORTS.next();
+    let serverip = udp_addr(&mgr);
+    let clientip = udp_addr(&mgr);
     let server = UdpMgr::start_udp(mgr.clone(), &serverip, &clientip); // server has to know client ip
     let client = UdpMgr::start_udp(mgr.clone(), &clientip, &serverip);
-    client.send(Frame::Header { id: 123, length: 9876 }).unwrap(); //send ping
+    client.send(header(123, 9876)).unwrap(); //send ping
     let frame = server.recv().unwrap(); //wait for ping
     match frame {
-        Frame::Header { id, length } => {
+        Frame::Header { id, length, .. } => {
             assert_eq!(id, 123);
             assert_eq!(length, 9876);
         },
-        Frame::Data { .. } => {
+        Frame::Data { .. } | Frame::Sequenced { .. } => {
             assert!(false);
         },
     }
@@ -432,7 +1028,7 @@ fn udp_pingpong() {
         .unwrap(); //send pong
     let frame = client.recv().unwrap(); //wait for pong
     match frame {
-        Frame::Header { .. } => {
+        Frame::Header { .. } | Frame::Sequenced { .. } => {
             assert!(false);
         },
         Frame::Data { id, frame_no, data } => {
@@ -449,23 +1045,23 @@ fn udp_pingpong() {
 #[test]
 fn udp_pingpong_2clients() {
     let mgr = UdpMgr::new();
-    let serverip = PORTS.next();
-    let clientip = PORTS.next();
-    let clientip2 = PORTS.next();
+    let serverip = udp_addr(&mgr);
+    let clientip = udp_addr(&mgr);
+    let clientip2 = udp_addr(&mgr);
     let server = UdpMgr::start_udp(mgr.clone(), &serverip, &clientip);
     let server2 = UdpMgr::start_udp(mgr.clone(), &serverip, &clientip2);
     let client = UdpMgr::start_udp(mgr.clone(), &clientip, &serverip);
     let client2 = UdpMgr::start_udp(mgr.clone(), &clientip2, &serverip);
-    client.send(Frame::Header { id: 123, length: 9876 }).unwrap(); //send ping
+    client.send(header(123, 9876)).unwrap(); //send ping
     println!("send");
     let frame = server.recv().unwrap(); //wait for ping
     println!("recved");
     match frame {
-        Frame::Header { id, length } => {
+        Frame::Header { id, length, .. } => {
             assert_eq!(id, 123);
             assert_eq!(length, 9876);
         },
-        Frame::Data { .. } => {
+        Frame::Data { .. } | Frame::Sequenced { .. } => {
             assert!(false);
         },
     }
@@ -478,7 +1074,7 @@ fn udp_pingpong_2clients() {
         .unwrap(); //send pong
     let frame = client2.recv().unwrap(); //wait for pong
     match frame {
-        Frame::Header { .. } => {
+        Frame::Header { .. } | Frame::Sequenced { .. } => {
             assert!(false);
         },
         Frame::Data { id, frame_no, data } => {
@@ -497,15 +1093,15 @@ fn udp_pingpong_2clients() {
 #[test]
 fn udp_pingpong_1000() {
     let mgr = UdpMgr::new();
-    let serverip = PORTS.next();
-    let clientip = PORTS.next();
+    let serverip = udp_addr(&mgr);
+    let clientip = udp_addr(&mgr);
     let server = UdpMgr::start_udp(mgr.clone(), &serverip, &clientip); // server has to know client ip
     let client = UdpMgr::start_udp(mgr.clone(), &clientip, &serverip);
     let clientclone = client.clone();
     let serverclone = server.clone();
     let handle = thread::spawn(move || {
         for i in 0..1 {
-            clientclone.send(Frame::Header { id: 123, length: 9876 }).unwrap(); //send ping
+            clientclone.send(header(123, 9876)).unwrap(); //send ping
             if i % 80 == 0 {
                 thread::sleep(Duration::from_millis(150));
                 // i cant send to much because then packages get droped by the udp UdpSocket
@@ -519,11 +1115,11 @@ fn udp_pingpong_1000() {
             println!("{}", i);
             let frame = serverclone.recv().unwrap(); //wait for ping
             match frame {
-                Frame::Header { id, length } => {
+                Frame::Header { id, length, .. } => {
                     assert_eq!(id, 123);
                     assert_eq!(length, 9876);
                 },
-                Frame::Data { .. } => {
+                Frame::Data { .. } | Frame::Sequenced { .. } => {
                     assert!(false);
                 },
             }
@@ -543,14 +1139,14 @@ fn udp_pingpong_1000() {
 //#[test]
 fn udp_pingpong_2clients_negative() {
     let mgr = UdpMgr::new();
-    let serverip = PORTS.next();
-    let clientip = PORTS.next();
-    let clientip2 = PORTS.next();
+    let serverip = udp_addr(&mgr);
+    let clientip = udp_addr(&mgr);
+    let clientip2 = udp_addr(&mgr);
     let _server = UdpMgr::start_udp(mgr.clone(), &serverip, &clientip); // server has to know client ip
     let server2 = UdpMgr::start_udp(mgr.clone(), &serverip, &clientip2); // server has to know client ip
     let client = UdpMgr::start_udp(mgr.clone(), &clientip, &serverip);
     let _client2 = UdpMgr::start_udp(mgr.clone(), &clientip2, &serverip);
-    client.send(Frame::Header { id: 123, length: 9876 }).unwrap(); //send ping
+    client.send(header(123, 9876)).unwrap(); //send ping
     let _frame = server2.recv().unwrap(); //wait for ping from other client
     assert!(false);
 }
@@ -13,7 +13,7 @@ use parking_lot::{Mutex, RwLock};
 // Parent
 use super::{
     packet::Frame,
-    protocol::{Protocol, PROTOCOL_FRAME_DATA, PROTOCOL_FRAME_HEADER},
+    protocol::{flags, parse_flags, Protocol, PROTOCOL_FRAME_DATA, PROTOCOL_FRAME_HEADER, PROTOCOL_FRAME_SEQUENCED},
     Error,
 };
 
@@ -50,11 +50,9 @@ impl Udp {
 
     pub fn received_raw_packet(&self, rawpacket: &Vec<u8>) {
         self.in_buffer.write().push_back(rawpacket.clone());
-        let mut lock = self.waiting_thread.lock();
-        if let Some(ref t) = *lock {
+        if let Some(ref t) = *self.waiting_thread.lock() {
             t.unpark();
         }
-        *lock = None;
     }
 }
 
@@ -62,9 +60,15 @@ impl Protocol for Udp {
     fn send(&self, frame: Frame) -> Result<(), Error> {
         let socket = self.socket.read();
         match frame {
-            Frame::Header { id, length } => {
-                let mut buff = Vec::with_capacity(17);
+            Frame::Header {
+                id,
+                length,
+                compressed,
+                encrypted,
+            } => {
+                let mut buff = Vec::with_capacity(18);
                 buff.write_u8(PROTOCOL_FRAME_HEADER)?;
+                buff.write_u8(flags(compressed, encrypted))?;
                 buff.write_u64::<LittleEndian>(id)?;
                 buff.write_u64::<LittleEndian>(length)?;
                 socket.send_to(&buff, &self.remote)?;
@@ -80,44 +84,54 @@ impl Protocol for Udp {
                 socket.send_to(&buff, &self.remote)?;
                 Ok(())
             },
+            Frame::Sequenced {
+                id,
+                stream,
+                compressed,
+                encrypted,
+                data,
+            } => {
+                let mut buff = Vec::with_capacity(26 + data.len());
+                buff.write_u8(PROTOCOL_FRAME_SEQUENCED)?;
+                buff.write_u8(flags(compressed, encrypted))?;
+                buff.write_u64::<LittleEndian>(id)?;
+                buff.write_u64::<LittleEndian>(stream)?;
+                buff.write_u64::<LittleEndian>(data.len() as u64)?;
+                buff.write_all(&data)?;
+                socket.send_to(&buff, &self.remote)?;
+                Ok(())
+            },
         }
     }
 
     //blocking
     fn recv(&self) -> Result<Frame, Error> {
-        println!("r1");
+        // register before checking the buffer, so a packet arriving in between can't get lost
         {
-            if self.in_buffer.read().is_empty() {
-                {
-                    let mut lock = self.waiting_thread.lock();
-                    match *lock {
-                        Some(..) => panic!("Only one thread may wait for recv on udp"),
-                        None => {
-                            *lock = Some(thread::current());
-                        },
-                    }
-                }
-                while self.in_buffer.read().is_empty() {
-                    // hope a unpark does never happen in between those two statements
-                    println!("parked");
-                    thread::park();
-                    println!("unparked");
-                }
+            let mut lock = self.waiting_thread.lock();
+            if lock.is_some() {
+                panic!("Only one thread may wait for recv on udp");
             }
+            *lock = Some(thread::current());
         }
-        println!("r2");
-        let data;
-        {
-            let mut lock = self.in_buffer.write();
-            data = lock.pop_front().unwrap();
+        while self.in_buffer.read().is_empty() {
+            thread::park();
         }
+        *self.waiting_thread.lock() = None;
+        let data = self.in_buffer.write().pop_front().unwrap();
         let mut cur = Cursor::new(data);
         let frame = cur.read_u8()? as u8;
         match frame {
             1 => {
+                let (compressed, encrypted) = parse_flags(cur.read_u8()?);
                 let id = cur.read_u64::<LittleEndian>()? as u64;
                 let length = cur.read_u64::<LittleEndian>()? as u64;
-                Ok(Frame::Header { id, length })
+                Ok(Frame::Header {
+                    id,
+                    length,
+                    compressed,
+                    encrypted,
+                })
             },
             2 => {
                 let id = cur.read_u64::<LittleEndian>()? as u64;
@@ -127,6 +141,21 @@ impl Protocol for Udp {
                 cur.read_exact(&mut data)?;
                 Ok(Frame::Data { id, frame_no, data })
             },
+            3 => {
+                let (compressed, encrypted) = parse_flags(cur.read_u8()?);
+                let id = cur.read_u64::<LittleEndian>()? as u64;
+                let stream = cur.read_u64::<LittleEndian>()? as u64;
+                let packet_size = cur.read_u64::<LittleEndian>()? as u64;
+                let mut data = vec![0; packet_size as usize];
+                cur.read_exact(&mut data)?;
+                Ok(Frame::Sequenced {
+                    id,
+                    stream,
+                    compressed,
+                    encrypted,
+                    data,
+                })
+            },
             x => {
                 error!("invalid frame recieved: {}", x);
                 Err(Error::CannotDeserialize)
@@ -1,5 +1,6 @@
 // Standard
 use std::{
+    io,
     net::{SocketAddr, ToSocketAddrs, UdpSocket},
     sync::Arc,
     thread::{self, JoinHandle},
@@ -9,7 +10,7 @@ use std::{
 use parking_lot::RwLock;
 
 // Parent
-use super::udp::Udp;
+use super::{udp::Udp, Error};
 
 #[derive(Debug)]
 struct UdpInfo {
@@ -40,38 +41,46 @@ impl UdpMgr {
         })
     }
 
-    pub fn start_udp<A: ToSocketAddrs>(mgr: Arc<UdpMgr>, listen: &A, remote: &A) -> Arc<Udp> {
-        let mut socket_info = None;
-        let listen = listen.to_socket_addrs().unwrap().next().unwrap();
-        let remote = remote.to_socket_addrs().unwrap().next().unwrap();
+    // Find the socket listening on `listen`, or bind a new one. Binding port 0 always creates a new socket
+    fn socket_info(mgr: &Arc<UdpMgr>, listen: SocketAddr) -> Result<Arc<SocketInfo>, Error> {
+        if let Some(si) = mgr
+            .sockets
+            .read()
+            .iter()
+            .find(|s| s.socket.local_addr().ok() == Some(listen))
         {
-            let subscriber = mgr.subscriber.read();
-            for c in &(*subscriber) {
-                if c.socket_info.socket.local_addr().unwrap() == listen {
-                    socket_info = Some(c.socket_info.clone());
-                    break;
-                }
-            }
+            return Ok(si.clone());
         }
 
-        if let None = socket_info {
-            // if non eist for this socket create
-            let socket = UdpSocket::bind(listen).unwrap();
-            let socketclone = socket.try_clone().unwrap();
-            let mgrclone = mgr.clone();
-            let recv_thread = thread::spawn(move || {
-                mgrclone.recv_worker_udp(socketclone);
-            });
-            let socketclone = socket.try_clone().unwrap();
-            let si = Arc::new(SocketInfo {
-                socket: socketclone,
-                recv_thread,
-            });
-            mgr.sockets.write().push(si.clone());
-            socket_info = Some(si.clone());
-            debug!("listen on new udp socket, started a new thread {}", listen);
-        }
-        let socket_info = socket_info.unwrap();
+        // if non eist for this socket create
+        let socket = UdpSocket::bind(listen)?;
+        let socketclone = socket.try_clone()?;
+        let mgrclone = mgr.clone();
+        let recv_thread = thread::spawn(move || {
+            mgrclone.recv_worker_udp(socketclone);
+        });
+        let si = Arc::new(SocketInfo { socket, recv_thread });
+        mgr.sockets.write().push(si.clone());
+        debug!(
+            "listen on new udp socket, started a new thread {}",
+            si.socket.local_addr()?
+        );
+        Ok(si)
+    }
+
+    // Bind a socket before the remote is known, returns the address the remote should send to
+    pub fn bind_udp<A: ToSocketAddrs>(mgr: Arc<UdpMgr>, listen: &A) -> Result<SocketAddr, Error> {
+        let listen = listen
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no address to bind udp to"))?;
+        Ok(UdpMgr::socket_info(&mgr, listen)?.socket.local_addr()?)
+    }
+
+    pub fn start_udp<A: ToSocketAddrs>(mgr: Arc<UdpMgr>, listen: &A, remote: &A) -> Arc<Udp> {
+        let listen = listen.to_socket_addrs().unwrap().next().unwrap();
+        let remote = remote.to_socket_addrs().unwrap().next().unwrap();
+        let socket_info = UdpMgr::socket_info(&mgr, listen).unwrap();
 
         let udp = Arc::new(Udp::new_stream(socket_info.socket.try_clone().unwrap(), remote).unwrap());
         debug!("created udp listnen on {} for remote {}", listen, remote);
@@ -83,8 +92,6 @@ impl UdpMgr {
 
         mgr.subscriber.write().push(ui);
         return udp.clone();
-        /*
-        manager.send(ConnectionMessage::OpenedUdp{ host: listen });*/
     }
 
     pub fn stop_udp(mgr: Arc<UdpMgr>, udp: Arc<Udp>) {
@@ -122,11 +129,11 @@ impl UdpMgr {
             let mut buff = vec![0; MAX_UDP_SIZE];
             let (size, remote) = socket.recv_from(&mut buff).unwrap();
             buff.resize(size, 0);
-            println!("rcved sth of  {} bytes on {}", size, socket.local_addr().unwrap());
+            trace!("rcved sth of  {} bytes on {}", size, socket.local_addr().unwrap());
             let subscriber = self.subscriber.read();
             for c in subscriber.iter() {
                 if remote == c.remote && socket.local_addr().unwrap() == c.socket_info.socket.local_addr().unwrap() {
-                    println!(
+                    trace!(
                         "forwarded it {} - {}",
                         c.remote,
                         c.socket_info.socket.local_addr().unwrap()
@@ -1,5 +1,5 @@
 // Standard
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, iter, sync::Arc, time::Duration};
 
 // Library
 use parking_lot::RwLock;
@@ -260,3 +260,21 @@ pub fn tick<
         }
     }
 }
+
+// Where a lone entity ends up after moving with `ctrl_acc` for `dt`, without any other entities in its way. Player
+// inputs are simulated with this by both the server and the client's prediction, so they agree
+pub fn simulate<CP: Send + Sync + 'static>(
+    chunk_mgr: &ChunkMgr<CP>,
+    pos: Vec3<f32>,
+    vel: Vec3<f32>,
+    ctrl_acc: Vec3<f32>,
+    dt: Duration,
+) -> (Vec3<f32>, Vec3<f32>) {
+    // Anything further than full speed or a jump is cut off
+    let ctrl_acc = ctrl_acc.map(|e| e.max(-1.0).min(1.0));
+    let entity = Arc::new(RwLock::new(Entity::<()>::new(pos, vel, ctrl_acc, Vec2::unit_y())));
+    let uid: Uid = 0;
+    tick(iter::once((&uid, &entity)), chunk_mgr, dt);
+    let entity = entity.read();
+    (*entity.pos(), *entity.vel())
+}
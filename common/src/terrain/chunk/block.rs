@@ -77,6 +77,15 @@ impl Block {
     }
 
     pub fn is_fluid(&self) -> bool { *self == Self::WATER }
+
+    // Whether the material is in one of the palette or gradient encodings, anything else can't be drawn
+    pub fn is_valid(&self) -> bool {
+        match self.mat.grad & 0xC0 {
+            0x40 | 0xC0 => true,
+            0x80 => self.mat.grad == 0x80,
+            _ => false,
+        }
+    }
 }
 
 impl Voxel for Block {
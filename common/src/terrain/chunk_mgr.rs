@@ -10,7 +10,7 @@ use vek::*;
 // Local
 use crate::terrain::{
     self,
-    chunk::{Block, ChunkContainer, ChunkSample},
+    chunk::{Block, Chunk, ChunkContainer, ChunkSample},
     Container, Key, PersState, VolCluster, VolGen, VolOffs, VoxAbs, VoxRel,
 };
 
@@ -22,6 +22,20 @@ impl Key for Vec3<VolOffs> {
     fn print(&self) -> String { return format!("c{},{},{}", self.x, self.y, self.z).to_string(); }
 }
 
+fn set_block_in<P>(con: &ChunkContainer<P>, off: Vec3<VoxRel>, block: Block) -> Option<Block> {
+    let old = {
+        let mut data = con.data_mut();
+        // Only the raw blocks can be written to, the other representations would be out of date afterwards
+        data.convert(PersState::Hetero);
+        if data.contains(PersState::Rle) {
+            data.remove(PersState::Rle);
+        }
+        data.get_mut(PersState::Hetero)?.replace_at(off, block)?
+    };
+    *con.payload_mut() = None;
+    Some(old)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ChunkSampleError {
     ChunkMissing { key: Vec3<VolOffs> },
@@ -73,6 +87,21 @@ impl<P: Send + Sync + 'static> ChunkMgr<P> {
         None
     }
 
+    // Change a block of a loaded chunk, returns the block it replaced. The chunk's payload is dropped, since it was
+    // generated from the old blocks
+    pub fn set_block(&self, pos: Vec3<VoxAbs>, block: Block) -> Option<Block> {
+        let key = terrain::voxabs_to_voloffs(pos, self.vol_size);
+        let off = terrain::voxabs_to_voxrel(pos, self.vol_size);
+        let con = self.pers.read().get(&key).cloned();
+        if let Some(con) = con {
+            return set_block_in(&con, off, block);
+        }
+        // It may have been generated or filled in, but not moved over yet
+        let con = self.pending.read().get(&key).cloned()?;
+        let con = con.lock();
+        set_block_in(con.as_ref()?, off, block)
+    }
+
     // Tries getting a Sample
     pub fn try_get_sample(&self, from: Vec3<VoxAbs>, to: Vec3<VoxAbs>) -> Result<ChunkSample, ChunkSampleError> {
         let mut c = 0;
@@ -148,6 +177,21 @@ impl<P: Send + Sync + 'static> ChunkMgr<P> {
         });
     }
 
+    // Fill in a pending chunk `gen_vol` left empty, e.g. because it has to come from a server. Its payload is
+    // generated again afterwards
+    pub fn fill(&self, pos: Vec3<VolOffs>, chunk: Chunk) -> bool {
+        let con = match self.pending.read().get(&pos) {
+            Some(con) => con.clone(),
+            None => return false,
+        };
+        *con.lock() = Some(ChunkContainer::new(chunk));
+        let gen_payload = self.gen.gen_payload.clone();
+        POOL.lock().execute(move || {
+            gen_payload(pos, con);
+        });
+        true
+    }
+
     pub fn drop(&self, pos: Vec3<VolOffs>) {
         // this function must work multithreaded
         let drop_vol = self.gen.drop_vol.clone();
@@ -301,6 +345,16 @@ impl<P: Send + Sync + 'static> ChunkMgr<P> {
 
     pub fn pending_chunk_cnt(&self) -> usize { self.pending.read().len() }
 
+    // Pending chunks that weren't generated or filled in yet, leaving out the ones being worked on right now
+    pub fn unfilled(&self) -> Vec<Vec3<VolOffs>> {
+        self.pending
+            .read()
+            .iter()
+            .filter(|(_, con)| con.try_lock().map(|con| con.is_none()).unwrap_or(false))
+            .map(|(pos, _)| *pos)
+            .collect()
+    }
+
     pub fn pers<F>(&self, filter: F) -> HashMap<Vec3<VolOffs>, Arc<ChunkContainer<P>>>
     where
         F: Fn(&Vec3<VolOffs>) -> bool,
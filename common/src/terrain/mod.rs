@@ -2,12 +2,14 @@ pub mod chunk;
 mod chunk_mgr;
 mod entity;
 pub mod figure;
+mod region;
 mod vol_gen;
 
 // Reexports
 pub use crate::terrain::{
     chunk_mgr::{BlockLoader, ChunkMgr},
     entity::Entity,
+    region::{RegionError, RegionStore, REGION_SIZE, REGION_VERSION},
     vol_gen::{FnDropFunc, FnGenFunc, VolGen},
 };
 
@@ -0,0 +1,210 @@
+// Standard
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+// Library
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use lz4_flex::{compress_prepend_size, decompress_size_prepended};
+use parking_lot::Mutex;
+use vek::*;
+
+// Local
+use crate::terrain::{chunk::Chunk, VolCluster, VolOffs};
+
+// Constants
+const MAGIC: [u8; 4] = *b"VREG";
+// Bump whenever the layout of a region file or of the chunks in it changes, older files are refused instead of misread
+pub const REGION_VERSION: u32 = 1;
+// How many chunks a region file holds along every axis
+pub const REGION_SIZE: VolOffs = 8;
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+// Magic, version and for every chunk its offset, length and checksum
+const HEADER_LEN: usize = 4 + 4 + REGION_CHUNKS * 12;
+// Refuse to allocate more than this for a single chunk, the size prefix comes from the file
+const MAX_CHUNK_LEN: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum RegionError {
+    IoErr(io::Error),
+    NotARegion,
+    UnsupportedVersion(u32),
+    CannotSerialize,
+}
+
+impl From<io::Error> for RegionError {
+    fn from(e: io::Error) -> RegionError { RegionError::IoErr(e) }
+}
+
+// CRC-32 (IEEE), to notice chunks that were damaged on disk
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn region_of(pos: Vec3<VolOffs>) -> Vec3<VolOffs> { pos.map(|e| e.div_euc(REGION_SIZE)) }
+
+fn index_of(pos: Vec3<VolOffs>) -> usize {
+    let rel = pos.map(|e| e.mod_euc(REGION_SIZE) as usize);
+    (rel.x * REGION_SIZE as usize + rel.y) * REGION_SIZE as usize + rel.z
+}
+
+// The compressed chunks of one region file
+struct Region {
+    chunks: Vec<Option<Vec<u8>>>,
+    dirty: bool,
+}
+
+impl Region {
+    fn new() -> Region {
+        Region {
+            chunks: vec![None; REGION_CHUNKS],
+            dirty: false,
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Region, RegionError> {
+        if bytes.len() < HEADER_LEN || bytes[0..4] != MAGIC {
+            return Err(RegionError::NotARegion);
+        }
+        let mut header = Cursor::new(&bytes[4..HEADER_LEN]);
+        let version = header.read_u32::<LittleEndian>()?;
+        if version != REGION_VERSION {
+            return Err(RegionError::UnsupportedVersion(version));
+        }
+
+        let mut region = Region::new();
+        for (idx, chunk) in region.chunks.iter_mut().enumerate() {
+            let offset = header.read_u32::<LittleEndian>()? as usize;
+            let len = header.read_u32::<LittleEndian>()? as usize;
+            let crc = header.read_u32::<LittleEndian>()?;
+            if offset == 0 {
+                continue;
+            }
+            // A damaged chunk is left out, it's generated again instead of failing the whole region
+            match bytes.get(offset..offset + len) {
+                Some(data) if checksum(data) == crc => *chunk = Some(data.to_vec()),
+                _ => warn!("Chunk {} of a region file is damaged, it's dropped", idx),
+            }
+        }
+        Ok(region)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        let mut data = vec![];
+        header.extend_from_slice(&MAGIC);
+        header.write_u32::<LittleEndian>(REGION_VERSION).unwrap();
+        for chunk in &self.chunks {
+            let (offset, len, crc) = match chunk {
+                Some(chunk) => {
+                    let offset = HEADER_LEN + data.len();
+                    data.extend_from_slice(chunk);
+                    (offset as u32, chunk.len() as u32, checksum(chunk))
+                },
+                None => (0, 0, 0),
+            };
+            header.write_u32::<LittleEndian>(offset).unwrap();
+            header.write_u32::<LittleEndian>(len).unwrap();
+            header.write_u32::<LittleEndian>(crc).unwrap();
+        }
+        header.extend_from_slice(&data);
+        header
+    }
+}
+
+// Keeps chunks in region files of `REGION_SIZE`³ chunks each, within one directory. Saved chunks are written when
+// `flush` is called, every region file is replaced at once so a crash leaves either the old or the new one
+pub struct RegionStore {
+    dir: PathBuf,
+    // Regions read or saved to since the last flush
+    regions: Mutex<HashMap<Vec3<VolOffs>, Region>>,
+}
+
+impl RegionStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> RegionStore {
+        RegionStore {
+            dir: dir.into(),
+            regions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, region: Vec3<VolOffs>) -> PathBuf {
+        self.dir
+            .join(format!("r.{}.{}.{}.region", region.x, region.y, region.z))
+    }
+
+    fn read_region(path: &Path) -> Result<Region, RegionError> {
+        let mut bytes = vec![];
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Region::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Region::from_bytes(&bytes)
+    }
+
+    fn with_region<R, F: FnOnce(&mut Region) -> R>(&self, pos: Vec3<VolOffs>, f: F) -> Result<R, RegionError> {
+        let region = region_of(pos);
+        let mut regions = self.regions.lock();
+        if !regions.contains_key(&region) {
+            regions.insert(region, Self::read_region(&self.path(region))?);
+        }
+        Ok(f(regions.get_mut(&region).unwrap()))
+    }
+
+    // The chunk saved at `pos`, None if there's none or it was damaged
+    pub fn load(&self, pos: Vec3<VolOffs>) -> Result<Option<Chunk>, RegionError> {
+        let compressed = match self.with_region(pos, |region| region.chunks[index_of(pos)].clone())? {
+            Some(compressed) => compressed,
+            None => return Ok(None),
+        };
+        let size = Cursor::new(&compressed).read_u32::<LittleEndian>()?;
+        if size == 0 || size > MAX_CHUNK_LEN {
+            return Ok(None);
+        }
+        Ok(decompress_size_prepended(&compressed)
+            .ok()
+            .and_then(|bytes| Chunk::from_bytes(&bytes).ok()))
+    }
+
+    // Keep `chunk` for `pos`, it's written to disk by the next `flush`
+    pub fn save(&self, pos: Vec3<VolOffs>, chunk: &mut Chunk) -> Result<(), RegionError> {
+        let compressed = compress_prepend_size(&chunk.to_bytes().map_err(|_| RegionError::CannotSerialize)?);
+        self.with_region(pos, |region| {
+            region.chunks[index_of(pos)] = Some(compressed);
+            region.dirty = true;
+        })
+    }
+
+    // Write every region with chunks saved since the last flush
+    pub fn flush(&self) -> Result<(), RegionError> {
+        let mut regions = self.regions.lock();
+        fs::create_dir_all(&self.dir)?;
+        for (pos, region) in regions.iter_mut().filter(|(_, region)| region.dirty) {
+            let path = self.path(*pos);
+            let tmp = path.with_extension("region.tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(&region.to_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            region.dirty = false;
+        }
+        // They're read again when needed, instead of piling up while players move around
+        regions.clear();
+        Ok(())
+    }
+}
@@ -45,11 +45,12 @@ impl<T: Managed> Manager<T> {
         manager
     }
 
+    // Workers added by a worker see the shutdown of the root as well
     fn new_child(&self) -> Manager<T> {
         Manager {
             internal: self.internal.clone(),
             root: false,
-            running: Arc::new(AtomicBool::new(true)),
+            running: self.running.clone(),
             workers: vec![],
         }
     }
@@ -2,5 +2,9 @@ pub mod clock;
 pub mod manager;
 pub mod msg;
 pub mod names;
+pub mod ping;
 pub mod post;
+pub mod prediction;
+pub mod snapshot;
+pub mod spatial;
 pub mod testutils;
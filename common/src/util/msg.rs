@@ -1,15 +1,175 @@
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
 // Library
+use rand::{thread_rng, Rng};
 use serde_derive::{Deserialize, Serialize};
 use vek::*;
 
 // Project
 use crate::{
-    net::Message,
-    util::post::{PostBox, PostOffice},
+    get_version,
+    net::{self, Message},
+    terrain::{chunk::Block, VolOffs, VoxAbs},
+    util::{
+        post::{PostBox, PostOffice},
+        snapshot::Snapshot,
+    },
 };
 
+// Version
+
+// Bump whenever the layout of a message changes. `ClientMsg::Connect`, `ServerMsg::Disconnect`, `Letter::KeyExchange`,
+// `Version` and `DisconnectReason::IncompatibleVersion` must keep their layout and position, so mismatched builds can
+// still tell each other why
+pub const PROTOCOL_VERSION: u32 = 15;
+
+// Optional features, only used if both sides announce them
+pub const FEATURE_UDP: &str = "udp";
+pub const FEATURE_LZ4: &str = "lz4";
+const FEATURES: &[&str] = &[FEATURE_UDP, FEATURE_LZ4];
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Version {
+    pub protocol: u32,
+    pub build: String,
+    pub features: Vec<String>,
+}
+
+impl Version {
+    pub fn current() -> Version {
+        Version {
+            protocol: PROTOCOL_VERSION,
+            build: get_version(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    pub fn is_compatible(&self, other: &Version) -> bool { self.protocol == other.protocol }
+
+    pub fn supports(&self, feature: &str) -> bool { self.features.iter().any(|f| f == feature) }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{} (protocol {})", self.build, self.protocol) }
+}
+
+// DisconnectReason
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    IncompatibleVersion { server: Version, client: Version },
+    Logout,
+    Timeout,
+    Kicked(String),
+    Shutdown,
+    // The connection broke without either side ending it
+    ConnectionLost,
+    // A resume token was presented after the server gave up waiting for it
+    SessionExpired,
+    // The credentials sent with `ClientMsg::Authenticate` weren't accepted
+    LoginFailed(AccountError),
+    // Refused for good by a moderator, until unbanned
+    Banned(String),
+}
+
+impl DisconnectReason {
+    // The player is kept for a while, so the client can come back with its resume token
+    pub fn is_resumable(&self) -> bool {
+        match self {
+            DisconnectReason::Timeout | DisconnectReason::ConnectionLost => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                DisconnectReason::IncompatibleVersion { server, client } => {
+                    format!("Incompatible version (server: {}, client: {})", server, client)
+                },
+                DisconnectReason::Logout => format!("Logout"),
+                DisconnectReason::Timeout => format!("Timedout"),
+                DisconnectReason::Kicked(msg) => format!("Kicked ({})", msg),
+                DisconnectReason::Shutdown => format!("Server shutting down"),
+                DisconnectReason::ConnectionLost => format!("Connection lost"),
+                DisconnectReason::SessionExpired => format!("Session expired"),
+                DisconnectReason::LoginFailed(e) => format!("Login failed ({})", e),
+                DisconnectReason::Banned(msg) => format!("Banned ({})", msg),
+            }
+        )
+    }
+}
+
+// ResumeToken
+
+// Handed out with `ServerMsg::Connected`, a client presents it to take over its player again after losing the connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ResumeToken([u8; 16]);
+
+impl ResumeToken {
+    pub fn generate() -> ResumeToken { ResumeToken(thread_rng().gen()) }
+}
+
+// LoginToken
+
+// Handed out for an account's password, a client presents it instead of the password when connecting
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LoginToken(pub [u8; 32]);
+
+impl LoginToken {
+    pub fn generate() -> LoginToken { LoginToken(thread_rng().gen()) }
+
+    // To keep it in a text file
+    pub fn to_hex(&self) -> String { net::to_hex(&self.0) }
+
+    pub fn from_hex(hex: &str) -> Option<LoginToken> { net::from_hex(hex).map(LoginToken) }
+}
+
+// Accounts
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Credentials {
+    // Play without an account, under an alias no account was registered with
+    Guest,
+    // Play as the account, under its name
+    Token { account: String, token: LoginToken },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AccountRequest {
+    Register { account: String, password: String },
+    Login { account: String, password: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AccountError {
+    InvalidName,
+    WeakPassword,
+    NameTaken,
+    WrongCredentials,
+    Unavailable,
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                AccountError::InvalidName => "Invalid account name",
+                AccountError::WeakPassword => "The password is too short",
+                AccountError::NameTaken => "The name belongs to an account",
+                AccountError::WrongCredentials => "Wrong account name or password",
+                AccountError::Unavailable => "Accounts are unavailable",
+            }
+        )
+    }
+}
+
 // SessionKind
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -17,6 +177,8 @@ pub enum SessionKind {
     Connect,
     Disconnect,
     Ping,
+    // Registering or logging in to get a `LoginToken`, without playing
+    Account,
 }
 
 impl Message for SessionKind {}
@@ -39,17 +201,31 @@ pub enum CompStore {
 pub enum ServerMsg {
     // SessionKind::Connect
     Connected {
+        version: Version,
         player_uid: Option<u64>,
         time: Duration,
+        resume_token: ResumeToken,
+        // the terrain comes from the server, a client may only generate it itself if its world has the same seed
+        world_seed: u32,
     },
 
-    // SessionKind::Disconnect
+    // SessionKind::Connect, SessionKind::Disconnect
     Disconnect {
-        reason: String,
+        reason: DisconnectReason,
     },
 
-    // SessionKind::Ping
-    Ping,
+    // SessionKind::Account
+    AccountResult {
+        result: Result<LoginToken, AccountError>,
+    },
+
+    // SessionKind::Ping, see `util::ping`
+    Ping {
+        id: u64,
+    },
+    Pong {
+        id: u64,
+    },
 
     // One-shot
     ChatMsg {
@@ -65,10 +241,53 @@ pub enum ServerMsg {
     },
 
     TimeUpdate(Duration),
+
+    // Sequenced over `snapshot::SNAPSHOT_STREAM`, the entities in view that changed since the acknowledged baseline
+    Snapshot(Snapshot),
+    // Sequenced over `PLAYER_STREAM`, where the player is after the inputs up to `seq`. The client needn't send them
+    // again, and simulates the ones after it again if it predicted something else
+    PlayerState {
+        seq: u64,
+        pos: Vec3<f32>,
+        vel: Vec3<f32>,
+    },
+    // A chunk the client requested, as `Chunk::to_bytes`
+    ChunkData {
+        pos: Vec3<VolOffs>,
+        data: Vec<u8>,
+    },
+    // A block was changed, sent to every client that may have its chunk. Sent with the same priority as `ChunkData`,
+    // so it can't overtake the chunk it belongs to
+    BlockUpdate {
+        pos: Vec3<VoxAbs>,
+        block: Block,
+    },
+    // The answer to a command, whether it was sent as `ClientMsg::Cmd` or as chat starting with '/'
+    CmdResult {
+        success: bool,
+        lines: Vec<String>,
+    },
 }
 
 impl Message for ServerMsg {}
 
+// PlayerInput
+
+// Every client tick covers this much time, and the player's input for it is simulated by the server
+pub const INPUT_DT: Duration = Duration::from_millis(20);
+
+// Sequenced stream player inputs and states are sent over
+pub const PLAYER_STREAM: u64 = 2;
+
+// How the player wants to move during one client tick
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    // counts up from 1
+    pub seq: u64,
+    pub ctrl_acc: Vec3<f32>,
+    pub look_dir: Vec2<f32>,
+}
+
 // ClientMsg
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -81,29 +300,64 @@ pub enum PlayMode {
 pub enum ClientMsg {
     // SessionKind::Connect
     Connect {
+        version: Version,
         alias: String,
         mode: PlayMode,
     },
+    // instead of `Connect`, to take over the player of a lost connection
+    Resume {
+        version: Version,
+        token: ResumeToken,
+    },
+    // right after `Connect`
+    Authenticate {
+        credentials: Credentials,
+    },
+
+    // SessionKind::Account, answered with `ServerMsg::AccountResult`
+    Account {
+        version: Version,
+        request: AccountRequest,
+    },
 
     // SessionKind::Disconnect
     Disconnect {
         reason: String,
     },
 
-    // SessionKind::Ping
-    Ping,
+    // SessionKind::Ping, see `util::ping`
+    Ping {
+        id: u64,
+    },
+    Pong {
+        id: u64,
+    },
 
     // One-shot
     ChatMsg {
         text: String,
     },
+    // The name of a command without its '/' and its arguments, answered with `ServerMsg::CmdResult`
     Cmd {
         args: Vec<String>,
     },
-    PlayerEntityUpdate {
-        pos: Vec3<f32>,
-        vel: Vec3<f32>,
-        dir: Vec2<f32>,
+    // Sequenced over `PLAYER_STREAM`, the newest inputs with the unacknowledged ones before repeated in case a message
+    // was lost
+    PlayerInputs {
+        inputs: Vec<PlayerInput>,
+    },
+    // Sequenced over `snapshot::SNAPSHOT_ACK_STREAM`
+    SnapshotAck {
+        id: u64,
+    },
+    // Answered with `ServerMsg::ChunkData` once the server has the chunk, if it's near the player
+    RequestChunk {
+        pos: Vec3<VolOffs>,
+    },
+    // The player wants to change a block, the server answers with `ServerMsg::BlockUpdate` if it may
+    SetBlock {
+        pos: Vec3<VoxAbs>,
+        block: Block,
     },
 }
 
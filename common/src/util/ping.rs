@@ -0,0 +1,136 @@
+// Standard
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Information
+// -----------
+// Both sides of a connection ping each other over a SessionKind::Ping session and answer every ping they receive
+// with a pong of the same id right away. The time until the pong arrives is the round trip time. This only keeps
+// track of which ping is due and which pong is awaited, sending and receiving is left to the caller.
+
+#[derive(Debug)]
+pub struct Pinger {
+    freq: Duration,
+    timeout: Duration,
+    next_id: u64,
+    next_ping: Instant,
+    // pings that weren't answered yet and when they were sent, oldest first
+    sent: VecDeque<(u64, Instant)>,
+    // oldest ping that wasn't answered yet
+    unanswered_since: Option<Instant>,
+}
+
+impl Pinger {
+    pub fn new(freq: Duration, timeout: Duration) -> Pinger {
+        Pinger {
+            freq,
+            timeout,
+            next_id: 0,
+            next_ping: Instant::now() + freq,
+            sent: VecDeque::new(),
+            unanswered_since: None,
+        }
+    }
+
+    // Id of the ping to send now, if one is due
+    pub fn poll(&mut self, now: Instant) -> Option<u64> {
+        if now < self.next_ping {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.next_ping = now + self.freq;
+        // a pong that takes longer than the timeout won't make a difference anymore
+        while self
+            .sent
+            .front()
+            .map(|(_, sent)| now - *sent > self.timeout)
+            .unwrap_or(false)
+        {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((id, now));
+        self.unanswered_since.get_or_insert(now);
+        Some(id)
+    }
+
+    // How long to wait for incoming messages before polling again
+    pub fn wait(&self, now: Instant) -> Duration {
+        if self.next_ping > now {
+            self.next_ping - now
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+
+    // A pong arrived, returns the round trip time if it answers a ping that is still awaited. The round trip may take
+    // longer than `freq`, so newer pings may be in flight already. Pings older than the answered one are given up on
+    pub fn pong(&mut self, id: u64, now: Instant) -> Option<Duration> {
+        let index = self.sent.iter().position(|(sent_id, _)| *sent_id == id)?;
+        let (_, sent) = self.sent.drain(..=index).last()?;
+        // the remote is alive, only the pings sent since may be overdue
+        self.unanswered_since = self.sent.front().map(|(_, sent)| *sent);
+        Some(now - sent)
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        self.unanswered_since
+            .map(|since| now - since > self.timeout)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pinger;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pinger() {
+        let mut pinger = Pinger::new(Duration::from_secs(2), Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(pinger.poll(start), None);
+
+        let t = start + Duration::from_secs(2);
+        assert_eq!(pinger.poll(t), Some(0));
+        assert_eq!(pinger.poll(t), None);
+        assert_eq!(pinger.wait(t), Duration::from_secs(2));
+        assert_eq!(pinger.pong(1, t), None);
+        assert_eq!(
+            pinger.pong(0, t + Duration::from_millis(40)),
+            Some(Duration::from_millis(40))
+        );
+        assert_eq!(pinger.pong(0, t + Duration::from_millis(50)), None);
+
+        // no pong for longer than the timeout
+        let t = start + Duration::from_secs(4);
+        assert_eq!(pinger.poll(t), Some(1));
+        assert_eq!(pinger.poll(t + Duration::from_secs(2)), Some(2));
+        assert!(!pinger.timed_out(t + Duration::from_secs(10)));
+        assert!(pinger.timed_out(t + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn test_pinger_slow_pongs() {
+        let mut pinger = Pinger::new(Duration::from_secs(2), Duration::from_secs(10));
+        let start = Instant::now();
+
+        // the round trip takes longer than the ping frequency, there are always two pings in flight
+        let t = start + Duration::from_secs(2);
+        assert_eq!(pinger.poll(t), Some(0));
+        assert_eq!(pinger.poll(t + Duration::from_secs(2)), Some(1));
+        assert_eq!(pinger.poll(t + Duration::from_secs(4)), Some(2));
+        assert_eq!(pinger.pong(0, t + Duration::from_secs(3)), Some(Duration::from_secs(3)));
+        assert_eq!(pinger.pong(0, t + Duration::from_secs(3)), None);
+        // the pings sent since are awaited from when they were sent
+        assert!(!pinger.timed_out(t + Duration::from_secs(12)));
+        assert!(pinger.timed_out(t + Duration::from_secs(13)));
+
+        // answering a newer ping gives up on the older ones
+        assert_eq!(pinger.pong(2, t + Duration::from_secs(7)), Some(Duration::from_secs(3)));
+        assert_eq!(pinger.pong(1, t + Duration::from_secs(7)), None);
+        assert!(!pinger.timed_out(t + Duration::from_secs(60)));
+    }
+}
@@ -1,34 +1,42 @@
 // Standard
 use std::{
     collections::HashMap,
-    io,
-    net::{TcpStream, ToSocketAddrs},
+    fmt, io,
+    marker::PhantomData,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
     sync::{
-        atomic::{AtomicU64, Ordering},
-        mpsc::{self, RecvError, RecvTimeoutError, SendError},
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvError, RecvTimeoutError, SendError, TryRecvError},
         Arc,
     },
     time::Duration,
 };
 
 // Library
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use serde_derive::{Deserialize, Serialize};
 
 // Local
 use crate::{
-    net::{Connection, Error, Message, UdpMgr},
+    net::{
+        Connection, Delivery, Error, Handshake, Identity, Message, NetStats, PublicKeyBytes, Transport, UdpMgr,
+        PRIO_DEFAULT, PRIO_MAX,
+    },
     util::manager::{Managed, Manager},
 };
 
+// How long a stopped PostOffice keeps trying to deliver what was queued before it
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
 // Information
 // -----------
 //
 // The post system is a one-to-many relationship between a single postoffice and many postboxes.
-// We use mpscs to communicate between each: the outgoing mpsc is owned by the postoffice, the
-// incoming mpscs are owned by each postbox respectively. When a message comes in, it gets sent
-// off to the corresponding postbox's receiving mpsc. When a message gets sent from a postbox, it
-// gets sent through the postoffice's outgoing mpsc.
+// The incoming mpscs are owned by each postbox respectively. When a message comes in, it gets sent
+// off to the corresponding postbox's receiving mpsc, right on the thread that received it. When a
+// message gets sent from a postbox, it goes through the postoffice's outbox straight to the
+// connection. Neither needs a thread of its own, so a postoffice on a reactor driven transport
+// doesn't run any.
 //
 //      ,--> PostBox
 //     v
@@ -44,11 +52,40 @@ pub enum Letter<SK, M> {
     CloseBox(u64),
     Message { uid: u64, payload: M },
     OneShot(M),
+    // Tells the remote where to send udp datagrams to, answered with the remote's own address. Only taken once the
+    // connection is encrypted, and only for the host the connection comes from
+    OpenUdp { addr: SocketAddr },
+    // Public key of an x25519 exchange, answered with `KeyReply`. Always sent unencrypted
+    KeyExchange { public: PublicKeyBytes },
     Shutdown,
+    // The answer to `KeyExchange`: the remote's public key, and the identity it proves it holds
+    KeyReply(PublicKeyBytes, PublicKeyBytes),
 }
 
 impl<SK: Message, M: Message> Message for Letter<SK, M> {}
 
+// A letter on its way to the connection, together with how it should be delivered
+pub type Outgoing<SK, M> = Result<(Letter<SK, M>, Delivery), ()>;
+
+// Hands letters to the connection, refuses them once the postoffice stopped
+#[derive(Debug)]
+struct Outbox<SK: Message, RM: Message> {
+    conn: Arc<Connection<Letter<SK, RM>>>,
+    // a write lock is taken to stop, so nothing sent before can end up behind the shutdown
+    stopped: RwLock<bool>,
+}
+
+impl<SK: Message, RM: Message> Outbox<SK, RM> {
+    fn send<SM: Message>(&self, letter: Letter<SK, SM>, delivery: Delivery) -> Result<(), SendError<Outgoing<SK, SM>>> {
+        let stopped = self.stopped.read();
+        if *stopped {
+            return Err(SendError(Ok((letter, delivery))));
+        }
+        self.conn.send_with(letter, delivery);
+        Ok(())
+    }
+}
+
 // PostBoxSession
 
 #[derive(Debug)]
@@ -62,31 +99,46 @@ pub struct PostBoxSession<SK: Message, SM: Message, RM: Message> {
 #[derive(Debug)]
 pub struct PostBox<SK: Message, SM: Message, RM: Message> {
     uid: u64,
+    // All letters of a postbox share one priority, so they can't overtake each other
+    prio: u8,
     // The recv end for the incoming mpsc
     recv: mpsc::Receiver<RM>,
-    // The send end for the PostOffice outgoing mpsc
-    po_send: mpsc::Sender<Result<Letter<SK, SM>, ()>>,
+    // Shared with the PostOffice
+    outbox: Arc<Outbox<SK, RM>>,
+    _sent: PhantomData<SM>,
 }
 
 impl<SK: Message, SM: Message, RM: Message> PostBox<SK, SM, RM> {
-    pub fn send(&self, msg: SM) -> Result<(), SendError<Result<Letter<SK, SM>, ()>>> {
-        self.po_send.send(Ok(Letter::Message {
-            uid: self.uid,
-            payload: msg,
-        }))
+    pub fn send(&self, msg: SM) -> Result<(), SendError<Outgoing<SK, SM>>> {
+        self.outbox.send(
+            Letter::Message {
+                uid: self.uid,
+                payload: msg,
+            },
+            Delivery::Reliable { prio: self.prio },
+        )
     }
 
+    pub fn prio(&self) -> u8 { self.prio }
+
     pub fn recv(&self) -> Result<RM, RecvError> { self.recv.recv() }
 
     pub fn recv_timeout(&self, duration: Duration) -> Result<RM, RecvTimeoutError> { self.recv.recv_timeout(duration) }
 
-    pub fn close(self) -> Result<(), SendError<Result<Letter<SK, SM>, ()>>> {
-        self.po_send.send(Ok(Letter::CloseBox(self.uid)))
+    pub fn try_recv(&self) -> Result<RM, TryRecvError> { self.recv.try_recv() }
+
+    pub fn close(self) -> Result<(), SendError<Outgoing<SK, SM>>> {
+        self.outbox
+            .send(Letter::CloseBox(self.uid), Delivery::Reliable { prio: self.prio })
     }
 }
 
 impl<SK: Message, SM: Message, RM: Message> Drop for PostBox<SK, SM, RM> {
-    fn drop(&mut self) { let _ = self.po_send.send(Ok(Letter::CloseBox(self.uid))); }
+    fn drop(&mut self) {
+        let _ = self
+            .outbox
+            .send::<SM>(Letter::CloseBox(self.uid), Delivery::Reliable { prio: self.prio });
+    }
 }
 
 // PostOffice
@@ -95,17 +147,27 @@ impl<SK: Message, SM: Message, RM: Message> Drop for PostBox<SK, SM, RM> {
 pub struct PostOffice<SK: Message, SM: Message, RM: Message> {
     uid_counter: AtomicU64,
 
-    // The send + recv ends of the outgoing mpsc, used for cloning and passing to postboxes
-    outgoing_send: Mutex<mpsc::Sender<Result<Letter<SK, SM>, ()>>>,
-    outgoing_recv: Mutex<mpsc::Receiver<Result<Letter<SK, SM>, ()>>>,
+    // Shared with every postbox
+    outbox: Arc<Outbox<SK, RM>>,
 
     // The send + recv ends of the incoming mpsc, used for cloning and passing to postboxes
     incoming_send: Mutex<mpsc::Sender<Result<Incoming<SK, SM, RM>, ()>>>,
     incoming_recv: Mutex<mpsc::Receiver<Result<Incoming<SK, SM, RM>, ()>>>,
+    // Takes what `await_incoming` would return instead, see `forward_incoming`
+    forward: RwLock<Option<Forward<SK, SM, RM>>>,
+    // Incoming::End was handed out
+    ended: AtomicBool,
 
     // The send ends for the PostBox incoming mpscs
     pb_sends: Mutex<HashMap<u64, mpsc::Sender<RM>>>,
 
+    // What we answer key exchanges as, the side starting them has none
+    identity: Option<Identity>,
+    // Our half of a key exchange we started, and the mpsc passing on the remote's identity once it finished
+    handshake: Mutex<Option<Handshake>>,
+    encrypted_send: Mutex<mpsc::Sender<PublicKeyBytes>>,
+    encrypted_recv: Mutex<mpsc::Receiver<PublicKeyBytes>>,
+
     // Internal connection used for networking
     conn: Arc<Connection<Letter<SK, RM>>>,
 }
@@ -116,6 +178,12 @@ pub enum Incoming<SK: Message, SM: Message, RM: Message> {
     End,
 }
 
+struct Forward<SK: Message, SM: Message, RM: Message>(Box<dyn Fn(Incoming<SK, SM, RM>) + Send + Sync>);
+
+impl<SK: Message, SM: Message, RM: Message> fmt::Debug for Forward<SK, SM, RM> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Forward") }
+}
+
 impl<SK: Message, SM: Message, RM: Message> PostOffice<SK, SM, RM> {
     // Create a postoffice that runs on the client, talking to a server
     pub fn to_server<U: ToSocketAddrs>(remote_addr: U) -> Result<Manager<PostOffice<SK, SM, RM>>, Error> {
@@ -124,16 +192,36 @@ impl<SK: Message, SM: Message, RM: Message> PostOffice<SK, SM, RM> {
             1,
             //TcpStream::connect(remote_addr)?,
             Connection::new(&remote_addr, UdpMgr::new())?,
+            None,
         )?))
     }
 
-    // Create a postoffice that runs on the server, talking to a client
-    pub fn to_client(stream: TcpStream) -> Result<Manager<PostOffice<SK, SM, RM>>, Error> {
+    // Create a postoffice that runs on the server, talking to a client. Key exchanges are answered as `identity`
+    pub fn to_client(stream: TcpStream, identity: Identity) -> Result<Manager<PostOffice<SK, SM, RM>>, Error> {
         // Server-side UIDs start from 0 and count evens
         Ok(Manager::init(PostOffice::new_internal(
             0,
             //stream,
             Connection::new_stream(stream, UdpMgr::new())?,
+            Some(identity),
+        )?))
+    }
+
+    // Like `to_server`, but on any transport
+    pub fn to_server_with(transport: Transport) -> Result<Manager<PostOffice<SK, SM, RM>>, Error> {
+        Ok(Manager::init(PostOffice::new_internal(
+            1,
+            Connection::with_transport(transport, UdpMgr::new())?,
+            None,
+        )?))
+    }
+
+    // Like `to_client`, but on any transport
+    pub fn to_client_with(transport: Transport, identity: Identity) -> Result<Manager<PostOffice<SK, SM, RM>>, Error> {
+        Ok(Manager::init(PostOffice::new_internal(
+            0,
+            Connection::with_transport(transport, UdpMgr::new())?,
+            Some(identity),
         )?))
     }
 
@@ -141,25 +229,34 @@ impl<SK: Message, SM: Message, RM: Message> PostOffice<SK, SM, RM> {
     pub fn new_internal(
         start_uid: u64,
         conn: Arc<Connection<Letter<SK, RM>>>,
+        identity: Option<Identity>,
     ) -> Result<PostOffice<SK, SM, RM>, io::Error> {
         // Start the internal connection
         Connection::start(&conn);
 
-        // Create the mpsc for outgoing messages
-        let (outgoing_send, outgoing_recv) = mpsc::channel();
-        let (outgoing_send, outgoing_recv) = (Mutex::new(outgoing_send), Mutex::new(outgoing_recv));
-
-        // Create the mpsc for outgoing messages
+        // Create the mpsc for incoming messages
         let (incoming_send, incoming_recv) = mpsc::channel();
         let (incoming_send, incoming_recv) = (Mutex::new(incoming_send), Mutex::new(incoming_recv));
 
+        // Create the mpsc for finished key exchanges
+        let (encrypted_send, encrypted_recv) = mpsc::channel();
+        let (encrypted_send, encrypted_recv) = (Mutex::new(encrypted_send), Mutex::new(encrypted_recv));
+
         Ok(PostOffice {
             uid_counter: AtomicU64::new(start_uid),
-            outgoing_send,
-            outgoing_recv,
+            outbox: Arc::new(Outbox {
+                conn: conn.clone(),
+                stopped: RwLock::new(false),
+            }),
             incoming_send,
             incoming_recv,
+            forward: RwLock::new(None),
+            ended: AtomicBool::new(false),
             pb_sends: Mutex::new(HashMap::new()),
+            identity,
+            handshake: Mutex::new(None),
+            encrypted_send,
+            encrypted_recv,
             conn,
         })
     }
@@ -168,25 +265,31 @@ impl<SK: Message, SM: Message, RM: Message> PostOffice<SK, SM, RM> {
     fn gen_uid(&self) -> u64 { self.uid_counter.fetch_add(2, Ordering::Relaxed) }
 
     // Utility to create a new postbox with a predetermined UID (not visible to the user)
-    fn create_postbox_with_uid(&self, uid: u64) -> PostBox<SK, SM, RM> {
+    fn create_postbox_with_uid(&self, uid: u64, prio: u8) -> PostBox<SK, SM, RM> {
         let (pb_send, pb_recv) = mpsc::channel();
         self.pb_sends.lock().insert(uid, pb_send);
 
         PostBox {
             uid,
+            prio,
             recv: pb_recv,
-            po_send: self.outgoing_send.lock().clone(),
+            outbox: self.outbox.clone(),
+            _sent: PhantomData,
         }
     }
 
     // Create a new master postbox, triggering the creation of a slave postbox on the other end
     pub fn create_postbox(&self, kind: SK) -> PostBox<SK, SM, RM> {
+        self.create_postbox_with_priority(kind, PRIO_DEFAULT)
+    }
+
+    // Like `create_postbox`, but every letter of this postbox is sent with the given priority
+    pub fn create_postbox_with_priority(&self, kind: SK, prio: u8) -> PostBox<SK, SM, RM> {
         let uid = self.gen_uid();
         let _ = self
-            .outgoing_send
-            .lock()
-            .send(Ok(Letter::OpenBox::<SK, SM> { uid, kind }));
-        self.create_postbox_with_uid(uid)
+            .outbox
+            .send::<SM>(Letter::OpenBox { uid, kind }, Delivery::Reliable { prio });
+        self.create_postbox_with_uid(uid, prio)
     }
 
     // Handle incoming packets, returning any new incoming postboxes as they get created
@@ -199,67 +302,254 @@ impl<SK: Message, SM: Message, RM: Message> PostOffice<SK, SM, RM> {
         }
     }
 
+    // Like `await_incoming`, but gives up after `timeout`
+    pub fn await_incoming_timeout(&self, timeout: Duration) -> Result<Incoming<SK, SM, RM>, RecvTimeoutError> {
+        match self.incoming_recv.lock().recv_timeout(timeout) {
+            Ok(Ok(msg)) => Ok(msg),
+            Ok(Err(())) => Err(RecvTimeoutError::Disconnected),
+            Err(e) => Err(e),
+        }
+    }
+
     // Send a single one-off message to the remote postoffice
-    pub fn send_one(&self, msg: SM) -> Result<(), SendError<Result<Letter<SK, SM>, ()>>> {
-        self.outgoing_send.lock().send(Ok(Letter::OneShot(msg)))
+    pub fn send_one(&self, msg: SM) -> Result<(), SendError<Outgoing<SK, SM>>> {
+        self.send_one_with_priority(msg, PRIO_DEFAULT)
+    }
+
+    // Hand everything `await_incoming` would return to `f` instead, as soon as it arrives. `f` runs on the thread
+    // that received it, possibly a reactor, so it must not block. Don't call `await_incoming` anymore afterwards
+    pub fn forward_incoming<F: Fn(Incoming<SK, SM, RM>) + Send + Sync + 'static>(&self, f: F) {
+        let mut forward = self.forward.write();
+        // what arrived so far first
+        loop {
+            let queued = self.incoming_recv.lock().try_recv();
+            match queued {
+                Ok(Ok(incoming)) => f(incoming),
+                Ok(Err(_)) | Err(_) => break,
+            }
+        }
+        *forward = Some(Forward(Box::new(f)));
+    }
+
+    fn push_incoming(&self, incoming: Incoming<SK, SM, RM>) {
+        if let Incoming::End = incoming {
+            if self.ended.swap(true, Ordering::Relaxed) {
+                return;
+            }
+        }
+        match self.forward.read().as_ref() {
+            Some(Forward(f)) => f(incoming),
+            None => {
+                let _ = self.incoming_send.lock().send(Ok(incoming));
+            },
+        }
+    }
+
+    // Send a single one-off message, lower prio values get a larger share of the bandwidth
+    pub fn send_one_with_priority(&self, msg: SM, prio: u8) -> Result<(), SendError<Outgoing<SK, SM>>> {
+        self.outbox.send(Letter::OneShot(msg), Delivery::Reliable { prio })
     }
 
-    // Stop the PostOffice
+    // Send a one-off message which may get lost, and is dropped if a newer one of the same stream arrived first.
+    // Meant for state that is resent regularly anyway, like positions. Goes over tcp until `open_udp` succeeded
+    pub fn send_one_sequenced(&self, msg: SM, stream: u64) -> Result<(), SendError<Outgoing<SK, SM>>> {
+        self.outbox.send(Letter::OneShot(msg), Delivery::Sequenced { stream })
+    }
+
+    // Only enable this if the remote announced it supports compression
+    pub fn set_compression(&self, enabled: bool) { self.conn.set_compression(enabled) }
+
+    // Agree on session keys with the remote, everything sent afterwards is encrypted. Do this before any other
+    // traffic, so nothing is in flight while both sides switch over. Gives the identity the remote answered as, nothing
+    // secret may be sent before it was checked to be the expected one: anyone can answer as an identity of their own
+    pub fn exchange_keys(&self, timeout: Duration) -> Result<PublicKeyBytes, RecvTimeoutError> {
+        let handshake = Handshake::new();
+        let public = handshake.public();
+        *self.handshake.lock() = Some(handshake);
+        self.conn
+            .send_with_priority(Letter::<SK, SM>::KeyExchange { public }, PRIO_MAX);
+        self.encrypted_recv.lock().recv_timeout(timeout)
+    }
+
+    pub fn is_encrypted(&self) -> bool { self.conn.is_encrypted() }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> { self.conn.peer_addr() }
+
+    pub fn stats(&self) -> NetStats { self.conn.stats() }
+
+    pub fn record_rtt(&self, sample: Duration) { self.conn.record_rtt(sample) }
+
+    // Ask the remote to exchange udp addresses, so sequenced messages can be sent over udp. Exchange the keys first,
+    // the remote ignores the request on a connection that isn't encrypted
+    pub fn open_udp(&self) -> Result<(), Error> {
+        let addr = self.conn.bind_udp()?;
+        let _ = self
+            .outbox
+            .send::<SM>(Letter::OpenUdp { addr }, Delivery::Reliable { prio: PRIO_DEFAULT });
+        Ok(())
+    }
+
+    // Where the remote sends udp datagrams to, None while everything goes over tcp
+    pub fn local_udp(&self) -> Option<SocketAddr> { self.conn.local_udp() }
+
+    // Stop the PostOffice. Everything sent before still reaches the remote, unless that takes longer than
+    // FLUSH_TIMEOUT
     pub fn stop(&self) {
-        // Send shutdown message to the remote (we don't care if this fails)
-        let _ = self.outgoing_send.lock().send(Ok(Letter::Shutdown));
-        // Close the connection
-        let _ = self.outgoing_send.lock().send(Err(()));
+        {
+            let mut stopped = self.outbox.stopped.write();
+            if !*stopped {
+                *stopped = true;
+                // Send shutdown message to the remote, after everything sent before
+                self.conn.send(Letter::<SK, SM>::Shutdown);
+                // Deliver what is still queued, then close the connection
+                Connection::close(&self.conn, FLUSH_TIMEOUT);
+            }
+        }
+        // Nothing arrives anymore, postboxes waiting for the remote's answer give up once they took what they got
+        self.pb_sends.lock().clear();
         let _ = self.incoming_send.lock().send(Err(()));
     }
+
+    // Relay an incoming letter, None once the connection ended
+    fn handle_letter(&self, letter: Option<Letter<SK, RM>>) {
+        match letter {
+            Some(Letter::OpenBox { uid, kind }) => {
+                // Replies go out with the default priority, the remote's choice is not known here
+                self.push_incoming(Incoming::Session(PostBoxSession {
+                    postbox: self.create_postbox_with_uid(uid, PRIO_DEFAULT),
+                    kind,
+                }));
+            },
+            Some(Letter::CloseBox(uid)) => {
+                self.pb_sends.lock().remove(&uid);
+            },
+            Some(Letter::Message { uid, payload }) => {
+                self.pb_sends.lock().get(&uid).map(|s| s.send(payload));
+            },
+            Some(Letter::OneShot(m)) => self.push_incoming(Incoming::Msg(m)),
+            // Otherwise anyone could make us send datagrams to a host of their choice, or change the address on the way
+            Some(Letter::OpenUdp { addr })
+                if !self.conn.is_encrypted() || self.conn.peer_addr().map(|peer| peer.ip()) != Some(addr.ip()) =>
+            {
+                warn!(
+                    "Ignoring udp address {}, it isn't the remote's or came unencrypted",
+                    addr
+                );
+            },
+            Some(Letter::OpenUdp { addr }) => {
+                // If we didn't bind yet the remote asked us, otherwise this is the answer to our request
+                let answer = self.conn.local_udp().is_none();
+                match self.conn.bind_udp() {
+                    Ok(local) => {
+                        Connection::open_udp(&self.conn, local, addr);
+                        if answer {
+                            self.conn.send(Letter::<SK, SM>::OpenUdp { addr: local });
+                        }
+                    },
+                    Err(e) => warn!("Could not open udp, staying on tcp: {:?}", e),
+                }
+            },
+            Some(Letter::KeyExchange { .. }) | Some(Letter::KeyReply(..)) if self.conn.is_encrypted() => {
+                warn!("Ignoring key exchange on an encrypted connection");
+            },
+            Some(Letter::KeyExchange { public }) => match &self.identity {
+                Some(identity) => {
+                    let handshake = Handshake::new();
+                    let reply = Letter::<SK, SM>::KeyReply(handshake.public(), identity.public());
+                    if let Err(e) = handshake
+                        .respond(public, identity)
+                        .map(|cipher| self.conn.enable_encryption(cipher, Some(reply)))
+                    {
+                        warn!("Key exchange failed: {:?}", e);
+                    }
+                },
+                None => warn!("Ignoring key exchange, only the side with an identity answers them"),
+            },
+            Some(Letter::KeyReply(public, identity)) => match self.handshake.lock().take() {
+                Some(handshake) => match handshake
+                    .finish(public, identity)
+                    .map(|cipher| self.conn.enable_encryption::<Letter<SK, SM>>(cipher, None))
+                {
+                    Ok(()) => {
+                        let _ = self.encrypted_send.lock().send(identity);
+                    },
+                    Err(e) => warn!("Key exchange failed: {:?}", e),
+                },
+                None => warn!("Ignoring the answer to a key exchange we didn't start"),
+            },
+            // Notify the user that the other end has disconnected
+            Some(Letter::Shutdown) | None => self.push_incoming(Incoming::End),
+        }
+    }
 }
 
 impl<SK: Message, SM: Message, RM: Message> Managed for PostOffice<SK, SM, RM> {
     fn init_workers(&self, mgr: &mut Manager<Self>) {
-        // Create a worker to relay outgoing messages to the connection
-        Manager::add_worker(mgr, |po, running, _| {
-            // Hold the outgoing receiver permanently
-            let outgoing_recv = po.outgoing_recv.lock();
-            while running.load(Ordering::Relaxed) {
-                match outgoing_recv.recv() {
-                    Ok(Ok(letter)) => po.conn.send(letter),
-                    Ok(Err(_)) | Err(_) => break,
-                };
+        // Relay incoming letters right on the thread that received them, no worker needed
+        let po = Arc::downgrade(Manager::internal(mgr));
+        self.conn.dispatch_to(move |letter| {
+            if let Some(po) = po.upgrade() {
+                po.handle_letter(letter);
             }
-
-            // Stop the connection, terminating communication
-            Connection::stop(&po.conn);
         });
+    }
 
-        // Create a worker to relay incoming messages from the connection
-        Manager::add_worker(mgr, |po, running, _| {
-            // Hold the incoming sender permanently
-            let incoming_send = po.incoming_send.lock().clone();
-            while running.load(Ordering::Relaxed) {
-                match po.conn.recv() {
-                    Ok(Letter::OpenBox { uid, kind }) => {
-                        let _ = incoming_send.send(Ok(Incoming::Session(PostBoxSession {
-                            postbox: po.create_postbox_with_uid(uid),
-                            kind,
-                        })));
-                    },
-                    Ok(Letter::CloseBox(uid)) => {
-                        po.pb_sends.lock().remove(&uid);
-                    },
-                    Ok(Letter::Message { uid, payload }) => {
-                        po.pb_sends.lock().get(&uid).map(|s| s.send(payload));
-                    },
-                    Ok(Letter::OneShot(m)) => {
-                        let _ = incoming_send.send(Ok(Incoming::Msg(m)));
-                    },
-                    Ok(Letter::Shutdown) | Err(_) => break,
-                }
-            }
+    fn on_drop(&self, mgr: &mut Manager<Self>) { Manager::internal(mgr).stop(); }
+}
 
-            // Send an end message to notify the user that the other end has disconnected
-            let _ = incoming_send.send(Ok(Incoming::End));
-        });
+#[cfg(test)]
+mod tests {
+    use super::{Incoming, Letter, PostOffice};
+    use crate::{
+        net::{Identity, Message},
+        util::manager::Manager,
+    };
+    use serde_derive::{Deserialize, Serialize};
+    use std::{
+        net::{SocketAddr, TcpListener},
+        time::Duration,
+    };
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Ping;
+    impl Message for Ping {}
+
+    fn pair() -> (
+        Manager<PostOffice<Ping, Ping, Ping>>,
+        Manager<PostOffice<Ping, Ping, Ping>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = PostOffice::to_server(listener.local_addr().unwrap()).unwrap();
+        let server = PostOffice::to_client(listener.accept().unwrap().0, Identity::generate()).unwrap();
+        (client, server)
     }
 
-    fn on_drop(&self, mgr: &mut Manager<Self>) { Manager::internal(mgr).stop(); }
+    // Letters arrive in order, whatever was sent before the ping was handled once it's there
+    fn ping(from: &PostOffice<Ping, Ping, Ping>, to: &PostOffice<Ping, Ping, Ping>) {
+        from.send_one(Ping).unwrap();
+        match to.await_incoming() {
+            Ok(Incoming::Msg(Ping)) => {},
+            _ => panic!("expected a ping"),
+        }
+    }
+
+    #[test]
+    fn udp_only_opens_to_the_remote() {
+        // Not before the connection is encrypted
+        let (client, server) = pair();
+        client.open_udp().unwrap();
+        ping(&client, &server);
+        assert_eq!(server.conn.local_udp(), None);
+
+        // Nor to an address on another host
+        client.exchange_keys(Duration::from_secs(5)).unwrap();
+        let spoofed: SocketAddr = "192.0.2.1:14004".parse().unwrap();
+        client.conn.send(Letter::<Ping, Ping>::OpenUdp { addr: spoofed });
+        ping(&client, &server);
+        assert_eq!(server.conn.local_udp(), None);
+
+        client.open_udp().unwrap();
+        ping(&client, &server);
+        assert!(server.conn.local_udp().is_some());
+    }
 }
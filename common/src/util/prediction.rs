@@ -0,0 +1,208 @@
+// Standard
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Library
+use vek::*;
+
+// Local
+use super::{msg::PlayerInput, snapshot::EntityState};
+
+// Constants
+// How many of the newest inputs are sent every tick, so the server still gets them if a few messages are lost
+pub const INPUT_REDUNDANCY: usize = 8;
+// Inputs the server never acknowledges, e.g. while the connection is lost, aren't kept around forever
+const MAX_UNACKED: usize = 256;
+// How far the server may be off from the prediction before the inputs after it are simulated again, in blocks
+const TOLERANCE: f32 = 0.01;
+// Remote entities are shown this far in the past, so there's usually a snapshot on both sides of what is shown
+pub const INTERP_DELAY: Duration = Duration::from_millis(100);
+
+fn secs(d: Duration) -> f32 { d.as_secs() as f32 + d.subsec_micros() as f32 / 1_000_000.0 }
+
+// The local player's inputs the server didn't acknowledge yet, and where the client predicted each of them to go.
+// The client moves its player right away instead of waiting for the server to simulate the same inputs
+#[derive(Debug)]
+pub struct Prediction {
+    inputs: VecDeque<(PlayerInput, Vec3<f32>)>,
+    next_seq: u64,
+    acked: u64,
+}
+
+impl Prediction {
+    pub fn new() -> Prediction {
+        Prediction {
+            inputs: VecDeque::new(),
+            next_seq: 1,
+            acked: 0,
+        }
+    }
+
+    // Remember the input of this tick and where it took the player, returns the inputs to send
+    pub fn record(&mut self, ctrl_acc: Vec3<f32>, look_dir: Vec2<f32>, pos: Vec3<f32>) -> Vec<PlayerInput> {
+        let input = PlayerInput {
+            seq: self.next_seq,
+            ctrl_acc,
+            look_dir,
+        };
+        self.next_seq += 1;
+        self.inputs.push_back((input, pos));
+        while self.inputs.len() > MAX_UNACKED {
+            self.inputs.pop_front();
+        }
+
+        let skip = self.inputs.len().saturating_sub(INPUT_REDUNDANCY);
+        self.inputs.iter().skip(skip).map(|(input, _)| *input).collect()
+    }
+
+    pub fn unacked(&self) -> usize { self.inputs.len() }
+
+    // The server simulated the inputs up to `seq` and ended up at `pos` with `vel`. If the client predicted something
+    // else, the inputs after it are simulated again from there with `simulate`. Returns where that took the player
+    pub fn reconcile<F: FnMut(Vec3<f32>, Vec3<f32>, &PlayerInput) -> (Vec3<f32>, Vec3<f32>)>(
+        &mut self,
+        seq: u64,
+        pos: Vec3<f32>,
+        vel: Vec3<f32>,
+        mut simulate: F,
+    ) -> Option<(Vec3<f32>, Vec3<f32>)> {
+        // Nothing new, e.g. the server dropped the inputs after the acknowledged ones
+        if seq <= self.acked {
+            return None;
+        }
+        self.acked = seq;
+
+        let mut predicted = None;
+        while self.inputs.front().map(|(input, _)| input.seq <= seq).unwrap_or(false) {
+            let (input, input_pos) = self.inputs.pop_front().unwrap();
+            if input.seq == seq {
+                predicted = Some(input_pos);
+            }
+        }
+        match predicted {
+            Some(predicted) if (predicted - pos).magnitude() <= TOLERANCE => return None,
+            _ => {},
+        }
+
+        let (mut pos, mut vel) = (pos, vel);
+        for (input, input_pos) in self.inputs.iter_mut() {
+            let (new_pos, new_vel) = simulate(pos, vel, input);
+            pos = new_pos;
+            vel = new_vel;
+            *input_pos = pos;
+        }
+        Some((pos, vel))
+    }
+}
+
+// Where a remote entity was in the snapshots that arrived, it's shown moving between them `INTERP_DELAY` later
+#[derive(Debug)]
+pub struct Interpolation {
+    states: VecDeque<(Instant, EntityState)>,
+}
+
+impl Interpolation {
+    pub fn new() -> Interpolation {
+        Interpolation {
+            states: VecDeque::new(),
+        }
+    }
+
+    // A snapshot with the entity in `state` arrived at `time`
+    pub fn push(&mut self, time: Instant, state: EntityState) {
+        self.states.push_back((time, state));
+        // Everything before the one shown by now isn't needed anymore
+        while self.states.len() > 2 && self.states[1].0 + INTERP_DELAY <= time {
+            self.states.pop_front();
+        }
+    }
+
+    // Position, velocity and look direction to show at `now`. The entity stands still at the newest snapshot if
+    // there's none after it yet
+    pub fn sample(&self, now: Instant) -> Option<(Vec3<f32>, Vec3<f32>, Vec2<f32>)> {
+        let first = self.states.front()?;
+        let last = self.states.back()?;
+        let state = |s: &EntityState| (s.pos(), s.vel(), s.dir());
+
+        if now <= first.0 + INTERP_DELAY {
+            return Some(state(&first.1));
+        }
+        for ((from_time, from), (to_time, to)) in self.states.iter().zip(self.states.iter().skip(1)) {
+            let (from_shown, to_shown) = (*from_time + INTERP_DELAY, *to_time + INTERP_DELAY);
+            if from_shown <= now && now < to_shown {
+                let t = (secs(now - from_shown) / secs(*to_time - *from_time)).min(1.0);
+                return Some((
+                    Lerp::lerp(from.pos(), to.pos(), t),
+                    Lerp::lerp(from.vel(), to.vel(), t),
+                    Lerp::lerp(from.dir(), to.dir(), t),
+                ));
+            }
+        }
+        Some(state(&last.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Moves 1 block along x per input
+    fn walk(pos: Vec3<f32>, vel: Vec3<f32>, input: &PlayerInput) -> (Vec3<f32>, Vec3<f32>) {
+        (pos + input.ctrl_acc, vel)
+    }
+
+    #[test]
+    fn reconcile() {
+        let mut prediction = Prediction::new();
+        let mut pos = Vec3::zero();
+        for _ in 0..10 {
+            pos += Vec3::unit_x();
+            let sent = prediction.record(Vec3::unit_x(), Vec2::unit_y(), pos);
+            assert!(sent.len() <= INPUT_REDUNDANCY);
+        }
+
+        // The server agrees, nothing is simulated again
+        assert_eq!(
+            prediction.reconcile(4, Vec3::new(4.0, 0.0, 0.0), Vec3::zero(), walk),
+            None
+        );
+        assert_eq!(prediction.unacked(), 6);
+
+        assert_eq!(
+            prediction.reconcile(4, Vec3::new(4.0, 5.0, 0.0), Vec3::zero(), walk),
+            None,
+            "an old state is ignored"
+        );
+
+        // The server pushed the player aside, the 5 inputs after it move it on from there
+        assert_eq!(
+            prediction.reconcile(5, Vec3::new(5.0, 5.0, 0.0), Vec3::zero(), walk),
+            Some((Vec3::new(10.0, 5.0, 0.0), Vec3::zero()))
+        );
+        // and the next state agrees with that
+        assert_eq!(
+            prediction.reconcile(7, Vec3::new(7.0, 5.0, 0.0), Vec3::zero(), walk),
+            None
+        );
+    }
+
+    #[test]
+    fn interpolate() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let state = |x| EntityState::new(Vec3::new(x, 0.0, 0.0), Vec3::zero(), Vec2::unit_y());
+
+        let mut interp = Interpolation::new();
+        assert_eq!(interp.sample(start), None);
+        interp.push(at(0), state(0.0));
+        interp.push(at(50), state(10.0));
+
+        let x = |interp: &Interpolation, ms| interp.sample(at(ms) + INTERP_DELAY).unwrap().0.x;
+        assert_eq!(x(&interp, 0), 0.0);
+        assert!((x(&interp, 25) - 5.0).abs() < 0.1);
+        // No newer snapshot yet, it waits there
+        assert_eq!(x(&interp, 80), 10.0);
+    }
+}
@@ -0,0 +1,350 @@
+// Standard
+use std::collections::{HashMap, VecDeque};
+
+// Library
+use serde_derive::{Deserialize, Serialize};
+use vek::*;
+
+// Information
+// -----------
+// Instead of one message per component and entity, the server sends each client a single `Snapshot` per tick with the
+// entities in its view. A snapshot only lists what differs from a baseline, the newest snapshot the client acknowledged,
+// so entities that don't move cost nothing. Values are quantized to fixed point, which keeps a baseline exactly the same
+// on both sides and lets small movements be sent as small deltas. Snapshots may be lost, the next one is still relative
+// to the acknowledged baseline.
+
+// Sequenced streams snapshots and their acks are sent over
+pub const SNAPSHOT_STREAM: u64 = 0;
+pub const SNAPSHOT_ACK_STREAM: u64 = 1;
+
+// How many snapshots a baseline may be behind. Older ones are forgotten, and the server sends everything in full
+pub const HISTORY: u64 = 64;
+
+// Fixed point steps per unit
+const POS_SCALE: f32 = 64.0;
+const VEL_SCALE: f32 = 256.0;
+const DIR_SCALE: f32 = 8192.0;
+
+fn quantize(v: f32, scale: f32) -> i32 { (v * scale).round() as i32 }
+
+fn fits_i16(v: i32) -> bool { v >= i16::min_value() as i32 && v <= i16::max_value() as i32 }
+
+// The quantized state of an entity
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EntityState {
+    pos: Vec3<i32>,
+    vel: Vec3<i32>,
+    dir: Vec2<i16>,
+}
+
+impl EntityState {
+    pub fn new(pos: Vec3<f32>, vel: Vec3<f32>, dir: Vec2<f32>) -> EntityState {
+        let max = i16::max_value() as i32;
+        EntityState {
+            pos: pos.map(|v| quantize(v, POS_SCALE)),
+            vel: vel.map(|v| quantize(v, VEL_SCALE)),
+            dir: dir.map(|v| quantize(v, DIR_SCALE).max(-max).min(max) as i16),
+        }
+    }
+
+    pub fn pos(&self) -> Vec3<f32> { self.pos.map(|v| v as f32 / POS_SCALE) }
+
+    pub fn vel(&self) -> Vec3<f32> { self.vel.map(|v| v as f32 / VEL_SCALE) }
+
+    pub fn dir(&self) -> Vec2<f32> { self.dir.map(|v| v as f32 / DIR_SCALE) }
+}
+
+pub type State = HashMap<u64, EntityState>;
+
+// A component relative to its baseline value. Most changes from one tick to the next fit into the small one
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Delta {
+    Small(Vec3<i16>),
+    Full(Vec3<i32>),
+}
+
+impl Delta {
+    fn new(value: Vec3<i32>, baseline: Option<Vec3<i32>>) -> Option<Delta> {
+        match baseline {
+            Some(b) if b == value => None,
+            Some(b) => {
+                let d = Vec3::new(
+                    value.x.wrapping_sub(b.x),
+                    value.y.wrapping_sub(b.y),
+                    value.z.wrapping_sub(b.z),
+                );
+                if fits_i16(d.x) && fits_i16(d.y) && fits_i16(d.z) {
+                    Some(Delta::Small(d.map(|v| v as i16)))
+                } else {
+                    Some(Delta::Full(value))
+                }
+            },
+            None => Some(Delta::Full(value)),
+        }
+    }
+
+    fn apply(&self, baseline: Option<Vec3<i32>>) -> Option<Vec3<i32>> {
+        match (self, baseline) {
+            (Delta::Full(v), _) => Some(*v),
+            (Delta::Small(d), Some(b)) => Some(Vec3::new(
+                b.x.wrapping_add(d.x as i32),
+                b.y.wrapping_add(d.y as i32),
+                b.z.wrapping_add(d.z as i32),
+            )),
+            (Delta::Small(_), None) => None,
+        }
+    }
+}
+
+// The components of an entity that changed, the others are left out
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EntityDelta {
+    pub uid: u64,
+    pub pos: Option<Delta>,
+    pub vel: Option<Delta>,
+    pub dir: Option<Vec2<i16>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: u64,
+    // the snapshot this one is relative to, None if everything is listed in full
+    pub baseline: Option<u64>,
+    pub changed: Vec<EntityDelta>,
+    // entities of the baseline that aren't in this snapshot anymore
+    pub removed: Vec<u64>,
+}
+
+impl Snapshot {
+    pub fn diff(id: u64, baseline: Option<(u64, &State)>, state: &State) -> Snapshot {
+        let empty = State::new();
+        let base = baseline.map(|(_, b)| b).unwrap_or(&empty);
+
+        let changed = state
+            .iter()
+            .filter_map(|(uid, new)| {
+                let old = base.get(uid);
+                let delta = EntityDelta {
+                    uid: *uid,
+                    pos: Delta::new(new.pos, old.map(|o| o.pos)),
+                    vel: Delta::new(new.vel, old.map(|o| o.vel)),
+                    dir: if old.map(|o| o.dir) == Some(new.dir) {
+                        None
+                    } else {
+                        Some(new.dir)
+                    },
+                };
+                if delta.pos.is_none() && delta.vel.is_none() && delta.dir.is_none() {
+                    None
+                } else {
+                    Some(delta)
+                }
+            })
+            .collect();
+        let removed = base.keys().filter(|uid| !state.contains_key(uid)).cloned().collect();
+
+        Snapshot {
+            id,
+            baseline: baseline.map(|(id, _)| id),
+            changed,
+            removed,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.changed.is_empty() && self.removed.is_empty() }
+
+    // The state this snapshot describes, None if the baseline it needs is missing
+    pub fn apply(&self, baseline: Option<&State>) -> Option<State> {
+        let mut state = match (self.baseline, baseline) {
+            (None, _) => State::new(),
+            (Some(_), Some(b)) => b.clone(),
+            (Some(_), None) => return None,
+        };
+        for uid in &self.removed {
+            state.remove(uid);
+        }
+        for delta in &self.changed {
+            let old = state.get(&delta.uid).cloned();
+            let new = EntityState {
+                pos: match delta.pos {
+                    Some(d) => d.apply(old.map(|o| o.pos))?,
+                    None => old?.pos,
+                },
+                vel: match delta.vel {
+                    Some(d) => d.apply(old.map(|o| o.vel))?,
+                    None => old?.vel,
+                },
+                dir: match delta.dir {
+                    Some(d) => d,
+                    None => old?.dir,
+                },
+            };
+            state.insert(delta.uid, new);
+        }
+        Some(state)
+    }
+}
+
+// The snapshots the server sent to one client
+#[derive(Debug, Default)]
+pub struct SnapshotSender {
+    next_id: u64,
+    // sent but not acknowledged yet, oldest first
+    sent: VecDeque<(u64, State)>,
+    acked: Option<(u64, State)>,
+}
+
+impl SnapshotSender {
+    pub fn new() -> SnapshotSender { SnapshotSender::default() }
+
+    // The snapshot to send for this tick, None if the client is up to date already
+    pub fn snapshot(&mut self, state: State) -> Option<Snapshot> {
+        let id = self.next_id;
+        let baseline = self
+            .acked
+            .as_ref()
+            .filter(|(acked, _)| id - acked <= HISTORY)
+            .map(|(acked, b)| (*acked, b));
+        let snapshot = Snapshot::diff(id, baseline, &state);
+        if snapshot.is_empty() && self.sent.is_empty() {
+            return None;
+        }
+
+        self.next_id += 1;
+        self.sent.push_back((id, state));
+        while self.sent.len() as u64 > HISTORY {
+            self.sent.pop_front();
+        }
+        Some(snapshot)
+    }
+
+    pub fn ack(&mut self, id: u64) {
+        if let Some(i) = self.sent.iter().position(|(sent, _)| *sent == id) {
+            self.acked = self.sent.drain(..i + 1).last();
+        }
+    }
+}
+
+// The snapshots a client applied
+#[derive(Debug, Default)]
+pub struct SnapshotReceiver {
+    // oldest first
+    applied: VecDeque<(u64, State)>,
+}
+
+impl SnapshotReceiver {
+    pub fn new() -> SnapshotReceiver { SnapshotReceiver::default() }
+
+    // Applies a snapshot and returns the state it describes, None if it's outdated or its baseline was forgotten. The
+    // snapshot should be acknowledged if it was applied
+    pub fn receive(&mut self, snapshot: &Snapshot) -> Option<&State> {
+        if let Some((newest, _)) = self.applied.back() {
+            if snapshot.id <= *newest {
+                return None;
+            }
+        }
+        let state = match snapshot.baseline {
+            Some(baseline) => {
+                let baseline = self.applied.iter().find(|(applied, _)| *applied == baseline)?;
+                snapshot.apply(Some(&baseline.1))?
+            },
+            None => snapshot.apply(None)?,
+        };
+
+        self.applied.push_back((snapshot.id, state));
+        while self.applied.front().map(|(oldest, _)| snapshot.id - oldest > HISTORY) == Some(true) {
+            self.applied.pop_front();
+        }
+        self.applied.back().map(|(_, state)| state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntityState, SnapshotReceiver, SnapshotSender, State};
+    use vek::*;
+
+    fn state(entities: &[(u64, f32)]) -> State {
+        entities
+            .iter()
+            .map(|(uid, x)| {
+                (
+                    *uid,
+                    EntityState::new(Vec3::new(*x, 2.0, 3.0), Vec3::new(1.0, 0.0, 0.0), Vec2::unit_y()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_quantize() {
+        let s = EntityState::new(
+            Vec3::new(100.01, -5.5, 0.3),
+            Vec3::new(0.25, -9.81, 0.0),
+            Vec2::new(0.6, -0.8),
+        );
+        assert!((s.pos() - Vec3::new(100.01, -5.5, 0.3)).magnitude() < 0.02);
+        assert!((s.vel() - Vec3::new(0.25, -9.81, 0.0)).magnitude() < 0.005);
+        assert!((s.dir() - Vec2::new(0.6, -0.8)).magnitude() < 0.001);
+    }
+
+    #[test]
+    fn test_snapshot_delta() {
+        let mut sender = SnapshotSender::new();
+        let mut receiver = SnapshotReceiver::new();
+
+        // Nothing to tell
+        assert_eq!(sender.snapshot(State::new()), None);
+
+        // The first one lists everything
+        let s0 = state(&[(1, 0.0), (2, 10.0)]);
+        let snapshot = sender.snapshot(s0.clone()).unwrap();
+        assert_eq!(snapshot.baseline, None);
+        assert_eq!(snapshot.changed.len(), 2);
+        assert_eq!(receiver.receive(&snapshot), Some(&s0));
+        sender.ack(snapshot.id);
+
+        // Only what changed since the acked one
+        let s1 = state(&[(1, 0.5), (2, 10.0), (3, 1000.0)]);
+        let lost = sender.snapshot(s1.clone()).unwrap();
+        assert_eq!(lost.baseline, Some(0));
+        assert_eq!(lost.changed.len(), 2);
+
+        // Still relative to the acked one, so losing the previous one doesn't matter
+        let s2 = state(&[(1, 1.0), (3, 1000.0)]);
+        let snapshot = sender.snapshot(s2.clone()).unwrap();
+        assert_eq!(snapshot.baseline, Some(0));
+        assert_eq!(snapshot.removed, vec![2]);
+        assert_eq!(receiver.receive(&snapshot), Some(&s2));
+        sender.ack(snapshot.id);
+
+        // Outdated
+        assert_eq!(receiver.receive(&lost), None);
+
+        // Up to date, until something changes
+        assert_eq!(sender.snapshot(s2.clone()), None);
+        let snapshot = sender.snapshot(state(&[(1, 1.0)])).unwrap();
+        assert_eq!(snapshot.baseline, Some(2));
+        assert!(snapshot.changed.is_empty());
+        assert_eq!(snapshot.removed, vec![3]);
+    }
+
+    #[test]
+    fn test_snapshot_history() {
+        let mut sender = SnapshotSender::new();
+        let mut receiver = SnapshotReceiver::new();
+        let snapshot = sender.snapshot(state(&[(1, 0.0)])).unwrap();
+        receiver.receive(&snapshot).unwrap();
+        sender.ack(snapshot.id);
+
+        // No acks for a long time, the baseline gets too old and everything is sent in full again
+        for i in 0..super::HISTORY + 1 {
+            let snapshot = sender.snapshot(state(&[(1, i as f32 + 1.0)])).unwrap();
+            assert_eq!(snapshot.baseline.is_some(), i < super::HISTORY);
+        }
+        let s = state(&[(1, -1.0)]);
+        let snapshot = sender.snapshot(s.clone()).unwrap();
+        assert_eq!(snapshot.baseline, None);
+        assert_eq!(receiver.receive(&snapshot), Some(&s));
+    }
+}
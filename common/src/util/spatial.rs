@@ -0,0 +1,90 @@
+// Standard
+use std::collections::HashMap;
+
+// Library
+use vek::*;
+
+// Information
+// -----------
+// Buckets items by their horizontal position into square cells. Finding everything within some distance of a point
+// only looks at the cells that distance overlaps rather than at every item, so a query costs about as much as there
+// are items nearby. Cells are best about as large as the distances queried for.
+
+#[derive(Debug)]
+pub struct SpatialGrid<T> {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<(Vec3<f32>, T)>>,
+    len: usize,
+}
+
+impl<T> SpatialGrid<T> {
+    pub fn new(cell_size: f32) -> SpatialGrid<T> {
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    fn cell(&self, x: f32, y: f32) -> (i32, i32) {
+        ((x / self.cell_size).floor() as i32, (y / self.cell_size).floor() as i32)
+    }
+
+    pub fn insert(&mut self, pos: Vec3<f32>, item: T) {
+        let cell = self.cell(pos.x, pos.y);
+        self.cells.entry(cell).or_insert_with(Vec::new).push((pos, item));
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.len = 0;
+    }
+
+    // Everything at most `radius` away from `center`, along with where it is
+    pub fn within<'a>(&'a self, center: Vec3<f32>, radius: f32) -> impl Iterator<Item = (Vec3<f32>, &'a T)> + 'a {
+        let (min_x, min_y) = self.cell(center.x - radius, center.y - radius);
+        let (max_x, max_y) = self.cell(center.x + radius, center.y + radius);
+        (min_x..max_x + 1)
+            .flat_map(move |x| (min_y..max_y + 1).map(move |y| (x, y)))
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flat_map(|items| items.iter())
+            .filter(move |(pos, _)| (*pos - center).magnitude_squared() <= radius * radius)
+            .map(|(pos, item)| (*pos, item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpatialGrid;
+    use vek::*;
+
+    fn near(grid: &SpatialGrid<i32>, center: Vec3<f32>, radius: f32) -> Vec<i32> {
+        let mut items = grid.within(center, radius).map(|(_, i)| *i).collect::<Vec<_>>();
+        items.sort();
+        items
+    }
+
+    #[test]
+    fn test_spatial_grid() {
+        let mut grid = SpatialGrid::new(16.0);
+        grid.insert(Vec3::new(0.0, 0.0, 0.0), 0);
+        grid.insert(Vec3::new(10.0, 0.0, 0.0), 1);
+        grid.insert(Vec3::new(-20.0, -20.0, 0.0), 2);
+        grid.insert(Vec3::new(0.0, 0.0, 50.0), 3);
+        grid.insert(Vec3::new(1000.0, 1000.0, 0.0), 4);
+        assert_eq!(grid.len(), 5);
+
+        assert_eq!(near(&grid, Vec3::zero(), 12.0), vec![0, 1]);
+        assert_eq!(near(&grid, Vec3::zero(), 30.0), vec![0, 1, 2]);
+        assert_eq!(near(&grid, Vec3::zero(), 60.0), vec![0, 1, 2, 3]);
+        assert_eq!(near(&grid, Vec3::new(995.0, 1000.0, 0.0), 5.0), vec![4]);
+        assert_eq!(near(&grid, Vec3::new(500.0, 500.0, 0.0), 100.0), Vec::<i32>::new());
+
+        grid.clear();
+        assert_eq!(grid.len(), 0);
+        assert_eq!(near(&grid, Vec3::zero(), 2000.0), Vec::<i32>::new());
+    }
+}
@@ -1,4 +1,7 @@
 // Standard
+use std::{env, fs, path::PathBuf, process};
+
+// Library
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 
@@ -23,3 +26,18 @@ impl TestPorts {
 lazy_static! {
     pub static ref PORTS: TestPorts = TestPorts::new();
 }
+
+// A data directory of its own for every test, removed again once the test is done
+pub struct DataDir(pub PathBuf);
+
+impl DataDir {
+    pub fn new(name: &str) -> DataDir {
+        let dir = env::temp_dir().join(format!("test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        DataDir(dir)
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+}
@@ -3,15 +3,21 @@
 extern crate test;
 
 // Standard
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
 // Library
+use parking_lot::Mutex;
 use test::Bencher;
 use vek::*;
 
 // Project
 use common::terrain::{
-    chunk::{Block, BlockMat, BlockRle, Chunk, HeterogeneousData, RleData},
-    ConstructVolume, PersState, ReadWriteVolume, VolCluster, Voxel,
+    chunk::{Block, BlockRle, Chunk, ChunkContainer, HeterogeneousData, RleData},
+    ChunkMgr, ConstructVolume, Container, PersState, ReadWriteVolume, VolCluster, VolGen, VolOffs,
 };
 
 /* Reference Chunk
@@ -210,6 +216,78 @@ fn read_rle() {
     assert_eq!(access.at(Vec3::new(0, 3, 3)), Some(Block::AIR));
 }
 
+fn gen_nothing(_pos: Vec3<VolOffs>, _con: Arc<Mutex<Option<ChunkContainer<()>>>>) {}
+
+fn drop_nothing(_pos: Vec3<VolOffs>, _con: Arc<ChunkContainer<()>>) {}
+
+// Waits for the chunk manager's thread pool
+fn wait_for<F: Fn() -> bool>(f: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    f()
+}
+
+#[test]
+fn fill_pending_chunk() {
+    // Like a client, which gets its chunks from the server
+    let chunk_mgr = ChunkMgr::new(
+        Vec3::new(4, 4, 4),
+        VolGen::new(gen_nothing, gen_nothing, drop_nothing, drop_nothing),
+    );
+    let pos = Vec3::new(1, 2, 0);
+    assert!(!chunk_mgr.fill(pos, Chunk::Rle(gen_rle())));
+
+    chunk_mgr.gen(pos);
+    assert!(wait_for(|| chunk_mgr.unfilled() == vec![pos]));
+    chunk_mgr.maintain();
+    assert!(!chunk_mgr.exists_chunk(pos));
+
+    let mut chunk = Chunk::Hetero(gen_hetero());
+    let bytes = chunk.to_bytes().unwrap();
+    assert!(chunk_mgr.fill(pos, Chunk::from_bytes(&bytes).unwrap()));
+    assert!(chunk_mgr.unfilled().is_empty());
+    assert!(wait_for(|| {
+        chunk_mgr.maintain();
+        chunk_mgr.exists_chunk(pos)
+    }));
+}
+
+fn gen_unit_payload(_pos: Vec3<VolOffs>, con: Arc<Mutex<Option<ChunkContainer<()>>>>) {
+    if let Some(ref con) = *con.lock() {
+        *con.payload_mut() = Some(());
+    }
+}
+
+#[test]
+fn set_block() {
+    let chunk_mgr = ChunkMgr::new(
+        Vec3::new(4, 4, 4),
+        VolGen::new(gen_nothing, gen_unit_payload, drop_nothing, drop_nothing),
+    );
+    let pos = Vec3::new(1, 2, 0);
+    let block = Vec3::new(5, 10, 0);
+    assert_eq!(chunk_mgr.set_block(block, Block::GRASS), None);
+
+    chunk_mgr.gen(pos);
+    assert!(wait_for(|| chunk_mgr.unfilled() == vec![pos]));
+    assert!(chunk_mgr.fill(pos, Chunk::Rle(gen_rle())));
+    assert!(wait_for(|| {
+        chunk_mgr.maintain();
+        chunk_mgr.exists_chunk(pos)
+    }));
+    let con = chunk_mgr.pers(|p| *p == pos).remove(&pos).unwrap();
+    assert!(wait_for(|| con.payload().is_some()));
+
+    // The run length encoded blocks are stored as raw ones from now on
+    assert_eq!(chunk_mgr.set_block(block, Block::GRASS), Some(Block::EARTH));
+    assert!(con.data().contains(PersState::Hetero));
+    assert!(!con.data().contains(PersState::Rle));
+    assert_eq!(chunk_mgr.get_block(block), Some(Block::GRASS));
+    assert!(con.payload().is_none());
+}
+
 #[bench]
 fn raw_to_rle_speed(b: &mut Bencher) {
     b.iter(|| {
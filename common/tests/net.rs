@@ -1,12 +1,17 @@
 // Standard
-use std::{net::TcpListener, sync::Arc, thread, time::Duration};
+use std::{
+    net::TcpListener,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
 
 // Library
 use serde_derive::{Deserialize, Serialize};
 
 // Project
 use common::{
-    net::Message,
+    net::{Identity, Listener, MemoryListener, Message, NetProfile, PolledListener, Reactor, SimulatedListener},
     util::{
         manager::Manager,
         post::{Incoming, PostBox, PostOffice},
@@ -23,6 +28,7 @@ impl Message for ClientMsg {}
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 enum ServerMsg {
     Pong,
+    Tick(u64),
 }
 impl Message for ServerMsg {}
 
@@ -40,7 +46,7 @@ fn post_office() {
     let listener = TcpListener::bind(&server_addr).unwrap();
     thread::spawn(move || match listener.incoming().next() {
         Some(Ok(stream)) => {
-            thread::spawn(move || handle_client(PostOffice::to_client(stream).unwrap()));
+            thread::spawn(move || handle_client(PostOffice::to_client(stream, Identity::generate()).unwrap()));
         },
         Some(Err(e)) => panic!("Connection error: {}", e),
         None => panic!("No client received"),
@@ -89,3 +95,232 @@ fn handle_remote(po: Manager<PostOffice<SessionKind, ClientMsg, ServerMsg>>) {
         assert_eq!(ServerMsg::Pong, msg);
     }
 }
+
+#[test]
+fn post_office_sequenced() {
+    // Server, its side of the link loses and reorders datagrams
+    let server_addr = PORTS.next();
+    let profile: NetProfile = "latency=5,jitter=20,loss=0.2".parse().unwrap();
+    let listener = SimulatedListener::new(Arc::new(TcpListener::bind(&server_addr).unwrap()), profile);
+    thread::spawn(move || {
+        let po: Manager<PostOffice<SessionKind, ServerMsg, ClientMsg>> =
+            PostOffice::to_client_with(listener.accept().unwrap(), Identity::generate()).unwrap();
+        let await_ping = || match po.await_incoming() {
+            Ok(Incoming::Msg(ClientMsg::Ping)) => {},
+            _ => panic!("expected a ping"),
+        };
+        // letters arrive in order, so both sides have udp open once a ping made the round trip
+        await_ping();
+        let _ = po.send_one(ServerMsg::Pong);
+        await_ping();
+        for tick in 0..200 {
+            let _ = po.send_one_sequenced(ServerMsg::Tick(tick), 0);
+            thread::sleep(Duration::from_millis(1));
+        }
+        // the newest state is sent again until it got through, like positions are every tick
+        for _ in 0..8 {
+            let _ = po.send_one_sequenced(ServerMsg::Tick(1000), 0);
+            thread::sleep(Duration::from_millis(30));
+        }
+        let _ = po.send_one(ServerMsg::Pong);
+        thread::sleep(Duration::from_millis(500));
+    });
+
+    // Client
+    let po: Manager<PostOffice<SessionKind, ClientMsg, ServerMsg>> = PostOffice::to_server(&server_addr).unwrap();
+    po.exchange_keys(Duration::from_secs(5)).unwrap();
+    po.open_udp().unwrap();
+    let _ = po.send_one(ClientMsg::Ping);
+    match po.await_incoming() {
+        Ok(Incoming::Msg(ServerMsg::Pong)) => {},
+        _ => panic!("expected a pong"),
+    }
+    let _ = po.send_one(ClientMsg::Ping);
+    let mut ticks = vec![];
+    loop {
+        match po.await_incoming() {
+            Ok(Incoming::Msg(ServerMsg::Tick(tick))) => ticks.push(tick),
+            Ok(Incoming::Msg(ServerMsg::Pong)) => break,
+            _ => panic!("unexpected incoming"),
+        }
+    }
+    ticks.dedup();
+    // lost and stale datagrams are gone, what's left is in order and ends with the newest state
+    assert!(ticks.windows(2).all(|w| w[0] < w[1]));
+    assert!(ticks.len() > 50 && ticks.len() < 200);
+    assert_eq!(ticks.last(), Some(&1000));
+    assert!(po.stats().frames_stale > 0);
+}
+
+#[test]
+fn post_office_encrypted() {
+    // Server
+    let server_addr = PORTS.next();
+    let listener = TcpListener::bind(&server_addr).unwrap();
+    let identity = Identity::generate();
+    let public = identity.public();
+    thread::spawn(move || match listener.incoming().next() {
+        Some(Ok(stream)) => {
+            let po = PostOffice::to_client(stream, identity).unwrap();
+            // Key exchanges are answered without any help
+            handle_client(po);
+        },
+        Some(Err(e)) => panic!("Connection error: {}", e),
+        None => panic!("No client received"),
+    });
+
+    // Client
+    let po: Manager<PostOffice<SessionKind, ClientMsg, ServerMsg>> = PostOffice::to_server(&server_addr).unwrap();
+    assert!(!po.is_encrypted());
+    assert_eq!(po.exchange_keys(Duration::from_secs(5)).unwrap(), public);
+    assert!(po.is_encrypted());
+
+    let pb = po.create_postbox(SessionKind::PingPong);
+    for _ in 0..10 {
+        let _ = pb.send(ClientMsg::Ping);
+        assert_eq!(ServerMsg::Pong, pb.recv().unwrap());
+    }
+}
+
+#[test]
+fn post_office_memory() {
+    // Server
+    let listener = MemoryListener::new();
+    let server_listener = listener.clone();
+    thread::spawn(move || {
+        while let Ok(transport) = server_listener.accept() {
+            let po = PostOffice::to_client_with(transport, Identity::generate()).unwrap();
+            thread::spawn(move || handle_client(po));
+        }
+    });
+
+    // Clients, all within this process and without any socket
+    let clients: Vec<_> = (0..20)
+        .map(|_| {
+            let transport = listener.connect().unwrap();
+            thread::spawn(move || {
+                let po: Manager<PostOffice<SessionKind, ClientMsg, ServerMsg>> =
+                    PostOffice::to_server_with(Box::new(transport)).unwrap();
+                po.exchange_keys(Duration::from_secs(5)).unwrap();
+                let pb = po.create_postbox(SessionKind::PingPong);
+                for _ in 0..10 {
+                    let _ = pb.send(ClientMsg::Ping);
+                    assert_eq!(ServerMsg::Pong, pb.recv().unwrap());
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+#[test]
+fn listener_stop() {
+    // Stopping wakes an accept that's blocked already, even on a listener bound to every interface
+    let listener = Arc::new(TcpListener::bind("0.0.0.0:0").unwrap());
+    let accepting = listener.clone();
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        while Listener::accept(&*accepting).is_ok() {}
+        let _ = send.send(());
+    });
+    thread::sleep(Duration::from_millis(100));
+    listener.stop().unwrap();
+    recv.recv_timeout(Duration::from_secs(5))
+        .expect("accept still blocks after stopping");
+}
+
+#[test]
+fn post_office_graceful_close() {
+    // Server
+    let server_addr = PORTS.next();
+    let listener = TcpListener::bind(&server_addr).unwrap();
+    thread::spawn(move || {
+        let stream = listener.incoming().next().unwrap().unwrap();
+        let po: Manager<PostOffice<SessionKind, ServerMsg, ClientMsg>> =
+            PostOffice::to_client(stream, Identity::generate()).unwrap();
+        for _ in 0..1000 {
+            let _ = po.send_one(ServerMsg::Pong);
+        }
+        // Dropping stops the post office, everything queued is still delivered
+    });
+
+    // Client
+    let po: Manager<PostOffice<SessionKind, ClientMsg, ServerMsg>> = PostOffice::to_server(&server_addr).unwrap();
+    let mut pongs = 0;
+    loop {
+        match po.await_incoming() {
+            Ok(Incoming::Msg(ServerMsg::Pong)) => pongs += 1,
+            Ok(Incoming::End) => break,
+            _ => panic!("unexpected incoming"),
+        }
+    }
+    assert_eq!(pongs, 1000);
+}
+
+#[test]
+fn post_office_load() {
+    const CLIENTS: usize = 200;
+    const ROUNDS: usize = 5;
+
+    // Server, every connection is polled by the reactor and all of them are answered by a single thread
+    let reactor = Reactor::new().unwrap();
+    let listener = PolledListener::bind("127.0.0.1:0", reactor.clone()).unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        let mut postoffices = vec![];
+        while let Ok(transport) = listener.accept() {
+            let po: Arc<Manager<PostOffice<SessionKind, ServerMsg, ClientMsg>>> =
+                Arc::new(PostOffice::to_client_with(transport, Identity::generate()).unwrap());
+            let send = send.clone();
+            let po_ref = Arc::downgrade(&po);
+            po.forward_incoming(move |incoming| {
+                if let Some(po) = po_ref.upgrade() {
+                    let _ = send.send((po, incoming));
+                }
+            });
+            postoffices.push(po);
+        }
+    });
+    thread::spawn(move || {
+        while let Ok((po, incoming)) = recv.recv() {
+            if let Incoming::Msg(ClientMsg::Ping) = incoming {
+                let _ = po.send_one(ServerMsg::Pong);
+            }
+        }
+    });
+
+    // Clients, sharing the reactor instead of running threads of their own
+    let clients: Vec<Manager<PostOffice<SessionKind, ClientMsg, ServerMsg>>> = (0..CLIENTS)
+        .map(|_| PostOffice::to_server_with(Box::new(Reactor::connect(&reactor, &server_addr).unwrap())).unwrap())
+        .collect();
+    for _ in 0..ROUNDS {
+        for po in &clients {
+            po.send_one(ClientMsg::Ping).unwrap();
+        }
+        for po in &clients {
+            match po.await_incoming() {
+                Ok(Incoming::Msg(msg)) => assert_eq!(msg, ServerMsg::Pong),
+                _ => panic!("unexpected incoming"),
+            }
+        }
+    }
+}
+
+#[test]
+fn polled_listener_stop() {
+    // Stopping the listener the reactor accepts from wakes an accept that's blocked already too
+    let listener = PolledListener::bind("0.0.0.0:0", Reactor::new().unwrap()).unwrap();
+    let accepting = listener.clone();
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        while accepting.accept().is_ok() {}
+        let _ = send.send(());
+    });
+    thread::sleep(Duration::from_millis(100));
+    listener.stop().unwrap();
+    recv.recv_timeout(Duration::from_secs(5))
+        .expect("accept still blocks after stopping");
+}
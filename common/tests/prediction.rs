@@ -0,0 +1,198 @@
+// Standard
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// Library
+use parking_lot::Mutex;
+use vek::*;
+
+// Project
+use common::{
+    net::{Identity, Listener, MemoryListener, NetProfile, SimulatedListener, Simulator},
+    util::{
+        manager::Manager,
+        msg::{ClientMsg, ClientPostOffice, PlayerInput, ServerMsg, ServerPostOffice, PLAYER_STREAM},
+        post::{Incoming, PostOffice},
+        prediction::{Interpolation, Prediction},
+        snapshot::{EntityState, SnapshotReceiver, SnapshotSender, SNAPSHOT_STREAM},
+    },
+};
+
+const TICKS: u64 = 60;
+const TICK: Duration = Duration::from_millis(10);
+// The server pushes the player aside while simulating this input, which the client can't predict
+const PUSH_AT: u64 = 20;
+
+fn profile() -> NetProfile {
+    NetProfile {
+        latency: Duration::from_millis(50),
+        jitter: Duration::from_millis(10),
+        loss: 0.0,
+        bandwidth: None,
+    }
+}
+
+// Client and server within this process, with the latency of `profile` both ways
+fn connect() -> (Manager<ClientPostOffice>, Manager<ServerPostOffice>) {
+    let listener = MemoryListener::new();
+    let client = Simulator::reliable(Box::new(listener.connect().unwrap()), profile());
+    let server = SimulatedListener::new(listener, profile()).accept().unwrap();
+    (
+        PostOffice::to_server_with(Box::new(client)).unwrap(),
+        PostOffice::to_client_with(server, Identity::generate()).unwrap(),
+    )
+}
+
+// Everything the client receives, without blocking its ticks
+fn receive(po: &Manager<ClientPostOffice>) -> mpsc::Receiver<ServerMsg> {
+    let (send, recv) = mpsc::channel();
+    let send = Mutex::new(send);
+    po.forward_incoming(move |incoming| {
+        if let Incoming::Msg(msg) = incoming {
+            let _ = send.lock().send(msg);
+        }
+    });
+    recv
+}
+
+// Moves 1 block along x per input
+fn walk(pos: Vec3<f32>, vel: Vec3<f32>, input: &PlayerInput) -> (Vec3<f32>, Vec3<f32>) { (pos + input.ctrl_acc, vel) }
+
+fn serve_inputs(po: Manager<ServerPostOffice>) {
+    let (mut pos, mut vel, mut last_seq) = (Vec3::zero(), Vec3::zero(), 0);
+    while let Ok(incoming) = po.await_incoming() {
+        match incoming {
+            Incoming::Msg(ClientMsg::PlayerInputs { inputs }) => {
+                let simulated = last_seq;
+                for input in inputs.iter().filter(|input| input.seq > simulated) {
+                    let (new_pos, new_vel) = walk(pos, vel, input);
+                    pos = new_pos;
+                    vel = new_vel;
+                    last_seq = input.seq;
+                    if input.seq == PUSH_AT {
+                        pos.y += 5.0;
+                    }
+                }
+                let _ = po.send_one_sequenced(
+                    ServerMsg::PlayerState {
+                        seq: last_seq,
+                        pos,
+                        vel,
+                    },
+                    PLAYER_STREAM,
+                );
+            },
+            Incoming::End => break,
+            _ => {},
+        }
+    }
+}
+
+#[test]
+fn predict_with_latency() {
+    let (po, server) = connect();
+    thread::spawn(move || serve_inputs(server));
+    let recv = receive(&po);
+
+    let mut prediction = Prediction::new();
+    let (mut pos, mut vel) = (Vec3::zero(), Vec3::zero());
+    let mut replays = vec![];
+    let mut reconcile = |prediction: &mut Prediction, pos: &mut Vec3<f32>, vel: &mut Vec3<f32>| {
+        while let Ok(msg) = recv.try_recv() {
+            if let ServerMsg::PlayerState {
+                seq,
+                pos: s_pos,
+                vel: s_vel,
+            } = msg
+            {
+                if let Some((new_pos, new_vel)) = prediction.reconcile(seq, s_pos, s_vel, walk) {
+                    *pos = new_pos;
+                    *vel = new_vel;
+                    replays.push(seq);
+                }
+            }
+        }
+    };
+
+    let ctrl_acc = Vec3::unit_x();
+    for tick in 1..=TICKS {
+        let input = PlayerInput {
+            seq: tick,
+            ctrl_acc,
+            look_dir: Vec2::unit_y(),
+        };
+        let (new_pos, new_vel) = walk(pos, vel, &input);
+        pos = new_pos;
+        vel = new_vel;
+        let inputs = prediction.record(ctrl_acc, Vec2::unit_y(), pos);
+        let _ = po.send_one_sequenced(ClientMsg::PlayerInputs { inputs }, PLAYER_STREAM);
+
+        thread::sleep(TICK);
+        reconcile(&mut prediction, &mut pos, &mut vel);
+
+        // The player never waits for the server, nor is it pulled back to where the server was a round trip ago
+        assert_eq!(pos.x, tick as f32);
+        assert!(prediction.unacked() < TICKS as usize);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while prediction.unacked() > 0 && Instant::now() < deadline {
+        thread::sleep(TICK);
+        reconcile(&mut prediction, &mut pos, &mut vel);
+    }
+    assert_eq!(prediction.unacked(), 0);
+
+    // Only the push made the client simulate again, and it ended up where the server did
+    assert!(!replays.is_empty());
+    assert!(replays.iter().all(|seq| *seq >= PUSH_AT));
+    assert_eq!(pos, Vec3::new(TICKS as f32, 5.0, 0.0));
+}
+
+#[test]
+fn interpolate_with_latency() {
+    let (po, server) = connect();
+    let recv = receive(&po);
+
+    // A remote entity walks half a block along x per tick
+    const UID: u64 = 7;
+    let x = |tick: u64| tick as f32 * 0.5;
+    thread::spawn(move || {
+        let mut snapshots = SnapshotSender::new();
+        for tick in 0..TICKS {
+            let state = EntityState::new(Vec3::new(x(tick), 0.0, 0.0), Vec3::unit_x() * 50.0, Vec2::unit_y());
+            if let Some(snapshot) = snapshots.snapshot(vec![(UID, state)].into_iter().collect()) {
+                let _ = server.send_one_sequenced(ServerMsg::Snapshot(snapshot), SNAPSHOT_STREAM);
+            }
+            thread::sleep(TICK);
+        }
+        // Keep the connection up until the client saw everything
+        thread::sleep(Duration::from_secs(1));
+    });
+
+    let mut snapshots = SnapshotReceiver::new();
+    let mut interpolation = Interpolation::new();
+    let mut shown = vec![];
+    let end = Instant::now() + TICK * TICKS as u32 + Duration::from_millis(500);
+    while Instant::now() < end {
+        while let Ok(msg) = recv.try_recv() {
+            if let ServerMsg::Snapshot(snapshot) = msg {
+                if let Some(state) = snapshots.receive(&snapshot) {
+                    interpolation.push(Instant::now(), state[&UID]);
+                }
+            }
+        }
+        if let Some((pos, _, _)) = interpolation.sample(Instant::now()) {
+            shown.push(pos.x);
+        }
+        thread::sleep(TICK / 4);
+    }
+
+    // The entity moves smoothly, also between the snapshots, and never backwards
+    assert!(shown.windows(2).all(|w| w[0] <= w[1]));
+    assert!(shown.windows(2).all(|w| w[1] - w[0] <= x(4)));
+    assert!(shown.iter().any(|x| (x * 2.0).fract() != 0.0));
+    assert_eq!(shown.last(), Some(&x(TICKS - 1)));
+}
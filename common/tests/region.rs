@@ -0,0 +1,84 @@
+// Standard
+use std::{env, fs, path::PathBuf, process};
+
+// Library
+use vek::*;
+
+// Project
+use common::terrain::{
+    chunk::{Block, Chunk, HomogeneousData},
+    ConstructVolume, PersState, RegionError, RegionStore, VolCluster, VolOffs, REGION_SIZE,
+};
+
+// A directory of its own for every test
+fn world_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("region-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn chunk(block: Block) -> Chunk { Chunk::Homo(HomogeneousData::filled(Vec3::new(4, 4, 4), block)) }
+
+fn block_of(chunk: &mut Chunk) -> Option<Block> {
+    chunk.convert(PersState::Hetero);
+    chunk.get(PersState::Hetero)?.at(Vec3::new(1, 2, 3))
+}
+
+#[test]
+fn save_and_load() {
+    let dir = world_dir("save_and_load");
+    let (a, b) = (Vec3::new(0, 0, 0), Vec3::new(-1, 3, REGION_SIZE + 2));
+
+    let store = RegionStore::new(&dir);
+    assert!(store.load(a).unwrap().is_none());
+    store.save(a, &mut chunk(Block::STONE)).unwrap();
+    store.save(b, &mut chunk(Block::SAND)).unwrap();
+    store.flush().unwrap();
+    // Overwriting a chunk keeps the others of its region
+    store.save(a, &mut chunk(Block::GRASS)).unwrap();
+    store.flush().unwrap();
+
+    let store = RegionStore::new(&dir);
+    assert_eq!(block_of(&mut store.load(a).unwrap().unwrap()), Some(Block::GRASS));
+    assert_eq!(block_of(&mut store.load(b).unwrap().unwrap()), Some(Block::SAND));
+    assert!(store.load(Vec3::new(1, 0, 0)).unwrap().is_none());
+
+    // Nothing is left half written
+    assert!(fs::read_dir(&dir)
+        .unwrap()
+        .all(|entry| !entry.unwrap().path().to_string_lossy().ends_with(".tmp")));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn damaged_chunks() {
+    let dir = world_dir("damaged_chunks");
+    let (a, b): (Vec3<VolOffs>, Vec3<VolOffs>) = (Vec3::new(0, 0, 0), Vec3::new(0, 0, 1));
+
+    let store = RegionStore::new(&dir);
+    store.save(a, &mut chunk(Block::STONE)).unwrap();
+    store.save(b, &mut chunk(Block::SAND)).unwrap();
+    store.flush().unwrap();
+
+    // The chunks are stored in order after the header, flip a byte of the last one
+    let path = dir.join("r.0.0.0.region");
+    let mut bytes = fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    fs::write(&path, &bytes).unwrap();
+
+    let store = RegionStore::new(&dir);
+    assert_eq!(block_of(&mut store.load(a).unwrap().unwrap()), Some(Block::STONE));
+    assert!(store.load(b).unwrap().is_none());
+
+    // Files of other versions are refused, not overwritten
+    bytes[4] = 0xFF;
+    fs::write(&path, &bytes).unwrap();
+    let store = RegionStore::new(&dir);
+    match store.load(a) {
+        Err(RegionError::UnsupportedVersion(_)) => {},
+        other => panic!("expected an unsupported version, got {:?}", other.map(|c| c.is_some())),
+    }
+    assert!(store.save(a, &mut chunk(Block::GRASS)).is_err());
+    let _ = fs::remove_dir_all(&dir);
+}
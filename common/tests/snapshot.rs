@@ -0,0 +1,89 @@
+#![feature(test)]
+
+extern crate test;
+
+// Library
+use test::Bencher;
+use vek::*;
+
+// Project
+use common::util::{
+    msg::{CompStore, ServerMsg},
+    snapshot::{EntityState, Snapshot, SnapshotReceiver, SnapshotSender, State},
+};
+
+const ENTITIES: u64 = 100;
+const TICKS: u64 = 50;
+
+// A crowd of entities, about every fifth one walks a little each tick
+fn world(tick: u64) -> Vec<(u64, Vec3<f32>, Vec3<f32>, Vec2<f32>)> {
+    (0..ENTITIES)
+        .map(|uid| {
+            let steps = (tick + uid) / 5;
+            let pos = Vec3::new(uid as f32 * 3.0 + steps as f32 * 0.1, 50.0, 200.0);
+            (uid, pos, Vec3::new(5.0, 0.0, 0.0), Vec2::unit_y())
+        })
+        .collect()
+}
+
+fn state(tick: u64) -> State {
+    world(tick)
+        .into_iter()
+        .map(|(uid, pos, vel, dir)| (uid, EntityState::new(pos, vel, dir)))
+        .collect()
+}
+
+fn size(msg: &ServerMsg) -> usize { bincode::serialize(msg).unwrap().len() }
+
+// One `CompUpdate` per component and entity, every tick
+fn comp_update_bytes(tick: u64) -> usize {
+    world(tick)
+        .into_iter()
+        .map(|(uid, pos, vel, dir)| {
+            [CompStore::Pos(pos), CompStore::Vel(vel), CompStore::Dir(dir)]
+                .iter()
+                .map(|store| {
+                    size(&ServerMsg::CompUpdate {
+                        uid,
+                        store: store.clone(),
+                    })
+                })
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+#[test]
+fn snapshot_bytes_per_tick() {
+    let mut sender = SnapshotSender::new();
+    let mut receiver = SnapshotReceiver::new();
+
+    let (mut comp_updates, mut snapshots) = (0, 0);
+    for tick in 0..TICKS {
+        comp_updates += comp_update_bytes(tick);
+
+        let expected = state(tick);
+        if let Some(snapshot) = sender.snapshot(expected.clone()) {
+            snapshots += size(&ServerMsg::Snapshot(snapshot.clone()));
+            // Every third one gets lost
+            if tick % 3 != 2 {
+                assert_eq!(receiver.receive(&snapshot), Some(&expected));
+                sender.ack(snapshot.id);
+            }
+        }
+    }
+
+    // The first tick has to send everything, the others only what moved
+    assert!(
+        snapshots * 5 < comp_updates,
+        "bytes per tick: {} with CompUpdate, {} with snapshots",
+        comp_updates as u64 / TICKS,
+        snapshots as u64 / TICKS
+    );
+}
+
+#[bench]
+fn snapshot_diff(b: &mut Bencher) {
+    let (baseline, state) = (state(0), state(1));
+    b.iter(|| Snapshot::diff(1, Some((0, &baseline)), &state));
+}
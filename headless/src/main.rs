@@ -5,7 +5,7 @@
 extern crate log;
 
 // Standard
-use std::{io, sync::Arc};
+use std::{env, io, sync::Arc};
 
 // Library
 use parking_lot::Mutex;
@@ -13,9 +13,10 @@ use syrup::Window;
 use vek::*;
 
 // Project
-use client::{Client, ClientEvent, PlayMode};
+use client::{account, Client, ClientEvent, Credentials, PlayMode, ServerKey};
 use common::{
     audio::{AudioGen, Buffer, Stream},
+    net::{NetProfile, Simulator, Tcp, Transport},
     terrain::{chunk::ChunkContainer, VolOffs},
 };
 
@@ -41,8 +42,18 @@ fn gen_payload(_key: Vec3<VolOffs>, _con: Arc<Mutex<Option<ChunkContainer<<Paylo
 
 fn drop_payload(_key: Vec3<VolOffs>, _con: Arc<ChunkContainer<<Payloads as client::Payloads>::Chunk>>) {}
 
+// `--netsim <profile>` simulates a bad network for everything sent to the server
+fn netsim_profile() -> Option<NetProfile> {
+    let profile = env::args().skip_while(|a| a != "--netsim").nth(1)?;
+    match profile.parse() {
+        Ok(profile) => Some(profile),
+        Err(e) => panic!("invalid --netsim profile: {}", e),
+    }
+}
+
 fn main() {
     info!("Starting headless client...");
+    let netsim = netsim_profile();
 
     let mut remote_addr = String::new();
     println!("Remote server address [127.0.0.1:59003]:");
@@ -54,24 +65,55 @@ fn main() {
         remote_addr = "91.67.21.222:38888";
     }
 
-    let default_alias = common::util::names::generate();
-    println!("Alias: [{}]", default_alias);
-    let mut alias = String::new();
-    io::stdin().read_line(&mut alias).unwrap();
-    let mut alias = alias.trim().to_string();
-    if alias.is_empty() {
-        alias = default_alias.to_string();
+    // Play as the account login-cli logged in to, or as a guest
+    let credentials = account::stored_login().unwrap_or(Credentials::Guest);
+    let alias = if let Credentials::Token { account, .. } = &credentials {
+        println!("Playing as {}", account);
+        account.clone()
+    } else {
+        let default_alias = common::util::names::generate();
+        println!("Alias: [{}]", default_alias);
+        let mut alias = String::new();
+        io::stdin().read_line(&mut alias).unwrap();
+        let mut alias = alias.trim().to_string();
+        if alias.is_empty() {
+            alias = default_alias.to_string();
+        }
+        alias
+    };
+
+    let client = match netsim {
+        Some(profile) => {
+            info!("Simulating network conditions: {:?}", profile);
+            let remote_addr = remote_addr.to_string();
+            Client::<Payloads>::with_connector(
+                PlayMode::Headless,
+                alias,
+                credentials,
+                move || -> Result<Transport, common::net::Error> {
+                    Ok(Box::new(Simulator::reliable(
+                        Box::new(Tcp::new(&remote_addr)?),
+                        profile.clone(),
+                    )))
+                },
+                ServerKey::Remembered,
+                gen_payload,
+                drop_payload,
+                Arc::new(NoAudio {}),
+                0,
+            )
+        },
+        None => Client::<Payloads>::new(
+            PlayMode::Headless,
+            alias,
+            credentials,
+            &remote_addr.trim(),
+            gen_payload,
+            drop_payload,
+            Arc::new(NoAudio {}),
+            0,
+        ),
     }
-
-    let client = Client::<Payloads>::new(
-        PlayMode::Headless,
-        alias,
-        &remote_addr.trim(),
-        gen_payload,
-        drop_payload,
-        Arc::new(NoAudio {}),
-        0,
-    )
     .expect("error when attempting to initiate the client");
 
     let mut win = Window::initscr();
@@ -81,11 +123,24 @@ fn main() {
         for event in client.get_events() {
             match event {
                 ClientEvent::RecvChatMsg { text } => win.writeln(text),
+                ClientEvent::CmdResult { success, lines } => {
+                    for line in lines {
+                        win.writeln(if success { line } else { format!("Error: {}", line) });
+                    }
+                },
+                ClientEvent::ConnectionLost => win.writeln("Lost connection to the server, reconnecting..."),
+                ClientEvent::Reconnected => win.writeln("Reconnected"),
+                ClientEvent::Disconnected { reason: Some(reason) } => win.writeln(format!("Disconnected: {}", reason)),
+                ClientEvent::Disconnected { reason: None } => win.writeln("Lost connection to the server"),
             }
         }
 
         if let Some(msg) = win.get() {
-            client.send_chat_msg(msg.clone());
+            if msg.starts_with('/') {
+                client.send_cmd(msg[1..].split_whitespace().map(str::to_string).collect());
+            } else {
+                client.send_chat_msg(msg.clone());
+            }
         }
     }
 }
@@ -1,3 +1,99 @@
+extern crate clap;
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+// Standard
+use std::{fs, process};
+
+// Project
+use client::{account, Error};
+use common::util::msg::LoginToken;
+
+fn server_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("server")
+        .short("s")
+        .long("server")
+        .value_name("ADDR")
+        .help("Sets the server's address")
+        .takes_value(true)
+        .default_value("127.0.0.1:59003")
+}
+
+fn account_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("account")
+        .value_name("ACCOUNT")
+        .help("The name of the account")
+        .required(true)
+}
+
+fn read_password(prompt: &str) -> String {
+    match rpassword::prompt_password_stdout(prompt) {
+        Ok(password) => password,
+        Err(e) => fail(&format!("Could not read the password: {}", e)),
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1)
+}
+
+// Keep the token for voxygen and headless to connect with
+fn store(account: &str, token: Result<LoginToken, Error>) {
+    let token = match token {
+        Ok(token) => token,
+        Err(Error::Account(e)) => fail(&e.to_string()),
+        Err(Error::Disconnected(reason)) => fail(&format!("The server refused: {}", reason)),
+        Err(e) => fail(&format!("Could not reach the server: {:?}", e)),
+    };
+    if let Err(e) = account::store_login(account, &token) {
+        fail(&format!("Could not store the login in {}: {}", account::LOGIN_FILE, e));
+    }
+    println!("Logged in as {}", account);
+}
+
+fn register(args: &ArgMatches) {
+    let (server, account) = (args.value_of("server").unwrap(), args.value_of("account").unwrap());
+    let password = read_password("Password: ");
+    if password != read_password("Repeat the password: ") {
+        fail("The passwords don't match");
+    }
+    store(account, account::register(server, account, &password));
+}
+
+fn login(args: &ArgMatches) {
+    let (server, account) = (args.value_of("server").unwrap(), args.value_of("account").unwrap());
+    store(account, account::login(server, account, &read_password("Password: ")));
+}
+
+fn logout() {
+    match fs::remove_file(account::LOGIN_FILE) {
+        Ok(()) => println!("Logged out, the clients play as guests"),
+        Err(_) => println!("Not logged in"),
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args = App::new("Veloren login")
+        .about("Registers and logs in to accounts, voxygen and headless connect with the stored login")
+        .subcommand(
+            SubCommand::with_name("register")
+                .about("Registers a new account and logs in to it")
+                .arg(server_arg())
+                .arg(account_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("login")
+                .about("Logs in to an account")
+                .arg(server_arg())
+                .arg(account_arg()),
+        )
+        .subcommand(SubCommand::with_name("logout").about("Forgets the stored login"))
+        .get_matches();
+
+    match args.subcommand() {
+        ("register", Some(args)) => register(args),
+        ("login", Some(args)) => login(args),
+        ("logout", _) => logout(),
+        _ => fail(args.usage()),
+    }
 }
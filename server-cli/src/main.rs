@@ -1,8 +1,20 @@
 extern crate clap;
 use clap::{App, Arg};
 
+// Standard
+use std::{
+    io::{self, BufRead, Write},
+    net::TcpListener,
+    sync::Arc,
+    time::Instant,
+};
+
 // Project
-use server::{api::Api, net::DisconnectReason, player::Player, specs::Entity, Manager, Server};
+use common::net::{self, NetProfile, SimulatedListener};
+use server::{
+    api::Api, cmd::Commands, net::DisconnectReason, perms::Role, player::Player, specs::Entity, Manager, Server,
+    Wrapper,
+};
 
 struct Payloads;
 impl server::Payloads for Payloads {
@@ -41,6 +53,79 @@ impl server::Payloads for Payloads {
         println!("[CHAT] {}: {}", alias, text);
         Some(format!("{}: {}", alias, text))
     }
+
+    fn register_commands(&self, cmds: &mut Commands<Self>) {
+        let started = Instant::now();
+        cmds.add(
+            "uptime",
+            vec![],
+            "How long the server has been running",
+            Role::Player,
+            move |_, _, _| {
+                let secs = started.elapsed().as_secs();
+                Ok(vec![format!(
+                    "Up for {}h {}m {}s",
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60
+                )])
+            },
+        );
+    }
+}
+
+// What the operator typed at the console
+#[derive(Debug, PartialEq)]
+enum ConsoleLine<'a> {
+    Empty,
+    // Shut the server down, telling the players why if a reason was given
+    Stop(Option<String>),
+    // Any other command, run as an admin
+    Cmd(&'a str),
+}
+
+fn parse_line<'a>(line: &'a str) -> ConsoleLine<'a> {
+    // The '/' players type is optional here
+    let line = line.trim();
+    let line = if line.starts_with('/') { &line[1..] } else { line };
+
+    let mut words = line.split_whitespace();
+    match words.next() {
+        None => ConsoleLine::Empty,
+        Some("stop") => {
+            ConsoleLine::Stop(Some(words.collect::<Vec<_>>().join(" ")).filter(|reason| !reason.is_empty()))
+        },
+        Some(_) => ConsoleLine::Cmd(line),
+    }
+}
+
+// Run the operator's commands as an admin, until it stops the server. Returns false if there's nothing to read
+// commands from, e.g. when stdin is closed
+fn run_console<R: BufRead, W: Write>(server: &Manager<Wrapper<Server<Payloads>>>, input: R, out: &mut W) -> bool {
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        match parse_line(&line) {
+            ConsoleLine::Empty => {},
+            ConsoleLine::Stop(reason) => {
+                if let Some(reason) = reason {
+                    server.do_for(|srv| srv.broadcast_chat_msg(&format!("[Server stopping: {}]", reason)));
+                }
+                return true;
+            },
+            ConsoleLine::Cmd(cmd) => match server.do_for_mut(|srv| srv.run_console_cmd(cmd.split_whitespace())) {
+                Ok(lines) => lines.iter().for_each(|line| {
+                    let _ = writeln!(out, "{}", line);
+                }),
+                Err(lines) => lines.iter().for_each(|line| {
+                    let _ = writeln!(out, "[ERROR] {}", line);
+                }),
+            },
+        }
+    }
+    false
 }
 
 fn main() {
@@ -69,8 +154,195 @@ fn main() {
                 .takes_value(true)
                 .default_value("59003"),
         )
+        .arg(
+            Arg::with_name("data")
+                .short("d")
+                .long("data")
+                .value_name("DIR")
+                .help("Sets the directory the world, the players, the accounts and the permissions are kept in")
+                .takes_value(true)
+                .default_value("."),
+        )
+        .arg(
+            Arg::with_name("netsim")
+                .long("netsim")
+                .value_name("PROFILE")
+                .help(
+                    "Simulates a bad network for everything sent to clients, either a preset (lan, wifi, bad-wifi, \
+                     mobile) or e.g. latency=120,jitter=40,loss=0.05,bandwidth=100000 (ms, bytes/s)",
+                )
+                .takes_value(true)
+                .validator(|p| p.parse::<NetProfile>().map(|_| ())),
+        )
         .get_matches();
     let addr = args.value_of("addr").unwrap().to_owned() + ":" + args.value_of("port").unwrap(); //safe because of default_value
+    let data_dir = args.value_of("data").unwrap(); //safe because of default_value
     println!("[INFO] Starting server on {}", addr);
-    Manager::await_shutdown(Server::<Payloads>::new(Payloads, addr).expect("Could not start server"));
+    let server = match args.value_of("netsim") {
+        Some(profile) => {
+            let profile: NetProfile = profile.parse().unwrap(); //safe because of validator
+            println!("[INFO] Simulating network conditions: {:?}", profile);
+            let listener = TcpListener::bind(&addr).expect("Could not bind to address");
+            Server::<Payloads>::with_listener(Payloads, SimulatedListener::new(Arc::new(listener), profile), data_dir)
+        },
+        None => Server::<Payloads>::new(Payloads, addr, data_dir),
+    };
+    let server = server.expect("Could not start server");
+    // Players can compare it with the key their client remembered for the server
+    println!(
+        "[INFO] Server key {}",
+        net::to_hex(&server.do_for(|srv| srv.public_key()))
+    );
+
+    println!("[INFO] Type help for the commands, or stop [reason] to shut the server down");
+    let stdin = io::stdin();
+    if run_console(&server, stdin.lock(), &mut io::stdout()) {
+        println!("[INFO] Stopping server");
+        // Dropping the server disconnects every player and saves the world and the players
+        drop(server);
+        println!("[INFO] Server stopped");
+    } else {
+        Manager::await_shutdown(server);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line, run_console, ConsoleLine, Manager, Payloads, Server, Wrapper};
+    use client::{Client, ClientEvent, Credentials, DisconnectReason, PlayMode, ServerKey};
+    use common::{
+        audio::{AudioGen, Buffer, Stream},
+        net::MemoryListener,
+        terrain::{chunk::ChunkContainer, VolOffs},
+        util::testutils::DataDir,
+    };
+    use parking_lot::Mutex;
+    use std::{
+        io::Cursor,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+    use vek::*;
+
+    struct NoAudio;
+
+    impl AudioGen for NoAudio {
+        fn gen_stream(&self, _id: u64, _buffer: &Buffer, _stream: &Stream) {}
+
+        fn gen_buffer(&self, _id: u64, _buffer: &Buffer) {}
+
+        fn drop_stream(&self, _id: u64, _buffer: &Buffer, _stream: &Stream) {}
+
+        fn drop_buffer(&self, _id: u64, _buffer: &Buffer) {}
+    }
+
+    struct ClientPayloads;
+
+    impl client::Payloads for ClientPayloads {
+        type Chunk = ();
+        type Entity = ();
+        type Audio = NoAudio;
+    }
+
+    fn gen_payload(_pos: Vec3<VolOffs>, _con: Arc<Mutex<Option<ChunkContainer<()>>>>) {}
+
+    fn drop_payload(_pos: Vec3<VolOffs>, _con: Arc<ChunkContainer<()>>) {}
+
+    // Feeds the lines to the console, returns whether they stopped the server and what was printed
+    fn console(server: &Manager<Wrapper<Server<Payloads>>>, input: &str) -> (bool, Vec<String>) {
+        let mut out = vec![];
+        let stopped = run_console(server, Cursor::new(input), &mut out);
+        (
+            stopped,
+            String::from_utf8(out).unwrap().lines().map(str::to_string).collect(),
+        )
+    }
+
+    // The events of the client up to the first one `f` picks
+    fn wait_for<T, F: FnMut(ClientEvent) -> Option<T>>(client: &Client<ClientPayloads>, mut f: F) -> Option<T> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Some(found) = client.get_events().into_iter().filter_map(&mut f).next() {
+                return Some(found);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(parse_line(""), ConsoleLine::Empty);
+        assert_eq!(parse_line("  / "), ConsoleLine::Empty);
+        assert_eq!(parse_line("players"), ConsoleLine::Cmd("players"));
+        assert_eq!(
+            parse_line(" /kick bob  spamming \n"),
+            ConsoleLine::Cmd("kick bob  spamming")
+        );
+        assert_eq!(parse_line("stopwatch"), ConsoleLine::Cmd("stopwatch"));
+        assert_eq!(parse_line("stop"), ConsoleLine::Stop(None));
+        assert_eq!(
+            parse_line("/stop  back in   5 minutes"),
+            ConsoleLine::Stop(Some("back in 5 minutes".to_string()))
+        );
+    }
+
+    #[test]
+    fn commands_run_as_admin() {
+        let dir = DataDir::new("console-admin");
+        let server = Server::with_listener(Payloads, MemoryListener::new(), &dir.0).unwrap();
+
+        // Until there's nothing left to read, the server keeps running
+        let (stopped, out) = console(&server, "players\n\n/uptime\nsettime 1h\nfly\n");
+        assert!(!stopped);
+        assert_eq!(
+            out,
+            vec![
+                "Online Players: ",
+                "Up for 0h 0m 0s",
+                "Set time to 3600",
+                "[ERROR] Unrecognised command /fly, try /help",
+            ]
+        );
+
+        drop(server);
+    }
+
+    #[test]
+    fn stop_tells_the_players() {
+        let dir = DataDir::new("console-stop");
+        let listener = MemoryListener::new();
+        let server = Server::with_listener(Payloads, listener.clone(), &dir.0).unwrap();
+        let client = Client::<ClientPayloads>::with_transport(
+            PlayMode::Headless,
+            "player".to_string(),
+            Credentials::Guest,
+            Box::new(listener.connect().unwrap()),
+            ServerKey::Pinned(server.do_for(|srv| srv.public_key())),
+            gen_payload,
+            drop_payload,
+            Arc::new(NoAudio),
+            0,
+        )
+        .unwrap();
+
+        // Nothing after the stop is run
+        assert_eq!(console(&server, "stop maintenance\nsettime 1h\n"), (true, vec![]));
+        assert!(wait_for(&client, |event| match event {
+            ClientEvent::RecvChatMsg { ref text } if text == "[Server stopping: maintenance]" => Some(()),
+            _ => None,
+        })
+        .is_some());
+
+        // Dropping the server disconnects the players, like main does
+        drop(server);
+        match wait_for(&client, |event| match event {
+            ClientEvent::Disconnected { reason } => Some(reason),
+            _ => None,
+        }) {
+            Some(Some(DisconnectReason::Shutdown)) => {},
+            reason => panic!("Not disconnected for the shutdown: {:?}", reason),
+        }
+    }
 }
@@ -0,0 +1,302 @@
+// Standard
+use std::{collections::HashMap, fs, io, path::PathBuf, time::Duration};
+
+// Library
+use hmac::Hmac;
+use parking_lot::Mutex;
+use pbkdf2::pbkdf2;
+use rand::{thread_rng, Rng};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+// Project
+use common::util::{
+    manager::Manager,
+    msg::{
+        AccountError, AccountRequest, ClientMsg, Credentials, DisconnectReason, LoginToken, ServerMsg, ServerPostBox,
+        ServerPostOffice, Version,
+    },
+};
+
+// Local
+use crate::{api::Api, db, Payloads, Server, Wrapper};
+
+// Constants
+// Where the accounts are saved, in the data directory
+pub(crate) const ACCOUNT_DB_FILE: &str = "accounts.db";
+// Bump whenever the layout of `Account` changes
+const ACCOUNT_DB_VERSION: u32 = 1;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_NAME_LEN: usize = 24;
+const MIN_PASSWORD_LEN: usize = 8;
+// Rounds of PBKDF2 per password, so passwords can't be guessed quickly from a stolen database
+const HASH_ROUNDS: u32 = 50_000;
+// Logging in on more devices than this logs the oldest one out
+const MAX_TOKENS: usize = 8;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Account {
+    // as it was registered, accounts are looked up by the lowercase name
+    name: String,
+    salt: [u8; 16],
+    password_hash: [u8; 32],
+    // hashes of the tokens handed out, the newest last
+    tokens: Vec<[u8; 32]>,
+}
+
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(bytes));
+    hash
+}
+
+// PBKDF2-HMAC-SHA256
+fn hash_password(password: &str, salt: &[u8; 16]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, HASH_ROUNDS, &mut hash).expect("HMAC takes keys of any length");
+    hash
+}
+
+// Takes as long wherever the first difference is, so it doesn't tell how much of a hash was right
+fn same_hash(a: &[u8; 32], b: &[u8; 32]) -> bool { a.ct_eq(b).into() }
+
+fn is_valid_name(name: &str) -> bool {
+    name.len() >= 3
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// The accounts registered on the server, in a single file. Unlike the player database, it's written right away, a
+// token that was handed out mustn't get lost
+pub struct AccountDb {
+    path: PathBuf,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl AccountDb {
+    // Fails if the file exists but can't be read, instead of replacing it with an empty one later
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<AccountDb> {
+        let path = path.into();
+        let accounts = match fs::read(&path) {
+            Ok(bytes) => match bincode::deserialize::<(u32, HashMap<String, Account>)>(&bytes) {
+                Ok((version, accounts)) if version == ACCOUNT_DB_VERSION => accounts,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unreadable account database",
+                    ))
+                },
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(AccountDb {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    fn save(&self, accounts: &HashMap<String, Account>) -> Result<(), AccountError> {
+        bincode::serialize(&(ACCOUNT_DB_VERSION, accounts))
+            .ok()
+            .and_then(|bytes| db::write_atomic(&self.path, &bytes).ok())
+            .ok_or(AccountError::Unavailable)
+    }
+
+    // Add a token to the account and save it, returns the token
+    fn hand_out_token(&self, accounts: &mut HashMap<String, Account>, key: &str) -> Result<LoginToken, AccountError> {
+        let token = LoginToken::generate();
+        let tokens = match accounts.get_mut(key) {
+            Some(account) => &mut account.tokens,
+            None => return Err(AccountError::WrongCredentials),
+        };
+        tokens.push(sha256(&token.0));
+        let excess = tokens.len().saturating_sub(MAX_TOKENS);
+        tokens.drain(..excess);
+        self.save(accounts).map(|_| token)
+    }
+
+    pub fn exists(&self, name: &str) -> bool { self.accounts.lock().contains_key(&name.to_lowercase()) }
+
+    pub fn register(&self, name: &str, password: &str) -> Result<LoginToken, AccountError> {
+        if !is_valid_name(name) {
+            return Err(AccountError::InvalidName);
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        let salt = thread_rng().gen();
+        let password_hash = hash_password(password, &salt);
+
+        let key = name.to_lowercase();
+        let mut accounts = self.accounts.lock();
+        if accounts.contains_key(&key) {
+            return Err(AccountError::NameTaken);
+        }
+        accounts.insert(
+            key.clone(),
+            Account {
+                name: name.to_string(),
+                salt,
+                password_hash,
+                tokens: vec![],
+            },
+        );
+        let token = self.hand_out_token(&mut accounts, &key);
+        if token.is_err() {
+            accounts.remove(&key);
+        }
+        token
+    }
+
+    pub fn login(&self, name: &str, password: &str) -> Result<LoginToken, AccountError> {
+        let key = name.to_lowercase();
+        // Hashing takes a while, the other accounts can be used meanwhile
+        let account = self.accounts.lock().get(&key).cloned();
+        // A password is hashed for unknown accounts too, so how long it takes doesn't tell which accounts exist
+        let (salt, password_hash) = match &account {
+            Some(account) => (account.salt, account.password_hash),
+            None => ([0; 16], [0; 32]),
+        };
+        let correct = same_hash(&hash_password(password, &salt), &password_hash);
+        match account {
+            Some(_) if correct => self.hand_out_token(&mut self.accounts.lock(), &key),
+            _ => Err(AccountError::WrongCredentials),
+        }
+    }
+
+    // The name of the account if the token belongs to it
+    pub fn verify(&self, name: &str, token: &LoginToken) -> Option<String> {
+        let hash = sha256(&token.0);
+        self.accounts
+            .lock()
+            .get(&name.to_lowercase())
+            .filter(|account| account.tokens.iter().any(|t| same_hash(t, &hash)))
+            .map(|account| account.name.clone())
+    }
+}
+
+// Answer a client that registers or logs in to get a token, over `SessionKind::Account`
+pub(crate) fn handle_account<P: Payloads>(srv: &Wrapper<Server<P>>, po: &Manager<ServerPostOffice>, pb: ServerPostBox) {
+    let (version, request) = match pb.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(ClientMsg::Account { version, request }) => (version, request),
+        _ => return,
+    };
+    let server_version = Version::current();
+    if !server_version.is_compatible(&version) {
+        let _ = pb.send(ServerMsg::Disconnect {
+            reason: DisconnectReason::IncompatibleVersion {
+                server: server_version,
+                client: version,
+            },
+        });
+        return;
+    }
+    // Passwords never travel in plaintext
+    if !po.is_encrypted() {
+        return;
+    }
+
+    let accounts = srv.do_for(|srv| srv.accounts.clone());
+    let result = match request {
+        AccountRequest::Register { account, password } => accounts.register(&account, &password),
+        AccountRequest::Login { account, password } => accounts.login(&account, &password),
+    };
+    let _ = pb.send(ServerMsg::AccountResult { result });
+}
+
+// Server
+
+impl<P: Payloads> Server<P> {
+    /// Check the credentials a client connects with. Returns the alias to play under, and the account if it logged in
+    pub(crate) fn authenticate(
+        &self,
+        alias: String,
+        credentials: Credentials,
+    ) -> Result<(String, Option<String>), AccountError> {
+        match credentials {
+            Credentials::Guest if self.accounts.exists(&alias) => Err(AccountError::NameTaken),
+            Credentials::Guest if self.is_valid_alias(&alias) => Ok((alias, None)),
+            Credentials::Guest => Err(AccountError::InvalidName),
+            Credentials::Token { account, token } => match self.accounts.verify(&account, &token) {
+                Some(name) => Ok((name.clone(), Some(name))),
+                None => Err(AccountError::WrongCredentials),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccountDb, ACCOUNT_DB_FILE};
+    use crate::testutils::DataDir;
+    use common::util::msg::{AccountError, LoginToken};
+    use std::{fs, time::Instant};
+
+    fn open(dir: &DataDir) -> AccountDb {
+        fs::create_dir_all(&dir.0).unwrap();
+        AccountDb::open(dir.0.join(ACCOUNT_DB_FILE)).unwrap()
+    }
+
+    #[test]
+    fn register_and_login() {
+        let dir = DataDir::new("accounts-login");
+        let accounts = open(&dir);
+        let registered = accounts.register("Alice", "correct horse").unwrap();
+        assert!(accounts.exists("alice"));
+
+        // Names are looked up regardless of case, the account keeps the one it was registered with
+        let logged_in = accounts.login("ALICE", "correct horse").unwrap();
+        assert_ne!(registered, logged_in);
+        assert_eq!(
+            accounts.login("Alice", "wrong horse"),
+            Err(AccountError::WrongCredentials)
+        );
+        assert_eq!(
+            accounts.login("Bob", "correct horse"),
+            Err(AccountError::WrongCredentials)
+        );
+
+        assert_eq!(
+            accounts.register("alice", "another password"),
+            Err(AccountError::NameTaken)
+        );
+        assert_eq!(
+            accounts.register("a!", "another password"),
+            Err(AccountError::InvalidName)
+        );
+        assert_eq!(accounts.register("Bob", "short"), Err(AccountError::WeakPassword));
+        assert!(!accounts.exists("bob"));
+    }
+
+    #[test]
+    fn unknown_accounts_take_as_long() {
+        let dir = DataDir::new("accounts-timing");
+        let accounts = open(&dir);
+        accounts.register("alice", "correct horse").unwrap();
+        let time = |name| {
+            let start = Instant::now();
+            assert_eq!(accounts.login(name, "wrong horse"), Err(AccountError::WrongCredentials));
+            start.elapsed()
+        };
+        // The password is hashed either way, which takes far longer than looking the account up
+        let (known, unknown) = (time("alice"), time("bob"));
+        assert!(unknown * 4 > known, "{:?} vs {:?}", unknown, known);
+    }
+
+    #[test]
+    fn tokens_survive_a_restart() {
+        let dir = DataDir::new("accounts-tokens");
+        let token = open(&dir).register("Alice", "correct horse").unwrap();
+
+        let accounts = open(&dir);
+        assert_eq!(accounts.verify("alice", &token), Some("Alice".to_string()));
+        assert_eq!(
+            accounts.verify("alice", &LoginToken::from_hex(&token.to_hex()).unwrap()),
+            Some("Alice".to_string())
+        );
+        assert_eq!(accounts.verify("bob", &token), None);
+        assert_eq!(accounts.verify("alice", &LoginToken::generate()), None);
+    }
+}
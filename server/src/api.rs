@@ -1,12 +1,15 @@
+// Standard
+use std::time::Instant;
+
 // Library
-use specs::{prelude::*, saveload::Marker};
+use specs::prelude::*;
 
 // Project
-use common::{ecs::net::UidMarker, util::msg::ServerMsg};
+use common::util::msg::{ServerMsg, SessionKind};
 
 // Local
 use crate::{
-    net::{Client, DisconnectReason},
+    net::{Client, Detached, DisconnectReason},
     player::Player,
     Payloads, Server,
 };
@@ -26,9 +29,31 @@ pub trait Api {
 
 impl<P: Payloads> Api for Server<P> {
     fn disconnect_player(&mut self, player: Entity, reason: DisconnectReason) {
-        // Stop the postoffice
-        if let Some(client) = self.world.read_storage::<Client>().get(player) {
-            let _ = client.postoffice.stop(); // We don't care if this fails
+        self.record_player(player);
+
+        // Tell the client why and stop the postoffice, it still delivers everything queued so far
+        let client = self.world.write_storage::<Client>().remove(player);
+        if let Some(client) = &client {
+            let _ = client
+                .postoffice
+                .create_postbox(SessionKind::Disconnect)
+                .send(ServerMsg::Disconnect { reason: reason.clone() }); // We don't care if this fails
+            let _ = client.postoffice.stop();
+        }
+
+        // Keep the player of a lost connection around for a while, the client may resume it
+        if let (Some(client), true) = (client, reason.is_resumable()) {
+            let _ = self.world.write_storage::<Detached>().insert(
+                player,
+                Detached {
+                    token: client.resume_token,
+                    since: Instant::now(),
+                },
+            );
+            if let Some(player_comp) = self.world.read_storage::<Player>().get(player) {
+                self.broadcast_chat_msg(&format!("[{} lost connection: {}]", player_comp.alias, reason));
+            }
+            return;
         }
 
         if let Some(player_comp) = self.world.read_storage::<Player>().get(player) {
@@ -36,10 +61,7 @@ impl<P: Payloads> Api for Server<P> {
             self.payload.on_player_disconnect(self, player, reason);
         }
 
-        if let Some(uid) = self.world.read_storage::<UidMarker>().get(player) {
-            self.broadcast_net_msg(ServerMsg::EntityDeleted { uid: uid.id() });
-        }
-
+        // The clients that had the player in view are told it's gone on the next sync
         let _ = self.world.delete_entity(player);
     }
 
@@ -68,5 +90,6 @@ impl<P: Payloads> Api for Server<P> {
 
     fn world_mut(&mut self) -> &mut World { &mut self.world }
 
-    fn is_valid_alias(&self, alias: &str) -> bool { alias.len() > 0 }
+    // Accounts play under their name, nobody else may use it
+    fn is_valid_alias(&self, alias: &str) -> bool { alias.len() > 0 && !self.accounts.exists(alias) }
 }
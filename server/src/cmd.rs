@@ -0,0 +1,560 @@
+// Standard
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+// Library
+use specs::{Entity, Join};
+use vek::*;
+
+// Project
+use common::util::msg::ServerMsg;
+
+// Local
+use crate::{api::Api, perms::Role, player::Player, Payloads, Server};
+
+// ArgKind
+
+// What a parameter takes, and how it's parsed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArgKind {
+    // Three numbers, x y z
+    Coords,
+    // The alias of a player that's online
+    Alias,
+    // A number of seconds, or of minutes, hours or days with an m, h or d after it
+    Duration,
+    // The name of a command
+    Command,
+    // One of the given words
+    Choice(&'static [&'static str]),
+    // Any single word
+    Word,
+    // Everything left on the line, only for the last parameter
+    Text,
+}
+
+impl ArgKind {
+    fn describe(&self) -> String {
+        match self {
+            ArgKind::Coords => "coordinates, three numbers".to_string(),
+            ArgKind::Alias => "the alias of a player online".to_string(),
+            ArgKind::Duration => "a time such as 90, 90s, 5m, 2h or 1d".to_string(),
+            ArgKind::Command => "the name of a command".to_string(),
+            ArgKind::Choice(choices) => format!("one of {}", choices.join(", ")),
+            ArgKind::Word => "a word".to_string(),
+            ArgKind::Text => "any text".to_string(),
+        }
+    }
+}
+
+fn parse_duration(word: &str) -> Option<Duration> {
+    let (number, unit) = match word.char_indices().last()? {
+        (idx, unit) if unit.is_alphabetic() => (&word[..idx], unit),
+        _ => (word, 's'),
+    };
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok().map(|n| Duration::from_secs(n * scale))
+}
+
+// The candidates that start with what was typed so far
+fn matching<'a, I: IntoIterator<Item = &'a str>>(typed: &str, candidates: I) -> Vec<String> {
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(typed))
+        .map(str::to_string)
+        .collect()
+}
+
+fn find_player<P: Payloads>(srv: &Server<P>, alias: &str) -> Option<Entity> {
+    (&srv.world.entities(), &srv.world.read_storage::<Player>())
+        .join()
+        .find(|(_, player)| player.alias == alias)
+        .map(|(player, _)| player)
+}
+
+// Caller
+
+// Who runs a command
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Caller {
+    Player(Entity),
+    // The operator at the server's console, who has the admin role
+    Console,
+}
+
+impl Caller {
+    // The player running the command, for the commands that act on it
+    pub fn player(&self) -> Result<Entity, CmdError> {
+        match self {
+            Caller::Player(player) => Ok(*player),
+            Caller::Console => Err(CmdError::Failed("Only players can do that".to_string())),
+        }
+    }
+}
+
+// Param
+
+#[derive(Clone, Debug)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+impl Param {
+    pub fn required(name: &'static str, kind: ArgKind) -> Param {
+        Param {
+            name,
+            kind,
+            optional: false,
+        }
+    }
+
+    pub fn optional(name: &'static str, kind: ArgKind) -> Param {
+        Param {
+            name,
+            kind,
+            optional: true,
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.kind, self.optional) {
+            (ArgKind::Coords, false) => write!(f, "<x> <y> <z>"),
+            (ArgKind::Coords, true) => write!(f, "[x y z]"),
+            (ArgKind::Choice(choices), false) => write!(f, "<{}>", choices.join("|")),
+            (ArgKind::Choice(choices), true) => write!(f, "[{}]", choices.join("|")),
+            (_, false) => write!(f, "<{}>", self.name),
+            (_, true) => write!(f, "[{}]", self.name),
+        }
+    }
+}
+
+// Args
+
+#[derive(Clone, Debug)]
+enum ArgValue {
+    Coords(Vec3<f32>),
+    Player(Entity),
+    Duration(Duration),
+    Word,
+}
+
+// The arguments a command was run with, by the name of their parameter
+pub struct Args {
+    values: BTreeMap<&'static str, (String, ArgValue)>,
+}
+
+impl Args {
+    fn get(&self, name: &str) -> Result<&(String, ArgValue), CmdError> {
+        self.values
+            .get(name)
+            .ok_or_else(|| CmdError::Usage(format!("<{}> is missing", name)))
+    }
+
+    // Whether an optional argument was given
+    pub fn has(&self, name: &str) -> bool { self.values.contains_key(name) }
+
+    // The argument as it was typed, for any kind of parameter
+    pub fn word(&self, name: &str) -> Result<&str, CmdError> { self.get(name).map(|(word, _)| word.as_str()) }
+
+    pub fn coords(&self, name: &str) -> Result<Vec3<f32>, CmdError> {
+        match self.get(name)? {
+            (_, ArgValue::Coords(coords)) => Ok(*coords),
+            _ => Err(CmdError::Usage(format!("<{}> aren't coordinates", name))),
+        }
+    }
+
+    pub fn player(&self, name: &str) -> Result<Entity, CmdError> {
+        match self.get(name)? {
+            (_, ArgValue::Player(player)) => Ok(*player),
+            _ => Err(CmdError::Usage(format!("<{}> isn't a player", name))),
+        }
+    }
+
+    pub fn duration(&self, name: &str) -> Result<Duration, CmdError> {
+        match self.get(name)? {
+            (_, ArgValue::Duration(duration)) => Ok(*duration),
+            _ => Err(CmdError::Usage(format!("<{}> isn't a duration", name))),
+        }
+    }
+}
+
+// CmdError
+
+#[derive(Clone, Debug)]
+pub enum CmdError {
+    // The command was used wrong, its usage is shown after the reason
+    Usage(String),
+    Failed(String),
+}
+
+// The lines to answer with
+pub type CmdResult = Result<Vec<String>, CmdError>;
+
+// Command
+
+type Handler<P> = Box<dyn Fn(&mut Server<P>, Caller, &Args) -> CmdResult + Send + Sync>;
+
+pub struct Command<P: Payloads> {
+    pub name: &'static str,
+    pub params: Vec<Param>,
+    pub help: &'static str,
+    // Needed unless the permissions config says otherwise
+    pub role: Role,
+    run: Handler<P>,
+}
+
+impl<P: Payloads> Command<P> {
+    pub fn usage(&self) -> String {
+        self.params
+            .iter()
+            .fold(format!("/{}", self.name), |usage, param| format!("{} {}", usage, param))
+    }
+
+    // Every parameter and what it takes
+    pub fn describe_params(&self) -> Vec<String> {
+        self.params
+            .iter()
+            .map(|param| format!("  {} - {}", param, param.kind.describe()))
+            .collect()
+    }
+
+    fn parse<'a, I: Iterator<Item = &'a str>>(&self, srv: &Server<P>, mut words: I) -> Result<Args, CmdError> {
+        let mut values = BTreeMap::new();
+        for param in &self.params {
+            let word = match param.kind {
+                ArgKind::Coords => {
+                    let coords = words.by_ref().take(3).collect::<Vec<_>>();
+                    match coords.len() {
+                        0 => None,
+                        3 => Some(coords.join(" ")),
+                        _ => return Err(CmdError::Usage("Coordinates need 3 numbers".to_string())),
+                    }
+                },
+                ArgKind::Text => Some(words.by_ref().collect::<Vec<_>>().join(" ")).filter(|text| !text.is_empty()),
+                _ => words.next().map(str::to_string),
+            };
+            let word = match word {
+                Some(word) => word,
+                None if param.optional => continue,
+                None => return Err(CmdError::Usage(format!("{} is missing", param))),
+            };
+
+            let invalid = || CmdError::Usage(format!("Invalid {}: {}", param, word));
+            let value = match param.kind {
+                ArgKind::Coords => {
+                    let mut coords = [0.0; 3];
+                    for (coord, word) in coords.iter_mut().zip(word.split(' ')) {
+                        *coord = word.parse().map_err(|_| invalid())?;
+                    }
+                    ArgValue::Coords(Vec3::from(coords))
+                },
+                ArgKind::Alias => match find_player(srv, &word) {
+                    Some(player) => ArgValue::Player(player),
+                    None => return Err(CmdError::Failed(format!("Could not locate {}!", word))),
+                },
+                ArgKind::Duration => ArgValue::Duration(parse_duration(&word).ok_or_else(invalid)?),
+                ArgKind::Choice(choices) if !choices.iter().any(|choice| *choice == word) => return Err(invalid()),
+                _ => ArgValue::Word,
+            };
+            values.insert(param.name, (word, value));
+        }
+
+        match words.next() {
+            Some(word) => Err(CmdError::Usage(format!("Unexpected argument: {}", word))),
+            None => Ok(Args { values }),
+        }
+    }
+}
+
+// Commands
+
+// Every command players can run, by name. See `msg::register_commands` for those of the server itself
+pub struct Commands<P: Payloads> {
+    commands: BTreeMap<&'static str, Command<P>>,
+}
+
+impl<P: Payloads> Commands<P> {
+    pub fn new() -> Commands<P> {
+        Commands {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Add a command, replacing any of the same name
+    pub fn add<F>(&mut self, name: &'static str, params: Vec<Param>, help: &'static str, role: Role, run: F)
+    where
+        F: Fn(&mut Server<P>, Caller, &Args) -> CmdResult + Send + Sync + 'static,
+    {
+        self.commands.insert(
+            name,
+            Command {
+                name,
+                params,
+                help,
+                role,
+                run: Box::new(run),
+            },
+        );
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command<P>> { self.commands.get(name) }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Command<P>> { self.commands.values() }
+
+    /// Run a command given as its name and arguments. Returns the lines to answer with, whether it succeeded or not
+    pub fn run<'a, I>(&self, srv: &mut Server<P>, caller: Caller, words: I) -> Result<Vec<String>, Vec<String>>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut words = words.into_iter();
+        let name = words.next().unwrap_or("");
+        let cmd = match self.get(name) {
+            Some(cmd) => cmd,
+            None => return Err(vec![format!("Unrecognised command /{}, try /help", name)]),
+        };
+        if !srv.may_use(caller, name) {
+            return Err(vec![format!("You don't have permission to use /{}", name)]);
+        }
+
+        match cmd.parse(srv, words).and_then(|args| (cmd.run)(srv, caller, &args)) {
+            Ok(lines) => Ok(lines),
+            Err(CmdError::Usage(reason)) => Err(vec![reason, format!("Usage: {}", cmd.usage())]),
+            Err(CmdError::Failed(reason)) => Err(vec![reason]),
+        }
+    }
+
+    // The commands the caller may use
+    fn names<'a>(&'a self, srv: &'a Server<P>, caller: Caller) -> impl Iterator<Item = &'static str> + 'a {
+        self.iter()
+            .filter(move |cmd| srv.may_use(caller, cmd.name))
+            .map(|cmd| cmd.name)
+    }
+
+    /// What the last word of a partly typed command line, without its leading '/', could be completed to
+    pub fn complete(&self, srv: &Server<P>, caller: Caller, line: &str) -> Vec<String> {
+        let mut words = line.split(' ').collect::<Vec<_>>();
+        let last = words.pop().unwrap_or("");
+        let cmd = match words.first() {
+            None => return matching(last, self.names(srv, caller)),
+            Some(name) => match self.get(name) {
+                Some(cmd) if srv.may_use(caller, name) => cmd,
+                _ => return vec![],
+            },
+        };
+
+        // Find the parameter the last word is for, coordinates take three words
+        let mut idx = words.len() - 1;
+        for param in &cmd.params {
+            let len = if param.kind == ArgKind::Coords { 3 } else { 1 };
+            if idx < len {
+                return match param.kind {
+                    ArgKind::Alias => {
+                        let players = srv.world.read_storage::<Player>();
+                        let aliases = players.join().map(|player| player.alias.as_str()).collect::<Vec<_>>();
+                        matching(last, aliases)
+                    },
+                    ArgKind::Command => matching(last, self.names(srv, caller)),
+                    ArgKind::Choice(choices) => matching(last, choices.iter().cloned()),
+                    _ => vec![],
+                };
+            }
+            idx -= len;
+        }
+        vec![]
+    }
+}
+
+impl<P: Payloads> Default for Commands<P> {
+    fn default() -> Commands<P> { Commands::new() }
+}
+
+// Server
+
+impl<P: Payloads> Server<P> {
+    /// Run a command of a player and answer it with `ServerMsg::CmdResult`
+    pub(crate) fn run_cmd<'a, I: IntoIterator<Item = &'a str>>(&mut self, player: Entity, words: I) {
+        let cmds = self.commands.clone();
+        let (success, lines) = match cmds.run(self, Caller::Player(player), words) {
+            Ok(lines) => (true, lines),
+            Err(lines) => (false, lines),
+        };
+        self.send_net_msg(player, ServerMsg::CmdResult { success, lines });
+    }
+
+    /// Run a command of the operator, as an admin. Returns the lines to answer with, whether it succeeded or not
+    pub fn run_console_cmd<'a, I: IntoIterator<Item = &'a str>>(
+        &mut self,
+        words: I,
+    ) -> Result<Vec<String>, Vec<String>> {
+        let cmds = self.commands.clone();
+        cmds.run(self, Caller::Console, words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ArgKind, Caller, Commands, Param};
+    use crate::{
+        perms::Role,
+        testutils::{self, DataDir},
+        Payloads, Server,
+    };
+    use common::util::msg::PlayMode;
+    use std::time::Duration;
+
+    struct CmdPayloads;
+
+    impl Payloads for CmdPayloads {
+        type Chunk = ();
+        type Entity = ();
+        type Client = ();
+
+        fn register_commands(&self, cmds: &mut Commands<Self>) {
+            cmds.add(
+                "greet",
+                vec![
+                    Param::required("alias", ArgKind::Alias),
+                    Param::optional("greeting", ArgKind::Text),
+                ],
+                "Greet a player",
+                Role::Moderator,
+                |_, _, args| {
+                    Ok(vec![format!(
+                        "{}, {}!",
+                        args.word("greeting").unwrap_or("Hello"),
+                        args.word("alias")?
+                    )])
+                },
+            );
+        }
+    }
+
+    fn run<P: Payloads>(srv: &mut Server<P>, caller: Caller, line: &str) -> Result<Vec<String>, Vec<String>> {
+        let cmds = srv.commands.clone();
+        cmds.run(srv, caller, line.split_whitespace())
+    }
+
+    fn usage_error(reason: &str, usage: &str) -> Result<Vec<String>, Vec<String>> {
+        Err(vec![reason.to_string(), format!("Usage: {}", usage)])
+    }
+
+    #[test]
+    fn lookup_help_and_completion() {
+        let dir = DataDir::new("cmd-lookup");
+        let mut srv = testutils::server(&dir);
+        let (player, _client) = testutils::connect(&mut srv, "player", None, PlayMode::Headless);
+        assert_eq!(srv.commands.get("warp").unwrap().usage(), "/warp <x> <y> <z>");
+        assert!(srv.commands.get("fly").is_none());
+        assert_eq!(
+            run(&mut srv, Caller::Console, "fly"),
+            Err(vec!["Unrecognised command /fly, try /help".to_string()])
+        );
+
+        // Help and completion only offer the commands the caller may use
+        let help = run(&mut srv, Caller::Console, "help warp").unwrap();
+        assert_eq!(
+            help[..2],
+            ["/warp <x> <y> <z>".to_string(), "Offset your position".to_string()]
+        );
+        assert!(run(&mut srv, Caller::Player(player), "help warp").is_err());
+        let help = run(&mut srv, Caller::Player(player), "help").unwrap();
+        assert!(help.iter().any(|line| line.starts_with("/pos ")));
+        assert!(!help.iter().any(|line| line.starts_with("/settime ")));
+        let cmds = srv.commands.clone();
+        assert_eq!(cmds.complete(&srv, Caller::Console, "se"), vec!["settime".to_string()]);
+        assert!(cmds.complete(&srv, Caller::Player(player), "se").is_empty());
+        assert_eq!(
+            cmds.complete(&srv, Caller::Console, "tp pl"),
+            vec!["player".to_string()]
+        );
+        assert_eq!(
+            cmds.complete(&srv, Caller::Console, "op player a"),
+            vec!["admin".to_string()]
+        );
+    }
+
+    #[test]
+    fn argument_errors() {
+        let dir = DataDir::new("cmd-args");
+        let mut srv = testutils::server(&dir);
+        let console = Caller::Console;
+        assert_eq!(
+            run(&mut srv, console, "goto"),
+            usage_error("<x> <y> <z> is missing", "/goto <x> <y> <z>")
+        );
+        assert_eq!(
+            run(&mut srv, console, "goto 1 2"),
+            usage_error("Coordinates need 3 numbers", "/goto <x> <y> <z>")
+        );
+        assert_eq!(
+            run(&mut srv, console, "goto 1 2 x"),
+            usage_error("Invalid <x> <y> <z>: 1 2 x", "/goto <x> <y> <z>")
+        );
+        assert_eq!(
+            run(&mut srv, console, "settime 5y"),
+            usage_error("Invalid <time>: 5y", "/settime <time>")
+        );
+        assert_eq!(
+            run(&mut srv, console, "op someone wizard"),
+            usage_error("Invalid [moderator|admin]: wizard", "/op <name> [moderator|admin]")
+        );
+        assert_eq!(
+            run(&mut srv, console, "pos now"),
+            usage_error("Unexpected argument: now", "/pos")
+        );
+        assert_eq!(
+            run(&mut srv, console, "tp nobody"),
+            Err(vec!["Could not locate nobody!".to_string()])
+        );
+        // Arguments that parse still leave the command to fail on its own
+        assert_eq!(
+            run(&mut srv, console, "goto 1 2 3"),
+            Err(vec!["Only players can do that".to_string()])
+        );
+
+        assert_eq!(
+            run(&mut srv, console, "settime 5m"),
+            Ok(vec!["Set time to 300".to_string()])
+        );
+        assert_eq!(srv.clock_tick_time, Duration::from_secs(300));
+        assert!(run(&mut srv, console, "settime 2h").is_ok());
+        assert_eq!(srv.clock_tick_time, Duration::from_secs(2 * 60 * 60));
+    }
+
+    #[test]
+    fn payload_commands() {
+        let dir = DataDir::new("cmd-payload");
+        let mut srv = testutils::server_with(CmdPayloads, &dir);
+        srv.perms.set_role("warden", Role::Moderator).unwrap();
+        let (player, _player_client) = testutils::connect(&mut srv, "player", None, PlayMode::Headless);
+        let (warden, _warden_client) = testutils::connect(&mut srv, "warden", Some("warden"), PlayMode::Headless);
+
+        // They're permitted like the server's own, and listed in the permissions file
+        assert_eq!(srv.perms.required_role("greet"), Some(Role::Moderator));
+        assert_eq!(
+            run(&mut srv, Caller::Player(player), "greet warden"),
+            Err(vec!["You don't have permission to use /greet".to_string()])
+        );
+        assert_eq!(
+            run(&mut srv, Caller::Player(warden), "greet player"),
+            Ok(vec!["Hello, player!".to_string()])
+        );
+        assert_eq!(
+            run(&mut srv, Caller::Player(warden), "greet player good day to you"),
+            Ok(vec!["good day to you, player!".to_string()])
+        );
+        assert_eq!(
+            run(&mut srv, Caller::Player(warden), "greet"),
+            usage_error("<alias> is missing", "/greet <alias> [greeting]")
+        );
+    }
+}
@@ -0,0 +1,199 @@
+// Standard
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+// Library
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use specs::{Entity, Join};
+use vek::*;
+
+// Project
+use common::ecs::{
+    character::{Character, Health},
+    phys::Pos,
+};
+
+// Local
+use crate::{player::Player, Payloads, Server};
+
+// Constants
+// Where the players are saved, in the data directory
+pub(crate) const PLAYER_DB_FILE: &str = "players.db";
+// Bump whenever the layout of `PlayerRecord` changes, bincode can't tell an old record from a new one
+const PLAYER_DB_VERSION: u32 = 1;
+
+// Replace the file at `path` at once, so a crash leaves either the old or the new one
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+// What's kept of a player between sessions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerRecord {
+    pub pos: Vec3<f32>,
+    // Headless players have neither health nor a character
+    pub health: Option<u32>,
+    pub character: Option<String>,
+}
+
+struct Records {
+    players: HashMap<String, PlayerRecord>,
+    // changed since the last flush
+    dirty: bool,
+}
+
+// Every player with an account that was ever on the server, keyed by account, in a single file. Guests aren't kept,
+// anyone could join under a guest's alias. Changes are written when `flush` is called
+pub struct PlayerDb {
+    path: PathBuf,
+    records: Mutex<Records>,
+}
+
+impl PlayerDb {
+    // Fails if the file exists but can't be read, instead of replacing it with an empty one later
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<PlayerDb> {
+        let path = path.into();
+        let players = match fs::read(&path) {
+            Ok(bytes) => match bincode::deserialize::<(u32, HashMap<String, PlayerRecord>)>(&bytes) {
+                Ok((version, players)) if version == PLAYER_DB_VERSION => players,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unreadable player database")),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(PlayerDb {
+            path,
+            records: Mutex::new(Records { players, dirty: false }),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<PlayerRecord> { self.records.lock().players.get(key).cloned() }
+
+    pub fn insert(&self, key: &str, record: PlayerRecord) {
+        let mut records = self.records.lock();
+        records.players.insert(key.to_string(), record);
+        records.dirty = true;
+    }
+
+    // Write the records to disk if any changed since the last flush
+    pub fn flush(&self) -> io::Result<()> {
+        let mut records = self.records.lock();
+        if !records.dirty {
+            return Ok(());
+        }
+        let bytes = bincode::serialize(&(PLAYER_DB_VERSION, &records.players))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        write_atomic(&self.path, &bytes)?;
+        records.dirty = false;
+        Ok(())
+    }
+}
+
+// Server
+
+impl<P: Payloads> Server<P> {
+    /// Put a player where it was when it last left, with the health it had then. Guests start anew
+    pub(crate) fn load_player(&mut self, player: Entity) {
+        let record = match self.world.read_storage::<Player>().get(player) {
+            Some(Player {
+                account: Some(account), ..
+            }) => self.player_db.get(account),
+            _ => None,
+        };
+        let record = match record {
+            Some(record) => record,
+            None => return,
+        };
+
+        let _ = self.world.write_storage::<Pos>().insert(player, Pos(record.pos));
+        if let (Some(health), Some(health_comp)) = (record.health, self.world.write_storage::<Health>().get_mut(player))
+        {
+            health_comp.0 = health;
+        }
+        if let (Some(name), Some(character)) = (
+            record.character,
+            self.world.write_storage::<Character>().get_mut(player),
+        ) {
+            character.name = name;
+        }
+    }
+
+    /// Remember where a player with an account is, it's written to disk with the next `save_players`
+    pub(crate) fn record_player(&self, player: Entity) {
+        let (players, pos) = (self.world.read_storage::<Player>(), self.world.read_storage::<Pos>());
+        let (account, pos) = match (players.get(player), pos.get(player)) {
+            (
+                Some(Player {
+                    account: Some(account), ..
+                }),
+                Some(pos),
+            ) => (account, pos),
+            _ => return,
+        };
+        let record = PlayerRecord {
+            pos: pos.0,
+            health: self.world.read_storage::<Health>().get(player).map(|h| h.0),
+            character: self
+                .world
+                .read_storage::<Character>()
+                .get(player)
+                .map(|c| c.name.clone()),
+        };
+        self.player_db.insert(account, record);
+    }
+
+    /// Record every player that's on the server and write the player database
+    pub(crate) fn save_players(&self) {
+        for player in (&self.world.entities(), &self.world.read_storage::<Player>())
+            .join()
+            .map(|(player, _)| player)
+            .collect::<Vec<_>>()
+        {
+            self.record_player(player);
+        }
+        let _ = self.player_db.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutils::{self, DataDir};
+    use common::{ecs::phys::Pos, util::msg::PlayMode};
+    use vek::*;
+
+    #[test]
+    fn only_accounts_are_saved() {
+        let dir = DataDir::new("db-guests");
+        let moved = Pos(Vec3::new(10.0, 20.0, 230.0));
+        {
+            let mut srv = testutils::server(&dir);
+            let (member, _member_client) = testutils::connect(&mut srv, "member", Some("member"), PlayMode::Character);
+            let (guest, _guest_client) = testutils::connect(&mut srv, "guest", None, PlayMode::Character);
+            for player in &[member, guest] {
+                srv.world.write_storage::<Pos>().insert(*player, moved).unwrap();
+            }
+            srv.save_players();
+        }
+
+        // Whoever joins under the guest's alias starts anew, the account picks up where it left
+        let mut srv = testutils::server(&dir);
+        assert!(srv.player_db.get("guest").is_none());
+        let (member, _member_client) = testutils::connect(&mut srv, "member", Some("member"), PlayMode::Character);
+        let (guest, _guest_client) = testutils::connect(&mut srv, "guest", None, PlayMode::Character);
+        srv.load_player(member);
+        srv.load_player(guest);
+        let pos = srv.world.read_storage::<Pos>();
+        assert_eq!(pos.get(member).unwrap().0, moved.0);
+        assert_eq!(pos.get(guest).unwrap().0, Vec3::new(0.0, 0.0, 215.0));
+    }
+}
@@ -1,15 +1,26 @@
 // Standard
 use std::io;
 
+// Project
+use common::{net, util::msg::Version};
+
 #[derive(Debug)]
 pub enum Error {
     ConnectionDropped,
-    NoConnectSession,
-    InvalidConnectSession,
     NoConnectMsg,
+    LoginFailed,
+    Banned,
+    IncompatibleVersion(Version),
+    Unencrypted,
+    SessionExpired,
     IoErr(io::Error),
+    NetworkErr(net::Error),
 }
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self { Error::IoErr(e) }
 }
+
+impl From<net::Error> for Error {
+    fn from(e: net::Error) -> Self { Error::NetworkErr(e) }
+}
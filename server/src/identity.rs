@@ -0,0 +1,70 @@
+// Standard
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+// Project
+use common::net::Identity;
+
+// Constants
+// The key the server answers key exchanges as, in the data directory. Clients remember it, a server that lost it looks
+// like an impostor to them
+pub(crate) const IDENTITY_FILE: &str = "identity.key";
+
+// The identity kept at `path`, a new one is generated and saved if there is none yet. Fails if the file exists but
+// can't be read, instead of replacing it
+pub fn open(path: &Path) -> io::Result<Identity> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            if bytes.len() != 32 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unreadable identity key"));
+            }
+            let mut key = [0; 32];
+            key.copy_from_slice(&bytes);
+            Ok(Identity::from_bytes(key))
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let identity = Identity::generate();
+            // Only the server's own user may read it
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut file = options.open(path)?;
+            file.write_all(&identity.to_bytes())?;
+            file.sync_all()?;
+            Ok(identity)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, IDENTITY_FILE};
+    use crate::testutils::DataDir;
+    use std::fs;
+
+    #[test]
+    fn identity_survives_a_restart() {
+        let dir = DataDir::new("identity");
+        fs::create_dir_all(&dir.0).unwrap();
+        let path = dir.0.join(IDENTITY_FILE);
+        let identity = open(&path).unwrap();
+        assert_eq!(open(&path).unwrap().public(), identity.public());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::write(&path, b"garbage").unwrap();
+        assert!(open(&path).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+// Standard
+use std::collections::{HashMap, HashSet};
+
+// Library
+use specs::{saveload::Marker, Component, Entity, Join, VecStorage};
+use vek::*;
+
+// Project
+use common::{
+    ecs::{
+        net::UidMarker,
+        phys::{Dir, Pos, Vel},
+        NetComp,
+    },
+    util::{
+        msg::{CompStore, ServerMsg},
+        snapshot::{EntityState, SnapshotSender, State, SNAPSHOT_STREAM},
+        spatial::SpatialGrid,
+    },
+};
+
+// Local
+use crate::{net::Client, Payloads, Server};
+
+// Constants
+pub const VIEW_DISTANCE: f32 = 256.0;
+// An entity has to get this much further away than the view distance before it leaves, so one moving along the
+// edge doesn't enter and leave all the time
+const LEAVE_MARGIN: f32 = 1.1;
+
+// The entities a client is told about
+#[derive(Debug)]
+pub struct Interest {
+    pub view_distance: f32,
+    // uids rather than entities, the entity may be gone by the time the client is told it left
+    visible: HashSet<u64>,
+    snapshots: SnapshotSender,
+}
+
+impl Interest {
+    pub fn new(view_distance: f32) -> Interest {
+        Interest {
+            view_distance,
+            visible: HashSet::new(),
+            snapshots: SnapshotSender::new(),
+        }
+    }
+
+    pub fn sees(&self, uid: u64) -> bool { self.visible.contains(&uid) }
+}
+
+impl Default for Interest {
+    fn default() -> Interest { Interest::new(VIEW_DISTANCE) }
+}
+
+impl Component for Interest {
+    type Storage = VecStorage<Self>;
+}
+
+// An entity clients may be told about, and what they are told
+struct Tracked {
+    uid: u64,
+    // sent in full once it enters a client's view
+    stores: Vec<CompStore>,
+    // snapshotted afterwards
+    state: EntityState,
+}
+
+// Server
+
+impl<P: Payloads> Server<P> {
+    fn track(&self, entity: Entity, uid: u64, pos: &Pos) -> Tracked {
+        let (vel, dir) = (self.world.read_storage::<Vel>(), self.world.read_storage::<Dir>());
+        let (vel, dir) = (vel.get(entity), dir.get(entity));
+        Tracked {
+            uid,
+            stores: pos
+                .to_store()
+                .into_iter()
+                .chain(vel.and_then(|c| c.to_store()))
+                .chain(dir.and_then(|c| c.to_store()))
+                .collect(),
+            state: EntityState::new(
+                pos.0,
+                vel.map(|c| c.0).unwrap_or(Vec3::zero()),
+                dir.map(|c| c.0).unwrap_or(Vec2::zero()),
+            ),
+        }
+    }
+
+    /// Tell every client about the entities within its view distance. Entities entering its view are sent in full and
+    /// reliably, the ones in view are updated by a snapshot, and the ones that leave are deleted
+    pub(crate) fn sync_players(&mut self) {
+        // Index everything a client could be told about
+        let mut grid = SpatialGrid::new(VIEW_DISTANCE);
+        for (entity, pos, uid) in (
+            &self.world.entities(),
+            &self.world.read_storage::<Pos>(),
+            &self.world.read_storage::<UidMarker>(),
+        )
+            .join()
+        {
+            grid.insert(pos.0, self.track(entity, uid.id(), pos));
+        }
+
+        let uids = self.world.read_storage::<UidMarker>();
+        for (player, client, pos, interest) in (
+            &self.world.entities(),
+            &self.world.read_storage::<Client>(),
+            &self.world.read_storage::<Pos>(),
+            &mut self.world.write_storage::<Interest>(),
+        )
+            .join()
+        {
+            let po = &client.postoffice;
+            let player_uid = uids.get(player).map(|uid| uid.id());
+
+            // A client isn't told about itself
+            let visible = grid
+                .within(pos.0, interest.view_distance * LEAVE_MARGIN)
+                .filter(|(_, tracked)| Some(tracked.uid) != player_uid)
+                .filter(|(tracked_pos, tracked)| {
+                    interest.sees(tracked.uid)
+                        || (*tracked_pos - pos.0).magnitude_squared() <= interest.view_distance.powi(2)
+                })
+                .map(|(_, tracked)| (tracked.uid, tracked))
+                .collect::<HashMap<_, _>>();
+
+            for uid in interest.visible.iter().filter(|uid| !visible.contains_key(*uid)) {
+                let _ = po.send_one(ServerMsg::EntityDeleted { uid: *uid });
+            }
+
+            for (uid, tracked) in visible.iter().filter(|(uid, _)| !interest.sees(**uid)) {
+                for store in &tracked.stores {
+                    let _ = po.send_one(ServerMsg::CompUpdate {
+                        uid: *uid,
+                        store: store.clone(),
+                    });
+                }
+            }
+
+            // Everything in view goes into the snapshot, it only carries what the client doesn't know yet
+            let state = visible
+                .iter()
+                .map(|(uid, tracked)| (*uid, tracked.state))
+                .collect::<State>();
+            if let Some(snapshot) = interest.snapshots.snapshot(state) {
+                let _ = po.send_one_sequenced(ServerMsg::Snapshot(snapshot), SNAPSHOT_STREAM);
+            }
+
+            interest.visible = visible.keys().cloned().collect();
+        }
+    }
+
+    /// The client applied a snapshot, later ones can be relative to it
+    pub(crate) fn ack_snapshot(&mut self, player: Entity, id: u64) {
+        if let Some(interest) = self.world.write_storage::<Interest>().get_mut(player) {
+            interest.snapshots.ack(id);
+        }
+    }
+
+    /// Whether `player` is the entity itself or has it in view
+    pub(crate) fn is_interested(&self, player: Entity, entity: Entity) -> bool {
+        player == entity
+            || match (
+                self.world.read_storage::<Interest>().get(player),
+                self.world.read_storage::<UidMarker>().get(entity),
+            ) {
+                (Some(interest), Some(uid)) => interest.sees(uid.id()),
+                _ => false,
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LEAVE_MARGIN, VIEW_DISTANCE};
+    use crate::testutils::{self, DataDir};
+    use common::{
+        ecs::{net::UidMarker, phys::Pos},
+        util::msg::{CompStore, PlayMode, ServerMsg},
+    };
+    use specs::{
+        saveload::{MarkedBuilder, Marker},
+        Builder,
+    };
+    use vek::*;
+
+    fn entered(msgs: &[ServerMsg], uid: u64) -> bool {
+        msgs.iter().any(|msg| match msg {
+            ServerMsg::CompUpdate {
+                uid: u,
+                store: CompStore::Pos(_),
+            } => *u == uid,
+            _ => false,
+        })
+    }
+
+    fn left(msgs: &[ServerMsg], uid: u64) -> bool {
+        msgs.iter().any(|msg| match msg {
+            ServerMsg::EntityDeleted { uid: u } => *u == uid,
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn only_entities_in_view() {
+        let dir = DataDir::new("interest");
+        let mut srv = testutils::server(&dir);
+        let (player, client) = testutils::connect(&mut srv, "watcher", None, PlayMode::Headless);
+        let at = |x: f32| Pos(Vec3::new(x, 0.0, 215.0));
+        let near = srv.world.create_entity().with(at(10.0)).marked::<UidMarker>().build();
+        let far = srv
+            .world
+            .create_entity()
+            .with(at(VIEW_DISTANCE * 2.0))
+            .marked::<UidMarker>()
+            .build();
+        let uid = |entity| srv.world.read_storage::<UidMarker>().get(entity).unwrap().id();
+        let (near_uid, far_uid) = (uid(near), uid(far));
+
+        srv.sync_players();
+        let msgs = client.recv_all();
+        assert!(entered(&msgs, near_uid));
+        assert!(!entered(&msgs, far_uid));
+        assert!(srv.is_interested(player, near) && !srv.is_interested(player, far));
+
+        // Just beyond the view distance it stays, past the margin it leaves
+        let _ = srv.world.write_storage::<Pos>().insert(near, at(VIEW_DISTANCE * 1.05));
+        srv.sync_players();
+        assert!(!left(&client.recv_all(), near_uid));
+        let _ = srv
+            .world
+            .write_storage::<Pos>()
+            .insert(near, at(VIEW_DISTANCE * LEAVE_MARGIN * 1.05));
+        let _ = srv.world.write_storage::<Pos>().insert(far, at(VIEW_DISTANCE * 0.5));
+        srv.sync_players();
+        let msgs = client.recv_all();
+        assert!(left(&msgs, near_uid));
+        assert!(entered(&msgs, far_uid));
+        assert!(!left(&msgs, far_uid));
+        assert!(!srv.is_interested(player, near) && srv.is_interested(player, far));
+    }
+}
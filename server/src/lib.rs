@@ -4,11 +4,21 @@
 pub extern crate specs;
 
 // Modules
+mod account;
 pub mod api;
+pub mod cmd;
+mod db;
 mod error;
+mod identity;
+mod interest;
+mod movement;
 mod msg;
 pub mod net;
+pub mod perms;
 pub mod player;
+mod terrain;
+#[cfg(test)]
+mod testutils;
 mod tick;
 
 // Reexports
@@ -18,28 +28,51 @@ pub use crate::error::Error;
 
 // Standard
 use std::{
-    net::{TcpListener, ToSocketAddrs},
-    sync::atomic::Ordering,
-    time::Duration,
+    fs,
+    net::ToSocketAddrs,
+    path::PathBuf,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 // Library
-use parking_lot::RwLock;
-use specs::{Entity, World};
+use parking_lot::{Mutex, RwLock};
+use specs::{Entity, Join, World};
 
 // Project
 use common::{
     ecs,
+    net::{Identity, Listener, PolledListener, PublicKeyBytes, Reactor},
+    terrain::{ChunkMgr, RegionStore},
     util::{clock::Clock, manager::Managed, msg::ServerPostOffice},
 };
 
 // Local
 use crate::{
+    account::AccountDb,
     api::Api,
-    net::{Client, DisconnectReason},
+    cmd::Commands,
+    db::PlayerDb,
+    interest::Interest,
+    movement::Movement,
+    net::{Client, Detached, DisconnectReason, Post},
+    perms::Permissions,
     player::Player,
+    terrain::ChunkRequests,
 };
 
+// Constants
+// How often the terrain and the players are saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+// How often the players are told the time
+const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+// How often the workers that rarely have something to do check whether the server is shutting down
+const IDLE_TICK: Duration = Duration::from_millis(100);
+
 pub trait Payloads: Send + Sync + 'static {
     type Chunk: Send + Sync + 'static;
     type Entity: Send + Sync + 'static;
@@ -58,12 +91,28 @@ pub trait Payloads: Send + Sync + 'static {
             text
         ))
     }
+    // Add commands of the payload's own, they're run and permitted like the server's
+    fn register_commands(&self, _cmds: &mut Commands<Self>)
+    where
+        Self: Sized,
+    {
+    }
 }
 
 pub struct Server<P: Payloads> {
-    listener: TcpListener,
+    listener: Arc<dyn Listener>,
+    identity: Identity,
+    // Everything the players send, see `net::handle_player_post`
+    post_send: Mutex<mpsc::Sender<Post>>,
+    post_recv: Mutex<Option<mpsc::Receiver<Post>>>,
     clock_tick_time: Duration,
     world: World,
+    chunk_mgr: Arc<ChunkMgr<P::Chunk>>,
+    regions: Arc<RegionStore>,
+    player_db: PlayerDb,
+    accounts: Arc<AccountDb>,
+    perms: Permissions,
+    commands: Arc<Commands<P>>,
     payload: P,
 }
 
@@ -78,17 +127,67 @@ impl<S> Wrapper<S> {
 }
 
 impl<P: Payloads> Server<P> {
-    pub fn new<S: ToSocketAddrs>(payload: P, bind_addr: S) -> Result<Manager<Wrapper<Self>>, Error> {
+    /// The world, the players, the accounts, the permissions and the server's key are kept in `data_dir`, which is created if needed
+    pub fn new<S: ToSocketAddrs, D: Into<PathBuf>>(
+        payload: P,
+        bind_addr: S,
+        data_dir: D,
+    ) -> Result<Manager<Wrapper<Self>>, Error> {
+        // All connections are polled by a single reactor instead of threads of their own
+        Server::with_listener(payload, PolledListener::bind(bind_addr, Reactor::new()?)?, data_dir)
+    }
+
+    /// Accept clients from any listener, e.g. a `MemoryListener` to run server and clients in one process
+    pub fn with_listener<D: Into<PathBuf>>(
+        payload: P,
+        listener: Arc<dyn Listener>,
+        data_dir: D,
+    ) -> Result<Manager<Wrapper<Self>>, Error> {
+        let server = Server::open(payload, listener, data_dir)?;
+        Ok(Manager::init(Wrapper(RwLock::new(server))))
+    }
+
+    /// The key the server proves it holds when a client connects. Clients remember it on their first connection, or
+    /// pin it if they already know it, e.g. when they run in the same process
+    pub fn public_key(&self) -> PublicKeyBytes { self.identity.public() }
+
+    // The server without any workers running it yet
+    fn open<D: Into<PathBuf>>(payload: P, listener: Arc<dyn Listener>, data_dir: D) -> Result<Self, Error> {
+        let data_dir = data_dir.into();
+        fs::create_dir_all(&data_dir)?;
+
         let mut world = ecs::create_world();
+        world.register::<ChunkRequests>();
         world.register::<Client>();
+        world.register::<Detached>();
+        world.register::<Interest>();
+        world.register::<Movement>();
         world.register::<Player>();
 
-        Ok(Manager::init(Wrapper(RwLock::new(Server {
-            listener: TcpListener::bind(bind_addr)?,
+        let (post_send, post_recv) = mpsc::channel();
+        let regions = Arc::new(RegionStore::new(data_dir.join(terrain::WORLD_DIR)));
+        let mut commands = Commands::new();
+        msg::register_commands(&mut commands);
+        payload.register_commands(&mut commands);
+        let perms = Permissions::open(
+            data_dir.join(perms::PERMS_FILE),
+            commands.iter().map(|cmd| (cmd.name, cmd.role)),
+        )?;
+        Ok(Server {
+            listener,
+            identity: identity::open(&data_dir.join(identity::IDENTITY_FILE))?,
+            post_send: Mutex::new(post_send),
+            post_recv: Mutex::new(Some(post_recv)),
             clock_tick_time: Duration::from_millis(0),
             world,
+            chunk_mgr: Arc::new(terrain::new_chunk_mgr(regions.clone())),
+            regions,
+            player_db: PlayerDb::open(data_dir.join(db::PLAYER_DB_FILE))?,
+            accounts: Arc::new(AccountDb::open(data_dir.join(account::ACCOUNT_DB_FILE))?),
+            perms,
+            commands: Arc::new(commands),
             payload,
-        }))))
+        })
     }
 }
 
@@ -96,20 +195,44 @@ impl<P: Payloads> Managed for Wrapper<Server<P>> {
     fn init_workers(&self, mgr: &mut Manager<Self>) {
         // Incoming clients worker
         Manager::add_worker(mgr, |srv, running, mut mgr| {
-            let listener = srv.do_for_mut(|srv| srv.listener.try_clone().expect("Failed to clone server TcpListener"));
-
-            while let (Ok((stream, _addr)), true) = (listener.accept(), running.load(Ordering::Relaxed)) {
-                // Convert the incoming stream to a postoffice ready to begin the connection handshake
-                if let Ok(po) = ServerPostOffice::to_client(stream) {
-                    Manager::add_worker(&mut mgr, move |srv, _, mgr| {
-                        if let Ok(client) = net::auth_client(srv, po) {
-                            net::handle_player_post(srv, client, mgr);
-                        }
+            let (listener, identity) = srv.do_for(|srv| (srv.listener.clone(), srv.identity.clone()));
+
+            while let (Ok(transport), true) = (listener.accept(), running.load(Ordering::Relaxed)) {
+                // Convert the incoming transport to a postoffice ready to begin the connection handshake
+                if let Ok(po) = ServerPostOffice::to_client_with(transport, identity.clone()) {
+                    Manager::add_worker(&mut mgr, move |srv, running, _| {
+                        net::handle_connection(srv, po, running)
                     });
                 }
             }
         });
 
+        // Post worker, handles what all players send
+        Manager::add_worker(mgr, |srv, running, mgr| {
+            let post = match srv.do_for(|srv| srv.post_recv.lock().take()) {
+                Some(post) => post,
+                None => return,
+            };
+            while running.load(Ordering::Relaxed) {
+                match post.recv_timeout(Duration::from_millis(100)) {
+                    Ok((player, po, incoming)) => net::handle_post(srv, player, &po, incoming, &mgr),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+
+        // Ping worker, kicks the players that stopped answering
+        Manager::add_worker(mgr, |srv, running, _| {
+            let mut clock = Clock::new(Duration::from_millis(10));
+            while running.load(Ordering::Relaxed) {
+                for (player, po) in srv.do_for(|srv| srv.ping_clients()) {
+                    srv.do_for_mut(|srv| srv.disconnect_client(player, &po, DisconnectReason::Timeout));
+                }
+                clock.tick();
+            }
+        });
+
         // Tick workers
         Manager::add_worker(mgr, |srv, running, _| {
             let mut clock = Clock::new(Duration::from_millis(20));
@@ -120,18 +243,65 @@ impl<P: Payloads> Managed for Wrapper<Server<P>> {
             }
         });
 
+        // Terrain worker
+        Manager::add_worker(mgr, |srv, running, _| {
+            let mut clock = Clock::new(Duration::from_millis(100));
+            while running.load(Ordering::Relaxed) {
+                srv.do_for(|srv| srv.load_chunks()).maintain();
+                srv.do_for_mut(|srv| srv.send_requested_chunks());
+                clock.tick();
+            }
+        });
+
+        // Save worker
+        Manager::add_worker(mgr, |srv, running, _| {
+            let mut clock = Clock::new(IDLE_TICK);
+            let mut next_save = Instant::now() + SAVE_INTERVAL;
+            while running.load(Ordering::Relaxed) {
+                clock.tick();
+                if Instant::now() >= next_save {
+                    srv.do_for(|srv| {
+                        srv.save_terrain();
+                        srv.save_players();
+                    });
+                    next_save += SAVE_INTERVAL;
+                }
+            }
+        });
+
         // Sync Time worker
         Manager::add_worker(mgr, |srv, running, _| {
-            let mut clock = Clock::new(Duration::from_millis(60000));
+            let mut clock = Clock::new(IDLE_TICK);
+            let mut next_sync = Instant::now();
             while running.load(Ordering::Relaxed) {
-                srv.do_for_mut(|srv| srv.tick_time());
+                if Instant::now() >= next_sync {
+                    srv.do_for_mut(|srv| srv.tick_time());
+                    next_sync += TIME_SYNC_INTERVAL;
+                }
                 clock.tick();
             }
         });
     }
 
     fn on_drop(&self, _: &mut Manager<Self>) {
-        self.do_for(|srv| srv.listener.set_nonblocking(true))
-            .expect("Failed to set nonblocking = true on server TcpListener");
+        self.do_for(|srv| srv.listener.stop())
+            .expect("Failed to stop the server's listener");
+
+        // Tell every client we're going away, and drop the players waiting for their client to resume
+        self.do_for_mut(|srv| {
+            let (clients, detached) = (srv.world.read_storage::<Client>(), srv.world.read_storage::<Detached>());
+            let players = srv
+                .world
+                .entities()
+                .join()
+                .filter(|player| clients.get(*player).is_some() || detached.get(*player).is_some())
+                .collect::<Vec<_>>();
+            drop((clients, detached));
+            for player in players {
+                srv.disconnect_player(player, DisconnectReason::Shutdown);
+            }
+            srv.save_all_terrain();
+            srv.save_players();
+        });
     }
 }
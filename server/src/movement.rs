@@ -0,0 +1,186 @@
+// Standard
+use std::time::{Duration, Instant};
+
+// Library
+use specs::{Component, Entity, VecStorage};
+use vek::*;
+
+// Project
+use common::{
+    ecs::phys::{Dir, Pos, Vel},
+    physics::physics,
+    util::msg::{PlayerInput, ServerMsg, INPUT_DT, PLAYER_STREAM},
+};
+
+// Local
+use crate::{net::Client, Payloads, Server};
+
+// Constants
+// How much simulated time a client may have saved up. Inputs arriving in a burst after a lag spike are still accepted,
+// but a client can't send them faster than real time for long
+const MAX_INPUT_BUDGET: Duration = Duration::from_millis(250);
+
+// The server simulates the player's inputs itself, the client only predicts where they take it
+#[derive(Debug)]
+pub struct Movement {
+    // newest input simulated
+    last_seq: u64,
+    budget: Duration,
+    refilled: Instant,
+}
+
+impl Movement {
+    pub fn new() -> Movement {
+        Movement {
+            last_seq: 0,
+            budget: MAX_INPUT_BUDGET,
+            refilled: Instant::now(),
+        }
+    }
+
+    // Use up the time of one input, false if the client sends them too fast
+    fn take_budget(&mut self, now: Instant) -> bool {
+        self.budget = (self.budget + (now - self.refilled)).min(MAX_INPUT_BUDGET);
+        self.refilled = now;
+        if self.budget >= INPUT_DT {
+            self.budget -= INPUT_DT;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Component for Movement {
+    type Storage = VecStorage<Self>;
+}
+
+fn is_finite2(v: Vec2<f32>) -> bool { v.x.is_finite() && v.y.is_finite() }
+
+// Every axis goes from -1 (full speed backwards) to 1 (full speed, or a jump), anything else wasn't sent by a client
+fn is_valid_ctrl_acc(v: Vec3<f32>) -> bool { [v.x, v.y, v.z].iter().all(|e| e.is_finite() && e.abs() <= 1.0) }
+
+// Server
+
+impl<P: Payloads> Server<P> {
+    /// Simulate the inputs of a player that weren't yet, and tell the client where they took it
+    pub(crate) fn handle_inputs(&mut self, player: Entity, inputs: Vec<PlayerInput>) {
+        let (mut pos, mut vel, mut dir) = match (
+            self.world.read_storage::<Pos>().get(player),
+            self.world.read_storage::<Vel>().get(player),
+            self.world.read_storage::<Dir>().get(player),
+        ) {
+            (Some(pos), vel, dir) => (
+                pos.0,
+                vel.map(|v| v.0).unwrap_or(Vec3::zero()),
+                dir.map(|d| d.0).unwrap_or(Vec2::zero()),
+            ),
+            _ => return,
+        };
+
+        let mut movements = self.world.write_storage::<Movement>();
+        let movement = match movements.get_mut(player) {
+            Some(movement) => movement,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let simulated = movement.last_seq;
+        for input in inputs.iter().filter(|input| input.seq > simulated) {
+            // Inputs that are too fast or invalid are dropped, the client sends them again until they are accepted
+            if !is_valid_ctrl_acc(input.ctrl_acc) || !is_finite2(input.look_dir) || !movement.take_budget(now) {
+                break;
+            }
+            movement.last_seq = input.seq;
+            let (new_pos, new_vel) = physics::simulate(&*self.chunk_mgr, pos, vel, input.ctrl_acc, INPUT_DT);
+            pos = new_pos;
+            vel = new_vel;
+            dir = input.look_dir;
+        }
+        let seq = movement.last_seq;
+        drop(movements);
+        if seq == simulated {
+            return;
+        }
+
+        self.update_comp(player, Pos(pos));
+        self.update_comp(player, Vel(vel));
+        self.update_comp(player, Dir(dir));
+
+        if let Some(client) = self.world.read_storage::<Client>().get(player) {
+            let _ = client
+                .postoffice
+                .send_one_sequenced(ServerMsg::PlayerState { seq, pos, vel }, PLAYER_STREAM);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Movement, MAX_INPUT_BUDGET};
+    use crate::testutils::{self, DataDir};
+    use common::{
+        ecs::phys::Pos,
+        util::msg::{PlayMode, PlayerInput, INPUT_DT},
+    };
+    use vek::*;
+
+    fn input(seq: u64, ctrl_acc: Vec3<f32>) -> PlayerInput {
+        PlayerInput {
+            seq,
+            ctrl_acc,
+            look_dir: Vec2::unit_y(),
+        }
+    }
+
+    #[test]
+    fn input_flood_is_capped() {
+        let dir = DataDir::new("movement-flood");
+        let mut srv = testutils::server(&dir);
+        let (player, _client) = testutils::connect(&mut srv, "runner", None, PlayMode::Character);
+
+        let inputs = (1..=1000).map(|seq| input(seq, Vec3::unit_x())).collect();
+        srv.handle_inputs(player, inputs);
+        // The time passed since the player joined refills the budget by at most one more input
+        let budget = (MAX_INPUT_BUDGET.as_millis() / INPUT_DT.as_millis()) as u64;
+        let simulated = srv.world.read_storage::<Movement>().get(player).unwrap().last_seq;
+        assert!(simulated >= budget && simulated <= budget + 1);
+
+        // The rest is taken once the client sent them again, in real time
+        srv.handle_inputs(player, vec![input(simulated + 1, Vec3::unit_x())]);
+        assert_eq!(
+            srv.world.read_storage::<Movement>().get(player).unwrap().last_seq,
+            simulated
+        );
+    }
+
+    #[test]
+    fn invalid_inputs_are_rejected() {
+        let dir = DataDir::new("movement-invalid");
+        let mut srv = testutils::server(&dir);
+        let (player, _client) = testutils::connect(&mut srv, "cheater", None, PlayMode::Character);
+        let start = srv.world.read_storage::<Pos>().get(player).unwrap().0;
+
+        for ctrl_acc in &[
+            Vec3::new(std::f32::NAN, 0.0, 0.0),
+            Vec3::new(0.0, std::f32::INFINITY, 0.0),
+            Vec3::new(100.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.5),
+        ] {
+            srv.handle_inputs(player, vec![input(1, *ctrl_acc)]);
+            assert_eq!(srv.world.read_storage::<Movement>().get(player).unwrap().last_seq, 0);
+            assert_eq!(srv.world.read_storage::<Pos>().get(player).unwrap().0, start);
+        }
+
+        // Nothing after an invalid input is simulated either, the client has to send it again
+        srv.handle_inputs(
+            player,
+            vec![
+                input(1, Vec3::unit_x()),
+                input(2, Vec3::new(2.0, 0.0, 0.0)),
+                input(3, Vec3::unit_x()),
+            ],
+        );
+        assert_eq!(srv.world.read_storage::<Movement>().get(player).unwrap().last_seq, 1);
+    }
+}
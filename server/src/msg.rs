@@ -1,50 +1,119 @@
 // Standard
-use std::{mem, time};
+use std::mem;
 
 // Library
 use specs::prelude::*;
-use vek::*;
 
 // Project
 use common::{ecs::phys::Pos, util::manager::Manager};
 
 // Local
-use crate::{api::Api, player::Player, Payloads, Server, Wrapper};
+use crate::{
+    api::Api,
+    cmd::{ArgKind, Caller, CmdError, Commands, Param},
+    net::{Client, DisconnectReason},
+    perms::Role,
+    player::Player,
+    Payloads, Server, Wrapper,
+};
+
+// Constants
+const ROLES: &[&str] = &["moderator", "admin"];
+
+// Who a moderation command is aimed at: the player online under `name`, or else the account or guest of that name
+struct Target {
+    player: Option<Entity>,
+    // What its role and bans are kept under
+    key: String,
+    account: bool,
+    role: Role,
+}
+
+fn target<P: Payloads>(srv: &Server<P>, name: &str) -> Target {
+    let online = (&srv.world.entities(), &srv.world.read_storage::<Player>())
+        .join()
+        .find(|(_, player)| player.alias == name)
+        .map(|(player, player_comp)| (player, player_comp.clone()));
+    match online {
+        Some((player, player_comp)) => Target {
+            player: Some(player),
+            key: player_comp.key().to_string(),
+            account: player_comp.account.is_some(),
+            role: player_comp.role,
+        },
+        None => Target {
+            player: None,
+            key: name.to_string(),
+            account: srv.accounts.exists(name),
+            role: srv.perms.role_of(name),
+        },
+    }
+}
+
+// Moderators can't act on players of a higher role than their own
+fn check_rank<P: Payloads>(srv: &Server<P>, caller: Caller, target: &Target) -> Result<(), CmdError> {
+    match srv.role_of_caller(caller) {
+        Some(role) if role >= target.role => Ok(()),
+        _ => Err(CmdError::Failed(format!("{} has a higher role than you", target.key))),
+    }
+}
 
 pub(crate) fn process_chat_msg<P: Payloads>(
     srv: &Wrapper<Server<P>>,
     text: String,
     player: Entity,
-    mgr: &Manager<Wrapper<Server<P>>>,
+    _mgr: &Manager<Wrapper<Server<P>>>,
 ) {
     if text.starts_with('/') {
-        let cmd = text[1..].split(' ');
-        process_cmd(srv, cmd, player, mgr);
+        srv.do_for_mut(|srv| srv.run_cmd(player, text[1..].split_whitespace()));
     } else if let Some(text) = srv.do_for(|srv| srv.payload.on_chat_msg(srv, player, &text)) {
         // Run the message past the payload interface
         srv.do_for(|srv| srv.broadcast_chat_msg(&text));
     }
 }
 
-pub(crate) fn process_cmd<'a, P: Payloads>(
-    srv: &Wrapper<Server<P>>,
-    mut cmd: impl Iterator<Item = &'a str> + 'a,
-    player: Entity,
-    _mgr: &Manager<Wrapper<Server<P>>>,
-) {
-    match cmd.next() {
-        Some("help") => srv.do_for(|srv| {
-            // Send the help information to the player
-            srv.send_chat_msg(player, "Available commands:");
-            srv.send_chat_msg(player, "/players - View all online players");
-            srv.send_chat_msg(player, "/tp <alias> - Teleport to a player");
-            srv.send_chat_msg(player, "/pos - Display your current position");
-            srv.send_chat_msg(player, "/alias <alias> - Change your alias");
-            srv.send_chat_msg(player, "/warp <dx> <dy> <dz> - Offset your position");
-            srv.send_chat_msg(player, "/goto <dx> <dy> <dz> - Teleport to specified position");
-            srv.send_chat_msg(player, "/settime <t> - Set time to t [seconds]");
-        }),
-        Some("players") => srv.do_for(|srv| {
+// A command sent as `ClientMsg::Cmd`
+pub(crate) fn process_cmd<P: Payloads>(srv: &Wrapper<Server<P>>, args: Vec<String>, player: Entity) {
+    srv.do_for_mut(|srv| srv.run_cmd(player, args.iter().map(String::as_str)));
+}
+
+// The commands of the server itself
+pub(crate) fn register_commands<P: Payloads>(cmds: &mut Commands<P>) {
+    cmds.add(
+        "help",
+        vec![Param::optional("command", ArgKind::Command)],
+        "List the commands you may use, or explain one",
+        Role::Player,
+        |srv, caller, args| {
+            let cmds = srv.commands.clone();
+            match args.word("command") {
+                Ok(name) => match cmds.get(name).filter(|_| srv.may_use(caller, name)) {
+                    Some(cmd) => {
+                        let mut lines = vec![cmd.usage(), cmd.help.to_string()];
+                        lines.extend(cmd.describe_params());
+                        Ok(lines)
+                    },
+                    None => Err(CmdError::Failed(format!("There's no /{} for you to use", name))),
+                },
+                Err(_) => {
+                    let mut lines = vec!["Available commands:".to_string()];
+                    lines.extend(
+                        cmds.iter()
+                            .filter(|cmd| srv.may_use(caller, cmd.name))
+                            .map(|cmd| format!("{} - {}", cmd.usage(), cmd.help)),
+                    );
+                    Ok(lines)
+                },
+            }
+        },
+    );
+
+    cmds.add(
+        "players",
+        vec![],
+        "View all online players",
+        Role::Player,
+        |srv, _, _| {
             // Find a list of player names and format them
             let player_names = srv
                 .world
@@ -53,182 +122,396 @@ pub(crate) fn process_cmd<'a, P: Payloads>(
                 .map(|p| p.alias.clone())
                 .collect::<Vec<_>>()
                 .join(", ");
+            Ok(vec![format!("Online Players: {}", player_names)])
+        },
+    );
 
-            // Send them back to the player
-            srv.send_chat_msg(player, &format!("Online Players: {}", player_names));
-        }),
-        Some("tp") => 'tp: {
-            // Find the alias the player typed (i.e: '/tp zesterer')
-            let tgt_alias = if let Some(s) = cmd.nth(0) {
-                s
-            } else {
-                srv.do_for(|srv| srv.send_chat_msg(player, "A second argument is needed: /tp <alias>"));
-                break 'tp;
-            };
-
-            // Find the position of the player with the given alias, if possible
-            let tgt_pos = if let Some(p) = srv.do_for(|srv| {
-                (&srv.world.read_storage::<Pos>(), &srv.world.read_storage::<Player>())
-                    .join()
-                    .find(|(_, player)| player.alias == tgt_alias) // This is the important bit
-                    .map(|(pos, _)| pos.0)
-            }) {
-                p
-            } else {
-                srv.do_for(|srv| srv.send_chat_msg(player, &format!("Could not locate {}!", tgt_alias)));
-                break 'tp;
+    cmds.add(
+        "tp",
+        vec![Param::required("alias", ArgKind::Alias)],
+        "Teleport to a player",
+        Role::Moderator,
+        |srv, caller, args| {
+            let player = caller.player()?;
+            let tgt_alias = args.word("alias")?;
+            let tgt_pos = match srv.do_for_comp::<Pos, _, _>(args.player("alias")?, |pos| pos.0) {
+                Some(pos) => pos,
+                None => return Err(CmdError::Failed(format!("Could not locate {}!", tgt_alias))),
             };
 
             // Set the position of the current player accordingly
-            srv.do_for_mut(|srv| {
-                if srv.update_comp(player, Pos(tgt_pos)) {
-                    srv.force_comp::<Pos>(player); // Force clients to update
-                    srv.send_chat_msg(player, &format!("Teleported to {}!", tgt_alias));
-                } else {
-                    srv.send_chat_msg(player, "You don't have a position!");
-                }
-            });
-        },
-        Some("pos") => srv.do_for(|srv| {
-            if let Some(pos_comp) = srv.world.read_storage::<Pos>().get(player) {
-                srv.send_chat_msg(player, &format!("Current position: {}", pos_comp.0));
+            if srv.update_comp(player, Pos(tgt_pos)) {
+                srv.force_comp::<Pos>(player); // Force clients to update
+                Ok(vec![format!("Teleported to {}!", tgt_alias)])
             } else {
-                srv.send_chat_msg(player, "You don't have a position!");
+                Err(CmdError::Failed("You don't have a position!".to_string()))
             }
-        }),
-        Some("alias") => srv.do_for_mut(|srv| 'nick: {
-            let alias = match cmd.nth(0) {
-                Some(alias) => alias,
-                _ => {
-                    srv.send_chat_msg(player, "A second argument is needed: /alias <alias>");
-                    break 'nick;
-                },
-            };
+        },
+    );
+
+    cmds.add(
+        "pos",
+        vec![],
+        "Display your current position",
+        Role::Player,
+        |srv, caller, _| match srv.do_for_comp::<Pos, _, _>(caller.player()?, |pos| pos.0) {
+            Some(pos) => Ok(vec![format!("Current position: {}", pos)]),
+            None => Err(CmdError::Failed("You don't have a position!".to_string())),
+        },
+    );
+
+    cmds.add(
+        "alias",
+        vec![Param::required("alias", ArgKind::Word)],
+        "Change your alias",
+        Role::Player,
+        |srv, caller, args| {
+            let player = caller.player()?;
+            let alias = args.word("alias")?;
 
             // Check if the alias is already used by another player.
-            for p in (&srv.world.read_storage::<Player>()).join() {
-                if p.alias == alias {
-                    srv.send_chat_msg(player, "This alias is already in use");
-                    break 'nick;
-                }
+            if srv.world.read_storage::<Player>().join().any(|p| p.alias == alias) {
+                return Err(CmdError::Failed("This alias is already in use".to_string()));
             }
-
             if !srv.is_valid_alias(&alias) {
-                srv.send_chat_msg(player, "The provided alias is invalid");
-                break 'nick;
+                return Err(CmdError::Failed("The provided alias is invalid".to_string()));
             }
 
             // Give the player their new alias, hold on to the old one temporarily
-            if let Some(old_alias) = srv.do_for_comp_mut::<Player, _, _>(player, |player_comp| {
+            match srv.do_for_comp_mut::<Player, _, _>(player, |player_comp| {
                 let mut alias = alias.to_string();
                 mem::swap(&mut player_comp.alias, &mut alias);
                 alias
             }) {
-                srv.force_comp::<Pos>(player); // Force clients to update
-                srv.broadcast_chat_msg(&format!("[{} changed their alias to {}]", old_alias, alias));
-            } else {
-                srv.send_chat_msg(player, "Could not change alias");
-                break 'nick;
-            }
-        }),
-        Some("warp") => srv.do_for_mut(|srv| 'warp: {
-            let mut tensor = [0.0; 3];
-            for i in 0..3 {
-                let arg = if let Some(a) = cmd.next() {
-                    a
-                } else {
-                    srv.send_chat_msg(player, "3 numbers are needed: /warp <dx> <dy> <dz>");
-                    break 'warp;
-                };
-
-                if let Ok(v) = arg.parse() {
-                    tensor[i] = v;
-                } else {
-                    srv.send_chat_msg(
-                        player,
-                        &format!("Invalid value for {}: /warp <x> <y> <z>", ['x', 'y', 'z'][i]),
-                    );
-                    break 'warp;
-                }
+                Some(old_alias) => {
+                    srv.force_comp::<Pos>(player); // Force clients to update
+                    srv.broadcast_chat_msg(&format!("[{} changed their alias to {}]", old_alias, alias));
+                    Ok(vec![])
+                },
+                None => Err(CmdError::Failed("Could not change alias".to_string())),
             }
+        },
+    );
 
-            if let Some(pos) = srv.do_for_comp_mut::<Pos, _, _>(player, |pos_comp| {
-                pos_comp.0 += Vec3::from(tensor);
+    cmds.add(
+        "warp",
+        vec![Param::required("offset", ArgKind::Coords)],
+        "Offset your position",
+        Role::Moderator,
+        |srv, caller, args| {
+            let player = caller.player()?;
+            let offset = args.coords("offset")?;
+            match srv.do_for_comp_mut::<Pos, _, _>(player, |pos_comp| {
+                pos_comp.0 += offset;
                 pos_comp.0
             }) {
-                srv.force_comp::<Pos>(player); // Force clients to update
-                srv.send_chat_msg(player, &format!("Warped to: {}!", pos));
-            } else {
-                srv.send_chat_msg(player, "You don't have a position!");
-                break 'warp;
-            }
-        }),
-        Some("goto") => srv.do_for_mut(|srv| 'goto: {
-            let mut tensor = [0.0; 3];
-            for i in 0..3 {
-                let arg = if let Some(a) = cmd.next() {
-                    a
-                } else {
-                    srv.send_chat_msg(player, "3 numbers are needed: /goto <dx> <dy> <dz>");
-                    break 'goto;
-                };
-
-                if let Ok(v) = arg.parse() {
-                    tensor[i] = v;
-                } else {
-                    srv.send_chat_msg(
-                        player,
-                        &format!("Invalid value for {}: /goto <x> <y> <z>", ['x', 'y', 'z'][i]),
-                    );
-                    break 'goto;
-                }
+                Some(pos) => {
+                    srv.force_comp::<Pos>(player); // Force clients to update
+                    Ok(vec![format!("Warped to: {}!", pos)])
+                },
+                None => Err(CmdError::Failed("You don't have a position!".to_string())),
             }
+        },
+    );
 
-            if let Some(pos) = srv.do_for_comp_mut::<Pos, _, _>(player, |pos_comp| {
-                pos_comp.0 = Vec3::from(tensor);
-                pos_comp.0
-            }) {
+    cmds.add(
+        "goto",
+        vec![Param::required("pos", ArgKind::Coords)],
+        "Teleport to specified position",
+        Role::Moderator,
+        |srv, caller, args| {
+            let player = caller.player()?;
+            let pos = args.coords("pos")?;
+            if srv.update_comp(player, Pos(pos)) {
                 srv.force_comp::<Pos>(player); // Force clients to update
-                srv.send_chat_msg(player, &format!("teleported to: {}!", pos));
+                Ok(vec![format!("teleported to: {}!", pos)])
             } else {
-                srv.send_chat_msg(player, "You don't have a position!");
-                break 'goto;
+                Err(CmdError::Failed("You don't have a position!".to_string()))
             }
-        }),
-        Some("settime") => 'settime: {
-            let t = match cmd.next() {
-                Some(t) => match t.parse::<u64>() {
-                    Ok(s) => s,
-                    _ => {
-                        srv.do_for(|srv| {
-                            srv.send_chat_msg(player, "Specified time is invalid");
-                        });
-                        break 'settime;
-                    },
-                },
-                _ => {
-                    srv.do_for(|srv| {
-                        srv.send_chat_msg(player, "A second argument is needed /settime t");
-                    });
+        },
+    );
 
-                    break 'settime;
+    cmds.add(
+        "settime",
+        vec![Param::required("time", ArgKind::Duration)],
+        "Set the time of day",
+        Role::Admin,
+        |srv, caller, args| {
+            srv.clock_tick_time = args.duration("time")?;
+            let t = srv.clock_tick_time.as_secs();
+            srv.sync_player_time();
+            let palias = match caller {
+                Caller::Player(player) => {
+                    srv.do_for_comp::<Player, _, _>(player, |player_comp| player_comp.alias.clone())
                 },
+                Caller::Console => Some("The server".to_string()),
             };
+            if let Some(palias) = palias {
+                srv.broadcast_chat_msg(&format!("[{} set time to {}s]", palias, t));
+            }
+            Ok(vec![format!("Set time to {}", t)])
+        },
+    );
+
+    cmds.add(
+        "netstats",
+        vec![],
+        "Display statistics of your connection",
+        Role::Player,
+        |srv, caller, _| match srv.world.read_storage::<Client>().get(caller.player()?) {
+            Some(client) => Ok(vec![format!("Network: {}", client.postoffice.stats())]),
+            None => Ok(vec![]),
+        },
+    );
+
+    cmds.add(
+        "broadcast",
+        vec![Param::required("message", ArgKind::Text)],
+        "Announce a message to every player",
+        Role::Admin,
+        |srv, _, args| {
+            srv.broadcast_chat_msg(&format!("[Server] {}", args.word("message")?));
+            Ok(vec![])
+        },
+    );
+
+    cmds.add(
+        "kick",
+        vec![
+            Param::required("alias", ArgKind::Alias),
+            Param::optional("reason", ArgKind::Text),
+        ],
+        "Disconnect a player",
+        Role::Admin,
+        |srv, caller, args| {
+            let tgt = target(srv, args.word("alias")?);
+            check_rank(srv, caller, &tgt)?;
+            let reason = args.word("reason").unwrap_or("No reason given").to_string();
+            srv.disconnect_player(args.player("alias")?, DisconnectReason::Kicked(reason));
+            Ok(vec![])
+        },
+    );
+
+    cmds.add(
+        "ban",
+        vec![
+            Param::required("name", ArgKind::Word),
+            Param::optional("reason", ArgKind::Text),
+        ],
+        "Keep a player from joining",
+        Role::Admin,
+        |srv, caller, args| {
+            let tgt = target(srv, args.word("name")?);
+            check_rank(srv, caller, &tgt)?;
+            let reason = args.word("reason").unwrap_or("No reason given").to_string();
+            if srv.perms.ban(&tgt.key, &reason).is_err() {
+                return Err(CmdError::Failed("Could not save the ban".to_string()));
+            }
+            if let Some(tgt_player) = tgt.player {
+                srv.disconnect_player(tgt_player, DisconnectReason::Banned(reason));
+            }
+            Ok(vec![format!("Banned {}", tgt.key)])
+        },
+    );
+
+    cmds.add(
+        "unban",
+        vec![Param::required("name", ArgKind::Word)],
+        "Allow a banned player to join again",
+        Role::Admin,
+        |srv, _, args| {
+            let name = args.word("name")?;
+            match srv.perms.unban(name) {
+                Ok(true) => Ok(vec![format!("Unbanned {}", name)]),
+                Ok(false) => Err(CmdError::Failed(format!("{} isn't banned", name))),
+                Err(_) => Err(CmdError::Failed("Could not save the ban".to_string())),
+            }
+        },
+    );
+
+    cmds.add(
+        "op",
+        vec![
+            Param::required("name", ArgKind::Word),
+            Param::optional("role", ArgKind::Choice(ROLES)),
+        ],
+        "Give a player a role, moderator unless told otherwise",
+        Role::Admin,
+        |srv, caller, args| {
+            let role = args
+                .word("role")
+                .unwrap_or("moderator")
+                .parse()
+                .unwrap_or(Role::Moderator);
+            set_role(srv, caller, args.word("name")?, role)
+        },
+    );
+
+    cmds.add(
+        "deop",
+        vec![Param::required("name", ArgKind::Word)],
+        "Take a player's role away",
+        Role::Admin,
+        |srv, caller, args| set_role(srv, caller, args.word("name")?, Role::Player),
+    );
+}
+
+fn set_role<P: Payloads>(srv: &mut Server<P>, caller: Caller, name: &str, role: Role) -> Result<Vec<String>, CmdError> {
+    // Roles can't be handed out beyond one's own, nor taken from those above
+    let tgt = target(srv, name);
+    check_rank(srv, caller, &tgt)?;
+    if srv.role_of_caller(caller).map_or(true, |own| role > own) {
+        return Err(CmdError::Failed("You can't give roles above your own".to_string()));
+    }
+    if !tgt.account {
+        return Err(CmdError::Failed(format!("{} isn't logged in to an account", tgt.key)));
+    }
+    if srv.perms.set_role(&tgt.key, role).is_err() {
+        return Err(CmdError::Failed("Could not save the role".to_string()));
+    }
+
+    if let Some(tgt_player) = tgt.player {
+        srv.do_for_comp_mut::<Player, _, _>(tgt_player, |player_comp| player_comp.role = role);
+        srv.send_chat_msg(tgt_player, &format!("You are now a {}", role));
+    }
+    Ok(vec![format!("{} is now a {}", tgt.key, role)])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cmd::Caller,
+        perms::{Role, PERMS_FILE},
+        player::Player,
+        testutils::{self, DataDir, TestPayloads},
+        Server, Wrapper,
+    };
+    use common::util::msg::{PlayMode, ServerMsg};
+    use parking_lot::RwLock;
+    use specs::Entity;
+    use std::fs;
+
+    fn run(srv: &mut Server<TestPayloads>, player: Entity, line: &str) -> Result<Vec<String>, Vec<String>> {
+        let cmds = srv.commands.clone();
+        cmds.run(srv, Caller::Player(player), line.split_whitespace())
+    }
+
+    fn role(srv: &Server<TestPayloads>, player: Entity) -> Role {
+        srv.world.read_storage::<Player>().get(player).unwrap().role
+    }
+
+    #[test]
+    fn commands_answer_with_cmd_result() {
+        let dir = DataDir::new("msg-cmd-result");
+        let mut srv = testutils::server(&dir);
+        let (player, client) = testutils::connect(&mut srv, "player", None, PlayMode::Headless);
+        let srv = Wrapper(RwLock::new(srv));
+        client.recv_all();
+
+        let results = |line: &str| {
+            super::process_cmd(&srv, line.split(' ').map(str::to_string).collect(), player);
+            client
+                .recv_all()
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    ServerMsg::CmdResult { success, lines } => Some((success, lines)),
+                    ServerMsg::ChatMsg { text } => panic!("Answered in chat: {}", text),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let lines = |lines: &[&str]| lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(results("players"), vec![(true, lines(&["Online Players: player"]))]);
+        assert_eq!(
+            results("settime 5"),
+            vec![(false, lines(&["You don't have permission to use /settime"]))]
+        );
+        assert_eq!(
+            results("alias"),
+            vec![(false, lines(&["<alias> is missing", "Usage: /alias <alias>"]))]
+        );
+        assert_eq!(
+            results("fly"),
+            vec![(false, lines(&["Unrecognised command /fly, try /help"]))]
+        );
+    }
+
+    #[test]
+    fn op_and_deop() {
+        let dir = DataDir::new("msg-op");
+        let mut srv = testutils::server(&dir);
+        srv.perms.set_role("boss", Role::Admin).unwrap();
+        let (boss, _boss_client) = testutils::connect(&mut srv, "boss", Some("boss"), PlayMode::Headless);
+        let (member, _member_client) = testutils::connect(&mut srv, "member", Some("member"), PlayMode::Headless);
+        let (guest, _guest_client) = testutils::connect(&mut srv, "guest", None, PlayMode::Headless);
+
+        // Nobody can make themselves an operator
+        assert!(run(&mut srv, member, "op member").is_err());
+        assert!(run(&mut srv, guest, "op guest admin").is_err());
+        assert_eq!(srv.perms.role_of("member"), Role::Player);
+
+        assert!(run(&mut srv, boss, "op member").is_ok());
+        assert_eq!(srv.perms.role_of("member"), Role::Moderator);
+        assert_eq!(role(&srv, member), Role::Moderator);
+        // Moderators still can't hand out roles
+        assert!(run(&mut srv, member, "op member admin").is_err());
+        assert!(run(&mut srv, member, "deop boss").is_err());
+        assert_eq!(role(&srv, boss), Role::Admin);
+
+        // Only accounts get roles
+        assert!(run(&mut srv, boss, "op guest").is_err());
+        assert_eq!(srv.perms.role_of("guest"), Role::Player);
+        assert!(run(&mut srv, boss, "op member wizard").is_err());
+
+        assert!(run(&mut srv, boss, "deop member").is_ok());
+        assert_eq!(srv.perms.role_of("member"), Role::Player);
+        assert_eq!(role(&srv, member), Role::Player);
+    }
+
+    #[test]
+    fn kick_ban_and_unban() {
+        let dir = DataDir::new("msg-ban");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join(PERMS_FILE), "[commands]\nban = \"moderator\"\n").unwrap();
+        {
+            let mut srv = testutils::server(&dir);
+            srv.perms.set_role("boss", Role::Admin).unwrap();
+            srv.perms.set_role("warden", Role::Moderator).unwrap();
+            let (boss, _boss_client) = testutils::connect(&mut srv, "boss", Some("boss"), PlayMode::Headless);
+            let (warden, _warden_client) = testutils::connect(&mut srv, "warden", Some("warden"), PlayMode::Headless);
+            let (member, _member_client) = testutils::connect(&mut srv, "member", Some("member"), PlayMode::Headless);
+            let (guest, _guest_client) = testutils::connect(&mut srv, "guest", None, PlayMode::Headless);
+
+            assert!(run(&mut srv, member, "kick guest").is_err());
+            assert!(srv.world.is_alive(guest));
+            assert!(run(&mut srv, boss, "kick guest being rude").is_ok());
+            assert!(!srv.world.is_alive(guest));
+            assert!(run(&mut srv, boss, "kick guest").is_err());
+
+            // Players can't ban at all, moderators only those of their own role or below
+            assert!(run(&mut srv, member, "ban boss").is_err());
+            assert_eq!(
+                run(&mut srv, warden, "ban boss"),
+                Err(vec!["boss has a higher role than you".to_string()])
+            );
+            assert_eq!(srv.perms.ban_of("boss"), None);
+
+            assert!(run(&mut srv, boss, "ban member griefing the spawn").is_ok());
+            assert!(!srv.world.is_alive(member));
+            assert_eq!(srv.perms.ban_of("member"), Some("griefing the spawn".to_string()));
+            // Players that are offline can be banned too
+            assert!(run(&mut srv, boss, "ban mallory").is_ok());
+        }
 
-            //we have a time to set the server to
-            srv.do_for_mut(|srv| {
-                srv.clock_tick_time = time::Duration::from_secs(t);
-            });
-
-            srv.do_for(|srv| {
-                srv.sync_player_time();
-                srv.send_chat_msg(player, &format!("Set time to {}", t));
-                if let Some(palias) = srv.do_for_comp::<Player, _, _>(player, |player_comp| player_comp.alias.clone()) {
-                    //This *should* always happen since the command *should* be sent by players only
-                    srv.broadcast_chat_msg(&format!("[{} set time to {}s]", palias, t));
-                }
-            });
-        },
-        _ => srv.do_for(|srv| srv.send_chat_msg(player, "Unrecognised command!")),
+        // Bans are kept across restarts
+        let mut srv = testutils::server(&dir);
+        assert_eq!(srv.perms.ban_of("member"), Some("griefing the spawn".to_string()));
+        assert_eq!(srv.perms.ban_of("mallory"), Some("No reason given".to_string()));
+        let (boss, _boss_client) = testutils::connect(&mut srv, "boss", Some("boss"), PlayMode::Headless);
+        assert_eq!(role(&srv, boss), Role::Admin);
+        assert!(run(&mut srv, boss, "unban member").is_ok());
+        assert!(run(&mut srv, boss, "unban member").is_err());
+        assert_eq!(srv.perms.ban_of("member"), None);
     }
 }
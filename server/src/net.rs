@@ -1,175 +1,335 @@
 // Standard
 use std::{
-    fmt,
-    sync::{atomic::Ordering, Arc},
-    thread,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{RecvTimeoutError, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 // Library
+use parking_lot::Mutex;
 use specs::{saveload::Marker, Builder, Component, Entity, Join, VecStorage};
 
 // Project
 use common::{
-    ecs::{
-        net::UidMarker,
-        phys::{Dir, Pos, Vel},
-        NetComp,
-    },
+    ecs::{net::UidMarker, phys::Pos, NetComp},
     util::{
         manager::Manager,
-        msg::{ClientMsg, ServerMsg, ServerPostOffice, SessionKind},
+        msg::{
+            ClientMsg, PlayMode, ResumeToken, ServerMsg, ServerPostBox, ServerPostOffice, SessionKind, Version,
+            FEATURE_LZ4,
+        },
+        ping::Pinger,
         post::Incoming,
     },
 };
+use world::World as WorldGen;
 
 // Local
-use crate::{api::Api, msg::process_chat_msg, Error, Payloads, Server, Wrapper};
+use crate::{
+    account,
+    api::Api,
+    msg::{process_chat_msg, process_cmd},
+    player::Player,
+    Error, Payloads, Server, Wrapper, IDLE_TICK,
+};
 
 // Constants
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 const PING_TIMEOUT: Duration = Duration::from_secs(10);
 const PING_FREQ: Duration = Duration::from_secs(2);
+const RESUME_GRACE: Duration = Duration::from_secs(60);
 
 // Server
 
 #[derive(Debug)]
 pub struct Client {
     pub postoffice: Arc<Manager<ServerPostOffice>>,
+    pub resume_token: ResumeToken,
+    ping: Mutex<(ServerPostBox, Pinger)>,
+}
+
+impl Client {
+    pub fn new(po: Manager<ServerPostOffice>, resume_token: ResumeToken) -> Client {
+        let pb = po.create_postbox(SessionKind::Ping);
+        Client {
+            postoffice: Arc::new(po),
+            resume_token,
+            ping: Mutex::new((pb, Pinger::new(PING_FREQ, PING_TIMEOUT))),
+        }
+    }
+
+    // Ping the client and answer its pings. Returns false once it stopped answering
+    fn ping(&self, now: Instant) -> bool {
+        let mut ping = self.ping.lock();
+        let (pb, pinger) = &mut *ping;
+        if pinger.timed_out(now) {
+            return false;
+        }
+        if let Some(id) = pinger.poll(now) {
+            if let Err(_) = pb.send(ServerMsg::Ping { id }) {
+                return false;
+            }
+        }
+        loop {
+            match pb.try_recv() {
+                Ok(ClientMsg::Ping { id }) => {
+                    if let Err(_) = pb.send(ServerMsg::Pong { id }) {
+                        return false;
+                    }
+                },
+                Ok(ClientMsg::Pong { id }) => {
+                    if let Some(rtt) = pinger.pong(id, Instant::now()) {
+                        self.postoffice.record_rtt(rtt);
+                    }
+                },
+                Err(TryRecvError::Empty) => return true,
+                _ => return false, // Anything other than a ping over this session is invalid
+            }
+        }
+    }
 }
 
 impl Component for Client {
     type Storage = VecStorage<Self>;
 }
 
-// DisconnectReason
+// A player whose connection was lost, kept until RESUME_GRACE has passed
+#[derive(Debug)]
+pub struct Detached {
+    pub token: ResumeToken,
+    pub since: Instant,
+}
 
-pub enum DisconnectReason {
-    Logout,
-    Timeout,
-    Kicked(String),
+impl Component for Detached {
+    type Storage = VecStorage<Self>;
 }
 
-impl fmt::Display for DisconnectReason {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                DisconnectReason::Logout => format!("Logout"),
-                DisconnectReason::Timeout => format!("Timedout"),
-                DisconnectReason::Kicked(msg) => format!("Kicked ({})", msg),
+// A session or message arriving for a player, along with the connection it arrived on
+pub(crate) type Post = (
+    Entity,
+    Arc<Manager<ServerPostOffice>>,
+    Incoming<SessionKind, ServerMsg, ClientMsg>,
+);
+
+// What a client asks for in its first message
+enum Hello {
+    Connect {
+        alias: String,
+        account: Option<String>,
+        mode: PlayMode,
+    },
+    Resume(ResumeToken),
+}
+
+// Reexports
+pub use common::util::msg::DisconnectReason;
+
+// Set up a new connection, its first session tells what the client wants. Connections that don't open one within
+// CONNECT_TIMEOUT are dropped, and so is every one still waiting when the server shuts down
+pub(crate) fn handle_connection<P: Payloads>(
+    srv: &Wrapper<Server<P>>,
+    po: Manager<ServerPostOffice>,
+    running: &AtomicBool,
+) {
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let session = loop {
+        match po.await_incoming_timeout(IDLE_TICK) {
+            Ok(Incoming::Session(session)) => break session,
+            Err(RecvTimeoutError::Timeout) if running.load(Ordering::Relaxed) && Instant::now() < deadline => {},
+            _ => return,
+        }
+    };
+    match session.kind {
+        SessionKind::Connect => {
+            if let Ok(player) = auth_client(srv, po, session.postbox) {
+                handle_player_post(srv, player);
             }
-        )
+        },
+        // Registering or logging in doesn't make a player
+        SessionKind::Account => account::handle_account(srv, &po, session.postbox),
+        _ => {},
     }
 }
 
-// Authenticate a client. If authentication is successful,
+// Authenticate a client. If authentication is successful, the player is created
 pub(crate) fn auth_client<P: Payloads>(
     srv: &Wrapper<Server<P>>,
     po: Manager<ServerPostOffice>,
+    pb: ServerPostBox,
 ) -> Result<Entity, Error> {
-    // Perform a connection handshake. If everything works out, create the player
-    // First, wait for the correct `Connect` session
-    let session = if let Ok(Incoming::Session(s)) = po.await_incoming() {
-        s
-    } else {
-        return Err(Error::NoConnectSession);
+    // Wait for a ClientMsg::Connect, thereby committing the client to connecting, or for a Resume of a lost connection
+    let (version, hello) = match pb.recv_timeout(CONNECT_TIMEOUT) {
+        Ok(ClientMsg::Connect { version, alias, mode }) => (
+            version,
+            Hello::Connect {
+                alias,
+                account: None,
+                mode,
+            },
+        ),
+        Ok(ClientMsg::Resume { version, token }) => (version, Hello::Resume(token)),
+        _ => return Err(Error::NoConnectMsg),
     };
 
-    // Verify that the first session is a SessionKind::Connect
-    if let SessionKind::Connect = session.kind {
-    } else {
-        return Err(Error::InvalidConnectSession);
+    // Turn away clients that speak a different protocol, and tell them why
+    let server_version = Version::current();
+    if !server_version.is_compatible(&version) {
+        let _ = pb.send(ServerMsg::Disconnect {
+            reason: DisconnectReason::IncompatibleVersion {
+                server: server_version,
+                client: version.clone(),
+            },
+        });
+        return Err(Error::IncompatibleVersion(version));
     }
 
-    // Wait for a ClientMsg::Connect, thereby committing the client to connecting
-    let (alias, mode) = if let Ok(ClientMsg::Connect { alias, mode }) = session.postbox.recv_timeout(CONNECT_TIMEOUT) {
-        (alias, mode)
-    } else {
-        return Err(Error::NoConnectMsg);
+    // The client exchanges keys before opening the session, so nothing it sends from here on travels in plaintext
+    if !po.is_encrypted() {
+        return Err(Error::Unencrypted);
+    }
+    po.set_compression(version.supports(FEATURE_LZ4));
+
+    // A new player has to log in to an account, or play as a guest
+    let hello = match hello {
+        Hello::Connect { alias, mode, .. } => {
+            let credentials = match pb.recv_timeout(CONNECT_TIMEOUT) {
+                Ok(ClientMsg::Authenticate { credentials }) => credentials,
+                _ => return Err(Error::NoConnectMsg),
+            };
+            let (alias, account) = match srv.do_for(|srv| srv.authenticate(alias, credentials)) {
+                Ok(login) => login,
+                Err(e) => {
+                    let _ = pb.send(ServerMsg::Disconnect {
+                        reason: DisconnectReason::LoginFailed(e),
+                    });
+                    return Err(Error::LoginFailed);
+                },
+            };
+            // Banned players are turned away, by account or by alias for guests
+            if let Some(reason) = srv.do_for(|srv| srv.perms.ban_of(account.as_ref().unwrap_or(&alias))) {
+                let _ = pb.send(ServerMsg::Disconnect {
+                    reason: DisconnectReason::Banned(reason),
+                });
+                return Err(Error::Banned);
+            }
+            Hello::Connect { alias, account, mode }
+        },
+        hello => hello,
     };
 
-    // Create the player's entity and return it
-    let (player, player_uid) = srv.do_for_mut(|srv| {
-        // Notify all other players
-        srv.broadcast_chat_msg(&format!("[{} has joined the server]", alias));
-
-        // Create a new player
-        let player = srv.create_player(alias.clone(), mode, po).build();
-
-        // Force an update to the player position to inform them where they are
-        srv.force_comp::<Pos>(player);
-
-        // Run the connecting player past the payload interface
-        srv.payload.on_player_connect(srv, player);
-
-        // Find the uid for the player's character entity (if the player has a character)
-        let player_uid = srv.world.read_storage::<UidMarker>().get(player).map(|sm| sm.id());
-        (player, player_uid)
-    });
+    // A fresh token every time, so a token can't be used twice
+    let resume_token = ResumeToken::generate();
+
+    // Create the player's entity, or take over the one of the lost connection, and return it
+    let (player, player_uid) = match hello {
+        Hello::Connect { alias, account, mode } => srv.do_for_mut(|srv| {
+            // Notify all other players
+            srv.broadcast_chat_msg(&format!("[{} has joined the server]", alias));
+
+            // Create a new player
+            let player = srv
+                .create_player(alias.clone(), account, mode, po, resume_token)
+                .build();
+            // Continue where the player left off last time
+            srv.load_player(player);
+
+            // Force an update to the player position to inform them where they are
+            srv.force_comp::<Pos>(player);
+
+            // Run the connecting player past the payload interface
+            srv.payload.on_player_connect(srv, player);
+
+            // Find the uid for the player's character entity (if the player has a character)
+            let player_uid = srv.world.read_storage::<UidMarker>().get(player).map(|sm| sm.id());
+            (player, player_uid)
+        }),
+        Hello::Resume(token) => {
+            let resumed: Result<_, Manager<ServerPostOffice>> = srv.do_for_mut(|srv| {
+                let player = srv.resume_player(token, po, resume_token)?;
+                if let Some(player_comp) = srv.world.read_storage::<Player>().get(player) {
+                    srv.broadcast_chat_msg(&format!("[{} reconnected]", player_comp.alias));
+                }
+                srv.force_comp::<Pos>(player);
+                Ok((
+                    player,
+                    srv.world.read_storage::<UidMarker>().get(player).map(|sm| sm.id()),
+                ))
+            });
+            match resumed {
+                Ok(resumed) => resumed,
+                Err(po) => {
+                    let _ = pb.send(ServerMsg::Disconnect {
+                        reason: DisconnectReason::SessionExpired,
+                    });
+                    // Dropping the postoffice delivers the reason before closing
+                    drop(po);
+                    return Err(Error::SessionExpired);
+                },
+            }
+        },
+    };
 
     // Inform the client that they've successfully connected
-    let _ = session.postbox.send(ServerMsg::Connected {
+    let _ = pb.send(ServerMsg::Connected {
+        version: server_version,
         player_uid,
         time: srv.do_for(|srv| srv.clock_tick_time),
+        resume_token,
+        world_seed: WorldGen::seed(),
     });
 
     Ok(player)
 }
 
-pub(crate) fn handle_player_post<P: Payloads>(
-    srv: &Wrapper<Server<P>>,
-    player: Entity,
-    mut mgr: Manager<Wrapper<Server<P>>>,
-) {
-    // Ping worker
-    Manager::add_worker(&mut mgr, move |srv, running, _| {
-        if let Some(pb) = srv.do_for(|srv| {
-            srv.world
-                .read_storage::<Client>()
-                .get(player)
-                .map(|p| p.postoffice.create_postbox(SessionKind::Ping))
-        }) {
-            // Wait for pings, respond with another ping
-            while running.load(Ordering::Relaxed) {
-                thread::sleep(PING_FREQ);
-
-                // Send a ping response
-                if let Err(_) = pb.send(ServerMsg::Ping) {
-                    break;
-                }
-
-                // Await a ping response from the client
-                match pb.recv_timeout(PING_TIMEOUT) {
-                    Ok(ClientMsg::Ping) => {},
-                    _ => break, // Anything other than a ping over this session is invalid
-                }
-            }
-
-            // Kick the player if the ping expires
-            srv.do_for_mut(|srv| srv.disconnect_player(player, DisconnectReason::Timeout));
-        }
-    });
-
-    // Await incoming sessions and one-shot messages
-    if let Some(po) = srv.do_for(|srv| {
+// Hand everything arriving on the player's connection to the server's post worker
+pub(crate) fn handle_player_post<P: Payloads>(srv: &Wrapper<Server<P>>, player: Entity) {
+    let (po, post) = match srv.do_for(|srv| {
         srv.world
             .read_storage::<Client>()
             .get(player)
-            .map(|p| p.postoffice.clone())
+            .map(|c| (c.postoffice.clone(), srv.post_send.lock().clone()))
     }) {
-        while let Ok(msg) = po.await_incoming() {
-            match msg {
-                Incoming::Session(_session) => {}, // TODO: Something here
-                Incoming::Msg(msg) => handle_oneshot(srv, msg, player, &mgr),
-                Incoming::End => break,
-            }
+        Some(client) => client,
+        None => return,
+    };
+
+    // The postoffice holds on to this, so it only gets a weak reference back
+    let po_ref = Arc::downgrade(&po);
+    let post = Mutex::new(post);
+    po.forward_incoming(move |incoming| {
+        if let Some(po) = po_ref.upgrade() {
+            let _ = post.lock().send((player, po, incoming));
         }
-    }
+    });
+}
 
-    // Disconnect the client
-    srv.do_for_mut(|srv| srv.disconnect_player(player, DisconnectReason::Logout));
+// Handle one session or message of a player. The connection it arrived on is passed along, if the client resumed on
+// another one since, it's left alone
+pub(crate) fn handle_post<P: Payloads>(
+    srv: &Wrapper<Server<P>>,
+    player: Entity,
+    po: &Arc<Manager<ServerPostOffice>>,
+    incoming: Incoming<SessionKind, ServerMsg, ClientMsg>,
+    mgr: &Manager<Wrapper<Server<P>>>,
+) {
+    if !srv.do_for(|srv| srv.is_attached(player, po)) {
+        return;
+    }
+    match incoming {
+        // The client is logging out
+        Incoming::Session(session) => match session.kind {
+            SessionKind::Disconnect => {
+                srv.do_for_mut(|srv| srv.disconnect_client(player, po, DisconnectReason::Logout))
+            },
+            _ => {},
+        },
+        Incoming::Msg(msg) => handle_oneshot(srv, msg, player, mgr),
+        // A connection that just ends was lost rather than closed
+        Incoming::End => srv.do_for_mut(|srv| srv.disconnect_client(player, po, DisconnectReason::ConnectionLost)),
+    }
 }
 
 pub(crate) fn handle_oneshot<P: Payloads>(
@@ -180,19 +340,83 @@ pub(crate) fn handle_oneshot<P: Payloads>(
 ) {
     match msg {
         ClientMsg::ChatMsg { text } => process_chat_msg(srv, text, player, mgr),
-        ClientMsg::PlayerEntityUpdate { pos, vel, dir } => {
-            // Update the player's entity
-            srv.do_for_mut(|srv| {
-                srv.update_comp(player, Pos(pos));
-                srv.update_comp(player, Vel(vel));
-                srv.update_comp(player, Dir(dir));
-            });
-        },
+        ClientMsg::Cmd { args } => process_cmd(srv, args, player),
+        ClientMsg::PlayerInputs { inputs } => srv.do_for_mut(|srv| srv.handle_inputs(player, inputs)),
+        ClientMsg::SnapshotAck { id } => srv.do_for_mut(|srv| srv.ack_snapshot(player, id)),
+        ClientMsg::RequestChunk { pos } => srv.do_for_mut(|srv| srv.request_chunk(player, pos)),
+        ClientMsg::SetBlock { pos, block } => srv.do_for(|srv| srv.handle_set_block(player, pos, block)),
         _ => {},
     }
 }
 
 impl<P: Payloads> Server<P> {
+    /// Whether the player is still on the given connection. It may have resumed on another one since.
+    pub(crate) fn is_attached(&self, player: Entity, po: &Arc<Manager<ServerPostOffice>>) -> bool {
+        self.world
+            .read_storage::<Client>()
+            .get(player)
+            .map(|c| Arc::ptr_eq(&c.postoffice, po))
+            .unwrap_or(false)
+    }
+
+    /// Disconnect a player, but only if it's still on the given connection
+    pub(crate) fn disconnect_client(
+        &mut self,
+        player: Entity,
+        po: &Arc<Manager<ServerPostOffice>>,
+        reason: DisconnectReason,
+    ) {
+        if self.is_attached(player, po) {
+            self.disconnect_player(player, reason);
+        }
+    }
+
+    /// Ping every client, returns the ones that timed out
+    pub(crate) fn ping_clients(&self) -> Vec<(Entity, Arc<Manager<ServerPostOffice>>)> {
+        let now = Instant::now();
+        (&self.world.entities(), &self.world.read_storage::<Client>())
+            .join()
+            .filter(|(_, client)| !client.ping(now))
+            .map(|(player, client)| (player, client.postoffice.clone()))
+            .collect()
+    }
+
+    /// Attach a new connection to the detached player holding the token. Gives the postoffice back if there is none.
+    pub(crate) fn resume_player(
+        &mut self,
+        token: ResumeToken,
+        po: Manager<ServerPostOffice>,
+        resume_token: ResumeToken,
+    ) -> Result<Entity, Manager<ServerPostOffice>> {
+        let player = (&self.world.entities(), &self.world.read_storage::<Detached>())
+            .join()
+            .find(|(_, detached)| detached.token == token)
+            .map(|(player, _)| player);
+        let player = match player {
+            Some(player) => player,
+            None => return Err(po),
+        };
+
+        self.world.write_storage::<Detached>().remove(player);
+        let _ = self
+            .world
+            .write_storage::<Client>()
+            .insert(player, Client::new(po, resume_token));
+        Ok(player)
+    }
+
+    /// Remove players whose connection was lost longer than RESUME_GRACE ago
+    pub(crate) fn expire_detached(&mut self) {
+        let expired = (&self.world.entities(), &self.world.read_storage::<Detached>())
+            .join()
+            .filter(|(_, detached)| detached.since.elapsed() > RESUME_GRACE)
+            .map(|(player, _)| player)
+            .collect::<Vec<_>>();
+        for player in expired {
+            self.disconnect_player(player, DisconnectReason::SessionExpired);
+        }
+    }
+
     /// Update the value of a component. Returns `true` if the component exists, and `false` otherwise.
     #[allow(dead_code)]
     pub(crate) fn update_comp<T: NetComp + Clone>(&mut self, entity: Entity, comp: T) -> bool {
@@ -218,10 +442,10 @@ impl<P: Payloads> Server<P> {
         self.world.read_storage::<T>().get(entity).map(|c| f(c))
     }
 
-    /// Update clients of a component's value, excepting those clients for whom that component is attributed
-    /// (e.g: a client won't get it's own player position sent back to it)
+    /// Update all clients that have the entity in view, and the entity's own client, of a component's value,
+    /// overriding any other values a client may have had
     #[allow(dead_code)]
-    pub(crate) fn notify_comp<T: NetComp>(&self, entity: Entity) {
+    pub(crate) fn force_comp<T: NetComp + Clone>(&self, entity: Entity) {
         // Convert the component (if it exists and if it support it) to a CompStore
         let store = if let Some(Some(s)) = self.world.read_storage::<T>().get(entity).map(|c| c.to_store()) {
             s
@@ -237,16 +461,8 @@ impl<P: Payloads> Server<P> {
         };
 
         // Send the store to all clients that need it
-        for (client_uid, client) in (
-            &self.world.read_storage::<UidMarker>(),
-            &self.world.read_storage::<Client>(),
-        )
-            .join()
-        {
-            let client_uid = client_uid.id();
-
-            // Don't notify a client of information concerning itself
-            if client_uid != entity_uid {
+        for (player, client) in (&self.world.entities(), &self.world.read_storage::<Client>()).join() {
+            if self.is_interested(player, entity) {
                 let _ = client.postoffice.send_one(ServerMsg::CompUpdate {
                     uid: entity_uid,
                     store: store.clone(),
@@ -255,40 +471,245 @@ impl<P: Payloads> Server<P> {
         }
     }
 
-    /// Update *all* clients of a component's value, overriding any other values a client may have had
-    #[allow(dead_code)]
-    pub(crate) fn force_comp<T: NetComp + Clone>(&self, entity: Entity) {
-        // Convert the component (if it exists and if it support it) to a CompStore
-        let store = if let Some(Some(s)) = self.world.read_storage::<T>().get(entity).map(|c| c.to_store()) {
-            s
-        } else {
-            return;
+    pub(crate) fn sync_player_time(&self) { self.broadcast_net_msg(ServerMsg::TimeUpdate(self.clock_tick_time)); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{auth_client, Client, Detached, DisconnectReason, RESUME_GRACE};
+    use crate::{
+        api::Api,
+        testutils::{self, DataDir, TestPayloads},
+        Error, Server, Wrapper,
+    };
+    use common::{
+        ecs::net::UidMarker,
+        net::Memory,
+        util::{
+            manager::Manager,
+            msg::{
+                ClientMsg, ClientPostOffice, Credentials, PlayMode, ResumeToken, ServerMsg, ServerPostOffice,
+                SessionKind, Version, FEATURE_LZ4, PROTOCOL_VERSION,
+            },
+            post::Incoming,
+        },
+    };
+    use parking_lot::RwLock;
+    use specs::{saveload::Marker, Entity};
+    use std::time::{Duration, Instant};
+
+    // Open a new connection and send `msgs` on its connect session, like a client does. Returns the server's verdict,
+    // its answer and the client's end of the connection
+    fn handshake(
+        srv: &Wrapper<Server<TestPayloads>>,
+        msgs: Vec<ClientMsg>,
+    ) -> (Result<Entity, Error>, ServerMsg, Manager<ClientPostOffice>) {
+        let (remote, local) = Memory::pair();
+        let client = ClientPostOffice::to_server_with(Box::new(remote)).unwrap();
+        let identity = srv.do_for(|srv| srv.identity.clone());
+        let po = ServerPostOffice::to_client_with(Box::new(local), identity.clone()).unwrap();
+        assert_eq!(client.exchange_keys(Duration::from_secs(5)).unwrap(), identity.public());
+        let pb = client.create_postbox(SessionKind::Connect);
+        for msg in msgs {
+            pb.send(msg).unwrap();
+        }
+
+        let session = match po.await_incoming() {
+            Ok(Incoming::Session(session)) => session,
+            _ => panic!("expected the connect session"),
         };
+        let result = auth_client(srv, po, session.postbox);
+        (result, pb.recv_timeout(Duration::from_secs(5)).unwrap(), client)
+    }
 
-        // Find the UID of the entity we're notifying clients of
-        let entity_uid = if let Some(u) = self.world.read_storage::<UidMarker>().get(entity) {
-            u.id()
-        } else {
-            return;
+    // Like a client that reconnects
+    fn resume(srv: &Wrapper<Server<TestPayloads>>, token: ResumeToken) -> (Result<Entity, Error>, ServerMsg) {
+        let hello = ClientMsg::Resume {
+            version: Version::current(),
+            token,
+        };
+        let (result, answer, _) = handshake(srv, vec![hello]);
+        (result, answer)
+    }
+
+    fn connect(
+        srv: &Wrapper<Server<TestPayloads>>,
+        alias: &str,
+        version: Version,
+    ) -> (Result<Entity, Error>, ServerMsg, Manager<ClientPostOffice>) {
+        let hello = ClientMsg::Connect {
+            version,
+            alias: alias.to_string(),
+            mode: PlayMode::Headless,
+        };
+        handshake(
+            srv,
+            vec![
+                hello,
+                ClientMsg::Authenticate {
+                    credentials: Credentials::Guest,
+                },
+            ],
+        )
+    }
+
+    fn token(srv: &Server<TestPayloads>, player: Entity) -> ResumeToken {
+        srv.world.read_storage::<Client>().get(player).unwrap().resume_token
+    }
+
+    // What the post worker does once the connection of a player ends
+    fn lose_connection(srv: &mut Server<TestPayloads>, player: Entity) {
+        let po = srv
+            .world
+            .read_storage::<Client>()
+            .get(player)
+            .unwrap()
+            .postoffice
+            .clone();
+        srv.disconnect_client(player, &po, DisconnectReason::ConnectionLost);
+    }
+
+    fn is_expired(answer: &ServerMsg) -> bool {
+        match answer {
+            ServerMsg::Disconnect {
+                reason: DisconnectReason::SessionExpired,
+            } => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn resume_takes_the_player_over() {
+        let dir = DataDir::new("net-resume");
+        let mut srv = testutils::server(&dir);
+        let (player, client) = testutils::connect(&mut srv, "player", None, PlayMode::Character);
+        let uid = srv.world.read_storage::<UidMarker>().get(player).unwrap().id();
+        let old_token = token(&srv, player);
+        drop(client);
+        lose_connection(&mut srv, player);
+        assert!(srv.world.read_storage::<Detached>().get(player).is_some());
+
+        let srv = Wrapper(RwLock::new(srv));
+        let (result, answer) = resume(&srv, old_token);
+        assert_eq!(result.ok(), Some(player));
+        let new_token = match answer {
+            ServerMsg::Connected {
+                player_uid,
+                resume_token,
+                ..
+            } => {
+                assert_eq!(player_uid, Some(uid));
+                resume_token
+            },
+            _ => panic!("expected the player back"),
         };
+        srv.do_for(|srv| {
+            assert!(srv.world.read_storage::<Detached>().get(player).is_none());
+            assert_eq!(token(srv, player), new_token);
+        });
+
+        // Every token is good for one resume only
+        assert_ne!(new_token, old_token);
+        let (result, answer) = resume(&srv, old_token);
+        assert!(result.is_err());
+        assert!(is_expired(&answer));
+    }
+
+    #[test]
+    fn sessions_expire_after_the_grace_period() {
+        let dir = DataDir::new("net-expire");
+        let mut srv = testutils::server(&dir);
+        let (player, client) = testutils::connect(&mut srv, "player", None, PlayMode::Character);
+        let token = token(&srv, player);
+        drop(client);
+        lose_connection(&mut srv, player);
+
+        // Still within the grace period
+        srv.expire_detached();
+        assert!(srv.world.is_alive(player));
+
+        srv.world.write_storage::<Detached>().get_mut(player).unwrap().since =
+            Instant::now() - RESUME_GRACE - Duration::from_secs(1);
+        srv.expire_detached();
+        assert!(!srv.world.is_alive(player));
+
+        let srv = Wrapper(RwLock::new(srv));
+        let (result, answer) = resume(&srv, token);
+        match result {
+            Err(Error::SessionExpired) => {},
+            _ => panic!("expected the session to be expired"),
+        }
+        assert!(is_expired(&answer));
+    }
 
-        // Send the store to all clients
-        self.broadcast_net_msg(ServerMsg::CompUpdate {
-            uid: entity_uid,
-            store: store.clone(),
+    #[test]
+    fn tokens_of_connected_players_are_refused() {
+        let dir = DataDir::new("net-foreign-token");
+        let mut srv = testutils::server(&dir);
+        let (victim, _victim_client) = testutils::connect(&mut srv, "victim", None, PlayMode::Character);
+        let token = token(&srv, victim);
+
+        // Another client can't take over a player that's still connected, or make up a token
+        let srv = Wrapper(RwLock::new(srv));
+        let (result, answer) = resume(&srv, token);
+        assert!(result.is_err());
+        assert!(is_expired(&answer));
+        let (result, _) = resume(&srv, ResumeToken::generate());
+        assert!(result.is_err());
+        srv.do_for(|srv| {
+            assert!(srv.world.read_storage::<Client>().get(victim).is_some());
+            assert!(srv.world.read_storage::<Detached>().get(victim).is_none());
         });
     }
 
-    pub(crate) fn sync_players(&self) {
-        // For each entity in the world...
-        // TODO: Add a notion of range? Don't update clients of entities that are nowhere near them
-        for entity in self.world.entities().join() {
-            // Notify clients of the following components...
-            self.notify_comp::<Pos>(entity);
-            self.notify_comp::<Vel>(entity);
-            self.notify_comp::<Dir>(entity);
+    #[test]
+    fn other_protocol_versions_are_refused() {
+        let dir = DataDir::new("net-version");
+        let srv = Wrapper(RwLock::new(testutils::server(&dir)));
+        let version = Version {
+            protocol: PROTOCOL_VERSION + 1,
+            ..Version::current()
+        };
+        let (result, answer, _client) = connect(&srv, "future", version);
+        match result {
+            Err(Error::IncompatibleVersion(version)) => assert_eq!(version.protocol, PROTOCOL_VERSION + 1),
+            _ => panic!("expected the version to be refused"),
+        }
+        match answer {
+            ServerMsg::Disconnect {
+                reason: DisconnectReason::IncompatibleVersion { server, client },
+            } => {
+                assert_eq!(server, Version::current());
+                assert_eq!(client.protocol, PROTOCOL_VERSION + 1);
+            },
+            _ => panic!("expected to be told about the versions"),
         }
     }
 
-    pub(crate) fn sync_player_time(&self) { self.broadcast_net_msg(ServerMsg::TimeUpdate(self.clock_tick_time)); }
+    #[test]
+    fn lz4_only_if_the_client_lists_it() {
+        let dir = DataDir::new("net-lz4");
+        let srv = Wrapper(RwLock::new(testutils::server(&dir)));
+        let text = "a".repeat(64 * 1024);
+
+        // The bytes it takes to send the player a message that compresses well
+        let bytes_recv = |alias: &str, version: Version| {
+            let (result, _, client) = connect(&srv, alias, version);
+            let player = result.unwrap();
+            let before = client.stats().bytes_recv;
+            srv.do_for(|srv| srv.send_chat_msg(player, &text));
+            loop {
+                match client.await_incoming_timeout(Duration::from_secs(5)) {
+                    Ok(Incoming::Msg(ServerMsg::ChatMsg { text: ref recv })) if *recv == text => break,
+                    Ok(_) => {},
+                    Err(_) => panic!("expected the message"),
+                }
+            }
+            client.stats().bytes_recv - before
+        };
+        let mut without_lz4 = Version::current();
+        without_lz4.features.retain(|feature| feature != FEATURE_LZ4);
+        assert!(bytes_recv("packed", Version::current()) < 16 * 1024);
+        assert!(bytes_recv("plain", without_lz4) > 64 * 1024);
+    }
 }
@@ -0,0 +1,243 @@
+// Standard
+use std::{collections::BTreeMap, fmt, fs, io, path::PathBuf, str::FromStr};
+
+// Library
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use specs::Entity;
+
+// Local
+use crate::{cmd::Caller, db, player::Player, Payloads, Server};
+
+// Constants
+// Where the roles, bans and the roles needed for every command are kept, in the data directory
+pub(crate) const PERMS_FILE: &str = "permissions.toml";
+
+// Role
+
+// Every role may do what the ones below it may
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    Moderator,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Role::Player => "player",
+                Role::Moderator => "moderator",
+                Role::Admin => "admin",
+            }
+        )
+    }
+}
+
+impl Default for Role {
+    fn default() -> Role { Role::Player }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Role, ()> {
+        match s {
+            "player" => Ok(Role::Player),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// Permissions
+
+#[derive(Default, Serialize, Deserialize)]
+struct Config {
+    // Who may change blocks
+    #[serde(default)]
+    build: Role,
+    #[serde(default)]
+    commands: BTreeMap<String, Role>,
+    // Players without an entry are players
+    #[serde(default)]
+    roles: BTreeMap<String, Role>,
+    // The reason of every ban
+    #[serde(default)]
+    bans: BTreeMap<String, String>,
+}
+
+// The permissions config file. Roles and bans are kept by lowercase account name, or by alias for guests. Only accounts
+// get roles, anyone could join under a guest's alias. Changes are written right away
+pub struct Permissions {
+    path: PathBuf,
+    config: Mutex<Config>,
+}
+
+impl Permissions {
+    // Fills in the commands the file leaves out with their default role, and writes it back so it lists every command
+    // that can be adjusted
+    pub fn open<'a, P, I>(path: P, commands: I) -> io::Result<Permissions>
+    where
+        P: Into<PathBuf>,
+        I: IntoIterator<Item = (&'a str, Role)>,
+    {
+        let path = path.into();
+        let mut config = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e),
+        };
+        for (cmd, role) in commands {
+            config.commands.entry(cmd.to_string()).or_insert(role);
+        }
+        let perms = Permissions {
+            path,
+            config: Mutex::new(Config::default()),
+        };
+        perms.save(&config)?;
+        *perms.config.lock() = config;
+        Ok(perms)
+    }
+
+    fn save(&self, config: &Config) -> io::Result<()> {
+        let text = toml::to_string(config).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        db::write_atomic(&self.path, text.as_bytes())
+    }
+
+    // None for commands the file doesn't know of
+    pub fn required_role(&self, cmd: &str) -> Option<Role> { self.config.lock().commands.get(cmd).cloned() }
+
+    pub fn build_role(&self) -> Role { self.config.lock().build }
+
+    pub fn role_of(&self, key: &str) -> Role {
+        self.config
+            .lock()
+            .roles
+            .get(&key.to_lowercase())
+            .cloned()
+            .unwrap_or(Role::Player)
+    }
+
+    pub fn set_role(&self, key: &str, role: Role) -> io::Result<()> {
+        let mut config = self.config.lock();
+        match role {
+            Role::Player => config.roles.remove(&key.to_lowercase()),
+            role => config.roles.insert(key.to_lowercase(), role),
+        };
+        self.save(&config)
+    }
+
+    // The reason the player was banned for, if it is
+    pub fn ban_of(&self, key: &str) -> Option<String> { self.config.lock().bans.get(&key.to_lowercase()).cloned() }
+
+    pub fn ban(&self, key: &str, reason: &str) -> io::Result<()> {
+        let mut config = self.config.lock();
+        config.bans.insert(key.to_lowercase(), reason.to_string());
+        self.save(&config)
+    }
+
+    // Whether the player was banned
+    pub fn unban(&self, key: &str) -> io::Result<bool> {
+        let mut config = self.config.lock();
+        let banned = config.bans.remove(&key.to_lowercase()).is_some();
+        self.save(&config).map(|_| banned)
+    }
+}
+
+// Server
+
+impl<P: Payloads> Server<P> {
+    /// The role of a player, players without the `Player` component have none
+    pub(crate) fn role_of(&self, player: Entity) -> Option<Role> {
+        self.world.read_storage::<Player>().get(player).map(|p| p.role)
+    }
+
+    /// The role of whoever runs a command
+    pub(crate) fn role_of_caller(&self, caller: Caller) -> Option<Role> {
+        match caller {
+            Caller::Player(player) => self.role_of(player),
+            Caller::Console => Some(Role::Admin),
+        }
+    }
+
+    /// Whether the player's role allows it to change blocks
+    pub(crate) fn may_build(&self, player: Entity) -> bool {
+        self.role_of(player)
+            .map_or(false, |role| role >= self.perms.build_role())
+    }
+
+    /// Whether the caller's role allows it to use the command. Unknown commands are left to fail on their own
+    pub(crate) fn may_use(&self, caller: Caller, cmd: &str) -> bool {
+        match (self.perms.required_role(cmd), self.role_of_caller(caller)) {
+            (None, _) => true,
+            (Some(required), Some(role)) => role >= required,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Permissions, Role, PERMS_FILE};
+    use crate::testutils::DataDir;
+    use std::fs;
+
+    fn open(dir: &DataDir) -> Permissions {
+        fs::create_dir_all(&dir.0).unwrap();
+        Permissions::open(
+            dir.0.join(PERMS_FILE),
+            vec![("kick", Role::Admin), ("tp", Role::Moderator)],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn roles_and_bans_survive_a_restart() {
+        let dir = DataDir::new("perms-restart");
+        {
+            let perms = open(&dir);
+            assert_eq!(perms.role_of("alice"), Role::Player);
+            perms.set_role("Alice", Role::Moderator).unwrap();
+            perms.set_role("bob", Role::Admin).unwrap();
+            perms.ban("Mallory", "griefing").unwrap();
+        }
+
+        // Names are kept in lowercase
+        let perms = open(&dir);
+        assert_eq!(perms.role_of("ALICE"), Role::Moderator);
+        assert_eq!(perms.role_of("bob"), Role::Admin);
+        assert_eq!(perms.ban_of("mallory"), Some("griefing".to_string()));
+        assert_eq!(perms.ban_of("alice"), None);
+
+        perms.set_role("bob", Role::Player).unwrap();
+        assert!(perms.unban("mallory").unwrap());
+        assert!(!perms.unban("mallory").unwrap());
+        let perms = open(&dir);
+        assert_eq!(perms.role_of("bob"), Role::Player);
+        assert_eq!(perms.ban_of("mallory"), None);
+    }
+
+    #[test]
+    fn the_file_overrides_command_roles() {
+        let dir = DataDir::new("perms-commands");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join(PERMS_FILE), "[commands]\nkick = \"moderator\"\n").unwrap();
+
+        let perms = open(&dir);
+        assert_eq!(perms.required_role("kick"), Some(Role::Moderator));
+        assert_eq!(perms.required_role("tp"), Some(Role::Moderator));
+        assert_eq!(perms.required_role("fly"), None);
+        assert_eq!(perms.build_role(), Role::Player);
+
+        // Commands the file left out are written back
+        assert!(fs::read_to_string(dir.0.join(PERMS_FILE))
+            .unwrap()
+            .contains("tp = \"moderator\""));
+    }
+}
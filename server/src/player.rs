@@ -1,6 +1,3 @@
-// Standard
-use std::sync::Arc;
-
 // Library
 use specs::{Builder, Component, EntityBuilder, VecStorage};
 use vek::*;
@@ -10,12 +7,14 @@ use common::{
     ecs::{phys::Pos, CreateUtil, NetComp},
     util::{
         manager::Manager,
-        msg::{CompStore, PlayMode, ServerPostOffice},
+        msg::{CompStore, PlayMode, ResumeToken, ServerPostOffice},
     },
 };
 
 // Local
-use crate::{net::Client, Payloads, Server};
+use crate::{
+    interest::Interest, movement::Movement, net::Client, perms::Role, terrain::ChunkRequests, Payloads, Server,
+};
 
 // Player
 
@@ -23,6 +22,14 @@ use crate::{net::Client, Payloads, Server};
 pub struct Player {
     pub alias: String,
     pub mode: PlayMode,
+    // None for guests
+    pub account: Option<String>,
+    pub role: Role,
+}
+
+impl Player {
+    // What the player is saved under
+    pub fn key(&self) -> &str { self.account.as_ref().unwrap_or(&self.alias) }
 }
 
 impl Component for Player {
@@ -44,17 +51,29 @@ impl<P: Payloads> Server<P> {
     pub(crate) fn create_player(
         &mut self,
         alias: String,
+        account: Option<String>,
         mode: PlayMode,
         po: Manager<ServerPostOffice>,
+        resume_token: ResumeToken,
     ) -> EntityBuilder {
+        // Guests are always players
+        let role = account
+            .as_ref()
+            .map_or(Role::Player, |account| self.perms.role_of(account));
         match mode {
             PlayMode::Headless => self.world.create_entity(),
             PlayMode::Character => self.world.create_character(alias.clone()),
         }
-        .with(Player { alias, mode })
-        .with(Client {
-            postoffice: Arc::new(po),
+        .with(Player {
+            alias,
+            mode,
+            account,
+            role,
         })
+        .with(Client::new(po, resume_token))
+        .with(Interest::default())
+        .with(Movement::new())
+        .with(ChunkRequests::default())
         .with(Pos(Vec3::new(0.0, 0.0, 215.0)))
     }
 }
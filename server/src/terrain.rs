@@ -0,0 +1,337 @@
+// Standard
+use std::{collections::HashSet, sync::Arc};
+
+// Library
+use parking_lot::{Mutex, RwLock};
+use specs::{Component, Entity, Join, VecStorage};
+use vek::*;
+
+// Project
+use common::{
+    ecs::phys::Pos,
+    net::PRIO_MIN,
+    terrain::{
+        self,
+        chunk::{Block, ChunkContainer, CHUNK_SIZE},
+        BlockLoader, ChunkMgr, Container, RegionStore, VolCluster, VolGen, VolOffs, VoxAbs,
+    },
+    util::msg::{PlayMode, ServerMsg},
+};
+use world::World as WorldGen;
+
+// Local
+use crate::{interest::VIEW_DISTANCE, net::Client, player::Player, Payloads, Server};
+
+// Constants
+// Where the terrain is saved in region files, in the data directory
+pub(crate) const WORLD_DIR: &str = "world";
+// How far around every player the terrain is kept loaded, in blocks
+const LOAD_DISTANCE: VoxAbs = 64;
+// How many chunks a client may have requested that aren't loaded yet, it asks again for the others
+const MAX_PENDING_REQUESTS: usize = 64;
+// How far from the player chunks may be requested, horizontally and in chunks
+const REQUEST_DISTANCE: VolOffs = VIEW_DISTANCE as VolOffs / CHUNK_SIZE.x as VolOffs + 1;
+// Clients drop chunks only a few chunks beyond the ones they requested
+const KEEP_DISTANCE: VolOffs = REQUEST_DISTANCE + 3;
+// How far from its position a player can change blocks
+const REACH_DISTANCE: f32 = 8.0;
+
+// Chunks a client requested that weren't loaded yet. They are loaded for it, and sent once they are
+#[derive(Debug, Default)]
+pub struct ChunkRequests {
+    pending: HashSet<Vec3<VolOffs>>,
+}
+
+impl Component for ChunkRequests {
+    type Storage = VecStorage<Self>;
+}
+
+// Chunks that were saved are loaded, the others generated
+fn gen_chunk<C: Send + Sync + 'static>(
+    regions: &RegionStore,
+    pos: Vec3<VolOffs>,
+    con: Arc<Mutex<Option<ChunkContainer<C>>>>,
+) {
+    let chunk = match regions.load(pos) {
+        Ok(Some(chunk)) => chunk,
+        _ => WorldGen::gen_chunk(pos.map(|e| e as i32)),
+    };
+    *con.lock() = Some(ChunkContainer::new(chunk));
+}
+
+fn gen_payload<C: Send + Sync + 'static>(_pos: Vec3<VolOffs>, _con: Arc<Mutex<Option<ChunkContainer<C>>>>) {}
+
+fn drop_chunk<C: Send + Sync + 'static>(regions: &RegionStore, pos: Vec3<VolOffs>, con: Arc<ChunkContainer<C>>) {
+    let _ = regions.save(pos, &mut con.data_mut());
+}
+
+fn drop_payload<C: Send + Sync + 'static>(_pos: Vec3<VolOffs>, _con: Arc<ChunkContainer<C>>) {}
+
+// Chunks are loaded in whole columns, only the horizontal distance counts
+fn chunk_dist(player_pos: &Pos, chunk: Vec3<VolOffs>) -> VolOffs {
+    let player_chunk = terrain::voxabs_to_voloffs(player_pos.0.map(|e| e as VoxAbs), CHUNK_SIZE);
+    Vec2::<VolOffs>::from(chunk - player_chunk)
+        .map(|e| e.abs())
+        .reduce_max()
+}
+
+pub(crate) fn new_chunk_mgr<C: Send + Sync + 'static>(regions: Arc<RegionStore>) -> ChunkMgr<C> {
+    let load = regions.clone();
+    ChunkMgr::new(
+        CHUNK_SIZE,
+        VolGen::new(
+            move |pos, con| gen_chunk(&load, pos, con),
+            gen_payload::<C>,
+            move |pos, con| drop_chunk(&regions, pos, con),
+            drop_payload::<C>,
+        ),
+    )
+}
+
+// Server
+
+impl<P: Payloads> Server<P> {
+    /// Keep the terrain around the players and the chunks they requested loaded. Returns the chunk manager to
+    /// maintain, which may take a while
+    pub(crate) fn load_chunks(&self) -> Arc<ChunkMgr<P::Chunk>> {
+        let mut loaders = self.chunk_mgr.block_loader_mut();
+        loaders.clear();
+        for (pos, _) in (&self.world.read_storage::<Pos>(), &self.world.read_storage::<Client>()).join() {
+            loaders.push(Arc::new(RwLock::new(BlockLoader {
+                pos: pos.0.map(|e| e as VoxAbs),
+                size: Vec3::broadcast(LOAD_DISTANCE),
+            })));
+        }
+        for requests in self.world.read_storage::<ChunkRequests>().join() {
+            for pos in &requests.pending {
+                loaders.push(Arc::new(RwLock::new(BlockLoader {
+                    pos: terrain::voloffs_to_voxabs(*pos, CHUNK_SIZE),
+                    size: Vec3::zero(),
+                })));
+            }
+        }
+        drop(loaders);
+        self.chunk_mgr.clone()
+    }
+
+    /// A client asks for a chunk, it's sent as soon as it's loaded. Only chunks the player could see are sent
+    pub(crate) fn request_chunk(&mut self, player: Entity, pos: Vec3<VolOffs>) {
+        let near = match self.world.read_storage::<Pos>().get(player) {
+            Some(player_pos) => chunk_dist(player_pos, pos) <= REQUEST_DISTANCE,
+            None => false,
+        };
+        if !near || self.send_chunk(player, pos) {
+            return;
+        }
+        if let Some(requests) = self.world.write_storage::<ChunkRequests>().get_mut(player) {
+            if requests.pending.len() < MAX_PENDING_REQUESTS {
+                requests.pending.insert(pos);
+            }
+        }
+    }
+
+    /// Send the requested chunks that were loaded since
+    pub(crate) fn send_requested_chunks(&mut self) {
+        for (player, requests) in (&self.world.entities(), &mut self.world.write_storage::<ChunkRequests>()).join() {
+            requests.pending.retain(|pos| !self.send_chunk(player, *pos));
+        }
+    }
+
+    // False if the chunk isn't loaded
+    fn send_chunk(&self, player: Entity, pos: Vec3<VolOffs>) -> bool {
+        let con = match self.chunk_mgr.pers(|p| *p == pos).remove(&pos) {
+            Some(con) => con,
+            None => return false,
+        };
+        let data = match con.data_mut().to_bytes() {
+            Ok(data) => data,
+            Err(_) => return true,
+        };
+        if let Some(client) = self.world.read_storage::<Client>().get(player) {
+            // Terrain is bulky, it mustn't hold up anything else
+            let _ = client
+                .postoffice
+                .send_one_with_priority(ServerMsg::ChunkData { pos, data }, PRIO_MIN);
+        }
+        true
+    }
+
+    /// A player wants to change a block, which it may if its role allows it to build, it's in reach and plays a
+    /// character. Every client that may have the chunk is told about the change, including the player's
+    pub(crate) fn handle_set_block(&self, player: Entity, pos: Vec3<VoxAbs>, block: Block) {
+        if !block.is_valid() || !self.may_build(player) {
+            return;
+        }
+        let allowed = match (
+            self.world.read_storage::<Player>().get(player),
+            self.world.read_storage::<Pos>().get(player),
+        ) {
+            (
+                Some(Player {
+                    mode: PlayMode::Character,
+                    ..
+                }),
+                Some(player_pos),
+            ) => (pos.map(|e| e as f32) + 0.5 - player_pos.0).magnitude() <= REACH_DISTANCE,
+            _ => false,
+        };
+        if allowed && self.chunk_mgr.set_block(pos, block).is_some() {
+            self.broadcast_block(pos, block);
+            // It's written with the next save, instead of only once the chunk is unloaded
+            self.save_chunk(terrain::voxabs_to_voloffs(pos, CHUNK_SIZE));
+        }
+    }
+
+    fn broadcast_block(&self, pos: Vec3<VoxAbs>, block: Block) {
+        let chunk = terrain::voxabs_to_voloffs(pos, CHUNK_SIZE);
+        for (client, player_pos) in (&self.world.read_storage::<Client>(), &self.world.read_storage::<Pos>()).join() {
+            if chunk_dist(player_pos, chunk) <= KEEP_DISTANCE {
+                let _ = client
+                    .postoffice
+                    .send_one_with_priority(ServerMsg::BlockUpdate { pos, block }, PRIO_MIN);
+            }
+        }
+    }
+
+    fn save_chunk(&self, pos: Vec3<VolOffs>) {
+        if let Some(con) = self.chunk_mgr.pers(|p| *p == pos).remove(&pos) {
+            let _ = self.regions.save(pos, &mut con.data_mut());
+        }
+    }
+
+    /// Write the chunks that were changed or unloaded since the last save to disk
+    pub(crate) fn save_terrain(&self) { let _ = self.regions.flush(); }
+
+    /// Save every loaded chunk, e.g. before the server shuts down
+    pub(crate) fn save_all_terrain(&self) {
+        for (pos, con) in self.chunk_mgr.pers(|_| true) {
+            let _ = self.regions.save(pos, &mut con.data_mut());
+        }
+        self.save_terrain();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::REQUEST_DISTANCE;
+    use crate::{
+        perms::{Role, PERMS_FILE},
+        testutils::{self, DataDir},
+        Wrapper,
+    };
+    use common::{
+        terrain::{
+            chunk::{Block, BlockMat},
+            Voxel,
+        },
+        util::msg::{PlayMode, ServerMsg},
+    };
+    use parking_lot::RwLock;
+    use std::{
+        fs,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+    use vek::*;
+
+    #[test]
+    fn set_block_checks_role_and_block() {
+        let dir = DataDir::new("terrain-set-block");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join(PERMS_FILE), "build = \"moderator\"\n").unwrap();
+        let mut srv = testutils::server(&dir);
+        srv.perms.set_role("builder", Role::Moderator).unwrap();
+        let (builder, builder_client) = testutils::connect(&mut srv, "builder", Some("builder"), PlayMode::Character);
+        let (guest, guest_client) = testutils::connect(&mut srv, "guest", None, PlayMode::Character);
+
+        let pos = Vec3::new(0, 0, 214);
+        for _ in 0..500 {
+            if srv.chunk_mgr.exists_block(pos) {
+                break;
+            }
+            srv.load_chunks().maintain();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let before = srv.chunk_mgr.get_block(pos);
+        let updates = |client: &testutils::TestClient| {
+            client
+                .recv_all()
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    ServerMsg::BlockUpdate { pos, block } => Some((pos, block)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        updates(&builder_client);
+
+        // Players below the role the permissions file asks for can't build
+        srv.handle_set_block(guest, pos, Block::GOLD);
+        assert_eq!(srv.chunk_mgr.get_block(pos), before);
+        assert!(updates(&guest_client).is_empty());
+
+        // Blocks in no encoding are refused
+        srv.handle_set_block(builder, pos, Block::new(BlockMat { grad: 0x00, index: 1 }));
+        srv.handle_set_block(builder, pos, Block::new(BlockMat { grad: 0x81, index: 1 }));
+        assert_eq!(srv.chunk_mgr.get_block(pos), before);
+        assert!(updates(&builder_client).is_empty());
+
+        // Everyone nearby hears of a change that is allowed
+        srv.handle_set_block(builder, pos, Block::GOLD);
+        assert_eq!(srv.chunk_mgr.get_block(pos), Some(Block::GOLD));
+        assert_eq!(updates(&builder_client), vec![(pos, Block::GOLD)]);
+        assert_eq!(updates(&guest_client), vec![(pos, Block::GOLD)]);
+    }
+
+    #[test]
+    fn request_chunks_while_loading() {
+        let dir = DataDir::new("terrain-requests");
+        let mut srv = testutils::server(&dir);
+        let (player, client) = testutils::connect(&mut srv, "digger", None, PlayMode::Headless);
+        let srv = Arc::new(Wrapper(RwLock::new(srv)));
+
+        // The terrain worker, while the post worker handles the requests
+        let running = Arc::new(AtomicBool::new(true));
+        let terrain = {
+            let (srv, running) = (srv.clone(), running.clone());
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    srv.do_for(|srv| srv.load_chunks()).maintain();
+                    srv.do_for_mut(|srv| srv.send_requested_chunks());
+                }
+            })
+        };
+        let requested = (-1..=1)
+            .flat_map(|x| (-1..=1).map(move |y| Vec3::new(x, y, 6)))
+            .chain(Some(Vec3::new(REQUEST_DISTANCE + 1, 0, 6)))
+            .collect::<Vec<_>>();
+        for _ in 0..20 {
+            for pos in &requested {
+                srv.do_for_mut(|srv| srv.request_chunk(player, *pos));
+            }
+        }
+
+        let mut sent = vec![];
+        for _ in 0..50 {
+            sent.extend(client.recv_all().into_iter().filter_map(|msg| match msg {
+                ServerMsg::ChunkData { pos, .. } => Some(pos),
+                _ => None,
+            }));
+            if sent.len() >= requested.len() - 1 {
+                break;
+            }
+        }
+        running.store(false, Ordering::Relaxed);
+        terrain.join().unwrap();
+
+        // Every chunk near the player arrives, the one too far away doesn't
+        for pos in &requested[..requested.len() - 1] {
+            assert!(sent.contains(pos));
+        }
+        assert!(!sent.contains(&requested[requested.len() - 1]));
+    }
+}
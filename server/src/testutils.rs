@@ -0,0 +1,83 @@
+// Reexports
+pub use common::util::testutils::DataDir;
+
+// Standard
+use std::{sync::mpsc, time::Duration};
+
+// Library
+use parking_lot::Mutex;
+use specs::{Builder, Entity};
+
+// Project
+use common::{
+    net::{Memory, MemoryListener},
+    util::{
+        manager::Manager,
+        msg::{ClientPostOffice, PlayMode, ResumeToken, ServerMsg, ServerPostOffice},
+        post::Incoming,
+    },
+};
+
+// Local
+use crate::{Payloads, Server};
+
+pub struct TestPayloads;
+
+impl Payloads for TestPayloads {
+    type Chunk = ();
+    type Entity = ();
+    type Client = ();
+}
+
+// A server without any workers, the test drives it
+pub fn server(dir: &DataDir) -> Server<TestPayloads> { server_with(TestPayloads, dir) }
+
+pub fn server_with<P: Payloads>(payload: P, dir: &DataDir) -> Server<P> {
+    Server::open(payload, MemoryListener::new(), &dir.0).unwrap()
+}
+
+// The client's end of a player's connection
+pub struct TestClient {
+    // Keeps the connection open
+    _po: Manager<ClientPostOffice>,
+    inbox: mpsc::Receiver<ServerMsg>,
+}
+
+impl TestClient {
+    // The one-off messages the server sent so far, waits until nothing arrived for a moment
+    pub fn recv_all(&self) -> Vec<ServerMsg> {
+        let mut msgs = vec![];
+        while let Ok(msg) = self.inbox.recv_timeout(Duration::from_millis(100)) {
+            msgs.push(msg);
+        }
+        msgs
+    }
+}
+
+// Add a player like a client that finished the handshake
+pub fn connect<P: Payloads>(
+    srv: &mut Server<P>,
+    alias: &str,
+    account: Option<&str>,
+    mode: PlayMode,
+) -> (Entity, TestClient) {
+    let (remote, local) = Memory::pair();
+    let po = ClientPostOffice::to_server_with(Box::new(remote)).unwrap();
+    let (send, inbox) = mpsc::channel();
+    let send = Mutex::new(send);
+    po.forward_incoming(move |incoming| {
+        if let Incoming::Msg(msg) = incoming {
+            let _ = send.lock().send(msg);
+        }
+    });
+    let player = srv
+        .create_player(
+            alias.to_string(),
+            account.map(|account| account.to_string()),
+            mode,
+            ServerPostOffice::to_client_with(Box::new(local), srv.identity.clone()).unwrap(),
+            ResumeToken::generate(),
+        )
+        .build();
+    (player, TestClient { _po: po, inbox })
+}
@@ -9,6 +9,7 @@ impl<P: Payloads> Server<P> {
     pub fn tick_once(&mut self, _dt: Duration) {
         // Sync entities with connected players
         self.sync_players();
+        self.expire_detached();
 
         self.world.maintain();
     }
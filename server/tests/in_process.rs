@@ -0,0 +1,141 @@
+// Standard
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Library
+use parking_lot::Mutex;
+use vek::*;
+
+// Project
+use client::{Client, ClientEvent, Credentials, PlayMode, ServerKey};
+use common::{
+    audio::{AudioGen, Buffer, Stream},
+    net::{MemoryListener, Protocol},
+    terrain::{chunk::ChunkContainer, VolOffs},
+    util::testutils::DataDir,
+};
+use server::{api::Api, player::Player, specs::Join, Server};
+
+const CLIENTS: usize = 4;
+
+struct NoAudio;
+
+impl AudioGen for NoAudio {
+    fn gen_stream(&self, _id: u64, _buffer: &Buffer, _stream: &Stream) {}
+
+    fn gen_buffer(&self, _id: u64, _buffer: &Buffer) {}
+
+    fn drop_stream(&self, _id: u64, _buffer: &Buffer, _stream: &Stream) {}
+
+    fn drop_buffer(&self, _id: u64, _buffer: &Buffer) {}
+}
+
+struct ClientPayloads;
+
+impl client::Payloads for ClientPayloads {
+    type Chunk = ();
+    type Entity = ();
+    type Audio = NoAudio;
+}
+
+struct ServerPayloads;
+
+impl server::Payloads for ServerPayloads {
+    type Chunk = ();
+    type Entity = ();
+    type Client = ();
+}
+
+fn gen_payload(_pos: Vec3<VolOffs>, _con: Arc<Mutex<Option<ChunkContainer<()>>>>) {}
+
+fn drop_payload(_pos: Vec3<VolOffs>, _con: Arc<ChunkContainer<()>>) {}
+
+// Waits for the server's and the clients' workers
+fn wait_for<F: FnMut() -> bool>(mut f: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if f() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+// Whether `f` returns within a few seconds
+fn finishes<F: FnOnce() + Send + 'static>(f: F) -> bool {
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        f();
+        let _ = send.send(());
+    });
+    recv.recv_timeout(Duration::from_secs(3)).is_ok()
+}
+
+#[test]
+fn server_and_clients_in_process() {
+    let dir = DataDir::new("in-process");
+    let listener = MemoryListener::new();
+    let server = Server::with_listener(ServerPayloads, listener.clone(), &dir.0).unwrap();
+
+    let clients = (0..CLIENTS)
+        .map(|i| {
+            Client::<ClientPayloads>::with_transport(
+                PlayMode::Headless,
+                format!("player{}", i),
+                Credentials::Guest,
+                Box::new(listener.connect().unwrap()),
+                ServerKey::Pinned(server.do_for(|srv| srv.public_key())),
+                gen_payload,
+                drop_payload,
+                Arc::new(NoAudio),
+                0,
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        server.do_for(|srv| srv.world().read_storage::<Player>().join().count()),
+        CLIENTS
+    );
+
+    // Everyone hears everyone else
+    for (i, client) in clients.iter().enumerate() {
+        client.send_chat_msg(format!("hello from {}", i));
+    }
+    for client in &clients {
+        let mut heard = vec![];
+        assert!(wait_for(|| {
+            for event in client.get_events() {
+                if let ClientEvent::RecvChatMsg { text } = event {
+                    heard.push(text);
+                }
+            }
+            (0..CLIENTS).all(|i| heard.contains(&format!("[player{}] hello from {}", i, i)))
+        }));
+    }
+
+    drop(clients);
+    drop(server);
+}
+
+#[test]
+fn idle_connections_dont_hold_up_shutdown() {
+    let dir = DataDir::new("idle");
+    let listener = MemoryListener::new();
+    let server = Server::with_listener(ServerPayloads, listener.clone(), &dir.0).unwrap();
+
+    // Connects, but never says what it wants
+    let idle = listener.connect().unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    assert!(
+        finishes(move || drop(server)),
+        "the server waits for the idle connection"
+    );
+    // The server hung up on it, after whatever it sent last
+    assert!(finishes(move || while idle.recv().is_ok() {}));
+}
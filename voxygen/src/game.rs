@@ -20,7 +20,7 @@ use vek::*;
 type FnvIndexMap<K, V> = IndexMap<K, V, FnvBuildHasher>;
 
 // Project
-use client::{self, Client, ClientEvent, PlayMode, CHUNK_SIZE};
+use client::{self, Client, ClientEvent, Credentials, PlayMode, CHUNK_SIZE};
 use common::{
     get_asset_path,
     terrain::{
@@ -99,22 +99,32 @@ fn to_4x4(v: &Mat4<f32>) -> [[f32; 4]; 4] {
     out
 }
 
+fn mesh(chunk: &Chunk) -> ChunkPayload {
+    ChunkPayload::Meshes(match *chunk {
+        Chunk::Homo(ref homo) => voxel::Mesh::from(homo),
+        Chunk::Hetero(ref hetero) => voxel::Mesh::from(hetero),
+        Chunk::Rle(ref rle) => voxel::Mesh::from(rle),
+        Chunk::HeteroAndRle(ref hetero, _) => voxel::Mesh::from(hetero),
+    })
+}
+
 fn gen_payload(_key: Vec3<VolOffs>, con: Arc<Mutex<Option<ChunkContainer<<Payloads as client::Payloads>::Chunk>>>>) {
     let conlock = con.lock();
     if let Some(ref con) = *conlock {
-        *con.payload_mut() = Some(ChunkPayload::Meshes(match *con.data() {
-            Chunk::Homo(ref homo) => voxel::Mesh::from(homo),
-            Chunk::Hetero(ref hetero) => voxel::Mesh::from(hetero),
-            Chunk::Rle(ref rle) => voxel::Mesh::from(rle),
-            Chunk::HeteroAndRle(ref hetero, _) => voxel::Mesh::from(hetero),
-        }));
+        *con.payload_mut() = Some(mesh(&con.data()));
     }
 }
 
 fn drop_payload(_key: Vec3<VolOffs>, _con: Arc<ChunkContainer<<Payloads as client::Payloads>::Chunk>>) {}
 
 impl Game {
-    pub fn new<R: ToSocketAddrs>(mode: PlayMode, alias: &str, remote_addr: R, view_distance: i64) -> Game {
+    pub fn new<R: ToSocketAddrs>(
+        mode: PlayMode,
+        alias: &str,
+        credentials: Credentials,
+        remote_addr: R,
+        view_distance: i64,
+    ) -> Game {
         let window = RenderWindow::new();
         let info = window.get_renderer_info();
         println!(
@@ -128,6 +138,7 @@ impl Game {
         let client = Client::new(
             mode,
             alias.to_string(),
+            credentials,
             remote_addr,
             gen_payload,
             drop_payload,
@@ -373,6 +384,12 @@ impl Game {
         {
             let trylock = &mut con.payload_try_mut(); //we try to lock it, if it is already written to we just ignore this chunk for a frame
             if let Some(ref mut lock) = trylock {
+                // chunks lose their payload when a block in them changes, mesh them again
+                if lock.is_none() {
+                    if let Some(data) = con.data_try() {
+                        **lock = Some(mesh(&data));
+                    }
+                }
                 //sometimes payload does not exist, dont render then
                 if let Some(ref mut payload) = **lock {
                     if let ChunkPayload::Meshes(ref mut mesh) = payload {
@@ -406,6 +423,20 @@ impl Game {
 
         events.drain(..).for_each(|event| match event {
             ClientEvent::RecvChatMsg { text } => self.hud.chat_box().add_chat_msg(text),
+            ClientEvent::CmdResult { lines, .. } => {
+                for line in lines {
+                    self.hud.chat_box().add_chat_msg(line);
+                }
+            },
+            ClientEvent::ConnectionLost => self
+                .hud
+                .chat_box()
+                .add_chat_msg("Lost connection to the server, reconnecting...".to_string()),
+            ClientEvent::Reconnected => self.hud.chat_box().add_chat_msg("Reconnected".to_string()),
+            ClientEvent::Disconnected { reason } => self.hud.chat_box().add_chat_msg(match reason {
+                Some(reason) => format!("Disconnected: {}", reason),
+                None => "Lost connection to the server".to_string(),
+            }),
         });
     }
 
@@ -414,7 +445,10 @@ impl Game {
 
         events.drain(..).for_each(|event| match event {
             HudEvent::ChatMsgSent { text } => {
-                if text.len() > 0 {
+                if text.starts_with('/') {
+                    self.client
+                        .send_cmd(text[1..].split_whitespace().map(str::to_string).collect());
+                } else if text.len() > 0 {
                     self.client.send_chat_msg(text);
                 }
             },
@@ -597,6 +631,20 @@ impl Game {
             .unwrap_or("Unknown position".to_string());
         self.hud.debug_box().pos_label.set_text(pos_text);
 
+        let stats = self.client.net_stats();
+        let ping_text = match stats.rtt {
+            Some(rtt) => format!("Ping: {} ms (±{} ms)", rtt.as_millis(), stats.rtt_jitter.as_millis()),
+            None => "Ping: unknown".to_string(),
+        };
+        self.hud.debug_box().ping_label.set_text(ping_text);
+        self.hud.debug_box().traffic_label.set_text(format!(
+            "Net: {} kB out, {} kB in, {} queued, {} retransmits",
+            stats.bytes_sent / 1000,
+            stats.bytes_recv / 1000,
+            stats.frames_queued,
+            stats.retransmits
+        ));
+
         self.hud.render(&mut renderer);
 
         self.window.swap_buffers();
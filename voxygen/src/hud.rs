@@ -153,6 +153,8 @@ pub struct DebugBox {
     pub buildtime_label: Rc<Label>,
     pub fps_label: Rc<Label>,
     pub pos_label: Rc<Label>,
+    pub ping_label: Rc<Label>,
+    pub traffic_label: Rc<Label>,
     vbox: Rc<VBox>,
 }
 
@@ -178,6 +180,8 @@ impl DebugBox {
         let buildtime_label = vbox.push_back(template_label.clone_all());
         let fps_label = vbox.push_back(template_label.clone_all());
         let pos_label = vbox.push_back(template_label.clone_all());
+        let ping_label = vbox.push_back(template_label.clone_all());
+        let traffic_label = vbox.push_back(template_label.clone_all());
 
         Self {
             version_label,
@@ -185,6 +189,8 @@ impl DebugBox {
             buildtime_label,
             fps_label,
             pos_label,
+            ping_label,
+            traffic_label,
             vbox,
         }
     }
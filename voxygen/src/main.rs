@@ -1,4 +1,4 @@
-#![feature(nll, euclidean_division, arbitrary_self_types, duration_float)]
+#![feature(nll, euclidean_division, arbitrary_self_types, duration_float, duration_as_u128)]
 
 // Graphics
 #[macro_use]
@@ -43,7 +43,7 @@ use chrono::{DateTime, TimeZone, Utc};
 use parking_lot::Mutex;
 
 // Project
-use client::PlayMode;
+use client::{account, Credentials, PlayMode};
 use common::get_version;
 
 // Local
@@ -117,15 +117,23 @@ fn main() {
         remote_addr = remote_addr.trim().to_string();
     }
 
-    println!("What name do you want to use?");
+    // Play as the account login-cli logged in to, or as a guest
+    let credentials = account::stored_login().unwrap_or(Credentials::Guest);
     let mut name_choice = String::new();
-    io::stdout().flush().expect("Failed to flush");
-    io::stdin().read_line(&mut name_choice).unwrap();
-    let mut name_choice = name_choice.trim();
-    if name_choice.len() == 0 {
-        println!("No name chosen, generating random one...");
-        name_choice = common::util::names::generate();
-    }
+    let name_choice = if let Credentials::Token { account, .. } = &credentials {
+        println!("Playing as {}, log in to another account with login-cli", account);
+        account.as_str()
+    } else {
+        println!("What name do you want to use?");
+        io::stdout().flush().expect("Failed to flush");
+        io::stdin().read_line(&mut name_choice).unwrap();
+        let mut name_choice = name_choice.trim();
+        if name_choice.len() == 0 {
+            println!("No name chosen, generating random one...");
+            name_choice = common::util::names::generate();
+        }
+        name_choice
+    };
 
     println!("");
     println!("What view distance do you want to use?");
@@ -147,5 +155,12 @@ fn main() {
     // wait 100ms to give the user time to lift their finger up from the enter key so the chat isn't opened immediately after start
     thread::sleep(Duration::from_millis(100));
 
-    Game::new(PlayMode::Character, name_choice, remote_addr, view_distance).run();
+    Game::new(
+        PlayMode::Character,
+        name_choice,
+        credentials.clone(),
+        remote_addr,
+        view_distance,
+    )
+    .run();
 }
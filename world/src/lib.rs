@@ -39,7 +39,8 @@ pub trait Gen<S> {
 }
 
 // Seed - used during worldgen initiation
-static SEED: AtomicU32 = AtomicU32::new(0);
+const WORLD_SEED: u32 = 0;
+static SEED: AtomicU32 = AtomicU32::new(WORLD_SEED);
 pub fn new_seed() -> u32 { SEED.fetch_add(1, Ordering::Relaxed) }
 
 lazy_static! {
@@ -49,6 +50,9 @@ lazy_static! {
 pub struct World;
 
 impl World {
+    // Worlds with the same seed generate the same chunks
+    pub fn seed() -> u32 { WORLD_SEED }
+
     pub fn gen_chunk(offs: Vec3<i32>) -> Chunk {
         // If the chunk is out of bounds, just generate air
         if offs.z < 0 || offs.z > 512 / CHUNK_SIZE.z as i32 {